@@ -0,0 +1,161 @@
+use image::{DynamicImage, GenericImageView, RgbImage};
+
+use crate::settings::Crop;
+
+/// Longest side of the copy of the image where its interesting parts are looked for
+const ANALYSIS_SIZE: u32 = 256;
+/// Levels of luminance counted by the entropy
+const ENTROPY_BINS: usize = 64;
+
+/// Where the window of width x height pixels of image to keep starts, choosing the most
+/// interesting part of the image according to crop. The window covers the whole image along
+/// one axis and slides along the other; among the windows as interesting, the one closest to
+/// the center wins, so that a uniform image is cropped in the center.
+pub fn position(image: &DynamicImage, width: u32, height: u32, crop: Crop) -> (u32, u32) {
+    let (image_width, image_height) = image.dimensions();
+    let center = ((image_width - width) / 2, (image_height - height) / 2);
+    if crop == Crop::Center || (width, height) == (image_width, image_height) {
+        return center;
+    }
+    let horizontal = width < image_width;
+    let reduce = |size: u32| {
+        (u64::from(size) * u64::from(ANALYSIS_SIZE) / u64::from(image_width.max(image_height)))
+            .max(1) as u32
+    };
+    let thumbnail = if image_width.max(image_height) > ANALYSIS_SIZE {
+        image
+            .thumbnail_exact(reduce(image_width), reduce(image_height))
+            .into_rgb8()
+    } else {
+        image.to_rgb8()
+    };
+    let (length, image_length, window) = if horizontal {
+        (thumbnail.width(), image_width, width)
+    } else {
+        (thumbnail.height(), image_height, height)
+    };
+    let window = ((u64::from(window) * u64::from(length) / u64::from(image_length)) as u32)
+        .clamp(1, length) as usize;
+    let scores = match crop {
+        Crop::Entropy => entropy_scores(&thumbnail, horizontal, window),
+        _ => attention_scores(&thumbnail, horizontal, window),
+    };
+
+    // Try the windows from the center outwards, keeping the first best one
+    let middle = scores.len() / 2;
+    let mut best = middle;
+    for distance in 1..=middle.max(scores.len() - middle) {
+        for offset in [middle.checked_sub(distance), Some(middle + distance)] {
+            if let Some(offset) = offset.filter(|offset| *offset < scores.len()) {
+                if scores[offset] > scores[best] {
+                    best = offset;
+                }
+            }
+        }
+    }
+    let start = (best as u64 * u64::from(image_length) / u64::from(length)) as u32;
+    if horizontal {
+        (start.min(image_width - width), 0)
+    } else {
+        (0, start.min(image_height - height))
+    }
+}
+
+fn luma(pixel: [u8; 3]) -> u32 {
+    (u32::from(pixel[0]) * 299 + u32::from(pixel[1]) * 587 + u32::from(pixel[2]) * 114) / 1000
+}
+
+/// The pixels of image along the axis the window slides on, each as the pixels across it
+fn lines(image: &RgbImage, horizontal: bool) -> Vec<Vec<[u8; 3]>> {
+    let (width, height) = image.dimensions();
+    if horizontal {
+        (0..width)
+            .map(|x| (0..height).map(|y| image.get_pixel(x, y).0).collect())
+            .collect()
+    } else {
+        (0..height)
+            .map(|y| (0..width).map(|x| image.get_pixel(x, y).0).collect())
+            .collect()
+    }
+}
+
+/// The entropy of the luminance of each window: the windows with the most detail score
+/// higher than those of a plain sky or wall
+fn entropy_scores(image: &RgbImage, horizontal: bool, window: usize) -> Vec<f64> {
+    let histograms: Vec<[u32; ENTROPY_BINS]> = lines(image, horizontal)
+        .into_iter()
+        .map(|line| {
+            let mut histogram = [0; ENTROPY_BINS];
+            for pixel in line {
+                histogram[luma(pixel) as usize * ENTROPY_BINS / 256] += 1;
+            }
+            histogram
+        })
+        .collect();
+    let mut histogram = [0u32; ENTROPY_BINS];
+    let mut scores = Vec::new();
+    for (i, line) in histograms.iter().enumerate() {
+        for (count, line) in histogram.iter_mut().zip(line) {
+            *count += line;
+        }
+        if i >= window {
+            for (count, line) in histogram.iter_mut().zip(&histograms[i - window]) {
+                *count -= line;
+            }
+        }
+        if i + 1 >= window {
+            let total: u32 = histogram.iter().sum();
+            scores.push(
+                histogram
+                    .iter()
+                    .filter(|count| **count > 0)
+                    .map(|count| {
+                        let p = f64::from(*count) / f64::from(total);
+                        -p * p.log2()
+                    })
+                    .sum(),
+            );
+        }
+    }
+    scores
+}
+
+/// How much each window draws the eye: its edges, its saturated colors and its skin tones,
+/// which favor the people in the image
+fn attention_scores(image: &RgbImage, horizontal: bool, window: usize) -> Vec<f64> {
+    let (width, height) = image.dimensions();
+    let luma_at = |x: i64, y: i64| {
+        let x = x.clamp(0, i64::from(width) - 1) as u32;
+        let y = y.clamp(0, i64::from(height) - 1) as u32;
+        luma(image.get_pixel(x, y).0) as i64
+    };
+    let mut line_scores = vec![0u64; if horizontal { width } else { height } as usize];
+    for (x, y, pixel) in image.enumerate_pixels() {
+        let (xi, yi) = (i64::from(x), i64::from(y));
+        let edge = (4 * luma_at(xi, yi)
+            - luma_at(xi - 1, yi)
+            - luma_at(xi + 1, yi)
+            - luma_at(xi, yi - 1)
+            - luma_at(xi, yi + 1))
+        .unsigned_abs();
+        let [r, g, b] = pixel.0;
+        let saturation = u64::from(r.max(g).max(b) - r.min(g).min(b));
+        let skin = if is_skin(r, g, b) { 255 } else { 0 };
+        line_scores[if horizontal { x } else { y } as usize] += edge + saturation + skin;
+    }
+    line_scores
+        .windows(window)
+        .map(|lines| lines.iter().sum::<u64>() as f64)
+        .collect()
+}
+
+/// Whether the color is a skin tone in daylight, after Kovač, Peer and Solina
+fn is_skin(r: u8, g: u8, b: u8) -> bool {
+    r > 95
+        && g > 40
+        && b > 20
+        && r.max(g).max(b) - r.min(g).min(b) > 15
+        && r.abs_diff(g) > 15
+        && r > g
+        && r > b
+}
@@ -0,0 +1,81 @@
+use image::{ImageBuffer, Rgba, RgbaImage};
+use lazy_static::lazy_static;
+
+use crate::settings::Dither;
+
+/// Side of the threshold maps, which are repeated across the image
+const MAP_SIZE: usize = 64;
+/// Spread of the energy of each pixel placed in the blue noise map
+const BLUE_NOISE_SIGMA: f32 = 1.5;
+
+lazy_static! {
+    /// Computed the first time an output dithers with it
+    static ref BLUE_NOISE: Vec<f32> = blue_noise_map();
+}
+
+/// Reduce a 16-bit image to 8 bits, adding a threshold from the map of method to each pixel
+/// before truncating it. Smooth gradients then become fine patterns of the two nearest
+/// levels instead of visible bands. Alpha is only rounded.
+pub fn dither(image: &ImageBuffer<Rgba<u16>, Vec<u16>>, method: Dither) -> RgbaImage {
+    let threshold: &dyn Fn(usize, usize) -> f32 = match method {
+        Dither::None => &|_, _| 0.5,
+        Dither::Ordered => &bayer,
+        Dither::BlueNoise => &|x, y| BLUE_NOISE[y * MAP_SIZE + x],
+    };
+    let mut dithered = RgbaImage::new(image.width(), image.height());
+    for ((x, y, pixel), dithered) in image.enumerate_pixels().zip(dithered.pixels_mut()) {
+        let threshold = threshold(x as usize % MAP_SIZE, y as usize % MAP_SIZE);
+        let [r, g, b, a] = pixel.0;
+        let level = |channel: u16| (f32::from(channel) / 257.0 + threshold).min(255.0) as u8;
+        *dithered = Rgba([
+            level(r),
+            level(g),
+            level(b),
+            ((u32::from(a) + 128) / 257) as u8,
+        ]);
+    }
+    dithered
+}
+
+/// Threshold of the Bayer matrix at x, y: the recursive 2x2 pattern spreads consecutive
+/// thresholds as far apart as possible
+fn bayer(x: usize, y: usize) -> f32 {
+    let mut rank = 0;
+    // The lowest bits of the coordinates choose the highest digits of the rank
+    for bit in 0..MAP_SIZE.trailing_zeros() {
+        let (x, y) = ((x >> bit) & 1, (y >> bit) & 1);
+        rank = rank * 4 + [[0, 2], [3, 1]][y][x];
+    }
+    (rank as f32 + 0.5) / (MAP_SIZE * MAP_SIZE) as f32
+}
+
+/// Thresholds without the regular pattern of the Bayer matrix: each rank goes to the pixel
+/// farthest from those already ranked, in the largest void, measured by the sum of gaussians
+/// centered on them, wrapping around so that the map tiles seamlessly
+fn blue_noise_map() -> Vec<f32> {
+    let len = MAP_SIZE * MAP_SIZE;
+    // Energy added at each offset from a ranked pixel
+    let kernel: Vec<f32> = (0..len)
+        .map(|i| {
+            let distance = |d: usize| d.min(MAP_SIZE - d) as f32;
+            let (dx, dy) = (distance(i % MAP_SIZE), distance(i / MAP_SIZE));
+            (-(dx * dx + dy * dy) / (2.0 * BLUE_NOISE_SIGMA * BLUE_NOISE_SIGMA)).exp()
+        })
+        .collect();
+    let mut energy = vec![0.0f32; len];
+    let mut map = vec![f32::NAN; len];
+    for rank in 0..len {
+        let void = (0..len)
+            .filter(|&i| map[i].is_nan())
+            .min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            .unwrap();
+        map[void] = (rank as f32 + 0.5) / len as f32;
+        let (vx, vy) = (void % MAP_SIZE, void / MAP_SIZE);
+        for (i, energy) in energy.iter_mut().enumerate() {
+            let dx = (i % MAP_SIZE + MAP_SIZE - vx) % MAP_SIZE;
+            let dy = (i / MAP_SIZE + MAP_SIZE - vy) % MAP_SIZE;
+            *energy += kernel[dy * MAP_SIZE + dx];
+        }
+    }
+    map
+}
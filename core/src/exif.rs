@@ -0,0 +1,406 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufReader, Read},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use chrono::NaiveDateTime;
+use image::DynamicImage;
+use lazy_static::lazy_static;
+
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_DATE_TIME: u16 = 0x0132;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+
+lazy_static! {
+    /// Capture times already read, with the modification time of their file
+    static ref CAPTURE_TIMES: Mutex<HashMap<PathBuf, (SystemTime, Option<NaiveDateTime>)>> =
+        Mutex::new(HashMap::new());
+}
+
+/// The metadata of a JPEG image that affect how it is displayed and sorted
+#[derive(Default)]
+pub struct Exif {
+    /// How the image must be rotated and flipped, as defined by the EXIF specification;
+    /// 1 means that it is stored upright
+    pub orientation: u16,
+    /// When the photo was taken
+    pub capture_time: Option<NaiveDateTime>,
+}
+
+/// Read the EXIF metadata of the image at path, None if it has none or it is not a JPEG
+pub fn read(path: &Path) -> Option<Exif> {
+    let file = File::open(path).ok()?;
+    let data = find_jpeg_exif(BufReader::new(file)).ok()??;
+    parse_tiff(&data)
+}
+
+/// When the image at path was taken, according to its EXIF metadata
+pub fn capture_time(path: &Path) -> Option<NaiveDateTime> {
+    let modified = fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()?;
+    let mut capture_times = CAPTURE_TIMES.lock().unwrap_or_else(|err| err.into_inner());
+    match capture_times.get(path) {
+        Some((cached_modified, capture_time)) if *cached_modified == modified => *capture_time,
+        _ => {
+            let capture_time = read(path).and_then(|exif| exif.capture_time);
+            capture_times.insert(path.to_path_buf(), (modified, capture_time));
+            capture_time
+        }
+    }
+}
+
+/// True if the image is stored on its side, so that its width and height are swapped
+pub fn swaps_dimensions(orientation: u16) -> bool {
+    (5..=8).contains(&orientation)
+}
+
+/// Rotate and flip the image so that it is upright
+pub fn apply_orientation(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Walk the segments of a JPEG file until the APP1 segment containing the EXIF data, which
+/// is returned without its "Exif" header
+fn find_jpeg_exif<R: Read>(mut reader: R) -> io::Result<Option<Vec<u8>>> {
+    let mut marker = [0; 2];
+    reader.read_exact(&mut marker)?;
+    if marker != [0xff, 0xd8] {
+        return Ok(None);
+    }
+    loop {
+        reader.read_exact(&mut marker)?;
+        // The metadata come before the image data (SOS) or the end of the image (EOI)
+        if marker[0] != 0xff || marker[1] == 0xda || marker[1] == 0xd9 {
+            return Ok(None);
+        }
+        let mut len = [0; 2];
+        reader.read_exact(&mut len)?;
+        let len = usize::from(u16::from_be_bytes(len)).saturating_sub(2);
+        let mut segment = vec![0; len];
+        reader.read_exact(&mut segment)?;
+        if marker[1] == 0xe1 && segment.starts_with(b"Exif\0\0") {
+            segment.drain(..6);
+            return Ok(Some(segment));
+        }
+    }
+}
+
+/// Reads the values of a TIFF structure, in its byte order
+struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl Tiff<'_> {
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self.data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    /// The entries of the IFD at offset, as (tag, offset of the entry)
+    fn entries(&self, offset: usize) -> impl Iterator<Item = (u16, usize)> + '_ {
+        let count = self.u16(offset).unwrap_or(0);
+        (0..usize::from(count)).filter_map(move |i| {
+            let entry = offset + 2 + i * 12;
+            Some((self.u16(entry)?, entry))
+        })
+    }
+
+    /// The date stored in an ASCII entry, e.g. "2021:07:14 18:30:00"
+    fn date(&self, entry: usize) -> Option<NaiveDateTime> {
+        let count = self.u32(entry + 4)? as usize;
+        // Values larger than 4 bytes are stored elsewhere, the entry contains their offset
+        let start = if count > 4 {
+            self.u32(entry + 8)? as usize
+        } else {
+            entry + 8
+        };
+        let text = std::str::from_utf8(self.data.get(start..start + count)?).ok()?;
+        NaiveDateTime::parse_from_str(text.trim_end_matches('\0'), "%Y:%m:%d %H:%M:%S").ok()
+    }
+}
+
+fn parse_tiff(data: &[u8]) -> Option<Exif> {
+    let little_endian = match data.get(..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let tiff = Tiff {
+        data,
+        little_endian,
+    };
+    let mut exif = Exif {
+        orientation: 1,
+        ..Exif::default()
+    };
+    let mut date_time = None;
+    let ifd0 = tiff.u32(4)? as usize;
+    for (tag, entry) in tiff.entries(ifd0) {
+        match tag {
+            TAG_ORIENTATION => exif.orientation = tiff.u16(entry + 8).unwrap_or(1),
+            TAG_DATE_TIME => date_time = tiff.date(entry),
+            TAG_EXIF_IFD => {
+                if let Some(exif_ifd) = tiff.u32(entry + 8) {
+                    exif.capture_time = tiff
+                        .entries(exif_ifd as usize)
+                        .find(|(tag, _)| *tag == TAG_DATE_TIME_ORIGINAL)
+                        .and_then(|(_, entry)| tiff.date(entry));
+                }
+            }
+            _ => {}
+        }
+    }
+    // DateTime is when the file was last changed, only use it when nothing better is known
+    exif.capture_time = exif.capture_time.or(date_time);
+    Some(exif)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATE_TIME: &str = "2022:01:02 03:04:05";
+    const DATE_TIME_ORIGINAL: &str = "2021:07:14 18:30:00";
+
+    /// A TIFF structure in the given byte order, with IFD0 right after the header holding
+    /// the orientation and DateTime, and an EXIF IFD holding DateTimeOriginal
+    fn tiff(
+        little_endian: bool,
+        orientation: Option<u16>,
+        date_time: Option<&str>,
+        date_time_original: Option<&str>,
+    ) -> Vec<u8> {
+        let u16_bytes = |value: u16| {
+            if little_endian {
+                value.to_le_bytes()
+            } else {
+                value.to_be_bytes()
+            }
+        };
+        let u32_bytes = |value: u32| {
+            if little_endian {
+                value.to_le_bytes()
+            } else {
+                value.to_be_bytes()
+            }
+        };
+        // The entries of each IFD, as (tag, type, count, value or offset)
+        let ifd0_len = 1 + usize::from(orientation.is_some()) + usize::from(date_time.is_some());
+        let exif_ifd = 8 + 2 + 12 * ifd0_len + 4;
+        let mut strings = exif_ifd + 2 + 12 + 4;
+        let mut ascii = |text: Option<&str>, tail: &mut Vec<u8>| {
+            text.map(|text| {
+                let offset = strings as u32;
+                tail.extend(text.as_bytes());
+                tail.push(0);
+                strings += text.len() + 1;
+                (text.len() as u32 + 1, offset)
+            })
+        };
+        let mut tail = Vec::new();
+        let date_time = ascii(date_time, &mut tail);
+        let date_time_original = ascii(date_time_original, &mut tail);
+        let mut ifd0 = Vec::new();
+        if let Some(orientation) = orientation {
+            // A SHORT is at the start of the 4 bytes of the value
+            let mut value = u16_bytes(orientation).to_vec();
+            value.extend([0, 0]);
+            ifd0.push((TAG_ORIENTATION, 3, 1, value));
+        }
+        if let Some((count, offset)) = date_time {
+            ifd0.push((TAG_DATE_TIME, 2, count, u32_bytes(offset).to_vec()));
+        }
+        ifd0.push((TAG_EXIF_IFD, 4, 1, u32_bytes(exif_ifd as u32).to_vec()));
+        let exif = date_time_original
+            .map(|(count, offset)| (TAG_DATE_TIME_ORIGINAL, 2, count, u32_bytes(offset).to_vec()));
+
+        let mut data = if little_endian { b"II" } else { b"MM" }.to_vec();
+        data.extend(u16_bytes(42));
+        data.extend(u32_bytes(8));
+        for ifd in [ifd0, exif.into_iter().collect()] {
+            data.extend(u16_bytes(ifd.len() as u16));
+            for (tag, kind, count, value) in &ifd {
+                data.extend(u16_bytes(*tag));
+                data.extend(u16_bytes(*kind));
+                data.extend(u32_bytes(*count));
+                data.extend(value);
+            }
+            // No next IFD; the EXIF IFD leaves room for its entry even when empty
+            data.extend(u32_bytes(0));
+            if ifd.is_empty() {
+                data.extend([0; 12]);
+            }
+        }
+        data.extend(tail);
+        data
+    }
+
+    /// A JPEG file with a JFIF segment, then the given segment and the start of the image data
+    fn jpeg(marker: u8, segment: &[u8]) -> Vec<u8> {
+        let mut data = vec![0xff, 0xd8, 0xff, 0xe0, 0, 7];
+        data.extend(b"JFIF\0");
+        data.extend([0xff, marker]);
+        data.extend((segment.len() as u16 + 2).to_be_bytes());
+        data.extend(segment);
+        data.extend([0xff, 0xda, 0, 2]);
+        data
+    }
+
+    fn date(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, "%Y:%m:%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn both_byte_orders() {
+        for little_endian in [true, false] {
+            let data = tiff(little_endian, Some(6), None, Some(DATE_TIME_ORIGINAL));
+            let exif = parse_tiff(&data).unwrap();
+            assert_eq!(exif.orientation, 6);
+            assert_eq!(exif.capture_time, Some(date(DATE_TIME_ORIGINAL)));
+        }
+        assert!(parse_tiff(b"XX\0\x2a\0\0\0\x08").is_none());
+    }
+
+    #[test]
+    fn orientations() {
+        for orientation in 1..=8 {
+            let exif = parse_tiff(&tiff(false, Some(orientation), None, None)).unwrap();
+            assert_eq!(exif.orientation, orientation);
+            assert_eq!(swaps_dimensions(orientation), orientation >= 5);
+        }
+        // Stored upright unless told otherwise
+        assert_eq!(
+            parse_tiff(&tiff(true, None, None, None))
+                .unwrap()
+                .orientation,
+            1
+        );
+
+        // A 2x1 image with a red pixel on the left, where each orientation moves it
+        let mut image = image::RgbImage::new(2, 1);
+        image.put_pixel(0, 0, image::Rgb([255, 0, 0]));
+        let red = |orientation| {
+            let image = apply_orientation(DynamicImage::ImageRgb8(image.clone()), orientation);
+            let image = image.to_rgb8();
+            let position = image
+                .enumerate_pixels()
+                .position(|(_, _, pixel)| pixel[0] == 255);
+            (image.dimensions(), position)
+        };
+        assert_eq!(red(1), ((2, 1), Some(0)));
+        assert_eq!(red(2), ((2, 1), Some(1)));
+        assert_eq!(red(3), ((2, 1), Some(1)));
+        assert_eq!(red(4), ((2, 1), Some(0)));
+        assert_eq!(red(5), ((1, 2), Some(0)));
+        assert_eq!(red(6), ((1, 2), Some(0)));
+        assert_eq!(red(7), ((1, 2), Some(1)));
+        assert_eq!(red(8), ((1, 2), Some(1)));
+    }
+
+    #[test]
+    fn date_time_original_takes_precedence() {
+        let both = tiff(true, None, Some(DATE_TIME), Some(DATE_TIME_ORIGINAL));
+        assert_eq!(
+            parse_tiff(&both).unwrap().capture_time,
+            Some(date(DATE_TIME_ORIGINAL))
+        );
+        let modified_only = tiff(true, None, Some(DATE_TIME), None);
+        assert_eq!(
+            parse_tiff(&modified_only).unwrap().capture_time,
+            Some(date(DATE_TIME))
+        );
+        assert_eq!(
+            parse_tiff(&tiff(true, None, None, None))
+                .unwrap()
+                .capture_time,
+            None
+        );
+    }
+
+    #[test]
+    fn invalid_offsets() {
+        let data = tiff(false, Some(6), Some(DATE_TIME), Some(DATE_TIME_ORIGINAL));
+        // Truncated anywhere after the header, what is left is still read
+        for len in 0..data.len() {
+            let exif = parse_tiff(&data[..len]);
+            assert_eq!(exif.is_some(), len >= 8, "{}", len);
+        }
+        // DateTimeOriginal is the last value, DateTime is used without it
+        assert_eq!(
+            parse_tiff(&data[..data.len() - 1]).unwrap().capture_time,
+            Some(date(DATE_TIME))
+        );
+
+        // IFD0 past the end of the data
+        let mut past_end = data.clone();
+        past_end[4..8].copy_from_slice(&u32::MAX.to_be_bytes());
+        let exif = parse_tiff(&past_end).unwrap();
+        assert_eq!((exif.orientation, exif.capture_time), (1, None));
+        // More entries than the data holds
+        let mut overlong = data.clone();
+        overlong[8..10].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert_eq!(parse_tiff(&overlong).unwrap().orientation, 6);
+        // The EXIF IFD and the dates past the end
+        let mut dates_past_end = data;
+        for entry in [8 + 2 + 12, 8 + 2 + 24] {
+            dates_past_end[entry + 8..entry + 12].copy_from_slice(&u32::MAX.to_be_bytes());
+        }
+        let exif = parse_tiff(&dates_past_end).unwrap();
+        assert_eq!((exif.orientation, exif.capture_time), (6, None));
+    }
+
+    #[test]
+    fn exif_segment_of_jpeg_files() {
+        let data = tiff(true, Some(3), None, None);
+        let exif_segment = [b"Exif\0\0".as_slice(), &data].concat();
+        assert_eq!(
+            find_jpeg_exif(jpeg(0xe1, &exif_segment).as_slice()).unwrap(),
+            Some(data)
+        );
+        // An APP1 segment of XMP metadata instead
+        let xmp = jpeg(0xe1, b"http://ns.adobe.com/xap/1.0/\0");
+        assert_eq!(find_jpeg_exif(xmp.as_slice()).unwrap(), None);
+        // The image data before any EXIF segment
+        let mut late = jpeg(0xe0, b"");
+        late.extend([0xff, 0xe1, 0, 8]);
+        late.extend(b"Exif\0\0");
+        assert_eq!(find_jpeg_exif(late.as_slice()).unwrap(), None);
+        // Not a JPEG file
+        assert_eq!(
+            find_jpeg_exif(b"\x89PNG\r\n\x1a\n".as_slice()).unwrap(),
+            None
+        );
+        // A segment longer than the file
+        let truncated = jpeg(0xe1, &exif_segment);
+        assert!(find_jpeg_exif(&truncated[..truncated.len() - 10]).is_err());
+    }
+}
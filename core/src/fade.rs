@@ -0,0 +1,60 @@
+use std::time::{Duration, Instant};
+
+use wayland_client::protocol::wl_shm;
+
+/// Darkens an output down to black when wpaperd exits, so that the surface going away does
+/// not flash the color behind it
+pub struct FadeOut {
+    start: Instant,
+    duration: Duration,
+    /// Set once a black frame has been drawn
+    black: bool,
+}
+
+impl FadeOut {
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            start: Instant::now(),
+            duration,
+            black: false,
+        }
+    }
+
+    /// How much of the colors is left, from 1 when the fade starts to 0 when it is over
+    pub fn brightness(&self) -> f32 {
+        if self.duration.is_zero() {
+            return 0.0;
+        }
+        1.0 - (self.start.elapsed().as_secs_f32() / self.duration.as_secs_f32()).min(1.0)
+    }
+
+    /// Whether the output has been drawn black, the last frame of the fade
+    pub fn is_over(&self) -> bool {
+        self.black
+    }
+
+    /// Darken the pixels in data, which are in the buffer format
+    pub fn apply(&mut self, data: &mut [u8], format: wl_shm::Format) {
+        let brightness = self.brightness();
+        self.black = brightness == 0.0;
+        for pixel in data.chunks_exact_mut(4) {
+            match format {
+                wl_shm::Format::Xbgr2101010 => {
+                    let value = u32::from_le_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]);
+                    let dimmed = (0..3).fold(0, |acc, channel| {
+                        let shift = channel * 10;
+                        let component = ((value >> shift) & 0x3ff) as f32;
+                        acc | (((component * brightness).round() as u32) << shift)
+                    });
+                    pixel.copy_from_slice(&dimmed.to_le_bytes());
+                }
+                // The alpha is kept, fading to black rather than to whatever is behind
+                _ => {
+                    for component in &mut pixel[..3] {
+                        *component = (f32::from(*component) * brightness).round() as u8;
+                    }
+                }
+            }
+        }
+    }
+}
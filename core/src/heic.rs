@@ -0,0 +1,1057 @@
+use std::{
+    cell::Cell,
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    process::{self, Command},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Local, Timelike, Utc};
+use color_eyre::{
+    eyre::{bail, ensure, eyre, WrapErr},
+    Result,
+};
+use image::DynamicImage;
+use lazy_static::lazy_static;
+use xdg::BaseDirectories;
+
+use crate::sun;
+
+/// The image of a dynamic wallpaper is chosen again this often
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// The sun is assumed to be this far north when the location is unknown; the longitude is
+/// guessed from the time zone
+const DEFAULT_LATITUDE: f64 = 45.0;
+/// Property lists nest a few levels at most, deeper ones are broken or malicious
+const MAX_PLIST_DEPTH: usize = 8;
+/// The schedules hold a few dozen objects; objects referenced over and over, which a small
+/// file can do to make a huge tree, are broken or malicious too
+const MAX_PLIST_OBJECTS: usize = 10_000;
+const XMP_CONTENT_TYPE: &str = "application/rdf+xml";
+
+lazy_static! {
+    /// Latitude and longitude of the user, to follow the sun
+    static ref LOCATION: Mutex<Option<(f64, f64)>> = Mutex::new(None);
+}
+
+/// Distinguishes the directories the images are being converted into
+static CONVERSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether path is a HEIF image, e.g. a dynamic wallpaper of macOS, which is decoded with
+/// heif-convert
+pub fn is_heic(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| {
+        extension.eq_ignore_ascii_case("heic") || extension.eq_ignore_ascii_case("heif")
+    })
+}
+
+/// Where the sun of the dynamic wallpapers is computed for, in degrees
+pub fn set_location(location: Option<(f64, f64)>) {
+    *LOCATION.lock().unwrap_or_else(|err| err.into_inner()) = location;
+}
+
+/// Which image of a dynamic wallpaper is shown when, by index among the images of the file
+#[derive(Clone, Debug, PartialEq)]
+pub enum Schedule {
+    /// From a time of the day, as a share of it, until the next one
+    Time(Vec<(f64, usize)>),
+    /// At a position of the sun: its altitude and azimuth, in degrees
+    Solar(Vec<(f64, f64, usize)>),
+    /// The light image while the sun is up, the dark one otherwise
+    Appearance { light: usize, dark: usize },
+}
+
+impl Schedule {
+    /// The index of the image shown at now
+    pub fn frame(&self, now: DateTime<Local>) -> usize {
+        match self {
+            Self::Time(times) => {
+                let day = f64::from(now.num_seconds_from_midnight()) / 86400.0;
+                // Before the first image of the day, the last one of the day before is shown
+                let by_time = |a: &&(f64, usize), b: &&(f64, usize)| a.0.total_cmp(&b.0);
+                times
+                    .iter()
+                    .filter(|(time, _)| *time <= day)
+                    .max_by(by_time)
+                    .or_else(|| times.iter().max_by(by_time))
+                    .map_or(0, |(_, index)| *index)
+            }
+            Self::Solar(positions) => {
+                let (altitude, azimuth) = sun_position(now);
+                // The angle between the sun and each position, on the sky
+                let distance = |&(a, z, _): &(f64, f64, usize)| {
+                    let (a, z, altitude, azimuth) = (
+                        a.to_radians(),
+                        z.to_radians(),
+                        altitude.to_radians(),
+                        azimuth.to_radians(),
+                    );
+                    -(a.sin() * altitude.sin() + a.cos() * altitude.cos() * (z - azimuth).cos())
+                };
+                positions
+                    .iter()
+                    .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+                    .map_or(0, |(_, _, index)| *index)
+            }
+            Self::Appearance { light, dark } => {
+                if sun_position(now).0 > 0.0 {
+                    *light
+                } else {
+                    *dark
+                }
+            }
+        }
+    }
+}
+
+/// The altitude and azimuth of the sun at now, at the location set or guessed
+fn sun_position(now: DateTime<Local>) -> (f64, f64) {
+    let location = *LOCATION.lock().unwrap_or_else(|err| err.into_inner());
+    let (latitude, longitude) = location.unwrap_or_else(|| {
+        // 15 degrees per hour east of UTC
+        let offset = f64::from(now.offset().local_minus_utc()) / 240.0;
+        (DEFAULT_LATITUDE, offset)
+    });
+    sun::altitude_azimuth(now.with_timezone(&Utc), latitude, longitude)
+}
+
+/// The schedule of the dynamic wallpaper at path, from the XMP metadata macOS reads; None
+/// if it is a plain image
+pub fn read_schedule(path: &Path) -> Result<Option<Schedule>> {
+    let mut file = File::open(path).with_context(|| format!("opening {:?}", path))?;
+    let meta = Meta::read(&mut file).with_context(|| format!("reading {:?}", path))?;
+    let xmp = match meta.xmp(&mut file)? {
+        Some(xmp) => xmp,
+        None => return Ok(None),
+    };
+    let xmp = String::from_utf8_lossy(&xmp);
+    for (name, key) in [
+        ("apple_desktop:h24", "ti"),
+        ("apple_desktop:solar", "si"),
+        ("apple_desktop:apr", ""),
+    ] {
+        let value = match xmp_value(&xmp, name) {
+            Some(value) => value,
+            None => continue,
+        };
+        let plist = base64(value)
+            .and_then(|data| Plist::parse(&data))
+            .with_context(|| format!("reading the {} metadata of {:?}", name, path))?;
+        let entries = plist.get(key).and_then(Plist::array).unwrap_or_default();
+        let index = |entry: &Plist| Some(entry.get("i")?.number()? as usize);
+        let schedule = match key {
+            "ti" => Schedule::Time(
+                entries
+                    .iter()
+                    .filter_map(|entry| Some((entry.get("t")?.number()?, index(entry)?)))
+                    .collect(),
+            ),
+            "si" => Schedule::Solar(
+                entries
+                    .iter()
+                    .filter_map(|entry| {
+                        Some((
+                            entry.get("a")?.number()?,
+                            entry.get("z")?.number()?,
+                            index(entry)?,
+                        ))
+                    })
+                    .collect(),
+            ),
+            _ => Schedule::Appearance {
+                light: plist.get("l").and_then(Plist::number).unwrap_or(0.0) as usize,
+                dark: plist.get("d").and_then(Plist::number).unwrap_or(1.0) as usize,
+            },
+        };
+        ensure!(
+            !matches!(&schedule, Schedule::Time(times) if times.is_empty())
+                && !matches!(&schedule, Schedule::Solar(positions) if positions.is_empty()),
+            "the {} metadata of {:?} lists no image",
+            name,
+            path
+        );
+        return Ok(Some(schedule));
+    }
+    Ok(None)
+}
+
+/// Width and height of the primary image of the HEIF file at path, once rotated
+pub fn size(path: &Path) -> Result<(u32, u32)> {
+    let mut file = File::open(path).with_context(|| format!("opening {:?}", path))?;
+    let meta = Meta::read(&mut file).with_context(|| format!("reading {:?}", path))?;
+    let mut size = None;
+    let mut rotated = false;
+    for &index in meta
+        .primary
+        .and_then(|id| meta.associations.get(&id))
+        .into_iter()
+        .flatten()
+    {
+        let (kind, data) = match meta.properties.get(index.wrapping_sub(1)) {
+            Some(property) => property,
+            None => continue,
+        };
+        match kind {
+            b"ispe" => {
+                let mut data = Bytes::new(data);
+                data.skip(4)?;
+                size = Some((data.uint(4)? as u32, data.uint(4)? as u32));
+            }
+            b"irot" => {
+                rotated = data.first().is_some_and(|angle| angle & 1 == 1);
+            }
+            _ => {}
+        }
+    }
+    let (width, height) = size.ok_or_else(|| eyre!("{:?} has no image size", path))?;
+    Ok(if rotated {
+        (height, width)
+    } else {
+        (width, height)
+    })
+}
+
+/// Decode the image of the HEIF file at path shown now: the one its schedule chooses for a
+/// dynamic wallpaper, the primary one otherwise
+pub fn decode(path: &Path) -> Result<DynamicImage> {
+    let frame = read_schedule(path)?.map(|schedule| schedule.frame(Local::now()));
+    let dir = converted_dir(path)?;
+    if !dir.is_dir() {
+        convert(path, &dir)?;
+    }
+    // A file holding several images gets them numbered from 1
+    let single = dir.join("frame.png");
+    let image = if single.is_file() {
+        single
+    } else {
+        dir.join(format!(
+            "frame-{}.png",
+            frame.unwrap_or(0).saturating_add(1)
+        ))
+    };
+    ensure!(
+        image.is_file(),
+        "{:?} has no image {}",
+        path,
+        frame.unwrap_or(0)
+    );
+    image::open(&image).with_context(|| format!("reading the converted image {:?}", path))
+}
+
+/// Where the images of the HEIF file at path are kept once converted, i.e.
+/// $XDG_CACHE_HOME/wpaper/heic/<hash of path>-<hash of its size and modification time>, so
+/// that a dynamic wallpaper is converted once rather than each time its image changes
+fn converted_dir(path: &Path) -> Result<PathBuf> {
+    let metadata = fs::metadata(path).with_context(|| format!("reading {:?}", path))?;
+    let hash = |value: &dyn Fn(&mut DefaultHasher)| {
+        let mut hasher = DefaultHasher::new();
+        value(&mut hasher);
+        hasher.finish()
+    };
+    let file = hash(&|hasher| path.hash(hasher));
+    let version = hash(&|hasher| (metadata.len(), metadata.modified().ok()).hash(hasher));
+    Ok(BaseDirectories::with_prefix("wpaper")?
+        .get_cache_home()
+        .join("heic")
+        .join(format!("{:016x}-{:016x}", file, version)))
+}
+
+/// Convert the images of the HEIF file at path to PNG into dir with heif-convert. They are
+/// written to another directory first, so that dir only appears once they are all there.
+/// The images of the previous versions of the file are removed.
+fn convert(path: &Path, dir: &Path) -> Result<()> {
+    let parent = dir.parent().unwrap();
+    let name = dir.file_name().unwrap().to_string_lossy().into_owned();
+    let partial = parent.join(format!(
+        ".{}-{}-{}",
+        name,
+        process::id(),
+        CONVERSIONS.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&partial).with_context(|| format!("creating {:?}", partial))?;
+    let res = Command::new("heif-convert")
+        .arg(path)
+        .arg(partial.join("frame.png"))
+        .output()
+        .context("running heif-convert")
+        .and_then(|output| {
+            ensure!(
+                output.status.success(),
+                "converting {:?}: {}",
+                path,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            Ok(())
+        });
+    // Another output might have converted it meanwhile, its images are as good
+    if res.is_err() || fs::rename(&partial, dir).is_err() {
+        let _ = fs::remove_dir_all(&partial);
+        return res;
+    }
+    let (file, _) = name.split_once('-').unwrap();
+    for entry in fs::read_dir(parent).into_iter().flatten().flatten() {
+        let entry_name = entry.file_name();
+        let entry_name = entry_name.to_string_lossy();
+        if entry_name.starts_with(file) && entry_name != name {
+            let _ = fs::remove_dir_all(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Where an item is: its construction method and its extents, offsets and lengths
+type Location = (u16, Vec<(u64, u64)>);
+
+/// The item properties of a HEIF file needed to find its metadata and its size, from its
+/// meta box
+#[derive(Default)]
+struct Meta {
+    primary: Option<u32>,
+    /// Item type and content type, by item id
+    items: HashMap<u32, ([u8; 4], String)>,
+    locations: HashMap<u32, Location>,
+    /// Type and content of each property, the first one having index 1
+    properties: Vec<([u8; 4], Vec<u8>)>,
+    /// Property indexes, by item id
+    associations: HashMap<u32, Vec<usize>>,
+    /// Content of the idat box, which the items with construction method 1 are in
+    idat: Vec<u8>,
+}
+
+impl Meta {
+    /// Find the meta box among the top level boxes of file and read it
+    fn read(file: &mut File) -> Result<Self> {
+        let end = file.seek(SeekFrom::End(0))?;
+        let mut offset = 0;
+        while offset < end {
+            file.seek(SeekFrom::Start(offset))?;
+            let mut header = [0; 16];
+            file.read_exact(&mut header[..8])?;
+            let mut header_len = 8;
+            let mut len = u64::from(u32::from_be_bytes([
+                header[0], header[1], header[2], header[3],
+            ]));
+            if len == 1 {
+                file.read_exact(&mut header[8..])?;
+                len = u64::from_be_bytes(header[8..16].try_into().unwrap());
+                header_len = 16;
+            } else if len == 0 {
+                len = end - offset;
+            }
+            ensure!(
+                len >= header_len
+                    && offset
+                        .checked_add(len)
+                        .is_some_and(|box_end| box_end <= end),
+                "truncated box"
+            );
+            if &header[4..8] == b"meta" {
+                let mut content = vec![0; (len - header_len) as usize];
+                file.read_exact(&mut content)?;
+                return Self::parse(&content);
+            }
+            offset += len;
+        }
+        bail!("not a HEIF image")
+    }
+
+    fn parse(content: &[u8]) -> Result<Self> {
+        let mut meta = Self::default();
+        // Version and flags
+        for (kind, data) in boxes(content.get(4..).unwrap_or_default())? {
+            let mut data = Bytes::new(data);
+            match &kind {
+                b"pitm" => {
+                    let version = data.full_box()?;
+                    meta.primary = Some(data.uint(if version == 0 { 2 } else { 4 })? as u32);
+                }
+                b"iinf" => {
+                    let version = data.full_box()?;
+                    data.skip(if version == 0 { 2 } else { 4 })?;
+                    for (kind, infe) in boxes(data.rest())? {
+                        let mut infe = Bytes::new(infe);
+                        let version = infe.full_box()?;
+                        if kind != *b"infe" || version < 2 {
+                            continue;
+                        }
+                        let id = infe.uint(if version == 2 { 2 } else { 4 })? as u32;
+                        // Protection index
+                        infe.skip(2)?;
+                        let item_type: [u8; 4] = infe.take(4)?.try_into().unwrap();
+                        // Name
+                        infe.string()?;
+                        let content_type = if &item_type == b"mime" {
+                            infe.string()?
+                        } else {
+                            String::new()
+                        };
+                        meta.items.insert(id, (item_type, content_type));
+                    }
+                }
+                b"iloc" => meta.locations = parse_iloc(&mut data)?,
+                b"iprp" => {
+                    for (kind, content) in boxes(data.rest())? {
+                        match &kind {
+                            b"ipco" => {
+                                meta.properties = boxes(content)?
+                                    .into_iter()
+                                    .map(|(kind, data)| (kind, data.to_vec()))
+                                    .collect();
+                            }
+                            b"ipma" => meta.associations = parse_ipma(&mut Bytes::new(content))?,
+                            _ => {}
+                        }
+                    }
+                }
+                b"idat" => meta.idat = data.rest().to_vec(),
+                _ => {}
+            }
+        }
+        Ok(meta)
+    }
+
+    /// The content of the XMP item, if any
+    fn xmp(&self, file: &mut File) -> Result<Option<Vec<u8>>> {
+        let id = match self.items.iter().find(|(_, (item_type, content_type))| {
+            item_type == b"mime" && content_type == XMP_CONTENT_TYPE
+        }) {
+            Some((id, _)) => *id,
+            None => return Ok(None),
+        };
+        let (method, extents) = self
+            .locations
+            .get(&id)
+            .ok_or_else(|| eyre!("the XMP metadata has no location"))?;
+        let file_len = file.seek(SeekFrom::End(0))?;
+        let mut xmp = Vec::new();
+        for &(offset, len) in extents {
+            ensure!(
+                offset.checked_add(len).is_some_and(|end| end <= file_len),
+                "the XMP metadata is out of the file"
+            );
+            match method {
+                0 => {
+                    file.seek(SeekFrom::Start(offset))?;
+                    let start = xmp.len();
+                    xmp.resize(start + len as usize, 0);
+                    file.read_exact(&mut xmp[start..])?;
+                }
+                1 => xmp.extend_from_slice(
+                    self.idat
+                        .get(offset as usize..(offset + len) as usize)
+                        .ok_or_else(|| eyre!("truncated idat box"))?,
+                ),
+                _ => bail!("unsupported item construction method {}", method),
+            }
+        }
+        Ok(Some(xmp))
+    }
+}
+
+fn parse_iloc(data: &mut Bytes) -> Result<HashMap<u32, Location>> {
+    let version = data.full_box()?;
+    let sizes = data.uint(1)? as usize;
+    let (offset_size, length_size) = (sizes >> 4, sizes & 0xf);
+    let sizes = data.uint(1)? as usize;
+    let base_offset_size = sizes >> 4;
+    let index_size = if version == 0 { 0 } else { sizes & 0xf };
+    let count = data.uint(if version < 2 { 2 } else { 4 })?;
+    let mut locations = HashMap::new();
+    for _ in 0..count {
+        let id = data.uint(if version < 2 { 2 } else { 4 })? as u32;
+        let method = if version == 0 {
+            0
+        } else {
+            data.uint(2)? as u16 & 0xf
+        };
+        // Data reference index
+        data.skip(2)?;
+        let base_offset = data.uint(base_offset_size)?;
+        let extent_count = data.uint(2)?;
+        // Extents read from nothing could be repeated endlessly
+        ensure!(
+            extent_count <= 1 || index_size + offset_size + length_size > 0,
+            "invalid iloc box"
+        );
+        let mut extents = Vec::new();
+        for _ in 0..extent_count {
+            data.skip(index_size)?;
+            let offset = data.uint(offset_size)?;
+            let length = data.uint(length_size)?;
+            let offset = base_offset
+                .checked_add(offset)
+                .ok_or_else(|| eyre!("invalid iloc box"))?;
+            extents.push((offset, length));
+        }
+        locations.insert(id, (method, extents));
+    }
+    Ok(locations)
+}
+
+fn parse_ipma(data: &mut Bytes) -> Result<HashMap<u32, Vec<usize>>> {
+    let version = data.uint(1)?;
+    let flags = data.uint(3)?;
+    let count = data.uint(4)?;
+    let mut associations = HashMap::new();
+    for _ in 0..count {
+        let id = data.uint(if version < 1 { 2 } else { 4 })? as u32;
+        let association_count = data.uint(1)?;
+        let mut indexes = Vec::new();
+        for _ in 0..association_count {
+            // The high bit tells whether the property is essential
+            let index = if flags & 1 == 1 {
+                data.uint(2)? & 0x7fff
+            } else {
+                data.uint(1)? & 0x7f
+            };
+            indexes.push(index as usize);
+        }
+        associations.insert(id, indexes);
+    }
+    Ok(associations)
+}
+
+/// The type and content of each box in data
+fn boxes(mut data: &[u8]) -> Result<Vec<([u8; 4], &[u8])>> {
+    let mut boxes = Vec::new();
+    while data.len() >= 8 {
+        let mut header = Bytes::new(data);
+        let mut len = header.uint(4)?;
+        let kind: [u8; 4] = header.take(4)?.try_into().unwrap();
+        let header_len = if len == 1 {
+            len = header.uint(8)?;
+            16
+        } else {
+            if len == 0 {
+                len = data.len() as u64;
+            }
+            8
+        };
+        ensure!(
+            len >= header_len && len <= data.len() as u64,
+            "truncated {} box",
+            String::from_utf8_lossy(&kind)
+        );
+        boxes.push((kind, &data[header_len as usize..len as usize]));
+        data = &data[len as usize..];
+    }
+    Ok(boxes)
+}
+
+/// Reads big-endian fields one after the other
+struct Bytes<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Bytes<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .position
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.position..end))
+            .ok_or_else(|| eyre!("truncated box"))?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<()> {
+        self.take(len).map(|_| ())
+    }
+
+    /// An unsigned integer of len bytes, 0 to 8; 0 bytes read 0
+    fn uint(&mut self, len: usize) -> Result<u64> {
+        ensure!(len <= 8, "invalid field size {}", len);
+        Ok(self
+            .take(len)?
+            .iter()
+            .fold(0, |acc, &byte| acc << 8 | u64::from(byte)))
+    }
+
+    /// The version of a full box, skipping its flags
+    fn full_box(&mut self) -> Result<u64> {
+        let version = self.uint(1)?;
+        self.skip(3)?;
+        Ok(version)
+    }
+
+    /// A string ending with a null byte
+    fn string(&mut self) -> Result<String> {
+        let rest = self.rest();
+        let len = rest
+            .iter()
+            .position(|&byte| byte == 0)
+            .ok_or_else(|| eyre!("unterminated string"))?;
+        self.position += len + 1;
+        Ok(String::from_utf8_lossy(&rest[..len]).into_owned())
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        &self.data[self.position.min(self.data.len())..]
+    }
+}
+
+/// The value of the XMP property name, written either as an attribute or as an element
+fn xmp_value<'a>(xmp: &'a str, name: &str) -> Option<&'a str> {
+    let rest = xmp[xmp.find(name)? + name.len()..].trim_start();
+    match rest.strip_prefix('=') {
+        Some(rest) => {
+            let rest = rest.trim_start();
+            let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+            rest[1..].split(quote).next()
+        }
+        None => rest.strip_prefix('>')?.split('<').next(),
+    }
+}
+
+fn base64(text: &str) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut bits, mut count) = (0u32, 0);
+    for c in text
+        .bytes()
+        .filter(|c| !c.is_ascii_whitespace() && *c != b'=')
+    {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => bail!("invalid base64"),
+        };
+        bits = bits << 6 | u32::from(value);
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            data.push((bits >> count) as u8);
+        }
+    }
+    Ok(data)
+}
+
+/// A value of a binary property list, the format of the schedules of dynamic wallpapers
+#[derive(Debug, PartialEq)]
+enum Plist {
+    Integer(i64),
+    Real(f64),
+    String(String),
+    Array(Vec<Plist>),
+    Dict(Vec<(String, Plist)>),
+    /// Booleans, dates, data and the like, which the schedules don't use
+    Other,
+}
+
+impl Plist {
+    fn parse(data: &[u8]) -> Result<Self> {
+        ensure!(
+            data.len() >= 40 && data.starts_with(b"bplist00"),
+            "not a binary property list"
+        );
+        let mut trailer = Bytes::new(&data[data.len() - 32..]);
+        trailer.skip(6)?;
+        let reader = PlistReader {
+            data,
+            objects: Cell::new(0),
+            offset_size: trailer.uint(1)? as usize,
+            ref_size: trailer.uint(1)? as usize,
+            count: trailer.uint(8)?,
+            top: trailer.uint(8)?,
+            table: trailer.uint(8)? as usize,
+        };
+        reader.object(reader.top, 0)
+    }
+
+    fn get(&self, key: &str) -> Option<&Self> {
+        match self {
+            Self::Dict(entries) => entries
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn array(&self) -> Option<&[Self]> {
+        match self {
+            Self::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    fn number(&self) -> Option<f64> {
+        match *self {
+            Self::Integer(value) => Some(value as f64),
+            Self::Real(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+struct PlistReader<'a> {
+    data: &'a [u8],
+    /// How many objects have been read so far
+    objects: Cell<usize>,
+    offset_size: usize,
+    ref_size: usize,
+    count: u64,
+    top: u64,
+    table: usize,
+}
+
+impl PlistReader<'_> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<Bytes<'_>> {
+        let data = offset
+            .checked_add(len)
+            .and_then(|end| self.data.get(offset..end))
+            .ok_or_else(|| eyre!("truncated property list"))?;
+        Ok(Bytes::new(data))
+    }
+
+    fn object(&self, index: u64, depth: usize) -> Result<Plist> {
+        self.objects.set(self.objects.get() + 1);
+        ensure!(
+            index < self.count
+                && depth < MAX_PLIST_DEPTH
+                && self.objects.get() <= MAX_PLIST_OBJECTS,
+            "invalid property list"
+        );
+        let entry = (index as usize)
+            .saturating_mul(self.offset_size)
+            .saturating_add(self.table);
+        let offset = self
+            .bytes(entry, self.offset_size)?
+            .uint(self.offset_size)? as usize;
+        let marker = self.bytes(offset, 1)?.uint(1)? as u8;
+        let info = usize::from(marker & 0xf);
+        Ok(match marker >> 4 {
+            0x1 => Plist::Integer(
+                self.bytes(offset.saturating_add(1), 1 << info)?
+                    .uint(1 << info)? as i64,
+            ),
+            0x2 => {
+                let value = self
+                    .bytes(offset.saturating_add(1), 1 << info)?
+                    .uint(1 << info)?;
+                match info {
+                    2 => Plist::Real(f64::from(f32::from_bits(value as u32))),
+                    3 => Plist::Real(f64::from_bits(value)),
+                    _ => bail!("invalid real in the property list"),
+                }
+            }
+            0x5 => {
+                let (start, len) = self.length(offset, info)?;
+                let text = self.bytes(start, len)?.rest();
+                Plist::String(String::from_utf8_lossy(text).into_owned())
+            }
+            0x6 => {
+                let (start, len) = self.length(offset, info)?;
+                let mut text = self.bytes(start, len.saturating_mul(2))?;
+                let units = (0..len)
+                    .map(|_| text.uint(2).map(|unit| unit as u16))
+                    .collect::<Result<Vec<_>>>()?;
+                Plist::String(String::from_utf16_lossy(&units))
+            }
+            0xa => {
+                let (start, len) = self.length(offset, info)?;
+                Plist::Array(
+                    self.refs(start, len)?
+                        .into_iter()
+                        .map(|index| self.object(index, depth + 1))
+                        .collect::<Result<_>>()?,
+                )
+            }
+            0xd => {
+                let (start, len) = self.length(offset, info)?;
+                let keys = self.refs(start, len)?;
+                let values =
+                    self.refs(start.saturating_add(len.saturating_mul(self.ref_size)), len)?;
+                let mut entries = Vec::with_capacity(len);
+                for (key, value) in keys.into_iter().zip(values) {
+                    // Keys are strings
+                    if let Plist::String(key) = self.object(key, depth + 1)? {
+                        entries.push((key, self.object(value, depth + 1)?));
+                    }
+                }
+                Plist::Dict(entries)
+            }
+            _ => Plist::Other,
+        })
+    }
+
+    /// Where the content of the object at offset starts and how many elements it holds; a
+    /// length of 15 or more follows the marker as an integer object
+    fn length(&self, offset: usize, info: usize) -> Result<(usize, usize)> {
+        if info != 0xf {
+            return Ok((offset.saturating_add(1), info));
+        }
+        let marker = self.bytes(offset.saturating_add(1), 1)?.uint(1)? as u8;
+        ensure!(marker >> 4 == 0x1, "invalid length in the property list");
+        let size = 1 << (marker & 0xf);
+        let len = self.bytes(offset.saturating_add(2), size)?.uint(size)? as usize;
+        Ok((offset.saturating_add(2 + size), len))
+    }
+
+    /// The indexes of the len objects referenced at offset
+    fn refs(&self, offset: usize, len: usize) -> Result<Vec<u64>> {
+        let mut refs = self.bytes(offset, len.saturating_mul(self.ref_size))?;
+        (0..len).map(|_| refs.uint(self.ref_size)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use chrono::TimeZone;
+
+    use super::*;
+
+    /// Images at 6:00 (1), 12:00 (0) and 18:00 (2), as macOS writes them
+    const H24: &str = "YnBsaXN0MDDSAQIDCFJhcFJ0adIEBQYHUWRRbBACEACjCQ4Q0goLDA1RaVF0EAEjP9AAAAAAAADSCgsGDyM/6AAAAAAAANIKCwcRIz/gAAAAAAAACA0QExgaHB4gJCkrLS84PUZLAAAAAAAAAQEAAAAAAAAAEgAAAAAAAAAAAAAAAAAAAFQ=";
+
+    fn heif_box(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        let mut data = (content.len() as u32 + 8).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(content);
+        data
+    }
+
+    /// The content of a meta box whose primary image is 5120x2880 and whose XMP metadata is
+    /// xmp_len bytes at xmp_offset in the file
+    fn meta_content(xmp_offset: u32, xmp_len: u32) -> Vec<u8> {
+        let mut infe = vec![2, 0, 0, 0, 0, 2, 0, 0];
+        infe.extend_from_slice(b"mime\0application/rdf+xml\0");
+        let mut iinf = vec![0, 0, 0, 0, 0, 1];
+        iinf.extend(heif_box(b"infe", &infe));
+        let mut iloc = vec![0, 0, 0, 0, 0x44, 0, 0, 1, 0, 2, 0, 0, 0, 1];
+        iloc.extend_from_slice(&xmp_offset.to_be_bytes());
+        iloc.extend_from_slice(&xmp_len.to_be_bytes());
+        let mut ispe = vec![0; 4];
+        ispe.extend_from_slice(&5120u32.to_be_bytes());
+        ispe.extend_from_slice(&2880u32.to_be_bytes());
+        let mut iprp = heif_box(b"ipco", &heif_box(b"ispe", &ispe));
+        iprp.extend(heif_box(b"ipma", &[0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 1, 0x81]));
+        let mut content = vec![0; 4];
+        content.extend(heif_box(b"pitm", &[0, 0, 0, 0, 0, 1]));
+        content.extend(heif_box(b"iinf", &iinf));
+        content.extend(heif_box(b"iloc", &iloc));
+        content.extend(heif_box(b"iprp", &iprp));
+        content
+    }
+
+    /// A HEIF file with the XMP of a time based dynamic wallpaper, without any image
+    fn dynamic_wallpaper(xmp_offset: Option<u32>) -> Vec<u8> {
+        let xmp = format!("<rdf:Description apple_desktop:h24=\"{}\"/>", H24);
+        let ftyp = heif_box(b"ftyp", b"heic\0\0\0\0mif1heic");
+        let meta_len = heif_box(b"meta", &meta_content(0, 0)).len();
+        let offset = xmp_offset.unwrap_or((ftyp.len() + meta_len) as u32);
+        let mut file = ftyp;
+        file.extend(heif_box(b"meta", &meta_content(offset, xmp.len() as u32)));
+        file.extend_from_slice(xmp.as_bytes());
+        file
+    }
+
+    fn write_temp(name: &str, data: &[u8]) -> PathBuf {
+        let path = env::temp_dir().join(format!("wpaper-test-{}-{}.heic", process::id(), name));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    /// A binary property list of objects, the first one being the top
+    fn bplist(objects: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = b"bplist00".to_vec();
+        let mut offsets = Vec::new();
+        for object in objects {
+            offsets.push(data.len() as u8);
+            data.extend_from_slice(object);
+        }
+        let table = data.len() as u64;
+        data.extend(offsets);
+        data.extend_from_slice(&[0; 6]);
+        data.extend_from_slice(&[1, 1]);
+        data.extend_from_slice(&(objects.len() as u64).to_be_bytes());
+        data.extend_from_slice(&0u64.to_be_bytes());
+        data.extend_from_slice(&table.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn dynamic_wallpapers_show_the_image_of_their_schedule() {
+        let path = write_temp("schedule", &dynamic_wallpaper(None));
+        let schedule = read_schedule(&path).unwrap();
+        let size = size(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            schedule,
+            Some(Schedule::Time(vec![(0.25, 1), (0.75, 2), (0.5, 0)]))
+        );
+        assert_eq!(size, (5120, 2880));
+        let frame_at = |hour| {
+            let now = Local.ymd(2024, 3, 1).and_hms(hour, 0, 0);
+            schedule.as_ref().unwrap().frame(now)
+        };
+        // Before 6:00, the image of the evening before is still shown
+        assert_eq!([3, 7, 13, 20].map(frame_at), [2, 1, 0, 2]);
+    }
+
+    #[test]
+    fn metadata_out_of_the_file_is_an_error() {
+        let path = write_temp("out-of-file", &dynamic_wallpaper(Some(u32::MAX - 4)));
+        let schedule = read_schedule(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(schedule.is_err());
+    }
+
+    #[test]
+    fn boxes_longer_than_their_parent_are_errors() {
+        let mut data = heif_box(b"pitm", &[0; 6]);
+        data.extend(heif_box(b"idat", &[1, 2, 3]));
+        let parsed = boxes(&data).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[1], (*b"idat", &[1, 2, 3][..]));
+        // A length of 0 runs to the end
+        assert_eq!(
+            boxes(&[0, 0, 0, 0, b'i', b'd', b'a', b't', 9]).unwrap()[0].1,
+            [9]
+        );
+        // A 64-bit length
+        let mut large = vec![0, 0, 0, 1];
+        large.extend_from_slice(b"idat");
+        large.extend_from_slice(&17u64.to_be_bytes());
+        large.push(7);
+        assert_eq!(boxes(&large).unwrap()[0].1, [7]);
+
+        data[3] += 1;
+        assert!(boxes(&data).is_err());
+        // Shorter than its own header
+        assert!(boxes(&[0, 0, 0, 4, b'p', b'i', b't', b'm']).is_err());
+        large[11] = 0xff;
+        assert!(boxes(&large).is_err());
+    }
+
+    #[test]
+    fn item_locations_are_read_until_they_are_truncated() {
+        // Version 1, 4-byte offsets and lengths, 4-byte base offset, one item of id 3 in
+        // the idat box at 100 + 2, 5 bytes long
+        let mut iloc = vec![1, 0, 0, 0, 0x44, 0x40, 0, 1, 0, 3, 0, 1, 0, 0];
+        iloc.extend_from_slice(&100u32.to_be_bytes());
+        iloc.extend_from_slice(&[0, 1]);
+        iloc.extend_from_slice(&2u32.to_be_bytes());
+        iloc.extend_from_slice(&5u32.to_be_bytes());
+        let locations = parse_iloc(&mut Bytes::new(&iloc)).unwrap();
+        assert_eq!(locations[&3], (1, vec![(102, 5)]));
+        for len in 0..iloc.len() {
+            assert!(parse_iloc(&mut Bytes::new(&iloc[..len])).is_err());
+        }
+
+        // The base offset and the offset add up beyond 64 bits
+        let mut overflow = vec![0, 0, 0, 0, 0x88, 0x80, 0, 1, 0, 1, 0, 0];
+        overflow.extend_from_slice(&u64::MAX.to_be_bytes());
+        overflow.extend_from_slice(&[0, 1]);
+        overflow.extend_from_slice(&1u64.to_be_bytes());
+        overflow.extend_from_slice(&1u64.to_be_bytes());
+        assert!(parse_iloc(&mut Bytes::new(&overflow)).is_err());
+
+        // Every size is 0, the extents would take no room
+        let empty = [0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 0, 0, 0xff, 0xff];
+        assert!(parse_iloc(&mut Bytes::new(&empty)).is_err());
+    }
+
+    #[test]
+    fn property_associations_drop_the_essential_bit() {
+        // Flags 1: 2-byte indexes
+        let ipma = [0, 0, 0, 1, 0, 0, 0, 1, 0, 7, 2, 0x80, 0x01, 0x00, 0x02];
+        let associations = parse_ipma(&mut Bytes::new(&ipma)).unwrap();
+        assert_eq!(associations[&7], [1, 2]);
+        for len in 0..ipma.len() {
+            assert!(parse_ipma(&mut Bytes::new(&ipma[..len])).is_err());
+        }
+    }
+
+    #[test]
+    fn malformed_meta_boxes_do_not_panic() {
+        let content = meta_content(1000, 100);
+        for len in 0..content.len() {
+            let _ = Meta::parse(&content[..len]);
+        }
+        for position in 0..content.len() {
+            for byte in [0, 1, 0x7f, 0xff] {
+                let mut content = content.clone();
+                content[position] = byte;
+                let _ = Meta::parse(&content);
+            }
+        }
+    }
+
+    #[test]
+    fn base64_decodes_padded_and_wrapped_text() {
+        assert_eq!(base64("aGVs\nbG8=").unwrap(), b"hello");
+        assert_eq!(base64("aGVsbG8").unwrap(), b"hello");
+        // The bits that do not make a whole byte are dropped
+        assert_eq!(base64("aGVsbG").unwrap(), b"hell");
+        assert_eq!(base64("").unwrap(), b"");
+        assert!(base64("aGV*bG8=").is_err());
+        assert!(base64("aGVsbG8=é").is_err());
+    }
+
+    #[test]
+    fn property_lists_are_read() {
+        // { "t": [1, "ab"] }
+        let data = bplist(&[
+            vec![0xd1, 1, 2],
+            vec![0x51, b't'],
+            vec![0xa2, 3, 4],
+            vec![0x10, 1],
+            vec![0x52, b'a', b'b'],
+        ]);
+        assert_eq!(
+            Plist::parse(&data).unwrap(),
+            Plist::Dict(vec![(
+                "t".to_string(),
+                Plist::Array(vec![Plist::Integer(1), Plist::String("ab".to_string())])
+            )])
+        );
+        let schedule = Plist::parse(&base64(H24).unwrap()).unwrap();
+        assert_eq!(schedule.get("ti").and_then(Plist::array).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn malformed_property_lists_are_errors() {
+        let data = base64(H24).unwrap();
+        for len in 0..data.len() {
+            let _ = Plist::parse(&data[..len]);
+        }
+        for position in 0..data.len() {
+            for byte in [0, 0x0f, 0x7f, 0xff] {
+                let mut data = data.clone();
+                data[position] = byte;
+                let _ = Plist::parse(&data);
+            }
+        }
+        assert!(Plist::parse(b"bplist00").is_err());
+        assert!(Plist::parse(&[0; 64]).is_err());
+        // An array holding itself
+        assert!(Plist::parse(&bplist(&[vec![0xa1, 0]])).is_err());
+        // A reference out of the objects
+        assert!(Plist::parse(&bplist(&[vec![0xa1, 5]])).is_err());
+        // A string longer than the file
+        assert!(Plist::parse(&bplist(&[vec![0x5f, 0x13, 0xff, 0xff, b'a']])).is_err());
+        // Seven arrays of 14 references to the next one, 14^7 objects in all
+        let mut objects: Vec<Vec<u8>> = (1..8u8)
+            .map(|next| [vec![0xae], vec![next; 14]].concat())
+            .collect();
+        objects.push(vec![0x10, 1]);
+        assert!(Plist::parse(&bplist(&objects)).is_err());
+    }
+}
@@ -0,0 +1,445 @@
+use std::{convert::TryInto, fs, path::Path};
+
+use color_eyre::{
+    eyre::{bail, ensure, eyre, WrapErr},
+    Result,
+};
+use image::{ImageBuffer, Rgb, RgbaImage};
+
+/// sRGB primaries converted to the D50 white point of the ICC profile connection space
+const SRGB_TO_XYZ_D50: [[f32; 3]; 3] = [
+    [0.436_074_7, 0.385_064_9, 0.143_080_4],
+    [0.222_504_5, 0.716_878_6, 0.060_616_9],
+    [0.013_932_2, 0.097_104_5, 0.714_173_3],
+];
+
+/// Number of entries in the lookup tables used to encode linear values for the display
+const LUT_SIZE: usize = 4096;
+
+enum ToneCurve {
+    Gamma(f32),
+    /// Samples evenly spaced over the input range
+    Table(Vec<f32>),
+    /// ICC parametric curve: the function type and its parameters g, a, b, c, d, e, f
+    Parametric(u16, [f32; 7]),
+}
+
+impl ToneCurve {
+    /// Convert an encoded value to linear light
+    fn eval(&self, x: f32) -> f32 {
+        match self {
+            ToneCurve::Gamma(gamma) => x.powf(*gamma),
+            ToneCurve::Table(table) => {
+                let pos = x.clamp(0.0, 1.0) * (table.len() - 1) as f32;
+                let i = (pos as usize).min(table.len() - 2);
+                let t = pos - i as f32;
+                table[i] * (1.0 - t) + table[i + 1] * t
+            }
+            ToneCurve::Parametric(kind, [g, a, b, c, d, e, f]) => match kind {
+                0 => x.powf(*g),
+                1 if x >= -b / a => (a * x + b).powf(*g),
+                1 => 0.0,
+                2 if x >= -b / a => (a * x + b).powf(*g) + c,
+                2 => *c,
+                3 if x >= *d => (a * x + b).powf(*g),
+                3 => c * x,
+                _ if x >= *d => (a * x + b).powf(*g) + e,
+                _ => c * x + f,
+            },
+        }
+    }
+
+    /// Tabulate the inverse of the curve, so that linear values can be encoded for the display.
+    /// Tone curves are monotonic, so each entry is found by bisection.
+    fn inverse_lut(&self) -> Vec<f32> {
+        (0..LUT_SIZE)
+            .map(|i| {
+                let y = i as f32 / (LUT_SIZE - 1) as f32;
+                let (mut low, mut high) = (0.0_f32, 1.0_f32);
+                for _ in 0..24 {
+                    let mid = (low + high) / 2.0;
+                    if self.eval(mid) < y {
+                        low = mid;
+                    } else {
+                        high = mid;
+                    }
+                }
+                (low + high) / 2.0
+            })
+            .collect()
+    }
+}
+
+/// Converts sRGB images to the color space of a display described by an ICC profile.
+/// Only matrix/TRC profiles are supported, which is what display profiles usually are.
+pub struct ColorTransform {
+    matrix: [[f32; 3]; 3],
+    inverse_curves: [Vec<f32>; 3],
+}
+
+impl ColorTransform {
+    pub fn from_icc_path(path: &Path) -> Result<Self> {
+        let data = fs::read(path).with_context(|| format!("reading ICC profile {:?}", path))?;
+        Self::from_icc(&data).with_context(|| format!("parsing ICC profile {:?}", path))
+    }
+
+    fn from_icc(data: &[u8]) -> Result<Self> {
+        ensure!(
+            data.len() >= 132 && &data[36..40] == b"acsp",
+            "not an ICC profile"
+        );
+        ensure!(&data[16..20] == b"RGB ", "only RGB profiles are supported");
+
+        let tag_count = read_u32(data, 128)? as usize;
+        ensure!(
+            tag_count <= (data.len() - 132) / 12,
+            "truncated ICC profile"
+        );
+        let tag = |signature: &[u8; 4]| -> Result<&[u8]> {
+            (0..tag_count)
+                .map(|i| 132 + i * 12)
+                .find(|&entry| data.get(entry..entry + 4) == Some(&signature[..]))
+                .ok_or_else(|| {
+                    eyre!(
+                        "tag {} not found, only matrix/TRC profiles are supported",
+                        String::from_utf8_lossy(signature)
+                    )
+                })
+                .and_then(|entry| {
+                    let offset = read_u32(data, entry + 4)? as usize;
+                    let size = read_u32(data, entry + 8)? as usize;
+                    offset
+                        .checked_add(size)
+                        .and_then(|end| data.get(offset..end))
+                        .ok_or_else(|| eyre!("tag out of the ICC profile"))
+                })
+        };
+
+        // The colorants are the columns of the matrix converting display RGB to XYZ
+        let mut display_to_xyz = [[0.0; 3]; 3];
+        for (column, signature) in [b"rXYZ", b"gXYZ", b"bXYZ"].iter().enumerate() {
+            let xyz = read_xyz(tag(signature)?)?;
+            for (row, value) in xyz.iter().enumerate() {
+                display_to_xyz[row][column] = *value;
+            }
+        }
+        let matrix = multiply(&invert(&display_to_xyz)?, &SRGB_TO_XYZ_D50);
+
+        let inverse_curves = [
+            read_curve(tag(b"rTRC")?)?.inverse_lut(),
+            read_curve(tag(b"gTRC")?)?.inverse_lut(),
+            read_curve(tag(b"bTRC")?)?.inverse_lut(),
+        ];
+
+        Ok(Self {
+            matrix,
+            inverse_curves,
+        })
+    }
+
+    /// Convert a linear sRGB color to encoded display values in the range 0..=1
+    fn convert(&self, rgb: [f32; 3]) -> [f32; 3] {
+        let mut out = [0.0; 3];
+        for (channel, value) in out.iter_mut().enumerate() {
+            let row = &self.matrix[channel];
+            let linear = (row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2]).clamp(0.0, 1.0);
+            *value =
+                self.inverse_curves[channel][(linear * (LUT_SIZE - 1) as f32).round() as usize];
+        }
+        out
+    }
+
+    pub fn apply_rgba8(&self, image: &mut RgbaImage) {
+        let to_linear: Vec<f32> = (0..=255)
+            .map(|value| srgb_to_linear(value as f32 / 255.0))
+            .collect();
+        for pixel in image.pixels_mut() {
+            let [r, g, b, _] = pixel.0;
+            let out = self.convert([
+                to_linear[r as usize],
+                to_linear[g as usize],
+                to_linear[b as usize],
+            ]);
+            for (channel, value) in out.iter().enumerate() {
+                pixel.0[channel] = (value * 255.0).round() as u8;
+            }
+        }
+    }
+
+    pub fn apply_rgb16(&self, image: &mut ImageBuffer<Rgb<u16>, Vec<u16>>) {
+        let to_linear: Vec<f32> = (0..=u16::MAX)
+            .map(|value| srgb_to_linear(value as f32 / 65535.0))
+            .collect();
+        for pixel in image.pixels_mut() {
+            let out = self.convert(pixel.0.map(|channel| to_linear[channel as usize]));
+            pixel.0 = out.map(|value| (value * 65535.0).round() as u16);
+        }
+    }
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    Ok(u32::from_be_bytes(
+        data.get(offset..offset + 4)
+            .ok_or_else(|| eyre!("truncated ICC profile"))?
+            .try_into()?,
+    ))
+}
+
+fn read_s15fixed16(data: &[u8], offset: usize) -> Result<f32> {
+    Ok(read_u32(data, offset)? as i32 as f32 / 65536.0)
+}
+
+fn read_xyz(tag: &[u8]) -> Result<[f32; 3]> {
+    ensure!(tag.starts_with(b"XYZ "), "invalid colorant tag");
+    Ok([
+        read_s15fixed16(tag, 8)?,
+        read_s15fixed16(tag, 12)?,
+        read_s15fixed16(tag, 16)?,
+    ])
+}
+
+fn read_curve(tag: &[u8]) -> Result<ToneCurve> {
+    let truncated = || eyre!("truncated tone curve");
+    match tag.get(0..4) {
+        Some(b"curv") => {
+            let count = read_u32(tag, 8)? as usize;
+            let entries = count
+                .checked_mul(2)
+                .and_then(|len| tag.get(12..12 + len))
+                .ok_or_else(truncated)?;
+            let entries: Vec<u16> = entries
+                .chunks_exact(2)
+                .map(|entry| u16::from_be_bytes([entry[0], entry[1]]))
+                .collect();
+            Ok(match entries.len() {
+                0 => ToneCurve::Gamma(1.0),
+                1 => ToneCurve::Gamma(f32::from(entries[0]) / 256.0),
+                _ => ToneCurve::Table(
+                    entries
+                        .into_iter()
+                        .map(|entry| f32::from(entry) / 65535.0)
+                        .collect(),
+                ),
+            })
+        }
+        Some(b"para") => {
+            let kind = u16::from_be_bytes(tag.get(8..10).ok_or_else(truncated)?.try_into()?);
+            let count = match kind {
+                0 => 1,
+                1 => 3,
+                2 => 4,
+                3 => 5,
+                4 => 7,
+                _ => bail!("unknown parametric curve type {}", kind),
+            };
+            let mut params = [0.0; 7];
+            for (i, param) in params.iter_mut().take(count).enumerate() {
+                *param = read_s15fixed16(tag, 12 + i * 4)?;
+            }
+            Ok(ToneCurve::Parametric(kind, params))
+        }
+        _ => bail!("unsupported tone curve type"),
+    }
+}
+
+fn multiply(a: &[[f32; 3]; 3], b: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    let mut out = [[0.0; 3]; 3];
+    for (i, row) in out.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    out
+}
+
+fn invert(m: &[[f32; 3]; 3]) -> Result<[[f32; 3]; 3]> {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    ensure!(det.abs() > f32::EPSILON, "the colorants are not invertible");
+    Ok([
+        [
+            (m[1][1] * m[2][2] - m[1][2] * m[2][1]) / det,
+            (m[0][2] * m[2][1] - m[0][1] * m[2][2]) / det,
+            (m[0][1] * m[1][2] - m[0][2] * m[1][1]) / det,
+        ],
+        [
+            (m[1][2] * m[2][0] - m[1][0] * m[2][2]) / det,
+            (m[0][0] * m[2][2] - m[0][2] * m[2][0]) / det,
+            (m[0][2] * m[1][0] - m[0][0] * m[1][2]) / det,
+        ],
+        [
+            (m[1][0] * m[2][1] - m[1][1] * m[2][0]) / det,
+            (m[0][1] * m[2][0] - m[0][0] * m[2][1]) / det,
+            (m[0][0] * m[1][1] - m[0][1] * m[1][0]) / det,
+        ],
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parameters of the sRGB transfer function as an ICC parametric curve of type 3
+    const SRGB_CURVE: [f32; 5] = [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045];
+
+    fn s15fixed16(value: f32) -> [u8; 4] {
+        ((value * 65536.0).round() as i32).to_be_bytes()
+    }
+
+    fn xyz(values: [f32; 3]) -> Vec<u8> {
+        let mut tag = b"XYZ \0\0\0\0".to_vec();
+        for value in values {
+            tag.extend(s15fixed16(value));
+        }
+        tag
+    }
+
+    fn para(kind: u16, params: &[f32]) -> Vec<u8> {
+        let mut tag = b"para\0\0\0\0".to_vec();
+        tag.extend(kind.to_be_bytes());
+        tag.extend([0, 0]);
+        for param in params {
+            tag.extend(s15fixed16(*param));
+        }
+        tag
+    }
+
+    fn curv(entries: &[u16]) -> Vec<u8> {
+        let mut tag = b"curv\0\0\0\0".to_vec();
+        tag.extend((entries.len() as u32).to_be_bytes());
+        for entry in entries {
+            tag.extend(entry.to_be_bytes());
+        }
+        tag
+    }
+
+    /// A profile made of the given tags, laid out after the tag table
+    fn profile(tags: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut data = vec![0; 128];
+        data[16..20].copy_from_slice(b"RGB ");
+        data[36..40].copy_from_slice(b"acsp");
+        data.extend((tags.len() as u32).to_be_bytes());
+        let mut offset = 132 + tags.len() * 12;
+        for (signature, tag) in tags {
+            data.extend(*signature);
+            data.extend((offset as u32).to_be_bytes());
+            data.extend((tag.len() as u32).to_be_bytes());
+            offset += tag.len();
+        }
+        for (_, tag) in tags {
+            data.extend(tag);
+        }
+        data
+    }
+
+    /// A display with the sRGB colorants and tone curves
+    fn srgb_profile() -> Vec<u8> {
+        let column = |i: usize| SRGB_TO_XYZ_D50.map(|row| row[i]);
+        profile(&[
+            (b"rXYZ", xyz(column(0))),
+            (b"gXYZ", xyz(column(1))),
+            (b"bXYZ", xyz(column(2))),
+            (b"rTRC", para(3, &SRGB_CURVE)),
+            (b"gTRC", para(3, &SRGB_CURVE)),
+            (b"bTRC", para(3, &SRGB_CURVE)),
+        ])
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-3, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn parametric_curves() {
+        let gamma = read_curve(&para(0, &[2.2])).unwrap();
+        assert_close(gamma.eval(0.5), 0.5_f32.powf(2.2));
+        let srgb = read_curve(&para(3, &SRGB_CURVE)).unwrap();
+        for x in [0.0, 0.02, 0.04045, 0.2, 0.5, 1.0] {
+            assert_close(srgb.eval(x), srgb_to_linear(x));
+        }
+        // Type 4 is type 3 with offsets
+        let offset = read_curve(&para(4, &[1.0, 1.0, 0.0, 0.5, 0.5, 0.25, 0.1])).unwrap();
+        assert_close(offset.eval(0.25), 0.225);
+        assert_close(offset.eval(0.75), 1.0);
+        assert!(read_curve(&para(5, &[1.0])).is_err());
+        // Missing parameters
+        assert!(read_curve(&para(3, &SRGB_CURVE[..4])).is_err());
+    }
+
+    #[test]
+    fn table_curves() {
+        assert_close(read_curve(&curv(&[])).unwrap().eval(0.3), 0.3);
+        // A single entry is a gamma in u8Fixed8
+        let gamma = read_curve(&curv(&[0x0233])).unwrap();
+        assert_close(gamma.eval(0.5), 0.5_f32.powf(2.2));
+        let table = read_curve(&curv(&[0, 0x4000, 0xffff])).unwrap();
+        assert_close(table.eval(0.0), 0.0);
+        assert_close(table.eval(0.25), 0.125);
+        assert_close(table.eval(0.5), 0.25);
+        assert_close(table.eval(1.0), 1.0);
+        // Out of range values are clamped
+        assert_close(table.eval(2.0), 1.0);
+        let inverse = table.inverse_lut();
+        assert_close(inverse[(LUT_SIZE - 1) / 4], 0.5);
+    }
+
+    #[test]
+    fn srgb_displays_keep_the_colors() {
+        let transform = ColorTransform::from_icc(&srgb_profile()).unwrap();
+        let pixels: Vec<u8> = (0..=255).flat_map(|v| [v, 255 - v, v / 2, 255]).collect();
+        let mut image = RgbaImage::from_raw(256, 1, pixels.clone()).unwrap();
+        transform.apply_rgba8(&mut image);
+        for (converted, original) in image.into_raw().iter().zip(&pixels) {
+            assert!(
+                (*converted as i16 - *original as i16).abs() <= 1,
+                "{} != {}",
+                converted,
+                original
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_profiles_are_errors() {
+        let data = srgb_profile();
+        for len in 0..data.len() {
+            assert!(ColorTransform::from_icc(&data[..len]).is_err(), "{}", len);
+        }
+        // More tags than the profile can hold
+        let mut data = srgb_profile();
+        data[128..132].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(ColorTransform::from_icc(&data).is_err());
+    }
+
+    #[test]
+    fn tags_out_of_the_profile_are_errors() {
+        for (offset, size) in [(u32::MAX, 20), (132, u32::MAX), (u32::MAX - 4, 20), (0, 0)] {
+            let mut data = srgb_profile();
+            data[136..140].copy_from_slice(&offset.to_be_bytes());
+            data[140..144].copy_from_slice(&size.to_be_bytes());
+            assert!(
+                ColorTransform::from_icc(&data).is_err(),
+                "{} {}",
+                offset,
+                size
+            );
+        }
+        // A missing tone curve
+        let mut data = srgb_profile();
+        data[132 + 3 * 12..132 + 4 * 12].copy_from_slice(&[0; 12]);
+        assert!(ColorTransform::from_icc(&data).is_err());
+        assert!(read_curve(&curv(&[0, 1, 2])[..14]).is_err());
+        let mut huge = curv(&[0, 1]);
+        huge[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(read_curve(&huge).is_err());
+    }
+}
@@ -0,0 +1,60 @@
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+};
+
+/// Remembers the last images displayed on an output, so that the user can go back to them
+pub struct ImagePicker {
+    history: VecDeque<PathBuf>,
+    capacity: usize,
+    /// Index in history of the image currently displayed
+    index: usize,
+}
+
+impl ImagePicker {
+    /// An empty history, keeping at most capacity images
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            history: VecDeque::with_capacity(capacity),
+            capacity,
+            index: 0,
+        }
+    }
+
+    /// The image displayed before the current one, if any
+    pub fn previous(&mut self) -> Option<PathBuf> {
+        if self.index == 0 {
+            return None;
+        }
+        self.index -= 1;
+        self.history.get(self.index).cloned()
+    }
+
+    /// After going back, the following images are replayed from the history; when the most
+    /// recent image is reached, None is returned and a new image should be chosen
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<PathBuf> {
+        if self.index + 1 >= self.history.len() {
+            return None;
+        }
+        self.index += 1;
+        self.history.get(self.index).cloned()
+    }
+
+    /// Record that an image has been displayed
+    pub fn displayed(&mut self, image: &Path) {
+        if self.history.get(self.index).map(PathBuf::as_path) == Some(image) {
+            return;
+        }
+        if self.history.len() == self.capacity {
+            self.history.pop_front();
+        }
+        self.history.push_back(image.to_path_buf());
+        self.index = self.history.len() - 1;
+    }
+
+    /// The images displayed, from the most recent
+    pub fn history(&self) -> impl Iterator<Item = &Path> {
+        self.history.iter().rev().map(PathBuf::as_path)
+    }
+}
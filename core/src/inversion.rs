@@ -0,0 +1,84 @@
+use std::time::{Duration, Instant};
+
+use wayland_client::protocol::wl_shm;
+
+/// How long it takes to go from the normal colors to the inverted ones and vice versa
+const TRANSITION_DURATION: Duration = Duration::from_millis(500);
+
+/// Negative mode of an output, toggled with a smooth transition
+#[derive(Default)]
+pub struct Inversion {
+    inverted: bool,
+    /// Level when the transition started
+    from: f32,
+    transition_start: Option<Instant>,
+}
+
+impl Inversion {
+    /// Without animated, the colors change at once
+    pub fn toggle(&mut self, animated: bool) {
+        self.from = self.level();
+        self.inverted = !self.inverted;
+        self.transition_start = animated.then(Instant::now);
+    }
+
+    /// How much the colors are inverted, from 0 (normal) to 1 (negative)
+    pub fn level(&self) -> f32 {
+        let target = if self.inverted { 1.0 } else { 0.0 };
+        match self.transition_start {
+            Some(start) => {
+                let progress =
+                    (start.elapsed().as_secs_f32() / TRANSITION_DURATION.as_secs_f32()).min(1.0);
+                self.from + (target - self.from) * progress
+            }
+            None => target,
+        }
+    }
+
+    /// Jump to the end of the running transition, if any
+    pub fn finish(&mut self) {
+        self.transition_start = None;
+    }
+
+    /// Returns true while the transition is running
+    pub fn is_animating(&mut self) -> bool {
+        if matches!(self.transition_start, Some(start) if start.elapsed() >= TRANSITION_DURATION) {
+            self.transition_start = None;
+        }
+        self.transition_start.is_some()
+    }
+
+    /// Invert the pixels in data, which are in the buffer format
+    pub fn apply(&self, data: &mut [u8], format: wl_shm::Format) {
+        invert(data, format, self.level());
+    }
+}
+
+/// Invert the pixels in data, which are in the buffer format, by level: from 0 (unchanged)
+/// to 1 (negative)
+pub fn invert(data: &mut [u8], format: wl_shm::Format, level: f32) {
+    if level <= 0.0 {
+        return;
+    }
+    let mix = |value: f32, max: f32| value + level * (max - 2.0 * value);
+    for pixel in data.chunks_exact_mut(4) {
+        match format {
+            wl_shm::Format::Xbgr2101010 => {
+                let value = u32::from_le_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]);
+                let inverted = (0..3).fold(0, |acc, channel| {
+                    let shift = channel * 10;
+                    let component = ((value >> shift) & 0x3ff) as f32;
+                    acc | ((mix(component, 1023.0).round() as u32) << shift)
+                });
+                pixel.copy_from_slice(&inverted.to_le_bytes());
+            }
+            // Leave the alpha channel untouched; the colors are premultiplied by it
+            _ => {
+                let alpha = f32::from(pixel[3]);
+                for component in &mut pixel[..3] {
+                    *component = mix(f32::from(*component), alpha).round() as u8;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::time::{Duration, Instant};
+
+use wayland_client::protocol::wl_shm;
+
+/// How much larger than the output the image is loaded, i.e. how far the effect zooms in
+pub const ZOOM: f32 = 1.2;
+/// How long a movement lasts when the image is not rotated
+const MOVE_DURATION: Duration = Duration::from_secs(300);
+
+/// A region of the source image, in pixels
+#[derive(Clone, Copy)]
+struct Rect {
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+}
+
+/// Slowly pans and zooms across an image larger than the output. Each movement goes from the
+/// whole image to a zoomed in region, or the other way around; when it ends, a new one starts
+/// from where the last one stopped.
+pub struct KenBurns {
+    /// Pixels of the source image, in the buffer format
+    data: Vec<u8>,
+    width: u32,
+    height: u32,
+    format: wl_shm::Format,
+    from: Rect,
+    to: Rect,
+    start: Instant,
+    duration: Duration,
+}
+
+impl KenBurns {
+    pub fn new(data: Vec<u8>, width: u32, height: u32, format: wl_shm::Format) -> Self {
+        let mut ken_burns = Self {
+            data,
+            width,
+            height,
+            format,
+            from: Rect {
+                x: 0.0,
+                y: 0.0,
+                width: width as f32,
+                height: height as f32,
+            },
+            to: Rect {
+                x: 0.0,
+                y: 0.0,
+                width: width as f32,
+                height: height as f32,
+            },
+            start: Instant::now(),
+            duration: MOVE_DURATION,
+        };
+        ken_burns.to = ken_burns.random_rect(true);
+        ken_burns
+    }
+
+    /// Start the movement again, making it last duration; None keeps the default duration
+    pub fn restart(&mut self, duration: Option<Duration>) {
+        self.start = Instant::now();
+        self.duration = duration
+            .filter(|duration| !duration.is_zero())
+            .unwrap_or(MOVE_DURATION);
+    }
+
+    /// A random region, either the whole image or one as large as the output
+    fn random_rect(&self, zoomed: bool) -> Rect {
+        let scale = if zoomed { 1.0 / ZOOM } else { 1.0 };
+        let width = self.width as f32 * scale;
+        let height = self.height as f32 * scale;
+        Rect {
+            x: rand::random::<f32>() * (self.width as f32 - width),
+            y: rand::random::<f32>() * (self.height as f32 - height),
+            width,
+            height,
+        }
+    }
+
+    /// Render the current frame, scaled to width x height
+    pub fn frame(&mut self, width: u32, height: u32) -> Vec<u8> {
+        let mut progress = self.start.elapsed().as_secs_f32() / self.duration.as_secs_f32();
+        if progress >= 1.0 {
+            let zoomed = self.to.width < self.width as f32;
+            self.from = self.to;
+            self.to = self.random_rect(!zoomed);
+            self.start = Instant::now();
+            progress = 0.0;
+        }
+        // Ease in and out, so that consecutive movements join smoothly
+        let t = progress * progress * (3.0 - 2.0 * progress);
+        let lerp = |from: f32, to: f32| from + (to - from) * t;
+        let rect = Rect {
+            x: lerp(self.from.x, self.to.x),
+            y: lerp(self.from.y, self.to.y),
+            width: lerp(self.from.width, self.to.width),
+            height: lerp(self.from.height, self.to.height),
+        };
+        self.resample(rect, width, height)
+    }
+
+    /// Scale rect of the source to width x height with bilinear filtering
+    fn resample(&self, rect: Rect, width: u32, height: u32) -> Vec<u8> {
+        // For each destination coordinate: the two source coordinates around it and the
+        // weight of the second one, out of 256
+        let samples =
+            |start: f32, size: f32, len: u32, source_len: u32| -> Vec<(usize, usize, u32)> {
+                (0..len)
+                    .map(|i| {
+                        let position = (start + (i as f32 + 0.5) * size / len as f32 - 0.5)
+                            .clamp(0.0, (source_len - 1) as f32);
+                        let first = position as usize;
+                        let second = (first + 1).min(source_len as usize - 1);
+                        let weight = ((position - first as f32) * 256.0) as u32;
+                        (first, second, weight)
+                    })
+                    .collect()
+            };
+        let columns = samples(rect.x, rect.width, width, self.width);
+        let rows = samples(rect.y, rect.height, height, self.height);
+
+        let pixel = |x: usize, y: usize| {
+            let offset = (y * self.width as usize + x) * 4;
+            u32::from_le_bytes(self.data[offset..offset + 4].try_into().unwrap())
+        };
+        // 10-bit buffers pack three channels of 10 bits, the others four channels of 8 bits
+        let (channels, bits) = match self.format {
+            wl_shm::Format::Xbgr2101010 => (3, 10),
+            _ => (4, 8),
+        };
+        let mask = (1 << bits) - 1;
+        let mut data = Vec::with_capacity((width * height * 4) as usize);
+        for &(y0, y1, wy) in &rows {
+            for &(x0, x1, wx) in &columns {
+                let corners = [pixel(x0, y0), pixel(x1, y0), pixel(x0, y1), pixel(x1, y1)];
+                let mut value = 0;
+                for channel in 0..channels {
+                    let shift = channel * bits;
+                    let [a, b, c, d] = corners.map(|corner| (corner >> shift) & mask);
+                    let top = a * (256 - wx) + b * wx;
+                    let bottom = c * (256 - wx) + d * wx;
+                    value |= ((top * (256 - wy) + bottom * wy) >> 16) << shift;
+                }
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        data
+    }
+}
@@ -0,0 +1,69 @@
+//! The wallpaper engine of wpaperd, for the programs that show images the way it does, e.g.
+//! lock screens, greeters and wallpaper pickers.
+//!
+//! An image goes through the [pipeline](pipeline): a [`Decoder`](pipeline::Decoder) reads
+//! the file, a [`Scaler`](pipeline::Scaler) brings it to the size of the frame and
+//! [`to_buffer_data`](pipeline::to_buffer_data) converts it to the pixels of a wayland
+//! buffer, which a [`FrameSink`](pipeline::FrameSink) shows. The transitions then work on
+//! those pixels, and [`selection`] chooses the next image.
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! use wayland_client::protocol::wl_shm;
+//! use wpaper_core::pipeline::{decode_image, decode_size, to_buffer_data, FitScaler, Scaler};
+//! use wpaper_core::settings::{Color, Crop, Dither, Fit, ScalingFilter};
+//!
+//! let (width, height) = (1920, 1080);
+//! let (decode_width, decode_height) = decode_size(Fit::Fill, width, height);
+//! let image = decode_image(Path::new("wallpaper.jpg"), decode_width, decode_height)?;
+//! let black = Color([0, 0, 0, 0xff]);
+//! let filter = ScalingFilter::Auto;
+//! let scaler = FitScaler::new(
+//!     Fit::Fill,
+//!     filter,
+//!     Crop::Entropy,
+//!     black,
+//!     1.0,
+//!     false,
+//!     false,
+//!     false,
+//! );
+//! let image = scaler.scale(image, width, height);
+//! let data = to_buffer_data(image, wl_shm::Format::Abgr8888, None, black, Dither::None);
+//! # Ok::<(), color_eyre::Report>(())
+//! ```
+
+/// Smart cropping of the images that cover the output
+pub mod crop;
+/// Reduction of the images with more than 8 bits per channel
+pub mod dither;
+/// Orientation and capture time of the photos
+pub mod exif;
+/// Fade to black of an output that goes away
+pub mod fade;
+/// HEIF images and the dynamic wallpapers of macOS
+pub mod heic;
+/// Color correction with the ICC profile of a display
+pub mod icc;
+/// History of the images shown on an output
+pub mod image_picker;
+/// Transition to and from the negative of the image
+pub mod inversion;
+/// Slow pan and zoom across the image
+pub mod ken_burns;
+/// Scaling in linear light, which keeps the brightness of the fine details
+pub mod linear;
+/// Shift of the image following the pointer
+pub mod parallax;
+/// Decoding, scaling and conversion of the images to wayland buffers
+pub mod pipeline;
+/// Images already rendered for the screen, RGBA framebuffers and QOI
+pub mod raw;
+/// Random choice of the next image
+pub mod selection;
+pub mod settings;
+/// Position of the sun, from the date and the location
+pub mod sun;
+/// Rasterization of SVG images
+pub mod svg;
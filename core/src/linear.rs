@@ -0,0 +1,58 @@
+use image::{DynamicImage, ImageBuffer, Rgba};
+use lazy_static::lazy_static;
+
+use crate::pipeline::widen;
+
+lazy_static! {
+    /// Linear light of each 16-bit sRGB level, computed the first time an output scales in
+    /// linear light
+    static ref TO_LINEAR: Vec<u16> = lookup_table(srgb_to_linear);
+    /// sRGB level of each 16-bit linear light level
+    static ref TO_SRGB: Vec<u16> = lookup_table(linear_to_srgb);
+}
+
+/// Call resize with the image in linear light, then bring the result back to sRGB. Averaging
+/// the sRGB levels darkens the fine details, e.g. a grid of black and white lines reduced to
+/// a gray much darker than the light it gives. The image keeps 16 bits per channel, the dark
+/// levels would be lost in 8 bits of linear light; alpha is unchanged.
+pub fn resize_in_linear_light(
+    image: DynamicImage,
+    resize: impl FnOnce(DynamicImage) -> DynamicImage,
+) -> DynamicImage {
+    let mut image = widen(image);
+    convert(&mut image, &TO_LINEAR);
+    let mut image = widen(resize(DynamicImage::ImageRgba16(image)));
+    convert(&mut image, &TO_SRGB);
+    DynamicImage::ImageRgba16(image)
+}
+
+fn convert(image: &mut ImageBuffer<Rgba<u16>, Vec<u16>>, table: &[u16]) {
+    for pixel in image.pixels_mut() {
+        for channel in &mut pixel.0[..3] {
+            *channel = table[usize::from(*channel)];
+        }
+    }
+}
+
+fn lookup_table(f: fn(f64) -> f64) -> Vec<u16> {
+    (0..=u16::MAX)
+        .map(|level| (f(f64::from(level) / 65535.0) * 65535.0).round() as u16)
+        .collect()
+}
+
+/// The transfer functions of the sRGB standard
+fn srgb_to_linear(value: f64) -> f64 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f64) -> f64 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
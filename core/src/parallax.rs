@@ -0,0 +1,157 @@
+use std::time::Instant;
+
+/// How quickly the image follows the pointer: the distance left is divided by e every this
+/// many seconds
+const EASING: f32 = 0.15;
+
+/// Shows a part of an image slightly larger than the output, shifted toward where the pointer
+/// is, so that the wallpaper seems to lie behind the windows. The image follows the pointer
+/// with a short delay and goes back to the center when the pointer leaves the output.
+pub struct Parallax {
+    /// Pixels of the source image, in the buffer format
+    data: Vec<u8>,
+    width: u32,
+    height: u32,
+    /// The shift drawn last and the one to reach, from -1 to 1 on each axis, 0 being centered
+    current: (f32, f32),
+    target: (f32, f32),
+    last_frame: Instant,
+}
+
+impl Parallax {
+    pub fn new(data: Vec<u8>, width: u32, height: u32) -> Self {
+        Self {
+            data,
+            width,
+            height,
+            current: (0.0, 0.0),
+            target: (0.0, 0.0),
+            last_frame: Instant::now(),
+        }
+    }
+
+    /// Follow the pointer, at x and y from -1 to 1 across the output; None centers the image
+    pub fn point_at(&mut self, position: Option<(f32, f32)>) {
+        let (x, y) = position.unwrap_or((0.0, 0.0));
+        self.target = (x.clamp(-1.0, 1.0), y.clamp(-1.0, 1.0));
+    }
+
+    /// Whether the image has not caught up with the pointer yet, at width x height
+    pub fn is_moving(&self, width: u32, height: u32) -> bool {
+        self.offset(self.current, width, height) != self.offset(self.target, width, height)
+    }
+
+    /// Render the current frame, the part of width x height of the image. An image smaller
+    /// than that has its last row and column repeated, so that the frame always has width x
+    /// height pixels.
+    pub fn frame(&mut self, width: u32, height: u32) -> Vec<u8> {
+        let elapsed = self.last_frame.elapsed().as_secs_f32();
+        self.last_frame = Instant::now();
+        let t = 1.0 - (-elapsed / EASING).exp();
+        let lerp = |from: f32, to: f32| from + (to - from) * t;
+        self.current = (
+            lerp(self.current.0, self.target.0),
+            lerp(self.current.1, self.target.1),
+        );
+        if !self.is_moving(width, height) {
+            self.current = self.target;
+        }
+        // Whole pixels, so that the rows are copied as they are
+        let (x, y) = self.offset(self.current, width, height);
+        let stride = self.width as usize * 4;
+        let (width, height) = (width as usize, height as usize);
+        let mut data = Vec::with_capacity(width * height * 4);
+        if stride == 0 || self.data.len() < stride {
+            data.resize(width * height * 4, 0);
+            return data;
+        }
+        let lines: Vec<&[u8]> = self.data.chunks_exact(stride).collect();
+        for j in 0..height {
+            let line = lines.get(y + j).unwrap_or(&lines[lines.len() - 1]);
+            let row = &line[x * 4..stride.min((x + width) * 4)];
+            data.extend_from_slice(row);
+            let last_pixel = &line[stride - 4..];
+            for _ in row.len() / 4..width {
+                data.extend_from_slice(last_pixel);
+            }
+        }
+        data
+    }
+
+    /// The top left corner of the part of width x height shifted by shift
+    fn offset(&self, (x, y): (f32, f32), width: u32, height: u32) -> (usize, usize) {
+        let position = |shift: f32, size: u32, len: u32| {
+            let margin = len.saturating_sub(size) as f32 / 2.0;
+            (margin * (1.0 + shift)).round() as usize
+        };
+        (
+            position(x, width, self.width),
+            position(y, height, self.height),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An image whose pixels hold their coordinates
+    fn parallax(width: u32, height: u32) -> Parallax {
+        let data = (0..height)
+            .flat_map(|y| (0..width).flat_map(move |x| [x as u8, y as u8, 0, 0xff]))
+            .collect();
+        Parallax::new(data, width, height)
+    }
+
+    #[test]
+    fn offsets_follow_the_shift() {
+        // A margin of 2 pixels on each side
+        let parallax = parallax(12, 8);
+        assert_eq!(parallax.offset((-1.0, -1.0), 8, 4), (0, 0));
+        assert_eq!(parallax.offset((0.0, 0.0), 8, 4), (2, 2));
+        assert_eq!(parallax.offset((1.0, 1.0), 8, 4), (4, 4));
+        assert_eq!(parallax.offset((1.0, -1.0), 8, 4), (4, 0));
+        // Without a margin, the image stays in place
+        assert_eq!(parallax.offset((1.0, 1.0), 12, 8), (0, 0));
+        assert_eq!(parallax.offset((1.0, 1.0), 20, 10), (0, 0));
+    }
+
+    #[test]
+    fn pointers_out_of_the_output_are_clamped() {
+        let mut parallax = parallax(12, 8);
+        parallax.point_at(Some((5.0, -3.0)));
+        assert_eq!(parallax.target, (1.0, -1.0));
+        assert!(parallax.is_moving(8, 4));
+        parallax.point_at(None);
+        assert_eq!(parallax.target, (0.0, 0.0));
+        assert!(!parallax.is_moving(8, 4));
+        // Less than half a pixel away
+        parallax.point_at(Some((0.2, 0.0)));
+        assert!(!parallax.is_moving(8, 4));
+    }
+
+    #[test]
+    fn frames_are_the_shifted_part_of_the_image() {
+        let mut parallax = parallax(12, 8);
+        parallax.current = (1.0, -1.0);
+        parallax.point_at(Some((1.0, -1.0)));
+        let frame = parallax.frame(8, 4);
+        assert_eq!(frame.len(), 8 * 4 * 4);
+        assert_eq!(&frame[..4], &[4, 0, 0, 0xff]);
+        assert_eq!(&frame[frame.len() - 4..], &[11, 3, 0, 0xff]);
+    }
+
+    #[test]
+    fn smaller_images_are_padded() {
+        let mut parallax = parallax(4, 2);
+        let frame = parallax.frame(6, 3);
+        assert_eq!(frame.len(), 6 * 3 * 4);
+        let pixel = |x: usize, y: usize| &frame[(y * 6 + x) * 4..][..2];
+        assert_eq!(pixel(0, 0), [0, 0]);
+        assert_eq!(pixel(5, 0), [3, 0]);
+        assert_eq!(pixel(2, 2), [2, 1]);
+        assert_eq!(pixel(5, 2), [3, 1]);
+        // Nothing to show at all
+        assert_eq!(Parallax::new(Vec::new(), 0, 0).frame(2, 2), vec![0; 16]);
+    }
+}
@@ -0,0 +1,673 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use color_eyre::eyre::{bail, eyre, Context};
+use color_eyre::Result;
+use image::codecs::jpeg::JpegDecoder;
+use image::imageops::{self, FilterType};
+use image::io::Reader;
+use image::{DynamicImage, GenericImageView, ImageBuffer, ImageError, ImageFormat, Rgb, Rgba};
+use tiff::decoder::DecodingResult;
+use wayland_client::protocol::wl_shm;
+
+use crate::crop;
+use crate::dither::dither;
+use crate::exif;
+use crate::heic;
+use crate::icc::ColorTransform;
+use crate::linear;
+use crate::raw;
+use crate::settings::{Color, Crop, Dither, Fit, ScalingFilter};
+use crate::svg;
+
+/// Turns an image file into pixels
+pub trait Decoder: Send + Sync {
+    /// Decode the image at path. It can be reduced while decoding, as long as it still
+    /// covers width x height.
+    fn decode(&self, path: &Path, width: u32, height: u32) -> Result<DynamicImage>;
+
+    /// Whether a reduced version of the image at path decodes much faster than the full one
+    fn decodes_reduced_fast(&self, _path: &Path) -> bool {
+        false
+    }
+}
+
+/// Scales a decoded image to the size of a frame
+pub trait Scaler {
+    /// An image of exactly width x height pixels
+    fn scale(&self, image: DynamicImage, width: u32, height: u32) -> DynamicImage;
+}
+
+/// Shows the frames, e.g. by attaching them to a wayland surface
+pub trait FrameSink {
+    /// Show data, a frame of width x height pixels in format
+    fn present(&mut self, data: &[u8], size: (u32, u32), format: wl_shm::Format) -> Result<()>;
+}
+
+/// Decodes the image files with the image crate
+pub struct FileDecoder;
+
+impl Decoder for FileDecoder {
+    fn decode(&self, path: &Path, width: u32, height: u32) -> Result<DynamicImage> {
+        decode_image(path, width, height)
+    }
+
+    fn decodes_reduced_fast(&self, path: &Path) -> bool {
+        // Only JPEG images are scaled down while decoding
+        Reader::open(path)
+            .and_then(Reader::with_guessed_format)
+            .is_ok_and(|reader| reader.format() == Some(ImageFormat::Jpeg))
+    }
+}
+
+/// The size an image needs to be decoded at, to be scaled to width x height with fit
+pub fn decode_size(fit: Fit, width: u32, height: u32) -> (u32, u32) {
+    match fit {
+        // Tiles are shown at the size of the image, the JPEG decoder must not reduce it
+        Fit::Tile => (u32::MAX, u32::MAX),
+        Fit::Fill | Fit::Contain => (width, height),
+    }
+}
+
+/// How much smaller than the output the copy of the image behind the bars is blurred
+const BLUR_REDUCTION: u32 = 8;
+/// Radius of the blur of that copy, in its reduced pixels
+const BLUR_SIGMA: f32 = 6.0;
+
+/// Scales the images according to fit, over a background
+pub struct FitScaler {
+    fit: Fit,
+    crop: Crop,
+    filter: ScalingFilter,
+    background: Color,
+    tile_scale: f32,
+    tile_mirror: bool,
+    blur_background: bool,
+    linear: bool,
+}
+
+impl FitScaler {
+    /// A scaler for fit with filter, in linear light with linear. Fit::Fill keeps the part of
+    /// the images chosen by crop, Fit::Contain shows background around them, or a blurred copy
+    /// of the image with blur_background, and Fit::Tile scales the tiles by tile_scale,
+    /// flipping every other one with tile_mirror. The background also shows through the
+    /// transparent areas.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        fit: Fit,
+        filter: ScalingFilter,
+        crop: Crop,
+        background: Color,
+        tile_scale: f32,
+        tile_mirror: bool,
+        blur_background: bool,
+        linear: bool,
+    ) -> Self {
+        Self {
+            fit,
+            crop,
+            filter,
+            background,
+            tile_scale,
+            tile_mirror,
+            blur_background,
+            linear,
+        }
+    }
+}
+
+impl Scaler for FitScaler {
+    fn scale(&self, image: DynamicImage, width: u32, height: u32) -> DynamicImage {
+        let scale = match self.fit {
+            Fit::Fill => f32::max(
+                width as f32 / image.width() as f32,
+                height as f32 / image.height() as f32,
+            ),
+            Fit::Contain => f32::min(
+                width as f32 / image.width() as f32,
+                height as f32 / image.height() as f32,
+            ),
+            Fit::Tile => self.tile_scale,
+        };
+        let filter = self
+            .filter
+            .filter_type(scale, u64::from(width) * u64::from(height));
+        let image = match self.fit {
+            Fit::Fill => resize_to_fill(image, width, height, filter, self.crop, self.linear),
+            Fit::Contain if self.blur_background => resize_to_contain_blurred(
+                image,
+                width,
+                height,
+                filter,
+                self.background,
+                self.linear,
+            ),
+            Fit::Contain => {
+                resize_to_contain(image, width, height, filter, self.background, self.linear)
+            }
+            Fit::Tile => tile(
+                image,
+                width,
+                height,
+                scale,
+                filter,
+                self.tile_mirror,
+                self.linear,
+            ),
+        };
+        // The transparent areas of the image show the background
+        if image.color().has_alpha() && !is_opaque(&image) {
+            over_background(image, width, height, self.background)
+        } else {
+            image
+        }
+    }
+}
+
+/// The part of a panorama shown by an output: its rectangle inside the layout of the
+/// outputs it spans, as fractions of the size of the layout
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Span {
+    /// Size of the whole layout, in the pixels of an output of width x height
+    pub fn layout_size(&self, width: u32, height: u32) -> (u32, u32) {
+        (
+            ((width as f32 / self.width).round() as u32).max(width),
+            ((height as f32 / self.height).round() as u32).max(height),
+        )
+    }
+}
+
+/// Scales an image to cover the layout of the outputs it spans and crops the part of one of
+/// them
+pub struct SpanScaler {
+    span: Span,
+    filter: ScalingFilter,
+    background: Color,
+    linear: bool,
+}
+
+impl SpanScaler {
+    /// A scaler for the part span of the layout with filter, in linear light with linear,
+    /// over background
+    pub fn new(span: Span, filter: ScalingFilter, background: Color, linear: bool) -> Self {
+        Self {
+            span,
+            filter,
+            background,
+            linear,
+        }
+    }
+}
+
+impl Scaler for SpanScaler {
+    fn scale(&self, image: DynamicImage, width: u32, height: u32) -> DynamicImage {
+        let (layout_width, layout_height) = self.span.layout_size(width, height);
+        let scale = f32::max(
+            layout_width as f32 / image.width() as f32,
+            layout_height as f32 / image.height() as f32,
+        );
+        let filter = self
+            .filter
+            .filter_type(scale, u64::from(layout_width) * u64::from(layout_height));
+        let image = resize_to_fill(
+            image,
+            layout_width,
+            layout_height,
+            filter,
+            Crop::Center,
+            self.linear,
+        );
+        let x = ((self.span.x * layout_width as f32).round() as u32).min(layout_width - width);
+        let y = ((self.span.y * layout_height as f32).round() as u32).min(layout_height - height);
+        let image = image.crop_imm(x, y, width, height);
+        if image.color().has_alpha() {
+            over_background(image, width, height, self.background)
+        } else {
+            image
+        }
+    }
+}
+
+/// Convert a scaled image to the pixels of a wayland buffer in format, applying the color
+/// transform of the display. Images with more than 8 bits per channel are dithered with
+/// method for 8-bit buffers, or rounded to the nearest level without dithering. Without a
+/// color transform, the pixels are packed straight from the image, whatever its depth.
+pub fn to_buffer_data(
+    image: DynamicImage,
+    format: wl_shm::Format,
+    color_transform: Option<&ColorTransform>,
+    background: Color,
+    method: Dither,
+) -> Vec<u8> {
+    match format {
+        wl_shm::Format::Xbgr2101010 => match color_transform {
+            Some(color_transform) => {
+                let mut image = to_rgb16(image);
+                color_transform.apply_rgb16(&mut image);
+                to_xbgr2101010(image.pixels().map(|pixel| pixel.0), image.len() / 3)
+            }
+            None => match image {
+                DynamicImage::ImageRgb16(image) => {
+                    to_xbgr2101010(image.pixels().map(|pixel| pixel.0), image.len() / 3)
+                }
+                DynamicImage::ImageRgba16(image) => to_xbgr2101010(
+                    image.pixels().map(|Rgba([r, g, b, _])| [*r, *g, *b]),
+                    image.len() / 4,
+                ),
+                image if is_8bit(&image) => {
+                    let image = image.into_rgba8();
+                    to_xbgr2101010(
+                        image
+                            .pixels()
+                            .map(|Rgba([r, g, b, _])| [*r, *g, *b].map(|c| u16::from(c) * 257)),
+                        image.len() / 4,
+                    )
+                }
+                image => {
+                    let image = image.into_rgb16();
+                    to_xbgr2101010(image.pixels().map(|pixel| pixel.0), image.len() / 3)
+                }
+            },
+        },
+        _ => {
+            let mut image = if is_8bit(&image) {
+                image.into_rgba8()
+            } else {
+                // The conversion of the image crate drops the low byte, darkening the image
+                dither(&widen(image), method)
+            };
+            if let Some(color_transform) = color_transform {
+                color_transform.apply_rgba8(&mut image);
+            }
+            let mut data = image.into_raw();
+            if !background.is_opaque() {
+                premultiply_alpha(&mut data);
+            }
+            data
+        }
+    }
+}
+
+/// Decode the image at path. JPEG images are scaled down while decoding, as long as they
+/// still cover width x height, which is much faster and avoids holding the full size image
+/// in memory; they are also rotated according to their EXIF orientation. SVG images are
+/// rasterized at the size that covers width x height. Raw images are read as they are. HEIF
+/// dynamic wallpapers give the image their schedule shows now.
+pub fn decode_image(path: &Path, width: u32, height: u32) -> Result<DynamicImage> {
+    if svg::is_svg(path) {
+        return svg::rasterize(path, width, height);
+    }
+    if heic::is_heic(path) {
+        return heic::decode(path);
+    }
+    if raw::is_raw(path) {
+        return raw::load(path, width, height);
+    }
+    let context = || format!("opening the image {:?}", path);
+    let reader = Reader::open(path)
+        .and_then(Reader::with_guessed_format)
+        .with_context(context)?;
+    if reader.format() == Some(ImageFormat::Jpeg) {
+        let orientation = exif::read(path).map_or(1, |exif| exif.orientation);
+        // The image is scaled before being rotated
+        let (width, height) = if exif::swaps_dimensions(orientation) {
+            (height, width)
+        } else {
+            (width, height)
+        };
+        let mut decoder = JpegDecoder::new(reader.into_inner()).with_context(context)?;
+        let clamp = |size: u32| size.min(u32::from(u16::MAX)) as u16;
+        decoder
+            .scale(clamp(width), clamp(height))
+            .with_context(context)?;
+        let image = DynamicImage::from_decoder(decoder).with_context(context)?;
+        Ok(exif::apply_orientation(image, orientation))
+    } else if reader.format() == Some(ImageFormat::Tiff) {
+        // The TIFF decoder of the image crate rejects the CMYK images exported for print
+        match reader.decode() {
+            Err(ImageError::Unsupported(_)) => decode_cmyk_tiff(path).with_context(context),
+            res => res.with_context(context),
+        }
+    } else {
+        reader.decode().with_context(context)
+    }
+}
+
+/// Decode a TIFF image in CMYK, with 8 or 16 bits per channel, into RGB with 16 bits per
+/// channel. The channels are the amounts of ink, from none to full, without a profile.
+fn decode_cmyk_tiff(path: &Path) -> Result<DynamicImage> {
+    let mut decoder = tiff::decoder::Decoder::new(BufReader::new(File::open(path)?))?;
+    let (width, height) = decoder.dimensions()?;
+    let color_type = decoder.colortype()?;
+    let cmyk: Vec<u16> = match (color_type, decoder.read_image()?) {
+        (tiff::ColorType::CMYK(8), DecodingResult::U8(data)) => {
+            data.into_iter().map(|ink| u16::from(ink) * 257).collect()
+        }
+        (tiff::ColorType::CMYK(16), DecodingResult::U16(data)) => data,
+        _ => bail!("unsupported color type {:?}", color_type),
+    };
+    let rgb = cmyk
+        .chunks_exact(4)
+        .flat_map(|pixel| {
+            let black = u32::from(u16::MAX - pixel[3]);
+            [0, 1, 2].map(|i| (u32::from(u16::MAX - pixel[i]) * black / 65535) as u16)
+        })
+        .collect();
+    ImageBuffer::from_raw(width, height, rgb)
+        .map(DynamicImage::ImageRgb16)
+        .ok_or_else(|| eyre!("the image is truncated"))
+}
+
+/// Call resize on image, in linear light with linear
+fn resized(
+    image: DynamicImage,
+    linear: bool,
+    resize: impl FnOnce(DynamicImage) -> DynamicImage,
+) -> DynamicImage {
+    if linear {
+        linear::resize_in_linear_light(image, resize)
+    } else {
+        resize(image)
+    }
+}
+
+/// Scale the image to cover width x height, keeping the part chosen by crop
+fn resize_to_fill(
+    image: DynamicImage,
+    width: u32,
+    height: u32,
+    filter: FilterType,
+    crop: Crop,
+    linear: bool,
+) -> DynamicImage {
+    if (image.width(), image.height()) == (width, height) {
+        return image;
+    }
+    if crop == Crop::Center {
+        return resized(image, linear, |image| {
+            image.resize_to_fill(width, height, filter)
+        });
+    }
+    let scale = f32::max(
+        width as f32 / image.width() as f32,
+        height as f32 / image.height() as f32,
+    );
+    let window =
+        |size: u32, image_size: u32| ((size as f32 / scale).round() as u32).clamp(1, image_size);
+    let (window_width, window_height) =
+        (window(width, image.width()), window(height, image.height()));
+    let (x, y) = crop::position(&image, window_width, window_height, crop);
+    resized(
+        image.crop_imm(x, y, window_width, window_height),
+        linear,
+        |image| image.resize_exact(width, height, filter),
+    )
+}
+
+/// Scale the image to fit inside width x height and center it over the background
+fn resize_to_contain(
+    image: DynamicImage,
+    width: u32,
+    height: u32,
+    filter: FilterType,
+    background: Color,
+    linear: bool,
+) -> DynamicImage {
+    if (image.width(), image.height()) == (width, height) {
+        return image;
+    }
+    over_background(
+        resized(image, linear, |image| image.resize(width, height, filter)),
+        width,
+        height,
+        background,
+    )
+}
+
+/// Scale the image to fit inside width x height and center it over a blurred copy of itself
+/// that covers width x height, which fills the bars with its colors
+fn resize_to_contain_blurred(
+    image: DynamicImage,
+    width: u32,
+    height: u32,
+    filter: FilterType,
+    Color(color): Color,
+    linear: bool,
+) -> DynamicImage {
+    if (image.width(), image.height()) == (width, height) {
+        return image;
+    }
+    // First pass: the copy is blurred at a fraction of the size of the output, which is much
+    // faster and looks the same once enlarged with a smooth filter
+    let reduce = |size: u32| (size / BLUR_REDUCTION).max(1);
+    let backdrop = image
+        .resize_to_fill(reduce(width), reduce(height), FilterType::Triangle)
+        .blur(BLUR_SIGMA)
+        .resize_exact(width, height, FilterType::Triangle);
+    // The transparent areas of the copy still show the background
+    let mut canvas =
+        ImageBuffer::from_pixel(width, height, Rgba(color.map(|c| u16::from(c) * 257)));
+    imageops::overlay(&mut canvas, &widen(backdrop), 0, 0);
+    // Second pass: the fitted image over the copy
+    let image = widen(resized(image, linear, |image| {
+        image.resize(width, height, filter)
+    }));
+    imageops::overlay(
+        &mut canvas,
+        &image,
+        (width - image.width()) / 2,
+        (height - image.height()) / 2,
+    );
+    DynamicImage::ImageRgba16(canvas)
+}
+
+/// Repeat the image, scaled by scale, across width x height pixels. With mirror, every other
+/// column of tiles is flipped horizontally and every other row vertically.
+#[allow(clippy::too_many_arguments)]
+fn tile(
+    image: DynamicImage,
+    width: u32,
+    height: u32,
+    scale: f32,
+    filter: FilterType,
+    mirror: bool,
+    linear: bool,
+) -> DynamicImage {
+    let image = if scale == 1.0 {
+        image
+    } else {
+        let scale = |size: u32| ((size as f32 * scale).round() as u32).max(1);
+        let (width, height) = (scale(image.width()), scale(image.height()));
+        resized(image, linear, |image| {
+            image.resize_exact(width, height, filter)
+        })
+    };
+    // 16 bits per channel preserve the depth of the image for 10-bit buffers
+    let image = widen(image);
+    let (tile_width, tile_height) = (image.width() as usize, image.height() as usize);
+    let row_len = width as usize * 4;
+    let mut data = vec![0; row_len * height as usize];
+
+    // Only the first row of tiles is read from the image, the rest of the rows and columns
+    // are copied from what has already been written
+    let period_x = if mirror { 2 * tile_width } else { tile_width } * 4;
+    for (y, row) in data.chunks_exact_mut(row_len).take(tile_height).enumerate() {
+        let src = &image.as_raw()[y * tile_width * 4..(y + 1) * tile_width * 4];
+        let len = src.len().min(row_len);
+        row[..len].copy_from_slice(&src[..len]);
+        if mirror {
+            for (x, pixel) in src.chunks_exact(4).rev().enumerate() {
+                let start = (tile_width + x) * 4;
+                if start >= row_len {
+                    break;
+                }
+                row[start..start + 4].copy_from_slice(pixel);
+            }
+        }
+        let mut x = period_x;
+        while x < row_len {
+            let len = period_x.min(row_len - x);
+            row.copy_within(..len, x);
+            x += period_x;
+        }
+    }
+    let period_y = if mirror { 2 * tile_height } else { tile_height };
+    for y in tile_height..height as usize {
+        let src = match y % period_y {
+            // Rows of flipped tiles, the first row of tiles read from the bottom up
+            y_in_period if y_in_period >= tile_height => period_y - 1 - y_in_period,
+            _ => y - period_y,
+        };
+        data.copy_within(src * row_len..(src + 1) * row_len, y * row_len);
+    }
+    // The buffer has exactly width x height pixels
+    DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, data).unwrap())
+}
+
+/// Center the image over width x height pixels of the background color, blending its
+/// transparent areas
+/// The image with 16 bits per channel. The conversion of the image crate shifts the 8-bit
+/// channels instead of scaling them, which turns white into a light gray.
+pub fn widen(image: DynamicImage) -> ImageBuffer<Rgba<u16>, Vec<u16>> {
+    if is_8bit(&image) {
+        let image = image.into_rgba8();
+        let (width, height) = image.dimensions();
+        let data = image.into_raw().into_iter().map(|c| u16::from(c) * 257);
+        // The length is unchanged
+        ImageBuffer::from_raw(width, height, data.collect()).unwrap()
+    } else {
+        image.into_rgba16()
+    }
+}
+
+/// The image in RGB with 16 bits per channel, scaling the 8-bit channels like widen does
+fn to_rgb16(image: DynamicImage) -> ImageBuffer<Rgb<u16>, Vec<u16>> {
+    if is_8bit(&image) {
+        let image = image.into_rgba8();
+        let (width, height) = image.dimensions();
+        let data = image
+            .pixels()
+            .flat_map(|Rgba([r, g, b, _])| [*r, *g, *b].map(|c| u16::from(c) * 257))
+            .collect();
+        // Three channels for each pixel
+        ImageBuffer::from_raw(width, height, data).unwrap()
+    } else {
+        image.into_rgb16()
+    }
+}
+
+fn is_8bit(image: &DynamicImage) -> bool {
+    matches!(
+        image,
+        DynamicImage::ImageRgba8(_)
+            | DynamicImage::ImageRgb8(_)
+            | DynamicImage::ImageLuma8(_)
+            | DynamicImage::ImageLumaA8(_)
+            | DynamicImage::ImageBgr8(_)
+            | DynamicImage::ImageBgra8(_)
+    )
+}
+
+/// Whether all the pixels of an image with 8 bits per channel are opaque, e.g. those of raw
+/// images rendered for the screen, which can then be used without a background
+fn is_opaque(image: &DynamicImage) -> bool {
+    match image {
+        DynamicImage::ImageRgba8(image) => image.pixels().all(|pixel| pixel[3] == u8::MAX),
+        _ => false,
+    }
+}
+
+fn over_background(
+    image: DynamicImage,
+    width: u32,
+    height: u32,
+    Color(color): Color,
+) -> DynamicImage {
+    // 16 bits per channel preserve the depth of the image for 10-bit buffers
+    let image = widen(image);
+    let mut canvas =
+        ImageBuffer::from_pixel(width, height, Rgba(color.map(|c| u16::from(c) * 257)));
+    imageops::overlay(
+        &mut canvas,
+        &image,
+        width.saturating_sub(image.width()) / 2,
+        height.saturating_sub(image.height()) / 2,
+    );
+    DynamicImage::ImageRgba16(canvas)
+}
+
+/// Wayland expects the color channels of buffers with alpha to be multiplied by it
+fn premultiply_alpha(data: &mut [u8]) {
+    for pixel in data.chunks_exact_mut(4) {
+        let alpha = u16::from(pixel[3]);
+        for component in &mut pixel[..3] {
+            *component = ((u16::from(*component) * alpha + 127) / 255) as u8;
+        }
+    }
+}
+
+/// Pack each of the count pixels in 32 bits little endian as x:B:G:R 2:10:10:10
+fn to_xbgr2101010(pixels: impl Iterator<Item = [u16; 3]>, count: usize) -> Vec<u8> {
+    let mut data = Vec::with_capacity(count * 4);
+    for pixel in pixels {
+        let [r, g, b] = pixel.map(|channel| (u32::from(channel) * 1023 + 32767) / 65535);
+        data.extend_from_slice(&((b << 20) | (g << 10) | r).to_le_bytes());
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Luma;
+
+    use super::*;
+
+    fn xbgr2101010(image: DynamicImage) -> Vec<u32> {
+        let data = to_buffer_data(
+            image,
+            wl_shm::Format::Xbgr2101010,
+            None,
+            Color([0, 0, 0, 0xff]),
+            Dither::None,
+        );
+        data.chunks_exact(4)
+            .map(|pixel| u32::from_le_bytes(pixel.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn ten_bit_buffers_are_packed_from_every_depth() {
+        // Red at full, green at about half and blue off, as x:B:G:R 2:10:10:10
+        let expected = (514 << 10) | 1023;
+        let half = 128 * 257;
+        for image in [
+            DynamicImage::ImageRgb8(ImageBuffer::from_pixel(3, 2, Rgb([255, 128, 0]))),
+            DynamicImage::ImageRgba8(ImageBuffer::from_pixel(3, 2, Rgba([255, 128, 0, 255]))),
+            DynamicImage::ImageRgb16(ImageBuffer::from_pixel(3, 2, Rgb([u16::MAX, half, 0]))),
+            DynamicImage::ImageRgba16(ImageBuffer::from_pixel(
+                3,
+                2,
+                Rgba([u16::MAX, half, 0, u16::MAX]),
+            )),
+        ] {
+            assert_eq!(xbgr2101010(image), [expected; 6]);
+        }
+        let gray = DynamicImage::ImageLuma16(ImageBuffer::from_pixel(1, 1, Luma([u16::MAX])));
+        assert_eq!(xbgr2101010(gray), [(1023 << 20) | (1023 << 10) | 1023]);
+    }
+
+    #[test]
+    fn color_transforms_keep_the_depth_of_8bit_images() {
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(1, 1, Rgb([255, 0, 1])));
+        let image = to_rgb16(image);
+        assert_eq!(image.get_pixel(0, 0).0, [u16::MAX, 0, 257]);
+    }
+}
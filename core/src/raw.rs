@@ -0,0 +1,222 @@
+use std::{fs, path::Path};
+
+use color_eyre::{
+    eyre::{bail, ensure, eyre, WrapErr},
+    Result,
+};
+use image::{DynamicImage, RgbaImage};
+
+/// Whether path is an image already rendered for the screen, which is read without the image
+/// crate: a raw RGBA framebuffer (.rgba) or a QOI image (.qoi)
+pub fn is_raw(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| {
+        extension.eq_ignore_ascii_case("rgba") || extension.eq_ignore_ascii_case("qoi")
+    })
+}
+
+/// Read the raw image at path. A .rgba file has no header, it must hold exactly width x height
+/// pixels of 4 bytes, red first. When the size of the image is that of the frame, it is
+/// neither scaled nor converted, its bytes are copied into the buffer as they are.
+pub fn load(path: &Path, width: u32, height: u32) -> Result<DynamicImage> {
+    let data = fs::read(path).with_context(|| format!("reading the image {:?}", path))?;
+    let is_qoi = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("qoi"));
+    let image = if is_qoi {
+        decode_qoi(&data).with_context(|| format!("decoding the QOI image {:?}", path))?
+    } else {
+        ensure!(
+            (width, height) != (u32::MAX, u32::MAX),
+            "the raw image {:?} has no size, it cannot be tiled",
+            path
+        );
+        let expected = 4 * u64::from(width) * u64::from(height);
+        ensure!(
+            data.len() as u64 == expected,
+            "the raw image {:?} is {} bytes, {} are expected for {}x{} pixels",
+            path,
+            data.len(),
+            expected,
+            width,
+            height
+        );
+        // The length is checked above
+        RgbaImage::from_raw(width, height, data).unwrap()
+    };
+    Ok(DynamicImage::ImageRgba8(image))
+}
+
+const QOI_MAGIC: &[u8] = b"qoif";
+const QOI_HEADER_SIZE: usize = 14;
+const QOI_OP_RGB: u8 = 0xfe;
+const QOI_OP_RGBA: u8 = 0xff;
+const QOI_OP_INDEX: u8 = 0x00;
+const QOI_OP_DIFF: u8 = 0x40;
+const QOI_OP_LUMA: u8 = 0x80;
+const QOI_OP_RUN: u8 = 0xc0;
+const QOI_MASK: u8 = 0xc0;
+
+/// Decode an image in the Quite OK Image format, https://qoiformat.org
+pub fn decode_qoi(data: &[u8]) -> Result<RgbaImage> {
+    ensure!(
+        data.len() >= QOI_HEADER_SIZE && data.starts_with(QOI_MAGIC),
+        "not a QOI image"
+    );
+    let be_u32 = |offset: usize| {
+        u32::from_be_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ])
+    };
+    let (width, height) = (be_u32(4), be_u32(8));
+    let pixels = u64::from(width) * u64::from(height);
+    // Each pixel takes at least one byte, unless it is part of a run
+    ensure!(
+        pixels > 0 && pixels <= 62 * data.len() as u64,
+        "invalid size {}x{}",
+        width,
+        height
+    );
+
+    let mut output = Vec::with_capacity(4 * pixels as usize);
+    let mut index = [[0u8; 4]; 64];
+    let mut pixel = [0, 0, 0, 0xff];
+    let mut bytes = data[QOI_HEADER_SIZE..].iter().copied();
+    let mut next = || bytes.next().ok_or_else(|| eyre!("the image is truncated"));
+    let mut decoded = 0;
+    while decoded < pixels {
+        let tag = next()?;
+        let mut run = 1;
+        match tag {
+            QOI_OP_RGB => {
+                for channel in &mut pixel[..3] {
+                    *channel = next()?;
+                }
+            }
+            QOI_OP_RGBA => {
+                for channel in &mut pixel {
+                    *channel = next()?;
+                }
+            }
+            _ => match tag & QOI_MASK {
+                QOI_OP_INDEX => pixel = index[usize::from(tag)],
+                QOI_OP_DIFF => {
+                    for (channel, shift) in pixel[..3].iter_mut().zip([4, 2, 0]) {
+                        *channel = channel.wrapping_add((tag >> shift) & 0x03).wrapping_sub(2);
+                    }
+                }
+                QOI_OP_LUMA => {
+                    let green = (tag & 0x3f).wrapping_sub(32);
+                    let byte = next()?;
+                    pixel[0] = pixel[0]
+                        .wrapping_add(green)
+                        .wrapping_add(byte >> 4)
+                        .wrapping_sub(8);
+                    pixel[1] = pixel[1].wrapping_add(green);
+                    pixel[2] = pixel[2]
+                        .wrapping_add(green)
+                        .wrapping_add(byte & 0x0f)
+                        .wrapping_sub(8);
+                }
+                QOI_OP_RUN => run = u64::from(tag & 0x3f) + 1,
+                _ => unreachable!(),
+            },
+        }
+        let [r, g, b, a] = pixel.map(usize::from);
+        index[(r * 3 + g * 5 + b * 7 + a * 11) % 64] = pixel;
+        let run = run.min(pixels - decoded);
+        for _ in 0..run {
+            output.extend_from_slice(&pixel);
+        }
+        decoded += run;
+    }
+    match RgbaImage::from_raw(width, height, output) {
+        Some(image) => Ok(image),
+        None => bail!("the image is truncated"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A QOI file of width x height made of chunks, with its end marker
+    fn qoi(width: u32, height: u32, chunks: &[u8]) -> Vec<u8> {
+        let mut data = b"qoif".to_vec();
+        data.extend_from_slice(&width.to_be_bytes());
+        data.extend_from_slice(&height.to_be_bytes());
+        data.extend_from_slice(&[4, 0]);
+        data.extend_from_slice(chunks);
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        data
+    }
+
+    #[test]
+    fn qoi_images_decode_every_kind_of_chunk() {
+        let data = qoi(
+            7,
+            1,
+            &[
+                0xfe,
+                10,
+                20,
+                30,                             // RGB
+                0x40 | (3 << 4) | (1 << 2) | 2, // DIFF of +1, -1, 0
+                0x80 | (32 + 5),
+                0x88, // LUMA of +5 for each channel
+                0xc1, // RUN of 2
+                0xff,
+                1,
+                2,
+                3,
+                4,  // RGBA
+                18, // INDEX of the pixel of the run
+            ],
+        );
+        let image = decode_qoi(&data).unwrap();
+        let pixels: Vec<[u8; 4]> = image.pixels().map(|pixel| pixel.0).collect();
+        assert_eq!(
+            pixels,
+            [
+                [10, 20, 30, 255],
+                [11, 19, 30, 255],
+                [16, 24, 35, 255],
+                [16, 24, 35, 255],
+                [16, 24, 35, 255],
+                [1, 2, 3, 4],
+                [16, 24, 35, 255],
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_qoi_images_are_errors() {
+        let chunks = [0xfe, 10, 20, 30, 0xff, 1, 2, 3, 4, 0xc0];
+        let data = qoi(3, 1, &chunks);
+        assert!(decode_qoi(&data).is_ok());
+        // Cut in the header, in a chunk and between two chunks
+        for len in [
+            0,
+            4,
+            QOI_HEADER_SIZE - 1,
+            QOI_HEADER_SIZE + 2,
+            QOI_HEADER_SIZE + 4,
+        ] {
+            assert!(decode_qoi(&data[..len]).is_err(), "{} bytes", len);
+        }
+        // A run longer than the image stops at its end
+        assert_eq!(decode_qoi(&qoi(2, 2, &[0xc7])).unwrap().len(), 16);
+    }
+
+    #[test]
+    fn qoi_sizes_larger_than_the_data_are_errors() {
+        // More pixels than 62 per byte could hold, none is allocated
+        assert!(decode_qoi(&qoi(u32::MAX, u32::MAX, &[0xfd])).is_err());
+        assert!(decode_qoi(&qoi(100_000, 100_000, &[0xfd])).is_err());
+        assert!(decode_qoi(&qoi(0, 10, &[])).is_err());
+        // Within the bound, but the runs end before the image
+        assert!(decode_qoi(&qoi(62, 10, &[0xfd, 0xfd])).is_err());
+    }
+}
@@ -0,0 +1,58 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use lazy_static::lazy_static;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+lazy_static! {
+    /// Shared by all the random choices of images, so that a seed makes all of them reproducible
+    static ref RNG: Mutex<StdRng> = Mutex::new(StdRng::from_entropy());
+}
+
+/// Make the choices reproducible, e.g. when testing
+pub fn set_seed(seed: u64) {
+    with_rng(|rng| *rng = StdRng::seed_from_u64(seed));
+}
+
+/// Call f with the generator shared by the random choices
+pub fn with_rng<T>(f: impl FnOnce(&mut StdRng) -> T) -> T {
+    // The generator is always in a valid state, even if a panic happened while holding it
+    f(&mut RNG.lock().unwrap_or_else(|err| err.into_inner()))
+}
+
+/// Choose an image with a probability proportional to its weight, skipping the ones shown
+/// recently, from the most recent. When the window covers every image, only the least
+/// recently shown ones remain available.
+pub fn choose<'a>(images: &'a [PathBuf], weights: &[u32], recent: &[PathBuf]) -> Option<&'a Path> {
+    let window = recent.len().min(images.len().saturating_sub(1));
+    let recent = &recent[..window];
+    let allowed = |image: &PathBuf| !recent.contains(image);
+    choose_weighted(images, weights, allowed).or_else(|| choose_weighted(images, weights, |_| true))
+}
+
+fn choose_weighted<'a>(
+    images: &'a [PathBuf],
+    weights: &[u32],
+    allowed: impl Fn(&PathBuf) -> bool,
+) -> Option<&'a Path> {
+    let candidates: Vec<(&PathBuf, u64)> = images
+        .iter()
+        .zip(weights)
+        .filter(|(image, _)| allowed(image))
+        .map(|(image, &weight)| (image, u64::from(weight)))
+        .collect();
+    let total: u64 = candidates.iter().map(|(_, weight)| weight).sum();
+    if total == 0 {
+        return None;
+    }
+    let mut target = with_rng(|rng| rng.gen_range(0..total));
+    for (image, weight) in candidates {
+        if target < weight {
+            return Some(image);
+        }
+        target -= weight;
+    }
+    None
+}
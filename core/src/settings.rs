@@ -0,0 +1,130 @@
+//! The settings of the pipeline, written in the configuration files of wpaperd with serde
+
+use image::imageops::FilterType;
+use serde::{Deserialize, Serialize};
+
+/// How an image is scaled to the size of the output
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// Cover the whole output, cropping the sides that don't fit
+    #[default]
+    Fill,
+    /// Show the whole image, adding bars on the sides that are not covered
+    Contain,
+    /// Repeat the image across the output, without scaling it to the size of the output
+    Tile,
+}
+
+/// An RGBA color, written as #rrggbb, #rrggbbaa or transparent
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Color(pub [u8; 4]);
+
+impl Color {
+    /// Whether nothing shows through the color
+    pub fn is_opaque(&self) -> bool {
+        self.0[3] == 0xff
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(color: String) -> Result<Self, Self::Error> {
+        if color == "transparent" {
+            return Ok(Color([0; 4]));
+        }
+        let invalid = || format!("invalid color {:?}, expected #rrggbb or #rrggbbaa", color);
+        let hex = color.strip_prefix('#').ok_or_else(invalid)?;
+        if !matches!(hex.len(), 6 | 8) || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut rgba = [0xff; 4];
+        for (i, component) in rgba.iter_mut().enumerate().take(hex.len() / 2) {
+            *component = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Color(rgba))
+    }
+}
+
+impl From<Color> for String {
+    fn from(Color([r, g, b, a]): Color) -> Self {
+        if a == 0xff {
+            format!("#{:02x}{:02x}{:02x}", r, g, b)
+        } else {
+            format!("#{:02x}{:02x}{:02x}{:02x}", r, g, b, a)
+        }
+    }
+}
+
+/// Filter used to scale the images, from the fastest to the sharpest
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScalingFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    #[default]
+    Lanczos3,
+    /// Chosen for each image, depending on how much it is scaled and on the size of the output
+    Auto,
+}
+
+/// How the images are reduced to 8 bits per channel
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Dither {
+    /// Round to the nearest level, gradients can show bands
+    #[default]
+    None,
+    /// Bayer matrix, cheap but with a visible regular pattern
+    Ordered,
+    /// Blue noise, which looks like fine grain
+    BlueNoise,
+}
+
+/// Which part of an image is kept when it is cropped to cover the output
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Crop {
+    /// The middle of the image
+    #[default]
+    Center,
+    /// The part with the most detail, leaving out plain skies and walls
+    Entropy,
+    /// The part that draws the eye: edges, saturated colors and skin tones
+    Attention,
+}
+
+/// Above this many pixels, e.g. 4K, the output is large enough for a cheap filter to look fine
+const LARGE_OUTPUT_PIXELS: u64 = 3840 * 2160;
+
+impl ScalingFilter {
+    /// The filter to scale an image by scale, greater than 1 when it is enlarged, to an
+    /// output of pixels pixels
+    pub fn filter_type(self, scale: f32, pixels: u64) -> FilterType {
+        match self {
+            ScalingFilter::Nearest => FilterType::Nearest,
+            ScalingFilter::Triangle => FilterType::Triangle,
+            ScalingFilter::CatmullRom => FilterType::CatmullRom,
+            ScalingFilter::Lanczos3 => FilterType::Lanczos3,
+            // Enlarging needs a sharp filter, but Lanczos3 rings
+            ScalingFilter::Auto if scale > 1.0 => FilterType::CatmullRom,
+            // The cost of a filter grows with how much the image is reduced, while the
+            // difference between them fades
+            ScalingFilter::Auto if scale < 0.5 || pixels >= LARGE_OUTPUT_PIXELS => {
+                FilterType::Triangle
+            }
+            ScalingFilter::Auto => FilterType::Lanczos3,
+        }
+    }
+
+    /// A filter at most as expensive as Triangle, used to save power
+    pub fn cheaper(self) -> Self {
+        match self {
+            ScalingFilter::Nearest => ScalingFilter::Nearest,
+            _ => ScalingFilter::Triangle,
+        }
+    }
+}
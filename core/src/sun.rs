@@ -0,0 +1,57 @@
+use std::f64::consts::PI;
+
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Utc};
+
+/// Julian date of 2000-01-01 at noon
+const J2000: f64 = 2_451_545.0;
+/// Days from 0001-01-01 to 2000-01-01, counting both
+const J2000_DAYS_FROM_CE: i32 = 730_120;
+/// Julian date of the Unix epoch
+const UNIX_EPOCH_JULIAN: f64 = 2_440_587.5;
+
+/// Declination of the sun, in radians, and the Julian date when it crosses the meridian
+pub fn position(date: NaiveDate, longitude: f64) -> (f64, f64) {
+    let days = f64::from(date.num_days_from_ce() - J2000_DAYS_FROM_CE);
+    let mean_solar_time = days + 0.0008 - longitude / 360.0;
+    let anomaly = (357.5291 + 0.985_600_28 * mean_solar_time).rem_euclid(360.0);
+    let center = 1.9148 * radians(anomaly).sin()
+        + 0.02 * radians(2.0 * anomaly).sin()
+        + 0.0003 * radians(3.0 * anomaly).sin();
+    let ecliptic_longitude = (anomaly + center + 180.0 + 102.9372).rem_euclid(360.0);
+    let transit = J2000 + mean_solar_time + 0.0053 * radians(anomaly).sin()
+        - 0.0069 * radians(2.0 * ecliptic_longitude).sin();
+    let declination = (radians(ecliptic_longitude).sin() * radians(23.4397).sin()).asin();
+    (declination, transit)
+}
+
+/// Altitude of the sun above the horizon and its azimuth, clockwise from the north, seen from
+/// the location at time, in degrees
+pub fn altitude_azimuth(time: DateTime<Utc>, latitude: f64, longitude: f64) -> (f64, f64) {
+    let (declination, transit) = position(time.naive_utc().date(), longitude);
+    let hour_angle = 2.0 * PI * (utc_to_julian(time) - transit);
+    let latitude = radians(latitude);
+    let altitude = (latitude.sin() * declination.sin()
+        + latitude.cos() * declination.cos() * hour_angle.cos())
+    .asin();
+    // From the south, westward
+    let azimuth = hour_angle
+        .sin()
+        .atan2(hour_angle.cos() * latitude.sin() - declination.tan() * latitude.cos());
+    (
+        altitude.to_degrees(),
+        (azimuth.to_degrees() + 180.0).rem_euclid(360.0),
+    )
+}
+
+pub fn julian_to_utc(julian: f64) -> DateTime<Utc> {
+    let seconds = (julian - UNIX_EPOCH_JULIAN) * 86400.0;
+    Utc.timestamp(seconds as i64, 0)
+}
+
+fn utc_to_julian(time: DateTime<Utc>) -> f64 {
+    time.timestamp() as f64 / 86400.0 + UNIX_EPOCH_JULIAN
+}
+
+fn radians(degrees: f64) -> f64 {
+    degrees * PI / 180.0
+}
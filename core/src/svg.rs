@@ -0,0 +1,166 @@
+use std::{fs, path::Path, process::Command};
+
+use color_eyre::{
+    eyre::{ensure, eyre, WrapErr},
+    Result,
+};
+use image::{DynamicImage, ImageFormat};
+
+/// Whether path is an SVG image, which is rasterized with resvg instead of being decoded
+pub fn is_svg(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("svg"))
+}
+
+/// Make sure that the resvg binary, which rasterize runs, is installed
+pub fn check_rasterizer() -> Result<()> {
+    let output = Command::new("resvg")
+        .arg("--version")
+        .output()
+        .context("resvg is needed to show SVG images but cannot be run")?;
+    ensure!(
+        output.status.success(),
+        "resvg is needed to show SVG images but does not work: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(())
+}
+
+/// Rasterize the SVG image at path at the smallest size that covers width x height, so that
+/// it is never scaled up afterwards; u32::MAX for both rasterizes it at its own size. This runs
+/// resvg, which has to be installed.
+pub fn rasterize(path: &Path, width: u32, height: u32) -> Result<DynamicImage> {
+    let (svg_width, svg_height) = size(path)?;
+    let scale = if (width, height) == (u32::MAX, u32::MAX) {
+        1.0
+    } else {
+        f32::max(width as f32 / svg_width, height as f32 / svg_height)
+    };
+    let pixels = |size: f32| ((size * scale).round() as u32).clamp(1, u32::from(u16::MAX));
+    let output = Command::new("resvg")
+        .arg("--width")
+        .arg(pixels(svg_width).to_string())
+        .arg("--height")
+        .arg(pixels(svg_height).to_string())
+        .arg(path)
+        // Write the PNG to the standard output
+        .arg("-c")
+        .output()
+        .context("running resvg")?;
+    ensure!(
+        output.status.success(),
+        "rasterizing {:?}: {}",
+        path,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    image::load_from_memory_with_format(&output.stdout, ImageFormat::Png)
+        .with_context(|| format!("reading the rasterized image {:?}", path))
+}
+
+/// Width and height of the SVG image at path, from the attributes of its root element: width
+/// and height when they are absolute, the viewBox otherwise
+pub fn size(path: &Path) -> Result<(f32, f32)> {
+    let content = fs::read_to_string(path).with_context(|| format!("reading {:?}", path))?;
+    root_size(&content).ok_or_else(|| eyre!("{:?} has no width and height nor viewBox", path))
+}
+
+fn root_size(content: &str) -> Option<(f32, f32)> {
+    let start = content.find("<svg")?;
+    let tag = &content[start..start + content[start..].find('>')?];
+    let length = |name: &str| {
+        let value = attribute(tag, name)?;
+        let value = value.strip_suffix("px").unwrap_or(value);
+        value.trim().parse::<f32>().ok().filter(|size| *size > 0.0)
+    };
+    if let (Some(width), Some(height)) = (length("width"), length("height")) {
+        return Some((width, height));
+    }
+    let view_box: Vec<f32> = attribute(tag, "viewBox")?
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|value| !value.is_empty())
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .ok()?;
+    match view_box[..] {
+        [_, _, width, height] if width > 0.0 && height > 0.0 => Some((width, height)),
+        _ => None,
+    }
+}
+
+/// The value of the attribute name of tag, quoted with either kind of quotes
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = tag;
+    while let Some(position) = rest.find(name) {
+        let preceded_by_space = rest[..position]
+            .chars()
+            .last()
+            .is_some_and(char::is_whitespace);
+        rest = &rest[position + name.len()..];
+        let value = rest.trim_start().strip_prefix('=').map(str::trim_start);
+        if let (true, Some(value)) = (preceded_by_space, value) {
+            let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+            let value = &value[1..];
+            return value.find(quote).map(|end| &value[..end]);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_of_the_root_element() {
+        for (svg, size) in [
+            (
+                r#"<svg width="1920" height="1080">"#,
+                Some((1920.0, 1080.0)),
+            ),
+            (
+                r#"<svg width='640px' height='480px'>"#,
+                Some((640.0, 480.0)),
+            ),
+            (
+                r#"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 300 200">"#,
+                Some((300.0, 200.0)),
+            ),
+            (r#"<svg viewBox='-10,-10, 50,25'>"#, Some((50.0, 25.0))),
+            // Relative and other units fall back to the viewBox
+            (
+                r#"<svg width="100%" height="100%" viewBox="0 0 40 30">"#,
+                Some((40.0, 30.0)),
+            ),
+            (
+                r#"<svg width="10cm" height="5cm" viewBox="0 0 4 3">"#,
+                Some((4.0, 3.0)),
+            ),
+            (r#"<svg width="10cm" height="5cm">"#, None),
+            // A missing height
+            (r#"<svg width="800" viewBox="0 0 16 9">"#, Some((16.0, 9.0))),
+            (r#"<svg width="800">"#, None),
+            (r#"<svg viewBox="0 0 0 9">"#, None),
+            (r#"<svg viewBox="0 0 16">"#, None),
+            (r#"<svg viewBox="a b c d">"#, None),
+            // The attributes of a nested element are not the ones of the root
+            (r#"<svg><rect width="5" height="5"/></svg>"#, None),
+            (r#"<svg width="1" height="1""#, None),
+            ("<html></html>", None),
+        ] {
+            assert_eq!(root_size(svg), size, "{}", svg);
+        }
+    }
+
+    #[test]
+    fn attributes() {
+        let tag = r#"<svg data-width="1" width = '2' stroke-width="3" height="4" title='a "b"'"#;
+        assert_eq!(attribute(tag, "width"), Some("2"));
+        assert_eq!(attribute(tag, "height"), Some("4"));
+        assert_eq!(attribute(tag, "title"), Some(r#"a "b""#));
+        assert_eq!(attribute(tag, "viewBox"), None);
+        // Unquoted and unterminated values
+        assert_eq!(attribute("<svg width=2", "width"), None);
+        assert_eq!(attribute("<svg width=\"2", "width"), None);
+        assert_eq!(attribute("<svg width", "width"), None);
+    }
+}
@@ -0,0 +1,1159 @@
+use std::{
+    env,
+    error::Error,
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use humantime::{format_duration, parse_duration};
+use xdg::BaseDirectories;
+
+/// A request sent by wpaperctl to the daemon. Each request is a single line of
+/// whitespace separated words, except for batches.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IpcMessage {
+    ContestStart {
+        output: String,
+        count: usize,
+    },
+    ContestStop {
+        output: String,
+    },
+    Vote {
+        output: String,
+        accept: bool,
+    },
+    ContestResults,
+    RecordStart {
+        output: String,
+        interval: u32,
+        framerate: u32,
+        path: PathBuf,
+    },
+    RecordStop {
+        output: String,
+    },
+    /// Without an output, all the outputs are affected, and they show their new images on the
+    /// same frame once they are all loaded
+    PreviousWallpaper {
+        output: Option<String>,
+    },
+    NextWallpaper {
+        output: Option<String>,
+    },
+    History {
+        output: String,
+        count: usize,
+    },
+    /// The image displayed on the output, or on each output as `<output> <image>`. With
+    /// verbose, written `--verbose` before the output, the details of the remote images
+    /// follow each one, indented: where it has been downloaded from, its author, its license
+    /// and its page.
+    Current {
+        output: Option<String>,
+        verbose: bool,
+    },
+    ToggleInversion {
+        output: Option<String>,
+    },
+    Palette {
+        output: String,
+    },
+    /// Save the frame on screen of the output, as composited with the filters and the
+    /// overlay, to a PNG in $XDG_RUNTIME_DIR/wpaper/frame and return its path
+    Frame {
+        output: String,
+    },
+    /// Stop the rotation, keeping the current image until resumed
+    Pause {
+        output: Option<String>,
+    },
+    Resume {
+        output: Option<String>,
+    },
+    /// Keep showing image, or the current image when None, until unpinned; the rotation
+    /// and the requests changing the image are refused meanwhile
+    Pin {
+        output: String,
+        image: Option<PathBuf>,
+    },
+    Unpin {
+        output: String,
+    },
+    /// Change how long each wallpaper is displayed, until the configuration is reloaded
+    SetDuration {
+        output: Option<String>,
+        duration: Duration,
+    },
+    /// Show an image right away, even if it is not in the configured path
+    SetWallpaper {
+        output: Option<String>,
+        path: PathBuf,
+    },
+    /// Show an image for a while, then go back to the current one, without moving the
+    /// rotation
+    Preview {
+        output: Option<String>,
+        path: PathBuf,
+        duration: Duration,
+    },
+    /// List the outputs and their capabilities, one per line
+    Outputs,
+    /// The settings used by an output, as TOML
+    GetSettings {
+        output: String,
+    },
+    /// Change some settings of an output and save them in the configuration file. The
+    /// settings are the content of a TOML inline table, e.g. `path = "/wallpapers", duration = "5m"`;
+    /// either all of them are applied or none is. With runtime, written `--runtime` before the
+    /// output, they are kept in memory only, over those of the files, until wpaperd exits.
+    SetSettings {
+        output: String,
+        settings: String,
+        runtime: bool,
+    },
+    /// The next count images of the output, one per line, each preceded by `*` if pinned
+    Queue {
+        output: String,
+        count: usize,
+    },
+    /// Remove an image from the queue, the first one without an image
+    QueueSkip {
+        output: String,
+        image: Option<PathBuf>,
+    },
+    /// Keep a queued image when the settings of the output change, or stop keeping it
+    QueuePin {
+        output: String,
+        pinned: bool,
+        image: PathBuf,
+    },
+    /// Show an image of the output directory after the current one
+    QueueFront {
+        output: String,
+        image: PathBuf,
+    },
+    /// Thumbnails of the image displayed on the output and of the next count ones, in the
+    /// freedesktop.org thumbnail cache. Each line is the image and its thumbnail, separated
+    /// by a tab. size is normal, large, x-large or xx-large.
+    Thumbnails {
+        output: String,
+        count: usize,
+        size: String,
+    },
+    /// Keep the connection open and receive an IpcEvent line for every change
+    Subscribe,
+    /// Switch every output to the sections of a profile of the configuration file, or
+    /// back to the top-level sections without a name
+    SetProfile {
+        name: Option<String>,
+    },
+    /// List the profiles of the configuration file, one per line, the active one marked
+    Profiles,
+    /// Enable or disable the animations of every output, over the animations setting of the
+    /// configuration file, until wpaperd exits. It is sent as `set-animations on` or `off`.
+    SetAnimations {
+        enabled: bool,
+    },
+    /// Index again the directories of the configuration, finding the duplicates and giving
+    /// the images that could not be decoded another chance. There is a line for each one.
+    Rescan,
+    /// Counters of the daemon in the Prometheus text exposition format, a line each: draws
+    /// and rotations by output, decode times, cache lookups and shared memory in use
+    Metrics,
+    /// Apply several requests before drawing the next frame, so that they all appear at once.
+    /// It is sent as a line with `batch` followed by a line for each request.
+    Batch(Vec<IpcMessage>),
+}
+
+impl IpcMessage {
+    /// The output targeted by the request, None if it affects all of them
+    pub fn output(&self) -> Option<&str> {
+        match self {
+            IpcMessage::ContestStart { output, .. }
+            | IpcMessage::ContestStop { output }
+            | IpcMessage::Vote { output, .. }
+            | IpcMessage::RecordStart { output, .. }
+            | IpcMessage::RecordStop { output }
+            | IpcMessage::History { output, .. }
+            | IpcMessage::Palette { output }
+            | IpcMessage::Frame { output }
+            | IpcMessage::Queue { output, .. }
+            | IpcMessage::QueueSkip { output, .. }
+            | IpcMessage::QueuePin { output, .. }
+            | IpcMessage::QueueFront { output, .. }
+            | IpcMessage::Thumbnails { output, .. }
+            | IpcMessage::Pin { output, .. }
+            | IpcMessage::Unpin { output } => Some(output),
+            IpcMessage::PreviousWallpaper { output }
+            | IpcMessage::NextWallpaper { output }
+            | IpcMessage::ToggleInversion { output }
+            | IpcMessage::Pause { output }
+            | IpcMessage::Resume { output }
+            | IpcMessage::SetDuration { output, .. }
+            | IpcMessage::SetWallpaper { output, .. }
+            | IpcMessage::Preview { output, .. }
+            | IpcMessage::Current { output, .. } => output.as_deref(),
+            IpcMessage::GetSettings { output } | IpcMessage::SetSettings { output, .. } => {
+                Some(output)
+            }
+            IpcMessage::ContestResults
+            | IpcMessage::Outputs
+            | IpcMessage::Subscribe
+            | IpcMessage::SetProfile { .. }
+            | IpcMessage::Profiles
+            | IpcMessage::SetAnimations { .. }
+            | IpcMessage::Rescan
+            | IpcMessage::Metrics
+            | IpcMessage::Batch(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for IpcMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpcMessage::ContestStart { output, count } => {
+                write!(f, "contest-start {} {}", output, count)
+            }
+            IpcMessage::ContestStop { output } => write!(f, "contest-stop {}", output),
+            IpcMessage::Vote { output, accept } => {
+                write!(
+                    f,
+                    "vote {} {}",
+                    output,
+                    if *accept { "accept" } else { "reject" }
+                )
+            }
+            IpcMessage::ContestResults => write!(f, "contest-results"),
+            IpcMessage::RecordStart {
+                output,
+                interval,
+                framerate,
+                path,
+            } => write!(
+                f,
+                "record-start {} {} {} {}",
+                output,
+                interval,
+                framerate,
+                path.display()
+            ),
+            IpcMessage::RecordStop { output } => write!(f, "record-stop {}", output),
+            IpcMessage::PreviousWallpaper { output } => {
+                write!(f, "previous-wallpaper {}", output.as_deref().unwrap_or(""))
+            }
+            IpcMessage::NextWallpaper { output } => {
+                write!(f, "next-wallpaper {}", output.as_deref().unwrap_or(""))
+            }
+            IpcMessage::History { output, count } => write!(f, "history {} {}", output, count),
+            IpcMessage::Current { output, verbose } => {
+                write!(f, "current")?;
+                if *verbose {
+                    write!(f, " {}", VERBOSE_FLAG)?;
+                }
+                write!(f, " {}", output.as_deref().unwrap_or(""))
+            }
+            IpcMessage::Palette { output } => write!(f, "palette {}", output),
+            IpcMessage::Frame { output } => write!(f, "frame {}", output),
+            IpcMessage::ToggleInversion { output } => {
+                write!(f, "toggle-inversion {}", output.as_deref().unwrap_or(""))
+            }
+            IpcMessage::Pause { output } => {
+                write!(f, "pause {}", output.as_deref().unwrap_or(""))
+            }
+            IpcMessage::Resume { output } => {
+                write!(f, "resume {}", output.as_deref().unwrap_or(""))
+            }
+            IpcMessage::Pin { output, image } => match image {
+                Some(image) => write!(f, "pin {} {}", output, image.display()),
+                None => write!(f, "pin {}", output),
+            },
+            IpcMessage::Unpin { output } => write!(f, "unpin {}", output),
+            IpcMessage::SetDuration { output, duration } => write!(
+                f,
+                "set-duration {} {}",
+                // e.g. "1h 30m" is sent as "1h30m", so that it is a single word
+                format_duration(*duration).to_string().replace(' ', ""),
+                output.as_deref().unwrap_or("")
+            ),
+            IpcMessage::SetWallpaper { output, path } => write!(
+                f,
+                "set-wallpaper {} {}",
+                // The path is last, so that it can contain whitespace
+                output.as_deref().unwrap_or(ALL_OUTPUTS),
+                path.display()
+            ),
+            IpcMessage::Preview {
+                output,
+                path,
+                duration,
+            } => write!(
+                f,
+                "preview {} {} {}",
+                format_duration(*duration).to_string().replace(' ', ""),
+                output.as_deref().unwrap_or(ALL_OUTPUTS),
+                path.display()
+            ),
+            IpcMessage::Outputs => write!(f, "outputs"),
+            IpcMessage::GetSettings { output } => write!(f, "get-settings {}", output),
+            IpcMessage::SetSettings {
+                output,
+                settings,
+                runtime,
+            } => {
+                write!(f, "set-settings ")?;
+                if *runtime {
+                    write!(f, "{} ", RUNTIME_FLAG)?;
+                }
+                write!(f, "{} {}", output, settings)
+            }
+            IpcMessage::Queue { output, count } => write!(f, "queue {} {}", output, count),
+            IpcMessage::QueueSkip { output, image } => match image {
+                Some(image) => write!(f, "queue-skip {} {}", output, image.display()),
+                None => write!(f, "queue-skip {}", output),
+            },
+            IpcMessage::QueuePin {
+                output,
+                pinned,
+                image,
+            } => write!(
+                f,
+                "{} {} {}",
+                if *pinned { "queue-pin" } else { "queue-unpin" },
+                output,
+                image.display()
+            ),
+            IpcMessage::QueueFront { output, image } => {
+                write!(f, "queue-front {} {}", output, image.display())
+            }
+            IpcMessage::Thumbnails {
+                output,
+                count,
+                size,
+            } => write!(f, "thumbnails {} {} {}", output, count, size),
+            IpcMessage::Subscribe => write!(f, "subscribe"),
+            IpcMessage::SetProfile { name } => {
+                write!(f, "set-profile {}", name.as_deref().unwrap_or(""))
+            }
+            IpcMessage::Profiles => write!(f, "profiles"),
+            IpcMessage::SetAnimations { enabled } => {
+                write!(f, "set-animations {}", if *enabled { "on" } else { "off" })
+            }
+            IpcMessage::Rescan => write!(f, "rescan"),
+            IpcMessage::Metrics => write!(f, "metrics"),
+            IpcMessage::Batch(messages) => {
+                write!(f, "batch")?;
+                for message in messages {
+                    write!(f, "\n{}", message)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl FromStr for IpcMessage {
+    type Err = IpcError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        // An empty batch is a line on its own
+        if let Some(requests) = line
+            .strip_prefix("batch")
+            .filter(|rest| rest.trim().is_empty() || rest.starts_with('\n'))
+        {
+            return requests
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| match line.parse()? {
+                    IpcMessage::Subscribe
+                    | IpcMessage::Thumbnails { .. }
+                    | IpcMessage::Batch(_) => Err(IpcError::NotInBatch(line.trim().to_string())),
+                    message => Ok(message),
+                })
+                .collect::<Result<_, _>>()
+                .map(IpcMessage::Batch);
+        }
+        let mut words = Words(line);
+        let command = words.next("command")?;
+        Ok(match command {
+            "contest-start" => IpcMessage::ContestStart {
+                output: words.next("output")?.to_string(),
+                count: words.parse("count")?,
+            },
+            "contest-stop" => IpcMessage::ContestStop {
+                output: words.next("output")?.to_string(),
+            },
+            "vote" => {
+                let output = words.next("output")?.to_string();
+                let accept = match words.next("vote")? {
+                    "accept" => true,
+                    "reject" => false,
+                    vote => return Err(IpcError::InvalidArgument(vote.to_string())),
+                };
+                IpcMessage::Vote { output, accept }
+            }
+            "contest-results" => IpcMessage::ContestResults,
+            "record-start" => IpcMessage::RecordStart {
+                output: words.next("output")?.to_string(),
+                interval: words.parse("interval")?,
+                framerate: words.parse("framerate")?,
+                path: words.rest("path")?.into(),
+            },
+            "record-stop" => IpcMessage::RecordStop {
+                output: words.next("output")?.to_string(),
+            },
+            "previous-wallpaper" => IpcMessage::PreviousWallpaper {
+                output: words.optional().map(str::to_string),
+            },
+            "next-wallpaper" => IpcMessage::NextWallpaper {
+                output: words.optional().map(str::to_string),
+            },
+            "history" => IpcMessage::History {
+                output: words.next("output")?.to_string(),
+                count: words.parse("count")?,
+            },
+            "current" => IpcMessage::Current {
+                verbose: words.flag(VERBOSE_FLAG),
+                output: words.optional().map(str::to_string),
+            },
+            "palette" => IpcMessage::Palette {
+                output: words.next("output")?.to_string(),
+            },
+            "frame" => IpcMessage::Frame {
+                output: words.next("output")?.to_string(),
+            },
+            "toggle-inversion" => IpcMessage::ToggleInversion {
+                output: words.optional().map(str::to_string),
+            },
+            "pause" => IpcMessage::Pause {
+                output: words.optional().map(str::to_string),
+            },
+            "resume" => IpcMessage::Resume {
+                output: words.optional().map(str::to_string),
+            },
+            "pin" => IpcMessage::Pin {
+                output: words.next("output")?.to_string(),
+                image: words.rest("image").ok().map(PathBuf::from),
+            },
+            "unpin" => IpcMessage::Unpin {
+                output: words.next("output")?.to_string(),
+            },
+            "set-duration" => {
+                let duration = words.next("duration")?;
+                IpcMessage::SetDuration {
+                    duration: parse_duration(duration)
+                        .map_err(|_| IpcError::InvalidArgument(duration.to_string()))?,
+                    output: words.optional().map(str::to_string),
+                }
+            }
+            "set-wallpaper" => IpcMessage::SetWallpaper {
+                output: match words.next("output")? {
+                    ALL_OUTPUTS => None,
+                    output => Some(output.to_string()),
+                },
+                path: words.rest("path")?.into(),
+            },
+            "preview" => {
+                let duration = words.next("duration")?;
+                IpcMessage::Preview {
+                    duration: parse_duration(duration)
+                        .map_err(|_| IpcError::InvalidArgument(duration.to_string()))?,
+                    output: match words.next("output")? {
+                        ALL_OUTPUTS => None,
+                        output => Some(output.to_string()),
+                    },
+                    path: words.rest("path")?.into(),
+                }
+            }
+            "outputs" => IpcMessage::Outputs,
+            "get-settings" => IpcMessage::GetSettings {
+                output: words.next("output")?.to_string(),
+            },
+            "set-settings" => {
+                let runtime = words.flag(RUNTIME_FLAG);
+                IpcMessage::SetSettings {
+                    output: words.next("output")?.to_string(),
+                    settings: words.rest("settings")?.to_string(),
+                    runtime,
+                }
+            }
+            "queue" => IpcMessage::Queue {
+                output: words.next("output")?.to_string(),
+                count: words.parse("count")?,
+            },
+            "queue-skip" => IpcMessage::QueueSkip {
+                output: words.next("output")?.to_string(),
+                image: words.rest("image").ok().map(PathBuf::from),
+            },
+            "queue-pin" | "queue-unpin" => IpcMessage::QueuePin {
+                output: words.next("output")?.to_string(),
+                pinned: command == "queue-pin",
+                image: words.rest("image")?.into(),
+            },
+            "queue-front" => IpcMessage::QueueFront {
+                output: words.next("output")?.to_string(),
+                image: words.rest("image")?.into(),
+            },
+            "thumbnails" => IpcMessage::Thumbnails {
+                output: words.next("output")?.to_string(),
+                count: words.parse("count")?,
+                size: words.next("size")?.to_string(),
+            },
+            "subscribe" => IpcMessage::Subscribe,
+            "set-profile" => IpcMessage::SetProfile {
+                name: words.optional().map(str::to_string),
+            },
+            "profiles" => IpcMessage::Profiles,
+            "set-animations" => IpcMessage::SetAnimations {
+                enabled: match words.next("state")? {
+                    "on" => true,
+                    "off" => false,
+                    state => return Err(IpcError::InvalidArgument(state.to_string())),
+                },
+            },
+            "rescan" => IpcMessage::Rescan,
+            "metrics" => IpcMessage::Metrics,
+            _ => return Err(IpcError::UnknownCommand(command.to_string())),
+        })
+    }
+}
+
+/// A change notified to the clients that sent IpcMessage::Subscribe
+#[derive(Debug, Clone, PartialEq)]
+pub enum IpcEvent {
+    OutputAdded {
+        output: String,
+    },
+    OutputRemoved {
+        output: String,
+    },
+    /// A new image is displayed on output
+    Wallpaper {
+        output: String,
+        image: PathBuf,
+    },
+    /// The settings of output have changed
+    Settings {
+        output: String,
+    },
+    /// Something went wrong while drawing output
+    Error {
+        output: String,
+        message: String,
+    },
+    /// The timer of output has been started again, the next image is shown after remaining
+    Timer {
+        output: String,
+        remaining: Duration,
+    },
+}
+
+impl IpcEvent {
+    /// The event as a JSON object on a single line, e.g.
+    /// `{"event":"wallpaper","output":"eDP-1","image":"/wallpapers/sunset.jpg"}`.
+    /// Durations are in seconds.
+    pub fn to_json(&self) -> String {
+        let (event, output) = match self {
+            IpcEvent::OutputAdded { output } => ("output-added", output),
+            IpcEvent::OutputRemoved { output } => ("output-removed", output),
+            IpcEvent::Wallpaper { output, .. } => ("wallpaper", output),
+            IpcEvent::Settings { output } => ("settings", output),
+            IpcEvent::Error { output, .. } => ("error", output),
+            IpcEvent::Timer { output, .. } => ("timer", output),
+        };
+        let mut json = format!(
+            "{{\"event\":{},\"output\":{}",
+            json_string(event),
+            json_string(output)
+        );
+        match self {
+            IpcEvent::Wallpaper { image, .. } => {
+                json.push_str(&format!(
+                    ",\"image\":{}",
+                    json_string(&image.to_string_lossy())
+                ));
+            }
+            IpcEvent::Error { message, .. } => {
+                json.push_str(&format!(",\"message\":{}", json_string(message)));
+            }
+            IpcEvent::Timer { remaining, .. } => {
+                json.push_str(&format!(",\"remaining\":{}", remaining.as_secs_f64()));
+            }
+            IpcEvent::OutputAdded { .. }
+            | IpcEvent::OutputRemoved { .. }
+            | IpcEvent::Settings { .. } => {}
+        }
+        json.push('}');
+        json
+    }
+}
+
+/// Quote and escape value as a JSON string
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if u32::from(c) < 0x20 => json.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+impl fmt::Display for IpcEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpcEvent::OutputAdded { output } => write!(f, "output-added {}", output),
+            IpcEvent::OutputRemoved { output } => write!(f, "output-removed {}", output),
+            IpcEvent::Wallpaper { output, image } => {
+                write!(f, "wallpaper {} {}", output, image.display())
+            }
+            IpcEvent::Settings { output } => write!(f, "settings {}", output),
+            // The message is last, so that it can contain whitespace; it is kept on one line
+            IpcEvent::Error { output, message } => {
+                write!(f, "error {} {}", output, message.replace('\n', " "))
+            }
+            IpcEvent::Timer { output, remaining } => write!(
+                f,
+                "timer {} {}",
+                output,
+                format_duration(*remaining).to_string().replace(' ', "")
+            ),
+        }
+    }
+}
+
+impl FromStr for IpcEvent {
+    type Err = IpcError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut words = Words(line);
+        let event = words.next("event")?;
+        Ok(match event {
+            "output-added" => IpcEvent::OutputAdded {
+                output: words.next("output")?.to_string(),
+            },
+            "output-removed" => IpcEvent::OutputRemoved {
+                output: words.next("output")?.to_string(),
+            },
+            "wallpaper" => IpcEvent::Wallpaper {
+                output: words.next("output")?.to_string(),
+                image: words.rest("image")?.into(),
+            },
+            "settings" => IpcEvent::Settings {
+                output: words.next("output")?.to_string(),
+            },
+            "error" => IpcEvent::Error {
+                output: words.next("output")?.to_string(),
+                message: words.rest("message")?.to_string(),
+            },
+            "timer" => {
+                let output = words.next("output")?.to_string();
+                let remaining = words.next("remaining")?;
+                IpcEvent::Timer {
+                    output,
+                    remaining: parse_duration(remaining)
+                        .map_err(|_| IpcError::InvalidArgument(remaining.to_string()))?,
+                }
+            }
+            _ => return Err(IpcError::UnknownCommand(event.to_string())),
+        })
+    }
+}
+
+/// Placeholder for the requests whose optional output is followed by other arguments
+const ALL_OUTPUTS: &str = "*";
+
+/// Written before the output of set-settings to keep the settings in memory only
+const RUNTIME_FLAG: &str = "--runtime";
+/// Written before the output of current to add the attribution of the images
+const VERBOSE_FLAG: &str = "--verbose";
+
+/// Split a request in words, allowing the last argument to contain whitespace (e.g. paths)
+struct Words<'a>(&'a str);
+
+impl<'a> Words<'a> {
+    fn next(&mut self, name: &'static str) -> Result<&'a str, IpcError> {
+        let line = self.0.trim_start();
+        let end = line.find(char::is_whitespace).unwrap_or(line.len());
+        let (word, rest) = line.split_at(end);
+        self.0 = rest;
+        if word.is_empty() {
+            Err(IpcError::MissingArgument(name))
+        } else {
+            Ok(word)
+        }
+    }
+
+    fn optional(&mut self) -> Option<&'a str> {
+        self.next("").ok()
+    }
+
+    /// Whether the next word is flag, which is then consumed
+    fn flag(&mut self, flag: &str) -> bool {
+        let mut words = Words(self.0);
+        if words.optional() == Some(flag) {
+            self.0 = words.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse<T: FromStr>(&mut self, name: &'static str) -> Result<T, IpcError> {
+        let word = self.next(name)?;
+        word.parse()
+            .map_err(|_| IpcError::InvalidArgument(word.to_string()))
+    }
+
+    /// Everything left in the line, leading and trailing whitespace excluded
+    fn rest(&mut self, name: &'static str) -> Result<&'a str, IpcError> {
+        let rest = self.0.trim();
+        self.0 = "";
+        if rest.is_empty() {
+            Err(IpcError::MissingArgument(name))
+        } else {
+            Ok(rest)
+        }
+    }
+}
+
+/// The answer of the daemon: either a list of lines to show to the user or an error
+#[derive(Debug, Clone, PartialEq)]
+pub enum IpcResponse {
+    Ok(Vec<String>),
+    Error(String),
+}
+
+impl IpcResponse {
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        match self {
+            IpcResponse::Ok(lines) => {
+                writeln!(writer, "ok")?;
+                for line in lines {
+                    writeln!(writer, "{}", line)?;
+                }
+            }
+            IpcResponse::Error(err) => writeln!(writer, "error {}", err)?,
+        }
+        writer.flush()
+    }
+
+    pub fn read_from<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut lines = reader.lines();
+        let status = lines.next().unwrap_or_else(|| Ok(String::new()))?;
+        if status == "ok" {
+            Ok(IpcResponse::Ok(lines.collect::<io::Result<_>>()?))
+        } else if let Some(err) = status.strip_prefix("error ") {
+            Ok(IpcResponse::Error(err.to_string()))
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected response from wpaperd: {:?}", status),
+            ))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum IpcError {
+    UnknownCommand(String),
+    MissingArgument(&'static str),
+    InvalidArgument(String),
+    NotInBatch(String),
+}
+
+impl fmt::Display for IpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpcError::UnknownCommand(command) => write!(f, "unknown command {:?}", command),
+            IpcError::MissingArgument(name) => write!(f, "missing argument {}", name),
+            IpcError::InvalidArgument(arg) => write!(f, "invalid argument {:?}", arg),
+            IpcError::NotInBatch(command) => {
+                write!(f, "{:?} cannot be part of a batch", command)
+            }
+        }
+    }
+}
+
+impl Error for IpcError {}
+
+/// Environment variable with the seat of the wpaperd instance, set by wpaperd --seat and
+/// wpaperctl --seat. When it is not set, the seat of the session in XDG_SEAT is used.
+pub const SEAT_VAR: &str = "WPAPER_SEAT";
+
+/// The seat whose outputs the wpaperd instance serves, None on a single-seat machine
+pub fn seat() -> Option<String> {
+    env::var(SEAT_VAR)
+        .or_else(|_| env::var("XDG_SEAT"))
+        .ok()
+        .filter(|seat| !seat.is_empty())
+}
+
+/// Name of the wpaperd instance, made of the wayland display and the seat, so that the
+/// instances running on different displays or seats don't share their sockets
+fn instance_name() -> String {
+    // WAYLAND_DISPLAY can also be the absolute path of the socket of the compositor
+    let display = env::var_os("WAYLAND_DISPLAY")
+        .and_then(|display| {
+            Path::new(&display)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        })
+        .unwrap_or_else(|| "wayland-0".to_string());
+    match seat() {
+        Some(seat) => format!("{}-{}", display, seat.replace('/', "_")),
+        None => display,
+    }
+}
+
+/// Path of the unix socket wpaperd listens on, inside XDG_RUNTIME_DIR
+pub fn socket_path() -> io::Result<PathBuf> {
+    let xdg_dirs = BaseDirectories::with_prefix("wpaper")?;
+    xdg_dirs.place_runtime_file(format!("wpaperd-{}.sock", instance_name()))
+}
+
+/// Path of the file locked by the running wpaperd instance, next to its socket
+pub fn lock_path() -> io::Result<PathBuf> {
+    let xdg_dirs = BaseDirectories::with_prefix("wpaper")?;
+    xdg_dirs.place_runtime_file(format!("wpaperd-{}.lock", instance_name()))
+}
+
+/// Path of the socket of the process serving a single output, when wpaperd runs with
+/// --per-output-process
+pub fn output_socket_path(output: &str) -> io::Result<PathBuf> {
+    let xdg_dirs = BaseDirectories::with_prefix("wpaper")?;
+    xdg_dirs.place_runtime_file(format!("wpaperd-{}-{}.sock", instance_name(), output))
+}
+
+/// Send a message to the running daemon and wait for its response
+pub fn send(message: &IpcMessage) -> io::Result<IpcResponse> {
+    send_to(&socket_path()?, message, None)
+}
+
+/// Subscribe to the changes happening in the daemon. The iterator ends when the daemon exits.
+pub fn subscribe() -> io::Result<impl Iterator<Item = io::Result<IpcEvent>>> {
+    let mut stream = UnixStream::connect(socket_path()?)?;
+    writeln!(stream, "{}", IpcMessage::Subscribe)?;
+    let mut lines = BufReader::new(stream).lines();
+    match lines.next().transpose()?.as_deref() {
+        Some("ok") => {}
+        Some(status) => {
+            let err = status.strip_prefix("error ").unwrap_or(status);
+            return Err(io::Error::other(err.to_string()));
+        }
+        None => return Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+    Ok(lines.map(|line| {
+        line?
+            .parse()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }))
+}
+
+/// Send a message to the daemon listening on socket_path and wait for its response, failing
+/// when the daemon takes longer than timeout to read the message or to answer
+pub fn send_to(
+    socket_path: &Path,
+    message: &IpcMessage,
+    timeout: Option<Duration>,
+) -> io::Result<IpcResponse> {
+    let mut stream = UnixStream::connect(socket_path)?;
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+    writeln!(stream, "{}", message)?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    IpcResponse::read_from(BufReader::new(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Paths the last argument of the requests has to carry as they are
+    const PATHS: [&str; 4] = [
+        "/wallpapers/sunset.jpg",
+        "/home/user/My Pictures/a  b.png",
+        "/wallpapers/\"quoted\" and 'single'.jpg",
+        "/wallpapers/--verbose *.jpg",
+    ];
+
+    fn messages() -> Vec<IpcMessage> {
+        let output = || "DP-1".to_string();
+        let mut messages = vec![
+            IpcMessage::ContestStart {
+                output: output(),
+                count: 5,
+            },
+            IpcMessage::ContestStop { output: output() },
+            IpcMessage::Vote {
+                output: output(),
+                accept: true,
+            },
+            IpcMessage::Vote {
+                output: output(),
+                accept: false,
+            },
+            IpcMessage::ContestResults,
+            IpcMessage::RecordStop { output: output() },
+            IpcMessage::History {
+                output: output(),
+                count: 10,
+            },
+            IpcMessage::Palette { output: output() },
+            IpcMessage::Frame { output: output() },
+            IpcMessage::Pin {
+                output: output(),
+                image: None,
+            },
+            IpcMessage::Unpin { output: output() },
+            IpcMessage::Outputs,
+            IpcMessage::GetSettings { output: output() },
+            IpcMessage::Queue {
+                output: output(),
+                count: 3,
+            },
+            IpcMessage::QueueSkip {
+                output: output(),
+                image: None,
+            },
+            IpcMessage::Thumbnails {
+                output: output(),
+                count: 2,
+                size: "x-large".to_string(),
+            },
+            IpcMessage::Subscribe,
+            IpcMessage::SetProfile { name: None },
+            IpcMessage::SetProfile {
+                name: Some("work".to_string()),
+            },
+            IpcMessage::Profiles,
+            IpcMessage::SetAnimations { enabled: true },
+            IpcMessage::SetAnimations { enabled: false },
+            IpcMessage::Rescan,
+            IpcMessage::Metrics,
+        ];
+        for output in [None, Some(output())] {
+            messages.extend([
+                IpcMessage::PreviousWallpaper {
+                    output: output.clone(),
+                },
+                IpcMessage::NextWallpaper {
+                    output: output.clone(),
+                },
+                IpcMessage::Current {
+                    output: output.clone(),
+                    verbose: false,
+                },
+                IpcMessage::Current {
+                    output: output.clone(),
+                    verbose: true,
+                },
+                IpcMessage::ToggleInversion {
+                    output: output.clone(),
+                },
+                IpcMessage::Pause {
+                    output: output.clone(),
+                },
+                IpcMessage::Resume {
+                    output: output.clone(),
+                },
+                IpcMessage::SetDuration {
+                    output: output.clone(),
+                    duration: Duration::from_secs(90 * 60),
+                },
+                IpcMessage::SetDuration {
+                    output: output.clone(),
+                    duration: Duration::from_millis(1500),
+                },
+            ]);
+            for runtime in [false, true] {
+                messages.push(IpcMessage::SetSettings {
+                    output: "eDP-1".to_string(),
+                    settings: r#"path = "/My Pictures", duration = "5m""#.to_string(),
+                    runtime,
+                });
+            }
+            for path in PATHS {
+                messages.extend([
+                    IpcMessage::SetWallpaper {
+                        output: output.clone(),
+                        path: path.into(),
+                    },
+                    IpcMessage::Preview {
+                        output: output.clone(),
+                        path: path.into(),
+                        duration: Duration::from_secs(30),
+                    },
+                ]);
+            }
+        }
+        for path in PATHS {
+            messages.extend([
+                IpcMessage::RecordStart {
+                    output: output(),
+                    interval: 2,
+                    framerate: 30,
+                    path: path.into(),
+                },
+                IpcMessage::Pin {
+                    output: output(),
+                    image: Some(path.into()),
+                },
+                IpcMessage::QueueSkip {
+                    output: output(),
+                    image: Some(path.into()),
+                },
+                IpcMessage::QueuePin {
+                    output: output(),
+                    pinned: true,
+                    image: path.into(),
+                },
+                IpcMessage::QueuePin {
+                    output: output(),
+                    pinned: false,
+                    image: path.into(),
+                },
+                IpcMessage::QueueFront {
+                    output: output(),
+                    image: path.into(),
+                },
+            ]);
+        }
+        messages
+    }
+
+    /// The request as sent by send_to, a line ending with a newline
+    fn round_trip(message: &IpcMessage) -> IpcMessage {
+        format!("{}\n", message)
+            .parse()
+            .unwrap_or_else(|err| panic!("{:?}: {}", message, err))
+    }
+
+    #[test]
+    fn every_request_round_trips() {
+        for message in messages() {
+            assert_eq!(round_trip(&message), message);
+        }
+    }
+
+    #[test]
+    fn batches_round_trip() {
+        let requests: Vec<IpcMessage> = messages()
+            .into_iter()
+            .filter(|message| {
+                !matches!(
+                    message,
+                    IpcMessage::Subscribe | IpcMessage::Thumbnails { .. }
+                )
+            })
+            .collect();
+        let batch = IpcMessage::Batch(requests);
+        assert_eq!(round_trip(&batch), batch);
+        assert_eq!(
+            round_trip(&IpcMessage::Batch(Vec::new())),
+            IpcMessage::Batch(Vec::new())
+        );
+    }
+
+    #[test]
+    fn batches_cannot_nest_nor_subscribe() {
+        for request in ["subscribe", "thumbnails DP-1 2 normal", "batch"] {
+            let batch = format!("batch\nrescan\n{}\n", request);
+            assert!(matches!(
+                batch.parse::<IpcMessage>(),
+                Err(IpcError::NotInBatch(_))
+            ));
+        }
+        // A single invalid request fails the whole batch
+        assert!("batch\nrescan\nnext-wallpapers\n"
+            .parse::<IpcMessage>()
+            .is_err());
+    }
+
+    #[test]
+    fn invalid_requests_are_errors() {
+        for (line, expected) in [
+            ("", "missing argument command"),
+            ("launch-rockets", "unknown command \"launch-rockets\""),
+            ("history DP-1", "missing argument count"),
+            ("history DP-1 many", "invalid argument \"many\""),
+            ("vote DP-1 maybe", "invalid argument \"maybe\""),
+            ("set-duration forever", "invalid argument \"forever\""),
+            ("set-wallpaper DP-1", "missing argument path"),
+            ("set-animations", "missing argument state"),
+        ] {
+            let err = line.parse::<IpcMessage>().unwrap_err();
+            assert_eq!(err.to_string(), expected, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn events_round_trip() {
+        let output = || "HDMI-A-1".to_string();
+        let mut events = vec![
+            IpcEvent::OutputAdded { output: output() },
+            IpcEvent::OutputRemoved { output: output() },
+            IpcEvent::Settings { output: output() },
+            IpcEvent::Error {
+                output: output(),
+                message: "decoding \"a b.png\": invalid data".to_string(),
+            },
+            IpcEvent::Timer {
+                output: output(),
+                remaining: Duration::from_secs(3725),
+            },
+        ];
+        events.extend(PATHS.map(|path| IpcEvent::Wallpaper {
+            output: output(),
+            image: path.into(),
+        }));
+        for event in events {
+            assert_eq!(event.to_string().parse::<IpcEvent>().unwrap(), event);
+        }
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        for response in [
+            IpcResponse::Ok(Vec::new()),
+            IpcResponse::Ok(vec!["DP-1 /My Pictures/a.jpg".to_string(), String::new()]),
+            IpcResponse::Error("output DP-3 not found".to_string()),
+        ] {
+            let mut data = Vec::new();
+            response.write_to(&mut data).unwrap();
+            assert_eq!(IpcResponse::read_from(&data[..]).unwrap(), response);
+        }
+        assert!(IpcResponse::read_from(&b"maybe\n"[..]).is_err());
+    }
+
+    #[test]
+    fn daemons_that_do_not_answer_time_out() {
+        let socket_path =
+            std::env::temp_dir().join(format!("wpaper-ipc-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket_path);
+        let listener = std::os::unix::net::UnixListener::bind(&socket_path).unwrap();
+        let err = send_to(
+            &socket_path,
+            &IpcMessage::Outputs,
+            Some(Duration::from_millis(50)),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ));
+        drop(listener);
+        std::fs::remove_file(&socket_path).unwrap();
+    }
+}
@@ -0,0 +1,474 @@
+use std::{
+    env,
+    io::{self, BufRead},
+    iter,
+    path::PathBuf,
+    time::Duration,
+};
+
+use clap::Parser;
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use wpaper_ipc::{IpcMessage, IpcResponse};
+
+#[derive(Parser)]
+#[clap(
+    author = "Danilo Spinella <danilo.spinella@suse.com>",
+    version,
+    about = "Control a running wpaperd instance"
+)]
+struct Opts {
+    #[clap(
+        long,
+        global = true,
+        help = "Control the wpaperd instance of this seat, started with wpaperd --seat; defaults to the seat of the session"
+    )]
+    seat: Option<String>,
+    #[clap(subcommand)]
+    command: SubCommand,
+}
+
+#[derive(Parser)]
+enum SubCommand {
+    /// Shortlist wallpapers and vote for your favorites
+    #[clap(subcommand)]
+    Contest(ContestCommand),
+    /// Record the wallpapers displayed on an output into a video, using ffmpeg
+    #[clap(subcommand)]
+    Record(RecordCommand),
+    /// Go back to the previous wallpaper
+    PreviousWallpaper {
+        #[clap(help = "Output to change, all the outputs if not set")]
+        output: Option<String>,
+    },
+    /// Show the next wallpaper
+    #[clap(alias = "next")]
+    NextWallpaper {
+        #[clap(help = "Output to change, all the outputs if not set")]
+        output: Option<String>,
+        #[clap(
+            long,
+            conflicts_with = "output",
+            help = "Change all the outputs, which show their new wallpapers on the same frame; the default without an output"
+        )]
+        all: bool,
+    },
+    /// Print the wallpaper displayed on an output, or on each output after its name
+    Current {
+        #[clap(help = "Output to print, all the outputs if not set")]
+        output: Option<String>,
+        #[clap(
+            short,
+            long,
+            help = "Credit the remote wallpapers: their source, author, license and page"
+        )]
+        verbose: bool,
+    },
+    /// List the wallpapers displayed on an output, from the most recent
+    History {
+        output: String,
+        #[clap(
+            short = 'n',
+            long,
+            default_value = "10",
+            help = "Number of wallpapers to list"
+        )]
+        count: usize,
+    },
+    /// List the next wallpapers of an output, or change them; pinned ones are marked with *
+    Queue {
+        output: String,
+        #[clap(
+            short = 'n',
+            long,
+            default_value = "10",
+            help = "Number of wallpapers to list"
+        )]
+        count: usize,
+        #[clap(subcommand)]
+        command: Option<QueueCommand>,
+    },
+    /// Print the thumbnails of the current and next wallpapers of an output, each after the
+    /// path of its wallpaper and a tab
+    Thumbnails {
+        output: String,
+        #[clap(
+            short = 'n',
+            long,
+            default_value = "10",
+            help = "Number of upcoming wallpapers"
+        )]
+        count: usize,
+        #[clap(
+            long,
+            default_value = "large",
+            possible_values = ["normal", "large", "x-large", "xx-large"],
+            help = "Size of the thumbnails: 128, 256, 512 or 1024 pixels"
+        )]
+        size: String,
+    },
+    /// Print the dominant colors of the wallpaper displayed on an output
+    Palette { output: String },
+    /// Save the frame on screen of an output, with its filters, as a PNG and print its path
+    Frame { output: String },
+    /// Switch between the normal and the inverted colors
+    Invert {
+        #[clap(help = "Output to change, all the outputs if not set")]
+        output: Option<String>,
+    },
+    /// Stop changing wallpaper, until resumed
+    Pause {
+        #[clap(help = "Output to pause, all the outputs if not set")]
+        output: Option<String>,
+    },
+    /// Start changing wallpaper again, the timer continues from where it was paused
+    Resume {
+        #[clap(help = "Output to resume, all the outputs if not set")]
+        output: Option<String>,
+    },
+    /// Keep showing a wallpaper on an output until it is unpinned
+    Pin {
+        output: String,
+        #[clap(help = "Image to show, the current one if not set")]
+        image: Option<PathBuf>,
+    },
+    /// Let the wallpaper of an output change again
+    Unpin { output: String },
+    /// Change how long each wallpaper is displayed, until the configuration is reloaded
+    SetDuration {
+        #[clap(
+            parse(try_from_str = humantime::parse_duration),
+            help = "New duration, e.g. 500ms, 30s, 5m or 1h30m"
+        )]
+        duration: Duration,
+        #[clap(help = "Output to change, all the outputs if not set")]
+        output: Option<String>,
+    },
+    /// Show an image right away, wherever it is; the rotation then continues as usual
+    SetWallpaper {
+        path: PathBuf,
+        #[clap(help = "Output to change, all the outputs if not set")]
+        output: Option<String>,
+    },
+    /// Show an image for a while, then go back to the current wallpaper; the rotation is
+    /// left as it is
+    Preview {
+        path: PathBuf,
+        #[clap(help = "Output to change, all the outputs if not set")]
+        output: Option<String>,
+        #[clap(
+            short,
+            long,
+            default_value = "10s",
+            parse(try_from_str = humantime::parse_duration),
+            help = "How long the image is shown, e.g. 5s or 1m"
+        )]
+        duration: Duration,
+    },
+    /// List the outputs and their capabilities
+    Outputs,
+    /// Print the settings of an output
+    GetSettings { output: String },
+    /// Change the settings of an output and save them in the configuration file
+    SetSettings {
+        #[clap(
+            long,
+            help = "Keep the settings until wpaperd exits instead of saving them"
+        )]
+        runtime: bool,
+        output: String,
+        #[clap(
+            required = true,
+            help = "Settings to change, e.g. 'duration = \"5m\"' 'force_8bit = true'"
+        )]
+        settings: Vec<String>,
+    },
+    /// Print a line for every change happening in wpaperd, until it exits
+    Subscribe {
+        #[clap(long, help = "Print each event as a JSON object")]
+        json: bool,
+    },
+    /// Read commands from stdin, one per line, and apply them all before the next frame
+    Batch,
+    /// Switch every output to a profile of the configuration file
+    Profile {
+        #[clap(
+            required_unless_present = "clear",
+            help = "Profile to activate, as named in the configuration file"
+        )]
+        name: Option<String>,
+        #[clap(
+            long,
+            conflicts_with = "name",
+            help = "Go back to the sections outside of the profiles"
+        )]
+        clear: bool,
+    },
+    /// List the profiles of the configuration file, the active one marked with *
+    Profiles,
+    /// Turn the transitions, the Ken Burns effect and the moving layers of every output on
+    /// or off, until wpaperd exits
+    Animations {
+        #[clap(possible_values = ["on", "off"])]
+        state: String,
+    },
+    /// Index the wallpaper directories again: find the duplicate images and retry the ones
+    /// that could not be decoded
+    Rescan,
+    /// Print the counters of the daemon in the Prometheus text format
+    Metrics,
+}
+
+#[derive(Parser)]
+enum ContestCommand {
+    /// Start cycling a random shortlist of images on an output
+    Start {
+        output: String,
+        #[clap(
+            short,
+            long,
+            default_value = "10",
+            help = "Number of images in the shortlist"
+        )]
+        count: usize,
+    },
+    /// Stop the contest running on an output
+    Stop { output: String },
+    /// Vote for the image currently displayed and show the next one
+    Accept { output: String },
+    /// Vote against the image currently displayed and show the next one
+    Reject { output: String },
+    /// Print the images ranked by their votes
+    Results,
+}
+
+#[derive(Parser)]
+enum QueueCommand {
+    /// Remove a wallpaper from the queue, the next one if not set
+    Skip { image: Option<PathBuf> },
+    /// Keep a queued wallpaper when the settings of the output change
+    Pin { image: PathBuf },
+    /// Stop keeping a queued wallpaper when the settings of the output change
+    Unpin { image: PathBuf },
+    /// Show a wallpaper of the output directory after the current one
+    Front { image: PathBuf },
+}
+
+#[derive(Parser)]
+enum RecordCommand {
+    /// Start recording an output
+    Start {
+        output: String,
+        #[clap(help = "Path of the video to create, e.g. timelapse.mp4")]
+        path: PathBuf,
+        #[clap(
+            short,
+            long,
+            default_value = "1",
+            help = "Record one frame every <interval> rotations"
+        )]
+        interval: u32,
+        #[clap(
+            short,
+            long,
+            default_value = "1",
+            help = "Frames per second of the video"
+        )]
+        framerate: u32,
+    },
+    /// Stop recording an output and finalize the video
+    Stop { output: String },
+}
+
+fn main() -> Result<()> {
+    color_eyre::install()?;
+
+    let opts = Opts::parse();
+    if let Some(seat) = &opts.seat {
+        env::set_var(wpaper_ipc::SEAT_VAR, seat);
+    }
+
+    let message = match opts.command {
+        SubCommand::Subscribe { json } => {
+            for event in wpaper_ipc::subscribe().context("subscribing to wpaperd")? {
+                let event = event.context("reading the events")?;
+                if json {
+                    println!("{}", event.to_json());
+                } else {
+                    println!("{}", event);
+                }
+            }
+            return Ok(());
+        }
+        SubCommand::Batch => read_batch()?,
+        command => to_message(command)?,
+    };
+
+    match wpaper_ipc::send(&message).context("sending the command to wpaperd")? {
+        IpcResponse::Ok(lines) => {
+            for line in lines {
+                println!("{}", line);
+            }
+        }
+        IpcResponse::Error(err) => bail!("wpaperd: {}", err),
+    }
+
+    Ok(())
+}
+
+/// Read a batch from stdin. Each line is a command, written as on the command line; empty
+/// lines and the ones starting with # are skipped.
+fn read_batch() -> Result<IpcMessage> {
+    let mut messages = Vec::new();
+    for (i, line) in io::stdin().lock().lines().enumerate() {
+        let line = line.context("reading the commands")?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let opts = Opts::try_parse_from(iter::once("wpaperctl").chain(line.split_whitespace()))
+            .with_context(|| format!("parsing line {}: {:?}", i + 1, line))?;
+        messages
+            .push(to_message(opts.command).with_context(|| format!("line {}: {:?}", i + 1, line))?);
+    }
+    Ok(IpcMessage::Batch(messages))
+}
+
+fn to_message(command: SubCommand) -> Result<IpcMessage> {
+    Ok(match command {
+        SubCommand::Contest(command) => match command {
+            ContestCommand::Start { output, count } => IpcMessage::ContestStart { output, count },
+            ContestCommand::Stop { output } => IpcMessage::ContestStop { output },
+            ContestCommand::Accept { output } => IpcMessage::Vote {
+                output,
+                accept: true,
+            },
+            ContestCommand::Reject { output } => IpcMessage::Vote {
+                output,
+                accept: false,
+            },
+            ContestCommand::Results => IpcMessage::ContestResults,
+        },
+        SubCommand::Record(command) => match command {
+            RecordCommand::Start {
+                output,
+                path,
+                interval,
+                framerate,
+            } => IpcMessage::RecordStart {
+                output,
+                interval,
+                framerate,
+                // The daemon does not share our working directory
+                path: env::current_dir()
+                    .context("getting the current directory")?
+                    .join(path),
+            },
+            RecordCommand::Stop { output } => IpcMessage::RecordStop { output },
+        },
+        SubCommand::PreviousWallpaper { output } => IpcMessage::PreviousWallpaper { output },
+        SubCommand::NextWallpaper { output, .. } => IpcMessage::NextWallpaper { output },
+        SubCommand::Current { output, verbose } => IpcMessage::Current { output, verbose },
+        SubCommand::History { output, count } => IpcMessage::History { output, count },
+        SubCommand::Queue {
+            output,
+            count,
+            command,
+        } => {
+            // The daemon does not share our working directory
+            let absolute = |image: PathBuf| -> Result<PathBuf> {
+                Ok(env::current_dir()
+                    .context("getting the current directory")?
+                    .join(image))
+            };
+            match command {
+                None => IpcMessage::Queue { output, count },
+                Some(QueueCommand::Skip { image }) => IpcMessage::QueueSkip {
+                    output,
+                    image: image.map(absolute).transpose()?,
+                },
+                Some(QueueCommand::Pin { image }) => IpcMessage::QueuePin {
+                    output,
+                    pinned: true,
+                    image: absolute(image)?,
+                },
+                Some(QueueCommand::Unpin { image }) => IpcMessage::QueuePin {
+                    output,
+                    pinned: false,
+                    image: absolute(image)?,
+                },
+                Some(QueueCommand::Front { image }) => IpcMessage::QueueFront {
+                    output,
+                    image: absolute(image)?,
+                },
+            }
+        }
+        SubCommand::Thumbnails {
+            output,
+            count,
+            size,
+        } => IpcMessage::Thumbnails {
+            output,
+            count,
+            size,
+        },
+        SubCommand::Palette { output } => IpcMessage::Palette { output },
+        SubCommand::Frame { output } => IpcMessage::Frame { output },
+        SubCommand::Invert { output } => IpcMessage::ToggleInversion { output },
+        SubCommand::Pause { output } => IpcMessage::Pause { output },
+        SubCommand::Resume { output } => IpcMessage::Resume { output },
+        SubCommand::Pin { output, image } => IpcMessage::Pin {
+            output,
+            // The daemon does not share our working directory
+            image: image
+                .map(|image| env::current_dir().map(|dir| dir.join(image)))
+                .transpose()
+                .context("getting the current directory")?,
+        },
+        SubCommand::Unpin { output } => IpcMessage::Unpin { output },
+        SubCommand::SetDuration { duration, output } => {
+            IpcMessage::SetDuration { output, duration }
+        }
+        SubCommand::SetWallpaper { path, output } => IpcMessage::SetWallpaper {
+            output,
+            // The daemon does not share our working directory
+            path: env::current_dir()
+                .context("getting the current directory")?
+                .join(path),
+        },
+        SubCommand::Preview {
+            path,
+            output,
+            duration,
+        } => IpcMessage::Preview {
+            output,
+            path: env::current_dir()
+                .context("getting the current directory")?
+                .join(path),
+            duration,
+        },
+        SubCommand::Outputs => IpcMessage::Outputs,
+        SubCommand::GetSettings { output } => IpcMessage::GetSettings { output },
+        SubCommand::SetSettings {
+            runtime,
+            output,
+            settings,
+        } => IpcMessage::SetSettings {
+            output,
+            settings: settings.join(", "),
+            runtime,
+        },
+        SubCommand::Profile { name, .. } => IpcMessage::SetProfile { name },
+        SubCommand::Profiles => IpcMessage::Profiles,
+        SubCommand::Animations { state } => IpcMessage::SetAnimations {
+            enabled: state == "on",
+        },
+        SubCommand::Rescan => IpcMessage::Rescan,
+        SubCommand::Metrics => IpcMessage::Metrics,
+        SubCommand::Subscribe { .. } | SubCommand::Batch => {
+            bail!("subscribe and batch cannot be part of a batch")
+        }
+    })
+}
@@ -0,0 +1,442 @@
+use std::{
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{eyre::WrapErr, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// Keys of JSON objects and names of XML elements holding each detail of an image, e.g. in
+/// RSS with the Dublin Core and Media RSS extensions, in Atom or in the answers of the
+/// Unsplash and NASA APIs
+const AUTHOR_KEYS: &[&str] = &[
+    "author",
+    "creator",
+    "credit",
+    "photographer",
+    "artist",
+    "copyright",
+    "user",
+];
+const LICENSE_KEYS: &[&str] = &["license", "licence", "rights"];
+const PAGE_KEYS: &[&str] = &["link", "html", "permalink"];
+/// The HTML elements that are never closed
+const VOID_ELEMENTS: &[&str] = &["img", "br", "hr", "meta", "link", "input", "source", "wbr"];
+/// Longer details are cut, they are meant to fit on a line
+const MAX_DETAIL_LEN: usize = 200;
+
+/// Who made an image downloaded from a remote source and where it comes from, kept next to
+/// the image in the cache so that it can be credited
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Attribution {
+    /// URL of the image itself
+    pub source: String,
+    pub author: Option<String>,
+    pub license: Option<String>,
+    /// Web page presenting the image, e.g. on the site of the photographer
+    pub page: Option<String>,
+}
+
+impl Attribution {
+    pub fn new(source: &str) -> Self {
+        Self {
+            source: source.to_string(),
+            ..Self::default()
+        }
+    }
+
+    /// The attribution saved next to image, None if there is none
+    pub fn read(image: &Path) -> Option<Self> {
+        let path = sidecar(image);
+        let content = fs::read_to_string(&path).ok()?;
+        toml::from_str(&content)
+            .map_err(|err| warn!("reading the attribution {:?}: {}", path, err))
+            .ok()
+    }
+
+    /// Save the attribution next to image, replacing the file atomically like the image
+    pub fn save(&self, image: &Path) -> Result<()> {
+        let path = sidecar(image);
+        let tmp_path = path.with_extension("toml.part");
+        fs::write(&tmp_path, toml::to_string(self)?)
+            .with_context(|| format!("writing {:?}", tmp_path))?;
+        fs::rename(&tmp_path, &path).with_context(|| format!("writing {:?}", path))
+    }
+
+    /// Remove the attribution saved next to image, along with the image
+    pub fn remove(image: &Path) {
+        let _ = fs::remove_file(sidecar(image));
+    }
+
+    /// The details known, one per line as `<name>: <value>`
+    pub fn describe(&self) -> Vec<String> {
+        [
+            ("source", Some(&self.source)),
+            ("author", self.author.as_ref()),
+            ("license", self.license.as_ref()),
+            ("page", self.page.as_ref()),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some(format!("{}: {}", name, value?)))
+        .collect()
+    }
+}
+
+fn sidecar(image: &Path) -> PathBuf {
+    image.with_extension("toml")
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Syntax {
+    Json,
+    Xml,
+}
+
+/// A detail found in a feed: the offset of its key, the range of text where it applies and
+/// its value
+struct Detail {
+    position: usize,
+    scope: Range<usize>,
+    value: String,
+}
+
+/// The attribution of each image of a feed, given with the offset of its URL in text. A
+/// detail applies to the images of the innermost object or element around it that has
+/// images, so that those of an item are preferred to those of the whole feed.
+pub fn from_feed(text: &str, images: &[(usize, String)]) -> Vec<Attribution> {
+    let syntax = if text.trim_start().starts_with(['{', '[']) {
+        Syntax::Json
+    } else {
+        Syntax::Xml
+    };
+    let containers = containers(text, syntax);
+    let details = |keys: &[&str]| -> Vec<Detail> {
+        details(text, syntax, &containers, keys)
+            .into_iter()
+            .filter_map(|(position, value)| {
+                let scope = containers
+                    .iter()
+                    .filter(|container| container.start < position && position < container.end)
+                    .filter(|container| images.iter().any(|(image, _)| container.contains(image)))
+                    .min_by_key(|container| container.len())?;
+                Some(Detail {
+                    position,
+                    scope: scope.clone(),
+                    value,
+                })
+            })
+            .collect()
+    };
+    let authors = details(AUTHOR_KEYS);
+    let licenses = details(LICENSE_KEYS);
+    let pages: Vec<Detail> = details(PAGE_KEYS)
+        .into_iter()
+        .filter(|page| page.value.starts_with("http://") || page.value.starts_with("https://"))
+        .collect();
+    let find = |details: &[Detail], image: usize| {
+        details
+            .iter()
+            .filter(|detail| detail.scope.contains(&image))
+            .min_by_key(|detail| (detail.scope.len(), detail.position))
+            .map(|detail| detail.value.clone())
+    };
+    images
+        .iter()
+        .map(|(position, url)| Attribution {
+            source: url.clone(),
+            author: find(&authors, *position),
+            license: find(&licenses, *position),
+            page: find(&pages, *position).filter(|page| page != url),
+        })
+        .collect()
+}
+
+/// The ranges spanned by the JSON objects and arrays, or by the XML elements, of text
+fn containers(text: &str, syntax: Syntax) -> Vec<Range<usize>> {
+    let mut containers = Vec::new();
+    match syntax {
+        Syntax::Json => {
+            let mut open = Vec::new();
+            let (mut in_string, mut escaped) = (false, false);
+            for (i, byte) in text.bytes().enumerate() {
+                if in_string {
+                    match byte {
+                        _ if escaped => escaped = false,
+                        b'\\' => escaped = true,
+                        b'"' => in_string = false,
+                        _ => {}
+                    }
+                    continue;
+                }
+                match byte {
+                    b'"' => in_string = true,
+                    b'{' | b'[' => open.push(i),
+                    b'}' | b']' => {
+                        if let Some(start) = open.pop() {
+                            containers.push(start..i + 1);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            containers.extend(open.into_iter().map(|start| start..text.len()));
+        }
+        Syntax::Xml => {
+            let mut open: Vec<(usize, &str)> = Vec::new();
+            for (start, end, tag) in tags(text) {
+                if let Some(name) = tag.strip_prefix('/') {
+                    let name = name.trim();
+                    if let Some(index) = open.iter().rposition(|(_, open)| *open == name) {
+                        // The elements left open inside it end with it
+                        for (start, _) in open.drain(index..) {
+                            containers.push(start..end);
+                        }
+                    }
+                } else if !tag.starts_with(['!', '?']) && !tag.ends_with('/') {
+                    let name = tag_name(tag);
+                    if !VOID_ELEMENTS.contains(&name.to_ascii_lowercase().as_str()) {
+                        open.push((start, name));
+                    }
+                }
+            }
+            containers.extend(open.into_iter().map(|(start, _)| start..text.len()));
+        }
+    }
+    containers
+}
+
+/// The tags of an XML or HTML document: where each one starts and ends and what is between
+/// its angle brackets. CDATA sections are skipped.
+fn tags(text: &str) -> impl Iterator<Item = (usize, usize, &str)> {
+    let mut rest = 0;
+    std::iter::from_fn(move || loop {
+        let start = rest + text[rest..].find('<')?;
+        if text[start..].starts_with("<![CDATA[") {
+            rest = text[start..]
+                .find("]]>")
+                .map_or(text.len(), |end| start + end + 3);
+            continue;
+        }
+        let end = text[start..]
+            .find('>')
+            .map_or(text.len(), |end| start + end + 1);
+        rest = end;
+        let tag = text[start + 1..end].trim_end_matches('>');
+        return Some((start, end, tag));
+    })
+}
+
+/// The name of the element of tag, without its namespace prefix
+fn tag_name(tag: &str) -> &str {
+    tag.split(|c: char| c.is_whitespace() || c == '/')
+        .next()
+        .unwrap_or_default()
+}
+
+fn local_name(name: &str) -> &str {
+    name.rsplit(':').next().unwrap_or(name)
+}
+
+/// The values of the keys, or elements, named like one of keys, with their offset in text
+fn details(
+    text: &str,
+    syntax: Syntax,
+    containers: &[Range<usize>],
+    keys: &[&str],
+) -> Vec<(usize, String)> {
+    let mut details = Vec::new();
+    match syntax {
+        Syntax::Json => {
+            for key in keys {
+                let quoted = format!("\"{}\"", key);
+                let mut rest = 0;
+                while let Some(offset) = text[rest..].find(&quoted) {
+                    let position = rest + offset;
+                    rest = position + quoted.len();
+                    let value = match text[rest..].trim_start().strip_prefix(':') {
+                        Some(value) => value.trim_start(),
+                        None => continue,
+                    };
+                    let value_start = text.len() - value.len();
+                    if let Some(value) = json_value(text, value_start, containers) {
+                        details.push((position, value));
+                    }
+                }
+            }
+        }
+        Syntax::Xml => {
+            for (start, end, tag) in tags(text) {
+                let name = tag_name(tag);
+                if name.starts_with(['/', '!', '?'])
+                    || !keys
+                        .iter()
+                        .any(|key| local_name(name).eq_ignore_ascii_case(key))
+                {
+                    continue;
+                }
+                // Atom links are in an attribute, the other details are the text inside. The
+                // links of HTML pages to their stylesheets and icons are not details.
+                let value = match attribute(tag, "href") {
+                    Some(href) if attribute(tag, "rel").is_none_or(|rel| rel == "alternate") => {
+                        href.to_string()
+                    }
+                    Some(_) => continue,
+                    None if tag.ends_with('/') => continue,
+                    None => {
+                        let closing = format!("</{}", name);
+                        let content_end = text[end..]
+                            .find(&closing)
+                            .map_or(text.len(), |offset| end + offset);
+                        xml_text(&text[end..content_end])
+                    }
+                };
+                if let Some(value) = clean(&value) {
+                    details.push((start, value));
+                }
+            }
+        }
+    }
+    details.sort_by_key(|(position, _)| *position);
+    details
+}
+
+/// The JSON value starting at start: a string, or the name of an object like the user of
+/// a photo
+fn json_value(text: &str, start: usize, ranges: &[Range<usize>]) -> Option<String> {
+    match text[start..].chars().next()? {
+        '"' => clean(&json_string(&text[start + 1..])),
+        '{' => {
+            let object = ranges.iter().find(|object| object.start == start)?;
+            let object = &text[object.clone()];
+            let inner = containers(object, Syntax::Json);
+            details(object, Syntax::Json, &inner, &["name"])
+                .into_iter()
+                .map(|(_, name)| name)
+                .next()
+        }
+        _ => None,
+    }
+}
+
+/// The content of the JSON string starting at text, after its opening quote, unescaped
+fn json_string(text: &str) -> String {
+    let mut value = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => match chars.next() {
+                Some('n') | Some('t') | Some('r') => value.push(' '),
+                Some('u') => {
+                    let code: String = chars.by_ref().take(4).collect();
+                    if let Some(c) = u32::from_str_radix(&code, 16).ok().and_then(char::from_u32) {
+                        value.push(c);
+                    }
+                }
+                Some(c) => value.push(c),
+                None => break,
+            },
+            c => value.push(c),
+        }
+    }
+    value
+}
+
+/// The text of an XML fragment, without its tags and with its entities replaced
+fn xml_text(fragment: &str) -> String {
+    let mut text = String::new();
+    let mut rest = fragment;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(cdata) = rest.strip_prefix("<![CDATA[") {
+            let end = cdata.find("]]>").unwrap_or(cdata.len());
+            text.push_str(&cdata[..end]);
+            rest = cdata.get(end + 3..).unwrap_or_default();
+        } else {
+            text.push(' ');
+            rest = rest.find('>').map_or("", |end| &rest[end + 1..]);
+        }
+    }
+    text.push_str(rest);
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&#39;", "'")
+        .replace("&copy;", "©")
+        .replace("&#169;", "©")
+}
+
+/// The value of the attribute name of tag
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let pattern = format!("{}=", name);
+    let start = tag.find(&pattern)? + pattern.len();
+    let value = &tag[start..];
+    let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let value = &value[1..];
+    Some(&value[..value.find(quote)?])
+}
+
+/// value on a single line and not too long, None if it is empty
+fn clean(value: &str) -> Option<String> {
+    let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+    if value.is_empty() {
+        return None;
+    }
+    Some(match value.char_indices().nth(MAX_DETAIL_LEN) {
+        Some((end, _)) => format!("{}…", &value[..end]),
+        None => value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feed_details_are_credited_to_the_images_of_their_item() {
+        let images = |text: &str, urls: &[&str]| -> Vec<(usize, String)> {
+            urls.iter()
+                .map(|url| (text.find(url).unwrap(), url.to_string()))
+                .collect()
+        };
+        let rss = r#"<rss><channel><title>Photos</title><dc:rights>CC BY 4.0</dc:rights>
+            <item><link>https://example.com/lake</link><dc:creator><![CDATA[Ann Smith]]></dc:creator>
+              <enclosure url="https://example.com/lake.jpg" type="image/jpeg"/></item>
+            <item><media:content url="https://example.com/dunes.png"/></item>
+            </channel></rss>"#;
+        let urls = [
+            "https://example.com/lake.jpg",
+            "https://example.com/dunes.png",
+        ];
+        let attributions = from_feed(rss, &images(rss, &urls));
+        assert_eq!(attributions[0].author.as_deref(), Some("Ann Smith"));
+        assert_eq!(
+            attributions[0].page.as_deref(),
+            Some("https://example.com/lake")
+        );
+        // The author of the other item is not credited, but the license of the feed is
+        assert_eq!(attributions[1].author, None);
+        assert_eq!(attributions[1].license.as_deref(), Some("CC BY 4.0"));
+        assert_eq!(attributions[1].source, urls[1]);
+
+        let json = r#"[{"urls": {"raw": "https://images.example.com/1"},
+            "links": {"html": "https://example.com/photos/1"}, "user": {"id": 4, "name": "Bo Åberg"}},
+            {"urls": {"raw": "https://images.example.com/2"}, "user": {"name": "Cy"}}]"#;
+        let urls = [
+            "https://images.example.com/1",
+            "https://images.example.com/2",
+        ];
+        let attributions = from_feed(json, &images(json, &urls));
+        assert_eq!(attributions[0].author.as_deref(), Some("Bo Åberg"));
+        assert_eq!(
+            attributions[0].page.as_deref(),
+            Some("https://example.com/photos/1")
+        );
+        assert_eq!(attributions[1].author.as_deref(), Some("Cy"));
+        assert_eq!(attributions[1].page, None);
+    }
+}
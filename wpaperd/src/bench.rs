@@ -0,0 +1,161 @@
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{ensure, eyre};
+use color_eyre::Result;
+use image::DynamicImage;
+use smithay_client_toolkit::reexports::client::protocol::wl_shm;
+use wpaper_core::icc::ColorTransform;
+use wpaper_core::inversion::invert;
+
+use crate::collection::read_image_settings;
+use crate::metrics::Stage;
+use crate::night::NightTint;
+use crate::output::{Dither, Output};
+use crate::pipeline::{decode_image, decode_size, fit_scaler, to_buffer_data, widen, Scaler};
+
+/// Sizes used when --bench-size is not given: the common resolutions of the displays
+pub const DEFAULT_SIZES: &[(u32, u32)] = &[(1920, 1080), (2560, 1440), (3840, 2160)];
+
+/// Parse a size written WIDTHxHEIGHT, e.g. 1920x1080
+pub fn parse_size(size: &str) -> Result<(u32, u32)> {
+    let invalid = || eyre!("invalid size {:?}, expected WIDTHxHEIGHT", size);
+    let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+    let width: u32 = width.parse().map_err(|_| invalid())?;
+    let height: u32 = height.parse().map_err(|_| invalid())?;
+    ensure!(width > 0 && height > 0, invalid());
+    Ok((width, height))
+}
+
+/// Run the stages of the pipeline on image iterations times for each size, like an output
+/// with the settings of output would, and print how long each stage took and how much memory
+/// it needed
+pub fn run(image: &Path, output: &Output, sizes: &[(u32, u32)], iterations: u32) -> Result<()> {
+    ensure!(
+        iterations > 0,
+        "the number of iterations must be at least 1"
+    );
+    let settings = read_image_settings(output, image)?;
+    let fit = settings.fit.unwrap_or(output.fit);
+    let format = wl_shm::Format::Abgr8888;
+    let color_transform = output
+        .icc_profile
+        .as_deref()
+        .map(ColorTransform::from_icc_path)
+        .transpose()?;
+    let night = NightTint::default();
+    println!(
+        "{:?}, {} iteration{}, fit {:?}, filter {:?}",
+        image,
+        iterations,
+        if iterations == 1 { "" } else { "s" },
+        fit,
+        output.scaling_filter
+    );
+
+    for &(width, height) in sizes {
+        let mut timings: Vec<Vec<Duration>> = vec![Vec::new(); Stage::ALL.len()];
+        let mut time = |stage: Stage, start: Instant| timings[stage as usize].push(start.elapsed());
+        let mut buffer = vec![0; (width * height * 4) as usize];
+        let mut sizes = (0, 0);
+        for _ in 0..iterations {
+            let start = Instant::now();
+            let (decode_width, decode_height) = decode_size(fit, width, height);
+            let decoded = decode_image(image, decode_width, decode_height)?;
+            time(Stage::Decode, start);
+            sizes.0 = decoded.as_bytes().len();
+
+            let start = Instant::now();
+            let decoded = if output.dither != Dither::None {
+                DynamicImage::ImageRgba16(widen(decoded))
+            } else {
+                decoded
+            };
+            let scaled =
+                fit_scaler(output, fit, output.scaling_filter).scale(decoded, width, height);
+            time(Stage::Scale, start);
+            sizes.1 = scaled.as_bytes().len();
+
+            let start = Instant::now();
+            let mut data = to_buffer_data(
+                scaled,
+                format,
+                color_transform.as_ref(),
+                output.background(),
+                output.dither,
+            );
+            time(Stage::Convert, start);
+
+            // The filters that run on every frame, the ones that depend on the time of the
+            // day and on the commands are all applied
+            let start = Instant::now();
+            invert(&mut data, format, 0.5);
+            night.apply(&mut data, format);
+            time(Stage::Filters, start);
+
+            // A buffer of shared memory is written like any other memory
+            let start = Instant::now();
+            buffer.copy_from_slice(&data);
+            time(Stage::Write, start);
+        }
+
+        println!();
+        println!("{}x{}", width, height);
+        println!(
+            "  {:<8} {:>10} {:>10} {:>10} {:>10}",
+            "stage", "min", "median", "mean", "max"
+        );
+        for stage in Stage::ALL {
+            let durations = &mut timings[stage as usize];
+            durations.sort();
+            let total: Duration = durations.iter().sum();
+            let ms = |duration: Duration| format!("{:.2}ms", duration.as_secs_f64() * 1000.0);
+            println!(
+                "  {:<8} {:>10} {:>10} {:>10} {:>10}",
+                stage.name(),
+                ms(durations[0]),
+                ms(durations[durations.len() / 2]),
+                ms(total / iterations),
+                ms(durations[durations.len() - 1])
+            );
+        }
+        println!(
+            "  memory: decoded {}, scaled {}, buffer {}",
+            mebibytes(sizes.0),
+            mebibytes(sizes.1),
+            mebibytes(buffer.len())
+        );
+    }
+
+    if let Some((peak, current)) = resident_memory() {
+        println!();
+        println!(
+            "resident memory: peak {}, at the end {}",
+            mebibytes(peak),
+            mebibytes(current)
+        );
+    }
+    Ok(())
+}
+
+fn mebibytes(bytes: usize) -> String {
+    format!("{:.1}MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+/// The peak and the current resident memory of the process, in bytes, from
+/// /proc/self/status
+fn resident_memory() -> Option<(usize, usize)> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let field = |name: &str| {
+        let line = status.lines().find(|line| line.starts_with(name))?;
+        let kibibytes: usize = line[name.len()..]
+            .trim()
+            .trim_end_matches("kB")
+            .trim()
+            .parse()
+            .ok()?;
+        Some(kibibytes * 1024)
+    };
+    Some((field("VmHWM:")?, field("VmRSS:")?))
+}
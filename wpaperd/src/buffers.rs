@@ -0,0 +1,206 @@
+use color_eyre::{
+    eyre::{ensure, eyre, WrapErr},
+    Result,
+};
+use log::warn;
+use smithay_client_toolkit::{
+    reexports::client::{
+        protocol::{wl_shm, wl_surface},
+        Attached,
+    },
+    shm::MemPool,
+};
+
+use crate::dmabuf::DmabufBuffers;
+use crate::pipeline::FrameSink;
+
+/// A third buffer is only needed when the compositor holds on to the other two, e.g. while
+/// an animation runs faster than the output refreshes
+const MAX_BUFFERS: usize = 3;
+
+/// The buffers of a surface. They are drawn into in turn, so that the compositor can keep
+/// reading the one on screen; their memory is kept across frames, and only the rows that
+/// changed are copied and damaged.
+pub struct Buffers {
+    shm: Attached<wl_shm::WlShm>,
+    /// The surface the buffers are attached to
+    surface: wl_surface::WlSurface,
+    slots: Vec<Slot>,
+    /// Slot attached last, its frame is the one on screen
+    current: Option<usize>,
+    /// Used instead of the shared memory when set, until they fail
+    dmabuf: Option<DmabufBuffers>,
+}
+
+struct Slot {
+    pool: MemPool,
+    /// Size of the pool in bytes, it never shrinks
+    size: usize,
+    /// Width, height and format of the frame held by the pool, None if it holds none
+    frame: Option<(u32, u32, wl_shm::Format)>,
+}
+
+impl Buffers {
+    pub fn new(
+        shm: Attached<wl_shm::WlShm>,
+        surface: wl_surface::WlSurface,
+        dmabuf: Option<DmabufBuffers>,
+    ) -> Self {
+        Self {
+            shm,
+            surface,
+            slots: Vec::new(),
+            current: None,
+            dmabuf,
+        }
+    }
+
+    /// Bytes of shared memory used by the pools, or of GPU memory
+    pub fn allocated(&self) -> usize {
+        self.slots.iter().map(|slot| slot.size).sum::<usize>()
+            + self.dmabuf.as_ref().map_or(0, DmabufBuffers::allocated)
+    }
+
+    /// Attach the frame on screen again, when the surface needs a new commit but not a new
+    /// frame. Returns the size of the frame, None if there is none. The surface still needs
+    /// to be committed.
+    pub fn reattach(&mut self) -> Option<(u32, u32)> {
+        if let Some(dmabuf) = &mut self.dmabuf {
+            let (buffer, (width, height)) = dmabuf.current()?;
+            self.surface.attach(Some(buffer), 0, 0);
+            self.surface
+                .damage_buffer(0, 0, width as i32, height as i32);
+            return Some((width, height));
+        }
+        let slot = &mut self.slots[self.current?];
+        let (width, height, format) = slot.frame?;
+        let buffer = slot
+            .pool
+            .buffer(0, width as i32, height as i32, 4 * width as i32, format);
+        self.surface.attach(Some(&buffer), 0, 0);
+        self.surface
+            .damage_buffer(0, 0, width as i32, height as i32);
+        Some((width, height))
+    }
+
+    /// A copy of the frame on screen, with its size and format
+    pub fn on_screen(&mut self) -> Result<(Vec<u8>, (u32, u32), wl_shm::Format)> {
+        if let Some(dmabuf) = &self.dmabuf {
+            return dmabuf.read_current();
+        }
+        let slot = &mut self.slots[self.current.ok_or_else(|| eyre!("nothing is drawn"))?];
+        let (width, height, format) = slot.frame.ok_or_else(|| eyre!("nothing is drawn"))?;
+        let len = 4 * width as usize * height as usize;
+        Ok((slot.pool.mmap()[..len].to_vec(), (width, height), format))
+    }
+}
+
+impl FrameSink for Buffers {
+    /// Write data to a buffer the compositor is not using and attach it to the surface,
+    /// damaging the rows that differ from the frame attached before. The surface still
+    /// needs to be committed.
+    fn present(
+        &mut self,
+        data: &[u8],
+        (width, height): (u32, u32),
+        format: wl_shm::Format,
+    ) -> Result<()> {
+        if let Some(dmabuf) = &mut self.dmabuf {
+            match dmabuf.write(data, (width, height), format) {
+                Ok(buffer) => {
+                    // Reading the frame on screen back from the GPU would cost more than
+                    // what damaging less saves
+                    self.surface.attach(Some(buffer), 0, 0);
+                    self.surface
+                        .damage_buffer(0, 0, width as i32, height as i32);
+                    return Ok(());
+                }
+                Err(err) => {
+                    warn!("{:?}, using shared memory from now on", err);
+                    self.dmabuf = None;
+                    self.current = None;
+                }
+            }
+        }
+        let frame = Some((width, height, format));
+        let stride = 4 * width as usize;
+        let len = stride * height as usize;
+
+        // Compare with the frame on screen before its buffer is possibly reused
+        let damage = match self.current {
+            Some(current) if self.slots[current].frame == frame => {
+                let on_screen = &self.slots[current].pool.mmap()[..len];
+                Some(changed_rows(on_screen, &data[..len], stride))
+            }
+            _ => None,
+        };
+
+        let index = match self.slots.iter().position(|slot| !slot.pool.is_used()) {
+            Some(index) => index,
+            None => {
+                ensure!(
+                    self.slots.len() < MAX_BUFFERS,
+                    "all the buffers are still used by the compositor"
+                );
+                let pool =
+                    MemPool::new(self.shm.clone(), |_| {}).context("creating a memory pool")?;
+                self.slots.push(Slot {
+                    pool,
+                    size: 0,
+                    frame: None,
+                });
+                self.slots.len() - 1
+            }
+        };
+        let slot = &mut self.slots[index];
+        slot.pool.resize(len).context("resizing the wayland pool")?;
+        slot.size = slot.size.max(len);
+        let canvas = &mut slot.pool.mmap()[..len];
+        if slot.frame == frame {
+            // The buffer holds an older frame of the same size, only update what changed
+            for (dst, src) in canvas.chunks_mut(stride).zip(data.chunks(stride)) {
+                if dst != src {
+                    dst.copy_from_slice(src);
+                }
+            }
+        } else {
+            canvas.copy_from_slice(&data[..len]);
+        }
+        slot.frame = frame;
+        let buffer = slot
+            .pool
+            .buffer(0, width as i32, height as i32, stride as i32, format);
+        self.current = Some(index);
+
+        self.surface.attach(Some(&buffer), 0, 0);
+        match damage {
+            Some(Some((first, last))) => {
+                self.surface
+                    .damage_buffer(0, first as i32, width as i32, (last - first + 1) as i32)
+            }
+            // An empty damage might never be answered with a frame callback, which the
+            // animations wait for
+            Some(None) => self.surface.damage_buffer(0, 0, 1, 1),
+            None => self
+                .surface
+                .damage_buffer(0, 0, width as i32, height as i32),
+        }
+        Ok(())
+    }
+}
+
+/// The first and the last rows that differ between two frames, None if they are equal
+fn changed_rows(a: &[u8], b: &[u8], stride: usize) -> Option<(usize, usize)> {
+    let rows = a.len() / stride;
+    let first = a
+        .chunks(stride)
+        .zip(b.chunks(stride))
+        .position(|(a, b)| a != b)?;
+    let last = rows
+        - 1
+        - a.chunks(stride)
+            .rev()
+            .zip(b.chunks(stride).rev())
+            .position(|(a, b)| a != b)?;
+    Some((first, last))
+}
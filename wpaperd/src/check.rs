@@ -0,0 +1,113 @@
+use color_eyre::{eyre::bail, Result};
+use smithay_client_toolkit::output::OutputInfo;
+use wpaper_core::svg;
+
+use crate::collection::Collection;
+use crate::config::Config;
+use crate::identity::Identity;
+use crate::output::Output;
+use crate::state::State;
+
+/// Check the configuration beyond what parsing it does: every section in use must have images
+/// to show, which catches mistyped tags and empty directories, and the tools its images need
+/// must be installed. Prints a line for each section and fails if any of them has a problem.
+pub fn check_config(config: &Config) -> Result<()> {
+    for warning in &config.warnings {
+        println!("warning: {}", warning);
+    }
+    let mut problems = 0;
+    for (name, output) in config.sections() {
+        match describe_sources(output) {
+            Ok(description) => println!("[{}] {}", name, description),
+            Err(err) => {
+                println!("[{}] error: {:#}", name, err);
+                problems += 1;
+            }
+        }
+    }
+    if problems > 0 {
+        bail!(
+            "{} section{} of {:?} cannot be displayed",
+            problems,
+            if problems == 1 { "" } else { "s" },
+            config.path
+        );
+    }
+    println!("{:?} is valid", config.path);
+    Ok(())
+}
+
+/// Print the wallpaper each of outputs would show when wpaperd starts: the one saved in the
+/// state, if it still belongs to the output, or one chosen like the daemon does
+pub fn dry_run(config: &Config, state: &State, outputs: &[OutputInfo]) {
+    for info in outputs {
+        let name = &info.name;
+        let output = config.get_output(&Identity::new(info, outputs));
+        let saved = state
+            .get_output_by_name(name)
+            .map(|output_state| &output_state.image)
+            .filter(|image| image.exists() && output.contains(image));
+        let image = if let Some(image) = saved {
+            format!("{} (restored)", image.display())
+        } else if let Some(url) = output.url() {
+            format!("downloaded from {}", url)
+        } else if output.rotates() {
+            match Collection::for_output(&output) {
+                Ok(collection) => match collection.choose(&[]) {
+                    Some(image) => format!(
+                        "{} (one of {} images)",
+                        image.display(),
+                        collection.images().len()
+                    ),
+                    None => "nothing, there are no images".to_string(),
+                },
+                Err(err) => format!("nothing: {:#}", err),
+            }
+        } else {
+            match &output.path {
+                Some(path) => path.display().to_string(),
+                None => "nothing".to_string(),
+            }
+        };
+        println!("{}: {}", name, image);
+    }
+}
+
+fn describe_sources(output: &Output) -> Result<String> {
+    if let Some(url) = output.url() {
+        return Ok(format!("downloads from {}", url));
+    }
+    if !output.rotates() {
+        return Ok(match &output.path {
+            Some(path) => {
+                if svg::is_svg(path) {
+                    svg::check_rasterizer()?;
+                }
+                format!("shows {}", path.display())
+            }
+            None => "shows nothing".to_string(),
+        });
+    }
+    let collection = Collection::for_output(output)?;
+    if collection.is_empty() {
+        bail!("there are no images to show");
+    }
+    if collection.images().iter().any(|image| svg::is_svg(image)) {
+        svg::check_rasterizer()?;
+    }
+    Ok(format!(
+        "rotates {} image{} from {}",
+        collection.images().len(),
+        if collection.images().len() == 1 {
+            ""
+        } else {
+            "s"
+        },
+        output
+            .sources()
+            .iter()
+            .map(|source| source.path.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    ))
+}
@@ -0,0 +1,343 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use color_eyre::{
+    eyre::{ensure, WrapErr},
+    Result,
+};
+use serde::Deserialize;
+use wpaper_core::selection;
+pub use wpaper_core::settings::Fit;
+
+use crate::file_list::list_images;
+use crate::index;
+use crate::mounts;
+use crate::output::Output;
+use crate::views;
+
+/// File in a wallpaper directory that overrides how its images are used
+pub const SIDECAR_FILE: &str = ".wpaperd.toml";
+
+/// A directory or an image shown by an output, with the tags of the collection it belongs to
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Source {
+    pub path: PathBuf,
+    pub tags: Vec<String>,
+}
+
+/// Content of the sidecar file. Paths are relative to the directory containing it.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Overrides {
+    /// Tags of every image of the directory
+    #[serde(default)]
+    tags: Vec<String>,
+    /// When not empty, only these files and directories are part of the rotation
+    #[serde(default)]
+    include: Vec<PathBuf>,
+    /// Files and directories never part of the rotation
+    #[serde(default)]
+    exclude: Vec<PathBuf>,
+    #[serde(default)]
+    images: HashMap<PathBuf, ImageOverride>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ImageOverride {
+    #[serde(default)]
+    exclude: bool,
+    fit: Option<Fit>,
+    /// How likely the image is to be chosen, compared to the others which have weight 1
+    weight: Option<u32>,
+    /// How long the image is displayed, instead of the duration of the output
+    #[serde(default, with = "humantime_serde")]
+    duration: Option<Duration>,
+    /// Tags of the image, in addition to those of the directory
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+/// How an image is displayed, as set in the sidecar file
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ImageSettings {
+    /// None to use the fit of the output
+    pub fit: Option<Fit>,
+    pub duration: Option<Duration>,
+}
+
+impl Overrides {
+    fn read(dir: &Path) -> Result<Self> {
+        let path = dir.join(SIDECAR_FILE);
+        let overrides: Self = match fs::read_to_string(&path) {
+            Ok(content) => {
+                toml::from_str(&content).with_context(|| format!("parsing {:?}", path))?
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(err) => return Err(err).with_context(|| format!("reading {:?}", path)),
+        };
+        ensure!(
+            overrides
+                .images
+                .values()
+                .all(|image| image.duration != Some(Duration::ZERO)),
+            "duration must be greater than 0, in {:?}",
+            path
+        );
+        Ok(overrides)
+    }
+
+    fn image(&self, relative_path: &Path) -> Option<&ImageOverride> {
+        self.images.get(relative_path)
+    }
+
+    /// Weight of the image in the rotation, 0 if it is excluded
+    fn weight(&self, relative_path: &Path) -> u32 {
+        let included = self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|include| relative_path.starts_with(include));
+        let excluded = self
+            .exclude
+            .iter()
+            .any(|exclude| relative_path.starts_with(exclude));
+        match self.image(relative_path) {
+            _ if !included || excluded => 0,
+            Some(image) if image.exclude => 0,
+            Some(image) => image.weight.unwrap_or(1),
+            None => 1,
+        }
+    }
+
+    /// Whether the image has one of tags, either its own or those of the directory
+    fn has_tag(&self, relative_path: &Path, tags: &[String]) -> bool {
+        let image_tags = self.image(relative_path).map(|image| image.tags.as_slice());
+        self.tags
+            .iter()
+            .chain(image_tags.unwrap_or_default())
+            .any(|tag| tags.contains(tag))
+    }
+}
+
+/// Returned when the directories of an output have no image to choose from, e.g. an empty
+/// directory or include patterns matching nothing, which is not a failure
+#[derive(Debug)]
+pub struct NoImages {
+    /// The directories, as listed in the messages
+    pub sources: String,
+    pub tags: Vec<String>,
+}
+
+impl fmt::Display for NoImages {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} contain no images", self.sources)?;
+        if !self.tags.is_empty() {
+            write!(f, " tagged {}", self.tags.join(" or "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for NoImages {}
+
+/// The images of one or more directories that are part of the rotation, with the overrides of
+/// their sidecar files applied. Duplicates and images that could not be decoded are left out.
+pub struct Collection {
+    /// Each directory with its sidecar file
+    dirs: Vec<(PathBuf, Overrides)>,
+    images: Vec<PathBuf>,
+    /// The position in dirs of the directory of each image
+    image_dirs: Vec<usize>,
+    weights: Vec<u32>,
+}
+
+impl Collection {
+    /// The images shown by output: those of its path, or of its collections, with one of its
+    /// tags if it has any
+    pub fn for_output(output: &Output) -> Result<Self> {
+        let (sources, tags) = (output.sources(), &output.tags);
+        let mut collection = Self {
+            dirs: Vec::new(),
+            images: Vec::new(),
+            image_dirs: Vec::new(),
+            weights: Vec::new(),
+        };
+        // Collections can share images, which are only listed once
+        let mut listed = HashSet::new();
+        for source in &sources {
+            // The images of a mount that is down could not be decoded anyway
+            if !mounts::is_available(&source.path) {
+                continue;
+            }
+            let dir = overrides_dir(&source.path);
+            let position = match collection.dirs.iter().position(|(other, _)| other == dir) {
+                Some(position) => position,
+                None => {
+                    collection
+                        .dirs
+                        .push((dir.to_path_buf(), Overrides::read(dir)?));
+                    collection.dirs.len() - 1
+                }
+            };
+            let overrides = &collection.dirs[position].1;
+            let images = if source.path.is_dir() {
+                index::filter(dir, list_images(dir)?)
+            } else {
+                vec![source.path.clone()]
+            };
+            for image in images {
+                let relative_path = image.strip_prefix(dir).unwrap_or(&image);
+                let tagged = tags.is_empty()
+                    || source.tags.iter().any(|tag| tags.contains(tag))
+                    || overrides.has_tag(relative_path, tags);
+                let weight = overrides.weight(relative_path);
+                if weight > 0 && tagged && (sources.len() == 1 || listed.insert(image.clone())) {
+                    collection.images.push(image);
+                    collection.image_dirs.push(position);
+                    collection.weights.push(weight);
+                }
+            }
+        }
+        Ok(collection)
+    }
+
+    pub fn images(&self) -> &[PathBuf] {
+        &self.images
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.images.is_empty()
+    }
+
+    /// Only choose the images whose aspect ratio is close to aspect, within the relative
+    /// tolerance, as long as there is at least one of them
+    pub fn prefer_aspect(&mut self, aspect: Option<(f32, f32)>) {
+        let (aspect, tolerance) = match aspect {
+            Some(aspect) => aspect,
+            None => return,
+        };
+        let mut dimensions = vec![None; self.images.len()];
+        for (position, (dir, _)) in self.dirs.iter().enumerate() {
+            let (indices, images): (Vec<usize>, Vec<PathBuf>) = self
+                .image_dirs
+                .iter()
+                .zip(&self.images)
+                .enumerate()
+                .filter(|(_, (image_dir, _))| **image_dir == position)
+                .map(|(i, (_, image))| (i, image.clone()))
+                .unzip();
+            for (i, image_dimensions) in indices.into_iter().zip(index::dimensions(dir, &images)) {
+                dimensions[i] = image_dimensions;
+            }
+        }
+        let matching: Vec<bool> = dimensions
+            .into_iter()
+            .map(|dimensions| {
+                dimensions.is_some_and(|(width, height)| {
+                    height > 0 && (width as f32 / height as f32 / aspect - 1.0).abs() <= tolerance
+                })
+            })
+            .collect();
+        if matching.contains(&true) {
+            for (weight, matching) in self.weights.iter_mut().zip(matching) {
+                if !matching {
+                    *weight = 0;
+                }
+            }
+        }
+    }
+
+    /// Only choose the images that no output has shown within period, as long as there is at
+    /// least one of them; otherwise only the ones shown the longest time ago, so that the
+    /// whole collection is eventually shown
+    pub fn prefer_unseen(&mut self, period: Option<Duration>) {
+        let period = match period {
+            Some(period) => period,
+            None => return,
+        };
+        let last_shown = views::last_shown(&self.images);
+        let since = views::now().saturating_sub(period.as_secs());
+        let candidates = || {
+            last_shown
+                .iter()
+                .zip(&self.weights)
+                .filter(|(_, weight)| **weight > 0)
+                .map(|(shown, _)| *shown)
+        };
+        let oldest = if candidates().any(|shown| shown.is_none_or(|shown| shown <= since)) {
+            since
+        } else {
+            match candidates().flatten().min() {
+                Some(oldest) => oldest,
+                None => return,
+            }
+        };
+        for (weight, shown) in self.weights.iter_mut().zip(last_shown) {
+            if shown.is_some_and(|shown| shown > oldest) {
+                *weight = 0;
+            }
+        }
+    }
+
+    /// A random image, the ones with a higher weight being more likely. The recent images,
+    /// from the most recent, are avoided.
+    pub fn choose(&self, recent: &[PathBuf]) -> Option<&Path> {
+        selection::choose(&self.images, &self.weights, recent)
+    }
+
+    pub fn image_settings(&self, image: &Path) -> ImageSettings {
+        // The sidecar file of the innermost directory applies
+        let dir = self
+            .dirs
+            .iter()
+            .filter(|(dir, _)| image.starts_with(dir))
+            .max_by_key(|(dir, _)| dir.components().count());
+        match dir {
+            Some((dir, overrides)) => image_settings(dir, overrides, image),
+            None => ImageSettings::default(),
+        }
+    }
+}
+
+/// The settings of image, as set in the sidecar file of the directory of output it belongs
+/// to; other images use the defaults
+pub fn read_image_settings(output: &Output, image: &Path) -> Result<ImageSettings> {
+    let dir = output
+        .sources()
+        .iter()
+        .map(|source| overrides_dir(&source.path).to_path_buf())
+        .filter(|dir| image.starts_with(dir))
+        .max_by_key(|dir| dir.components().count());
+    match dir {
+        Some(dir) => Ok(image_settings(&dir, &Overrides::read(&dir)?, image)),
+        None => Ok(ImageSettings::default()),
+    }
+}
+
+/// The directory whose sidecar file applies to the images of path
+fn overrides_dir(path: &Path) -> &Path {
+    if path.is_dir() {
+        path
+    } else {
+        path.parent().unwrap_or(path)
+    }
+}
+
+fn image_settings(dir: &Path, overrides: &Overrides, image: &Path) -> ImageSettings {
+    match image
+        .strip_prefix(dir)
+        .ok()
+        .and_then(|relative_path| overrides.image(relative_path))
+    {
+        Some(image) => ImageSettings {
+            fit: image.fit,
+            duration: image.duration,
+        },
+        None => ImageSettings::default(),
+    }
+}
@@ -1,61 +1,644 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
 };
 
-use color_eyre::{eyre::ensure, Result};
+use chrono::format::{Item, StrftimeItems};
+use color_eyre::{
+    eyre::{ensure, eyre, WrapErr},
+    Result,
+};
 use serde::Deserialize;
+use toml::{value::Table, Value};
 
+use crate::collection::Source;
+use crate::crash_report;
+use crate::identity::Identity;
+use crate::migration::{self, CONFIG_VERSION};
+use crate::mounts;
+use crate::night::NightMode;
 use crate::output::Output;
+use crate::power::OnBattery;
+
+/// The configuration shipped by the system, which the one of the user overrides
+const SYSTEM_CONFIG: &str = "/etc/wpaperd/wpaperd.conf";
+/// Beyond this, the image loaded for the parallax gets much larger than the output
+const MAX_PARALLAX: f32 = 0.1;
 
+/// The configuration in use: the system file, overridden by the file of the user, itself
+/// overridden by the settings changed at runtime with wpaperctl. Tables are merged key by
+/// key, any other value replaces the one below.
 #[derive(Deserialize)]
 pub struct Config {
     #[serde(flatten)]
     data: HashMap<String, Arc<Output>>,
+    /// Named sets of output sections, replacing the top-level ones while active
+    #[serde(default)]
+    profiles: HashMap<String, HashMap<String, Arc<Output>>>,
+    /// Settings that apply to every output while the system runs on battery
+    #[serde(rename = "on-battery")]
+    on_battery: Option<OnBattery>,
+    /// Cleared to disable the animations of every output, e.g. for the users sensitive to
+    /// motion
+    #[serde(default = "default_animations")]
+    animations: bool,
+    /// Tint and dim every output between sunset and sunrise
+    #[serde(rename = "night-mode")]
+    night_mode: Option<NightMode>,
+    /// Named sets of images, shown by the outputs listing them in collections
+    #[serde(default, rename = "collection")]
+    collections: HashMap<String, CollectionSection>,
     #[serde(skip)]
     default_config: Arc<Output>,
     #[serde(skip)]
+    profile: Option<String>,
+    /// Replaces animations until wpaperd exits, set with wpaperctl animations
+    #[serde(skip)]
+    pub animations_override: Option<bool>,
+    /// The file of the user
+    #[serde(skip)]
     pub path: PathBuf,
+    /// The output sections changed at runtime, they are lost when wpaperd exits
+    #[serde(skip)]
+    runtime: Table,
+    /// What has been migrated from older versions of the configuration and the unknown keys
+    #[serde(skip)]
+    pub warnings: Vec<String>,
     #[serde(skip)]
     pub reloaded: bool,
 }
 
+/// A named set of images: directories and single images, with the tags of all of them
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CollectionSection {
+    paths: Vec<PathBuf>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
 impl Config {
+    /// Lock the configuration shared with the threads watching the files. It is poisoned only
+    /// when one of them panicked while changing it, and cannot be trusted anymore then.
+    pub fn lock(config: &Mutex<Self>) -> Result<MutexGuard<'_, Self>> {
+        config
+            .lock()
+            .map_err(|_| eyre!("the configuration has been left in an invalid state"))
+    }
+
+    /// Read the configuration of the system and the one of the user at path; at least one of
+    /// them must exist
     pub fn new_from_path(path: &Path) -> Result<Self> {
         ensure!(
-            path.exists(),
+            path.exists() || Path::new(SYSTEM_CONFIG).exists(),
             "Configuration file {:?} does not exists",
             path
         );
-        let mut config_manager: Self = toml::from_str(&fs::read_to_string(path)?)?;
+        Self::load(path, Table::new())
+    }
+
+    /// Read the configuration files again, keeping the settings changed at runtime
+    pub fn reload(&self) -> Result<Self> {
+        Self::load(&self.path, self.runtime.clone())
+    }
+
+    fn load(path: &Path, runtime: Table) -> Result<Self> {
+        let mut warnings = Vec::new();
+        let system = system_layer(path, &mut warnings)?;
+        let user = read_layer(path, &mut warnings)?;
+        let mut config = Self::from_layers(system, user, runtime, path)?;
+        config.warnings = warnings;
+        Ok(config)
+    }
+
+    /// The files the configuration is read from, the one of the user last
+    pub fn files(&self) -> Vec<PathBuf> {
+        [Path::new(SYSTEM_CONFIG), &self.path]
+            .into_iter()
+            .filter(|file| file.exists())
+            .map(Path::to_path_buf)
+            .collect()
+    }
+
+    /// Merge the layers of the configuration, from the lowest, then validate the result
+    fn from_layers(system: Table, user: Table, runtime: Table, path: &Path) -> Result<Self> {
+        let mut document = system;
+        merge(&mut document, user);
+        crash_report::set_config(&toml::to_string(&document).unwrap_or_default());
+        merge(&mut document, runtime.clone());
+        let mut config = Self::parse(document, path)?;
+        config.runtime = runtime;
+        Ok(config)
+    }
+
+    /// Validate document, the merged configuration whose user file is at path
+    fn parse(document: Table, path: &Path) -> Result<Self> {
+        let mut config_manager: Self = Value::Table(document)
+            .try_into()
+            .with_context(|| format!("parsing configuration file {:?}", path))?;
+        for (name, collection) in &config_manager.collections {
+            ensure!(
+                !collection.paths.is_empty(),
+                "collection {} has no paths",
+                name
+            );
+            for path in &collection.paths {
+                ensure!(
+                    path.exists(),
+                    "File or directory {:?} of collection {} does not exist",
+                    path,
+                    name
+                );
+            }
+        }
+        let collections = &config_manager.collections;
+        resolve_collections(&mut config_manager.data, collections, "")?;
+        for (profile, sections) in &mut config_manager.profiles {
+            resolve_collections(sections, collections, &format!(" of profile {}", profile))?;
+        }
         config_manager.default_config = config_manager
             .data
             .get("default")
             .unwrap_or(&Arc::new(Output::default()))
             .clone();
-        for (name, config) in &config_manager.data {
-            let path = config.path.as_ref().unwrap();
-            ensure!(
-                path.exists(),
-                "File or directory {:?} for input {} does not exist",
-                path,
-                name
-            );
+
+        let default_duration = config_manager.default_config.duration;
+        validate_sections(&mut config_manager.data, default_duration, "")?;
+        for (profile, sections) in &mut config_manager.profiles {
+            // The sections of a profile fall back to its own default duration first
+            let default_duration = sections
+                .get("default")
+                .and_then(|default| default.duration)
+                .or(default_duration);
+            validate_sections(
+                sections,
+                default_duration,
+                &format!(" of profile {}", profile),
+            )?;
+        }
+
+        if let Some(on_battery) = &config_manager.on_battery {
             ensure!(
-                config.duration.is_none() || path.is_dir(),
-                "Duration can only be set when path points to a directory, for input {}",
-                name
+                on_battery.duration != Some(Duration::ZERO),
+                "Duration must be greater than 0, for section on-battery"
             );
         }
+        if let Some(night_mode) = &config_manager.night_mode {
+            night_mode.validate()?;
+        }
 
         config_manager.path = path.to_path_buf();
         config_manager.reloaded = false;
         Ok(config_manager)
     }
 
+    /// Merge settings, the content of a TOML inline table, into the section of the output
+    /// and save the configuration file of the user. An output without a section gets a copy
+    /// of the default one. Nothing is written if the resulting configuration is not valid.
+    pub fn set_output_settings(&self, name: &str, settings: &str) -> Result<()> {
+        let settings = parse_settings(settings)?;
+        let system = system_layer(&self.path, &mut Vec::new())?;
+        let mut document = read_layer(&self.path, &mut Vec::new())?;
+        let mut section = match document.get(name) {
+            Some(Value::Table(section)) => section.clone(),
+            // The settings of a section of the system are merged with it
+            _ if system.contains_key(name) => Table::new(),
+            _ => {
+                let mut merged = system.clone();
+                merge(&mut merged, document.clone());
+                new_section(&merged)
+            }
+        };
+        section.extend(settings);
+        document.insert(name.to_string(), Value::Table(section));
+
+        Self::from_layers(system, document.clone(), self.runtime.clone(), &self.path)?;
+        // The file is written in the current version of the configuration
+        let content = format!(
+            "version = {}\n\n{}",
+            CONFIG_VERSION,
+            toml::to_string(&document)?
+        );
+        fs::write(&self.path, content)
+            .with_context(|| format!("writing configuration file {:?}", self.path))
+    }
+
+    /// Like set_output_settings, but the settings are only kept in memory, over those of the
+    /// files, until wpaperd exits
+    pub fn set_runtime_settings(&mut self, name: &str, settings: &str) -> Result<()> {
+        let settings = parse_settings(settings)?;
+        let mut runtime = self.runtime.clone();
+        let mut section = match runtime.get(name) {
+            Some(Value::Table(section)) => section.clone(),
+            _ => {
+                let mut merged = system_layer(&self.path, &mut Vec::new())?;
+                merge(&mut merged, read_layer(&self.path, &mut Vec::new())?);
+                // The settings of an existing section are merged with it
+                if merged.contains_key(name) {
+                    Table::new()
+                } else {
+                    new_section(&merged)
+                }
+            }
+        };
+        section.extend(settings);
+        runtime.insert(name.to_string(), Value::Table(section));
+        let mut config = Self::load(&self.path, runtime)?;
+        // Keep the active profile, it has been validated already
+        config.profile = self.profile.take();
+        config.animations_override = self.animations_override;
+        config.reloaded = true;
+        *self = config;
+        Ok(())
+    }
+
+    pub fn on_battery(&self) -> Option<&OnBattery> {
+        self.on_battery.as_ref()
+    }
+
+    /// Whether the outputs can animate their images
+    pub fn animations(&self) -> bool {
+        self.animations_override.unwrap_or(self.animations)
+    }
+
+    pub fn night_mode(&self) -> Option<&NightMode> {
+        self.night_mode.as_ref()
+    }
+
+    /// The active profile, if any
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    /// The output sections in use, sorted by name: the top-level ones, replaced by those of
+    /// the active profile
+    pub fn sections(&self) -> Vec<(&str, &Output)> {
+        let mut sections: Vec<(&str, &Output)> = self
+            .active_profile()
+            .unwrap_or(&self.data)
+            .iter()
+            .map(|(name, output)| (name.as_str(), &**output))
+            .collect();
+        sections.sort_unstable_by_key(|(name, _)| *name);
+        sections
+    }
+
+    /// The names of the profiles defined in the configuration file, sorted
+    pub fn profiles(&self) -> Vec<&str> {
+        let mut profiles: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+        profiles.sort_unstable();
+        profiles
+    }
+
+    /// Activate the profile called name, or go back to the top-level sections with None.
+    /// The outputs pick up the new settings on the next iteration of the main loop.
+    pub fn set_profile(&mut self, name: Option<&str>) -> Result<()> {
+        if let Some(name) = name {
+            ensure!(
+                self.profiles.contains_key(name),
+                "profile {} is not defined in the configuration file",
+                name
+            );
+        }
+        self.profile = name.map(str::to_string);
+        self.reloaded = true;
+        Ok(())
+    }
+
+    fn active_profile(&self) -> Option<&HashMap<String, Arc<Output>>> {
+        self.profile
+            .as_ref()
+            .and_then(|profile| self.profiles.get(profile))
+    }
+
+    /// The directories of images used by the outputs
+    pub fn dirs(&self) -> HashSet<PathBuf> {
+        self.data
+            .values()
+            .chain(self.active_profile().into_iter().flat_map(|p| p.values()))
+            .chain([&self.default_config])
+            .flat_map(|output| output.sources())
+            .map(|source| source.path)
+            // Watching a directory of a mount that is down would block; until it is watched,
+            // its modification time is checked on every rotation
+            .filter(|path| mounts::is_available(path) && path.is_dir())
+            .collect()
+    }
+
     pub fn get_output_by_name(&self, name: &str) -> Arc<Output> {
-        self.data.get(name).unwrap_or(&self.default_config).clone()
+        self.find_section(&[name.to_string()])
+    }
+
+    /// The settings of the display with identity: the first section named after one of its
+    /// section names, see Identity::section_names, or the default one
+    pub fn get_output(&self, identity: &Identity) -> Arc<Output> {
+        self.find_section(&identity.section_names())
+    }
+
+    fn find_section(&self, names: &[String]) -> Arc<Output> {
+        let find = |sections: &'_ HashMap<String, Arc<Output>>| {
+            names.iter().find_map(|name| sections.get(name)).cloned()
+        };
+        self.active_profile()
+            .and_then(|profile| find(profile).or_else(|| profile.get("default").cloned()))
+            .or_else(|| find(&self.data))
+            .unwrap_or_else(|| self.default_config.clone())
+    }
+}
+
+fn default_animations() -> bool {
+    true
+}
+
+/// The settings given to wpaperctl, the content of a TOML inline table
+fn parse_settings(settings: &str) -> Result<Table> {
+    match toml::from_str::<Table>(&format!("settings = {{ {} }}", settings))
+        .context("parsing the settings")?
+        .remove("settings")
+    {
+        Some(Value::Table(settings)) => Ok(settings),
+        _ => Err(eyre!("invalid settings {:?}", settings)),
+    }
+}
+
+/// A section for a new output: a copy of the default one of document, if any
+fn new_section(document: &Table) -> Table {
+    match document.get("default") {
+        Some(Value::Table(section)) => section.clone(),
+        _ => Table::new(),
+    }
+}
+
+/// The system configuration, empty when there is none or when path is the system file itself
+fn system_layer(path: &Path, warnings: &mut Vec<String>) -> Result<Table> {
+    let system = Path::new(SYSTEM_CONFIG);
+    if path == system || !system.exists() {
+        return Ok(Table::new());
+    }
+    read_layer(system, warnings)
+}
+
+/// Read the configuration file at path and bring it to the current version, adding what has
+/// been changed to warnings. A missing file is empty.
+fn read_layer(path: &Path, warnings: &mut Vec<String>) -> Result<Table> {
+    if !path.exists() {
+        return Ok(Table::new());
+    }
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("reading configuration file {:?}", path))?;
+    let mut document = toml::from_str(&content)
+        .with_context(|| format!("parsing configuration file {:?}", path))?;
+    warnings.extend(migration::migrate(&mut document, path)?);
+    Ok(document)
+}
+
+/// Merge upper into lower: the tables in both are merged key by key, the other values of
+/// upper replace those of lower
+fn merge(lower: &mut Table, upper: Table) {
+    for (key, value) in upper {
+        match (lower.get_mut(&key), value) {
+            (Some(Value::Table(lower)), Value::Table(upper)) => merge(lower, upper),
+            (_, value) => {
+                lower.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Set the paths of the collections shown by each output section: those it lists or, when
+/// it only has tags, every collection. context is appended to the name of the section in the
+/// errors.
+fn resolve_collections(
+    sections: &mut HashMap<String, Arc<Output>>,
+    collections: &HashMap<String, CollectionSection>,
+    context: &str,
+) -> Result<()> {
+    for (name, config) in sections.iter_mut() {
+        let names: Vec<&String> = if !config.collections.is_empty() {
+            config.collections.iter().collect()
+        } else if config.path.is_none() && !config.tags.is_empty() {
+            let mut names: Vec<&String> = collections.keys().collect();
+            names.sort_unstable();
+            names
+        } else {
+            continue;
+        };
+        let mut sources = Vec::new();
+        for collection_name in names {
+            let collection = collections.get(collection_name).ok_or_else(|| {
+                eyre!(
+                    "collection {} is not defined, for input {}{}",
+                    collection_name,
+                    name,
+                    context
+                )
+            })?;
+            sources.extend(collection.paths.iter().map(|path| Source {
+                path: path.clone(),
+                tags: collection.tags.clone(),
+            }));
+        }
+        // Nothing else holds the sections while the configuration is being read
+        if let Some(config) = Arc::get_mut(config) {
+            config.collection_sources = sources;
+        }
+    }
+    Ok(())
+}
+
+/// Check every output section; the ones showing a directory without their own duration
+/// get default_duration. context is appended to the name of the section in the errors.
+fn validate_sections(
+    sections: &mut HashMap<String, Arc<Output>>,
+    default_duration: Option<Duration>,
+    context: &str,
+) -> Result<()> {
+    for (name, config) in sections.iter() {
+        ensure!(
+            config.path.is_none() || config.collections.is_empty(),
+            "path and collections cannot be set together, for input {}{}",
+            name,
+            context
+        );
+        if let Some(path) = config.local_path() {
+            if let Some(url) = config.url() {
+                // The images are downloaded when the output is drawn
+                fs::create_dir_all(&path)
+                    .with_context(|| format!("creating the cache directory of {}", url))?;
+            }
+            ensure!(
+                mounts::exists(&path),
+                "File or directory {:?} for input {}{} does not exist",
+                path,
+                name,
+                context
+            );
+        } else {
+            ensure!(
+                !config.collection_sources.is_empty(),
+                "path is not set, for input {}{}",
+                name,
+                context
+            );
+        }
+        if let Some(icc_profile) = &config.icc_profile {
+            ensure!(
+                icc_profile.is_file(),
+                "ICC profile {:?} for input {}{} does not exist",
+                icc_profile,
+                name,
+                context
+            );
+        }
+        ensure!(
+            config.palette_template.is_some() == config.palette_file.is_some(),
+            "palette_template and palette_file must be set together, for input {}{}",
+            name,
+            context
+        );
+        ensure!(
+            config.duration != Some(Duration::ZERO),
+            "Duration must be greater than 0, for input {}{}",
+            name,
+            context
+        );
+        ensure!(
+            config.ken_burns_fps != Some(0),
+            "ken_burns_fps must be greater than 0, for input {}{}",
+            name,
+            context
+        );
+        ensure!(
+            config
+                .parallax
+                .is_none_or(|parallax| parallax > 0.0 && parallax <= MAX_PARALLAX),
+            "parallax must be greater than 0 and at most {}, for input {}{}",
+            MAX_PARALLAX,
+            name,
+            context
+        );
+        ensure!(
+            config.parallax.is_none() || (!config.ken_burns && config.day_night.is_none()),
+            "parallax cannot be set with ken_burns nor day_night, for input {}{}",
+            name,
+            context
+        );
+        ensure!(
+            config.ttl.is_none() || config.url().is_some(),
+            "ttl can only be set when path is a URL, for input {}{}",
+            name,
+            context
+        );
+        ensure!(
+            config.ttl != Some(Duration::ZERO),
+            "ttl must be greater than 0, for input {}{}",
+            name,
+            context
+        );
+        ensure!(
+            config.duration.is_none() || config.rotates(),
+            "Duration can only be set when path points to a directory or with collections, for input {}{}",
+            name,
+            context
+        );
+        ensure!(
+            config.tile_scale() > 0.0,
+            "tile_scale must be greater than 0, for input {}{}",
+            name,
+            context
+        );
+        ensure!(
+            config
+                .aspect_tolerance
+                .is_none_or(|tolerance| tolerance >= 0.0),
+            "aspect_tolerance cannot be negative, for input {}{}",
+            name,
+            context
+        );
+        if let Some(fallback_path) = &config.fallback_path {
+            ensure!(
+                mounts::exists(fallback_path),
+                "fallback_path {:?} for input {}{} does not exist",
+                fallback_path,
+                name,
+                context
+            );
+        }
+        for (workspace, path) in &config.workspaces {
+            ensure!(
+                mounts::exists(path),
+                "File or directory {:?} for workspace {} of input {}{} does not exist",
+                path,
+                workspace,
+                name,
+                context
+            );
+        }
+        if let Some(overlay) = &config.overlay {
+            ensure!(
+                overlay.size() > 0.0,
+                "overlay size must be greater than 0, for input {}{}",
+                name,
+                context
+            );
+            ensure!(
+                (0.0..=1.0).contains(&overlay.opacity()),
+                "overlay opacity must be between 0 and 1, for input {}{}",
+                name,
+                context
+            );
+            ensure!(
+                !StrftimeItems::new(overlay.time_format()).any(|item| item == Item::Error),
+                "invalid overlay time_format {:?}, for input {}{}",
+                overlay.time_format(),
+                name,
+                context
+            );
+        }
+        if let Some(day_night) = &config.day_night {
+            ensure!(
+                config.path.is_some() && config.url().is_none() && !config.rotates(),
+                "day_night needs path to be an image, for input {}{}",
+                name,
+                context
+            );
+            ensure!(
+                !config.ken_burns,
+                "day_night and ken_burns cannot be set together, for input {}{}",
+                name,
+                context
+            );
+            day_night
+                .validate()
+                .map_err(|err| eyre!("{}, for input {}{}", err, name, context))?;
+        }
+        for layer in &config.layers {
+            ensure!(
+                layer.path.is_file(),
+                "layer {:?} for input {}{} does not exist",
+                layer.path,
+                name,
+                context
+            );
+            ensure!(
+                (0.0..=1.0).contains(&layer.opacity()),
+                "layer opacity must be between 0 and 1, for input {}{}",
+                name,
+                context
+            );
+        }
+    }
+
+    // Outputs showing a directory without their own duration use the default one
+    for config in sections.values_mut() {
+        if config.duration.is_none() && config.rotates() {
+            if let Some(config) = Arc::get_mut(config) {
+                config.duration = default_duration;
+            }
+        }
     }
+    Ok(())
 }
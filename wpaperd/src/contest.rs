@@ -0,0 +1,28 @@
+use std::{collections::VecDeque, path::PathBuf};
+
+use rand::seq::SliceRandom;
+use wpaper_core::selection;
+
+/// A shortlist of images that are displayed one after the other, so that the user can vote them
+pub struct Contest {
+    candidates: VecDeque<PathBuf>,
+}
+
+impl Contest {
+    /// Choose at most count random images from images
+    pub fn new(images: &[PathBuf], count: usize) -> Self {
+        Self {
+            candidates: selection::with_rng(|rng| {
+                images.choose_multiple(rng, count).cloned().collect()
+            }),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn next_candidate(&mut self) -> Option<PathBuf> {
+        self.candidates.pop_front()
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    backtrace::Backtrace,
+    collections::hash_map::DefaultHasher,
+    env, fs,
+    hash::{Hash, Hasher},
+    panic,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use lazy_static::lazy_static;
+use smithay_client_toolkit::output::OutputInfo;
+use xdg::BaseDirectories;
+
+/// What is known about the environment when a crash happens
+#[derive(Default)]
+struct Context {
+    /// Description of each output, by name
+    outputs: Vec<(String, String)>,
+    /// Hash of the configuration file, so that reports can be compared without disclosing it
+    config_digest: Option<u64>,
+}
+
+lazy_static! {
+    static ref CONTEXT: Mutex<Context> = Mutex::new(Context::default());
+}
+
+pub fn output_added(info: &OutputInfo) {
+    let mode = info
+        .modes
+        .iter()
+        .find(|mode| mode.is_current)
+        .map(|mode| {
+            format!(
+                "{}x{}@{}",
+                mode.dimensions.0, mode.dimensions.1, mode.refresh_rate
+            )
+        })
+        .unwrap_or_else(|| "unknown mode".to_string());
+    let description = format!(
+        "{} scale={} transform={:?}",
+        mode, info.scale_factor, info.transform
+    );
+    if let Ok(mut context) = CONTEXT.lock() {
+        context.outputs.push((info.name.clone(), description));
+    }
+}
+
+pub fn output_removed(name: &str) {
+    if let Ok(mut context) = CONTEXT.lock() {
+        context.outputs.retain(|(output, _)| output != name);
+    }
+}
+
+pub fn set_config(content: &str) {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    if let Ok(mut context) = CONTEXT.lock() {
+        context.config_digest = Some(hasher.finish());
+    }
+}
+
+/// Write a report for every panic, after the default message has been printed
+pub fn install_panic_hook() {
+    let previous_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        previous_hook(info);
+        write(&format!("{}\n\n{}", info, Backtrace::force_capture()));
+    }));
+}
+
+/// Save a report about error in the state directory and print where it is
+pub fn write(error: &str) {
+    match write_report(error) {
+        Some(path) => eprintln!(
+            "A crash report has been written to {}, please attach it to the bug report",
+            path.display()
+        ),
+        None => eprintln!("The crash report could not be written"),
+    }
+}
+
+fn write_report(error: &str) -> Option<PathBuf> {
+    // The context is not needed to write a report, don't give up if the lock is poisoned
+    let context = CONTEXT.lock().unwrap_or_else(|err| err.into_inner());
+    let mut report = format!(
+        "wpaperd {}\ncompositor: {}\nconfig digest: {}\noutputs:\n",
+        env!("CARGO_PKG_VERSION"),
+        env::var("XDG_CURRENT_DESKTOP").unwrap_or_else(|_| "unknown".to_string()),
+        context
+            .config_digest
+            .map_or_else(|| "none".to_string(), |digest| format!("{:016x}", digest))
+    );
+    for (name, description) in &context.outputs {
+        report.push_str(&format!("  {} {}\n", name, description));
+    }
+    drop(context);
+    report.push_str(&format!("\n{}\n", redact(error)));
+
+    let path = BaseDirectories::with_prefix("wpaper")
+        .ok()?
+        .place_state_file(format!(
+            "crash-{}.txt",
+            chrono::Local::now().format("%Y%m%d-%H%M%S")
+        ))
+        .ok()?;
+    fs::write(&path, report).ok()?;
+    Some(path)
+}
+
+/// Hide the home directory, which usually contains the user name, from the paths in text
+fn redact(text: &str) -> String {
+    match env::var("HOME") {
+        Ok(home) if !home.is_empty() && home != "/" => text.replace(&home, "~"),
+        _ => text.to_string(),
+    }
+}
@@ -0,0 +1,144 @@
+use std::{path::PathBuf, time::Duration};
+
+use chrono::{NaiveTime, Timelike};
+use color_eyre::{
+    eyre::{ensure, eyre},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use smithay_client_toolkit::reexports::client::protocol::wl_shm;
+
+/// The blend goes from one image to the other in this many steps, the output being drawn
+/// again at each one
+pub const BLEND_STEPS: u32 = 100;
+const DEFAULT_NIGHT_START: &str = "19:00";
+const DEFAULT_DAY_START: &str = "07:00";
+const DEFAULT_BLEND: Duration = Duration::from_secs(60 * 60);
+const DAY_SECS: f64 = 24.0 * 60.0 * 60.0;
+
+/// The day_night table of an output: path is shown during the day and night_path at night,
+/// one slowly replacing the other at night_start and at day_start
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DayNight {
+    pub night_path: PathBuf,
+    /// When the night image starts replacing the day one, e.g. "19:00", local time
+    pub night_start: Option<String>,
+    /// When the day image starts replacing the night one, e.g. "07:00", local time
+    pub day_start: Option<String>,
+    /// How long an image takes to replace the other
+    #[serde(default, with = "humantime_serde")]
+    pub blend: Option<Duration>,
+}
+
+impl DayNight {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.night_path.is_file(),
+            "night_path {:?} does not exist",
+            self.night_path
+        );
+        let (night_start, day_start) = (
+            parse_time(self.night_start())?,
+            parse_time(self.day_start())?,
+        );
+        ensure!(
+            night_start != day_start,
+            "night_start and day_start must differ"
+        );
+        // Each blend must be over before the next one starts
+        let day = (seconds(night_start) - seconds(day_start)).rem_euclid(DAY_SECS);
+        ensure!(
+            !self.blend().is_zero() && self.blend().as_secs_f64() <= day.min(DAY_SECS - day),
+            "blend must be greater than 0 and not longer than the day nor the night"
+        );
+        Ok(())
+    }
+
+    fn night_start(&self) -> &str {
+        self.night_start.as_deref().unwrap_or(DEFAULT_NIGHT_START)
+    }
+
+    fn day_start(&self) -> &str {
+        self.day_start.as_deref().unwrap_or(DEFAULT_DAY_START)
+    }
+
+    fn blend(&self) -> Duration {
+        self.blend.unwrap_or(DEFAULT_BLEND)
+    }
+
+    /// How much of the night image is shown at now, from 0 during the day to BLEND_STEPS
+    /// during the night
+    pub fn night_step(&self, now: NaiveTime) -> Result<u32> {
+        let (since_night, since_day) = self.since_starts(now)?;
+        let progress = |since: f64| {
+            ((since / self.blend().as_secs_f64() * f64::from(BLEND_STEPS)) as u32).min(BLEND_STEPS)
+        };
+        Ok(if since_night < since_day {
+            progress(since_night)
+        } else {
+            BLEND_STEPS - progress(since_day)
+        })
+    }
+
+    /// How long after now night_step() changes
+    pub fn next_change(&self, now: NaiveTime) -> Result<Duration> {
+        let (since_night, since_day) = self.since_starts(now)?;
+        let blend = self.blend().as_secs_f64();
+        let since = since_night.min(since_day);
+        let secs = if since < blend {
+            let step = blend / f64::from(BLEND_STEPS);
+            step - since % step
+        } else {
+            // Until the next blend starts
+            DAY_SECS - since_night.max(since_day)
+        };
+        Ok(Duration::from_secs_f64(secs))
+    }
+
+    /// Seconds since night_start and since day_start, the last time each was passed
+    fn since_starts(&self, now: NaiveTime) -> Result<(f64, f64)> {
+        let since = |start: &str| -> Result<f64> {
+            Ok((seconds(now) - seconds(parse_time(start)?)).rem_euclid(DAY_SECS))
+        };
+        Ok((since(self.night_start())?, since(self.day_start())?))
+    }
+}
+
+/// Mix the pixels of the day and night images, in the buffer format, with step out of
+/// BLEND_STEPS of the night one
+pub fn blend(day: &[u8], night: &[u8], step: u32, format: wl_shm::Format) -> Vec<u8> {
+    let mix = |day: u32, night: u32| {
+        (day * (BLEND_STEPS - step) + night * step + BLEND_STEPS / 2) / BLEND_STEPS
+    };
+    match format {
+        wl_shm::Format::Xbgr2101010 => day
+            .chunks_exact(4)
+            .zip(night.chunks_exact(4))
+            .flat_map(|(day, night)| {
+                let day = u32::from_le_bytes([day[0], day[1], day[2], day[3]]);
+                let night = u32::from_le_bytes([night[0], night[1], night[2], night[3]]);
+                let mixed = (0..3).fold(0, |acc, channel| {
+                    let shift = channel * 10;
+                    acc | (mix((day >> shift) & 0x3ff, (night >> shift) & 0x3ff) << shift)
+                });
+                mixed.to_le_bytes()
+            })
+            .collect(),
+        // Both are premultiplied, so is their mix
+        _ => day
+            .iter()
+            .zip(night)
+            .map(|(&day, &night)| mix(u32::from(day), u32::from(night)) as u8)
+            .collect(),
+    }
+}
+
+fn parse_time(time: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .map_err(|_| eyre!("invalid time {:?}, expected HH:MM", time))
+}
+
+fn seconds(time: NaiveTime) -> f64 {
+    f64::from(time.num_seconds_from_midnight()) + f64::from(time.nanosecond()) / 1e9
+}
@@ -0,0 +1,441 @@
+use std::{
+    cell::{Cell, RefCell},
+    ffi::c_void,
+    fs::{self, File, OpenOptions},
+    os::unix::io::{AsRawFd, RawFd},
+    ptr,
+    rc::Rc,
+};
+
+use color_eyre::{
+    eyre::{ensure, eyre, WrapErr},
+    Result,
+};
+use nix::{libc, unistd::close};
+use smithay_client_toolkit::{
+    environment::GlobalHandler,
+    reexports::{
+        client::{
+            protocol::{wl_buffer, wl_registry, wl_shm},
+            Attached, DispatchData, Main,
+        },
+        protocols::unstable::linux_dmabuf::v1::client::{
+            zwp_linux_buffer_params_v1,
+            zwp_linux_dmabuf_v1::{self, ZwpLinuxDmabufV1},
+        },
+    },
+};
+
+/// Same as for the buffers in shared memory
+const MAX_BUFFERS: usize = 3;
+const GBM_BO_USE_RENDERING: u32 = 1 << 2;
+const GBM_BO_USE_LINEAR: u32 = 1 << 4;
+const GBM_BO_TRANSFER_READ: u32 = 1;
+const GBM_BO_TRANSFER_WRITE: u32 = 1 << 1;
+/// The pixels are stored row after row, which is how they are written
+const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+/// The layout is agreed on by other means, the linear one for the buffers mapped by GBM
+const DRM_FORMAT_MOD_INVALID: u64 = 0x00ff_ffff_ffff_ffff;
+
+type Device = *mut c_void;
+type Bo = *mut c_void;
+
+/// The functions of libgbm, which is loaded at runtime so that wpaperd only needs it when
+/// the GPU buffers are asked for
+struct Functions {
+    create_device: unsafe extern "C" fn(RawFd) -> Device,
+    device_destroy: unsafe extern "C" fn(Device),
+    bo_create: unsafe extern "C" fn(Device, u32, u32, u32, u32) -> Bo,
+    bo_destroy: unsafe extern "C" fn(Bo),
+    bo_map: unsafe extern "C" fn(
+        Bo,
+        u32,
+        u32,
+        u32,
+        u32,
+        u32,
+        *mut u32,
+        *mut *mut c_void,
+    ) -> *mut c_void,
+    bo_unmap: unsafe extern "C" fn(Bo, *mut c_void),
+    bo_get_fd: unsafe extern "C" fn(Bo) -> RawFd,
+    bo_get_stride: unsafe extern "C" fn(Bo) -> u32,
+    bo_get_offset: unsafe extern "C" fn(Bo, i32) -> u32,
+}
+
+/// Binds the linux-dmabuf global, recording the formats the compositor can import in a
+/// linear layout. Its events must be handled, which SimpleGlobal doesn't do.
+#[derive(Default)]
+pub struct DmabufHandler {
+    global: Option<Attached<ZwpLinuxDmabufV1>>,
+    formats: Rc<RefCell<Vec<u32>>>,
+}
+
+impl DmabufHandler {
+    pub fn formats(&self) -> Vec<u32> {
+        self.formats.borrow().clone()
+    }
+}
+
+impl GlobalHandler<ZwpLinuxDmabufV1> for DmabufHandler {
+    fn created(
+        &mut self,
+        registry: Attached<wl_registry::WlRegistry>,
+        id: u32,
+        version: u32,
+        _: DispatchData,
+    ) {
+        // Version 4 only lists the formats through feedback objects
+        let dmabuf = registry.bind::<ZwpLinuxDmabufV1>(version.min(3), id);
+        let formats = self.formats.clone();
+        dmabuf.quick_assign(move |_, event, _| match event {
+            zwp_linux_dmabuf_v1::Event::Format { format } => formats.borrow_mut().push(format),
+            zwp_linux_dmabuf_v1::Event::Modifier {
+                format,
+                modifier_hi,
+                modifier_lo,
+            } => {
+                let modifier = (u64::from(modifier_hi) << 32) | u64::from(modifier_lo);
+                if modifier == DRM_FORMAT_MOD_LINEAR || modifier == DRM_FORMAT_MOD_INVALID {
+                    formats.borrow_mut().push(format);
+                }
+            }
+            _ => {}
+        });
+        self.global = Some((*dmabuf).clone());
+    }
+
+    fn get(&self) -> Option<Attached<ZwpLinuxDmabufV1>> {
+        self.global.clone()
+    }
+}
+
+/// A GBM device on the first render node of the system, which allocates the GPU buffers
+pub struct Gbm {
+    functions: Functions,
+    device: Device,
+    /// The device is created on it, it must stay open
+    _node: File,
+}
+
+impl Gbm {
+    pub fn open() -> Result<Self> {
+        let mut nodes: Vec<_> = fs::read_dir("/dev/dri")
+            .context("listing /dev/dri")?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with("renderD"))
+            })
+            .collect();
+        nodes.sort();
+        let path = nodes
+            .first()
+            .ok_or_else(|| eyre!("there is no render node in /dev/dri"))?;
+        let node = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("opening {:?}", path))?;
+
+        // The library is never unloaded, the functions stay valid
+        let library = unsafe { libc::dlopen(c"libgbm.so.1".as_ptr(), libc::RTLD_NOW) };
+        ensure!(!library.is_null(), "libgbm.so.1 cannot be loaded");
+        macro_rules! function {
+            ($name:literal) => {{
+                let symbol = unsafe { libc::dlsym(library, concat!($name, "\0").as_ptr().cast()) };
+                ensure!(!symbol.is_null(), "libgbm has no function {}", $name);
+                #[allow(clippy::missing_transmute_annotations)]
+                unsafe {
+                    std::mem::transmute(symbol)
+                }
+            }};
+        }
+        let functions = Functions {
+            create_device: function!("gbm_create_device"),
+            device_destroy: function!("gbm_device_destroy"),
+            bo_create: function!("gbm_bo_create"),
+            bo_destroy: function!("gbm_bo_destroy"),
+            bo_map: function!("gbm_bo_map"),
+            bo_unmap: function!("gbm_bo_unmap"),
+            bo_get_fd: function!("gbm_bo_get_fd"),
+            bo_get_stride: function!("gbm_bo_get_stride"),
+            bo_get_offset: function!("gbm_bo_get_offset"),
+        };
+        let device = unsafe { (functions.create_device)(node.as_raw_fd()) };
+        ensure!(!device.is_null(), "creating a GBM device on {:?}", path);
+        Ok(Self {
+            functions,
+            device,
+            _node: node,
+        })
+    }
+}
+
+impl Drop for Gbm {
+    fn drop(&mut self) {
+        unsafe { (self.functions.device_destroy)(self.device) };
+    }
+}
+
+/// The buffers of a surface allocated on the GPU and shared with the compositor through the
+/// linux-dmabuf protocol, so that it doesn't need to upload the frames itself. The frames
+/// are written straight into them.
+pub struct DmabufBuffers {
+    gbm: Rc<Gbm>,
+    dmabuf: Attached<ZwpLinuxDmabufV1>,
+    /// The formats the compositor can import
+    formats: Vec<u32>,
+    slots: Vec<Slot>,
+    /// Slot attached last, its frame is the one on screen
+    current: Option<usize>,
+}
+
+struct Slot {
+    gbm: Rc<Gbm>,
+    bo: Bo,
+    buffer: Main<wl_buffer::WlBuffer>,
+    /// Width, height and format of the frame held by the buffer
+    frame: (u32, u32, wl_shm::Format),
+    size: usize,
+    /// Set while the compositor reads the buffer
+    busy: Rc<Cell<bool>>,
+}
+
+impl DmabufBuffers {
+    pub fn new(
+        gbm: Rc<Gbm>,
+        dmabuf: Attached<ZwpLinuxDmabufV1>,
+        formats: Vec<u32>,
+    ) -> Result<Self> {
+        // Creating the buffers without a roundtrip needs version 2
+        ensure!(
+            dmabuf.as_ref().version() >= 2,
+            "the linux-dmabuf protocol of the compositor is too old"
+        );
+        Ok(Self {
+            gbm,
+            dmabuf,
+            formats,
+            slots: Vec::new(),
+            current: None,
+        })
+    }
+
+    /// Bytes of GPU memory used by the buffers
+    pub fn allocated(&self) -> usize {
+        self.slots.iter().map(|slot| slot.size).sum()
+    }
+
+    /// The buffer on screen and the size of its frame, None if there is none
+    pub fn current(&mut self) -> Option<(&wl_buffer::WlBuffer, (u32, u32))> {
+        let slot = &self.slots[self.current?];
+        slot.busy.set(true);
+        Some((&slot.buffer, (slot.frame.0, slot.frame.1)))
+    }
+
+    /// A copy of the frame on screen, read back from the GPU, with its size and format
+    pub fn read_current(&self) -> Result<(Vec<u8>, (u32, u32), wl_shm::Format)> {
+        let slot = &self.slots[self.current.ok_or_else(|| eyre!("nothing is drawn"))?];
+        let (width, height, format) = slot.frame;
+        Ok((slot.read()?, (width, height), format))
+    }
+
+    /// Write data to a buffer the compositor is not using, allocating it if needed, and
+    /// return it to be attached
+    pub fn write(
+        &mut self,
+        data: &[u8],
+        (width, height): (u32, u32),
+        format: wl_shm::Format,
+    ) -> Result<&wl_buffer::WlBuffer> {
+        let frame = (width, height, format);
+        let index = match self
+            .slots
+            .iter()
+            .position(|slot| !slot.busy.get() && slot.frame == frame)
+        {
+            Some(index) => index,
+            None => {
+                // A free buffer of another size is replaced, unless it is still on screen
+                if let Some(index) = self
+                    .slots
+                    .iter()
+                    .enumerate()
+                    .position(|(i, slot)| !slot.busy.get() && Some(i) != self.current)
+                {
+                    self.slots.remove(index);
+                    self.current = self.current.map(|current| {
+                        if current > index {
+                            current - 1
+                        } else {
+                            current
+                        }
+                    });
+                }
+                ensure!(
+                    self.slots.len() < MAX_BUFFERS,
+                    "all the buffers are still used by the compositor"
+                );
+                self.slots.push(self.allocate(frame)?);
+                self.slots.len() - 1
+            }
+        };
+        let slot = &self.slots[index];
+        slot.write(data)?;
+        slot.busy.set(true);
+        self.current = Some(index);
+        Ok(&self.slots[index].buffer)
+    }
+
+    fn allocate(&self, frame: (u32, u32, wl_shm::Format)) -> Result<Slot> {
+        let (width, height, format) = frame;
+        let fourcc = drm_format(format);
+        ensure!(
+            self.formats.contains(&fourcc),
+            "the compositor cannot import GPU buffers in {:?}",
+            format
+        );
+        let functions = &self.gbm.functions;
+        let bo = unsafe {
+            (functions.bo_create)(
+                self.gbm.device,
+                width,
+                height,
+                fourcc,
+                GBM_BO_USE_RENDERING | GBM_BO_USE_LINEAR,
+            )
+        };
+        ensure!(
+            !bo.is_null(),
+            "allocating a GPU buffer of {}x{}",
+            width,
+            height
+        );
+        let (fd, stride, offset) = unsafe {
+            (
+                (functions.bo_get_fd)(bo),
+                (functions.bo_get_stride)(bo),
+                (functions.bo_get_offset)(bo, 0),
+            )
+        };
+        if fd < 0 {
+            unsafe { (functions.bo_destroy)(bo) };
+            return Err(eyre!("exporting a GPU buffer"));
+        }
+        let params = self.dmabuf.create_params();
+        params.add(
+            fd,
+            0,
+            offset,
+            stride,
+            (DRM_FORMAT_MOD_LINEAR >> 32) as u32,
+            DRM_FORMAT_MOD_LINEAR as u32,
+        );
+        // The file descriptor is duplicated when the request is written
+        let _ = close(fd);
+        let buffer = params.create_immed(
+            width as i32,
+            height as i32,
+            fourcc,
+            zwp_linux_buffer_params_v1::Flags::empty(),
+        );
+        params.destroy();
+        let busy = Rc::new(Cell::new(false));
+        let busy_handle = busy.clone();
+        buffer.quick_assign(move |_, event, _| {
+            if let wl_buffer::Event::Release = event {
+                busy_handle.set(false);
+            }
+        });
+        Ok(Slot {
+            gbm: self.gbm.clone(),
+            bo,
+            buffer,
+            frame,
+            size: stride as usize * height as usize,
+            busy,
+        })
+    }
+}
+
+impl Slot {
+    /// Copy data into the buffer, row by row as the buffer might be padded
+    fn write(&self, data: &[u8]) -> Result<()> {
+        let (width, height, _) = self.frame;
+        let functions = &self.gbm.functions;
+        let mut stride = 0;
+        let mut map_data = ptr::null_mut();
+        let map = unsafe {
+            (functions.bo_map)(
+                self.bo,
+                0,
+                0,
+                width,
+                height,
+                GBM_BO_TRANSFER_WRITE,
+                &mut stride,
+                &mut map_data,
+            )
+        };
+        ensure!(!map.is_null(), "mapping a GPU buffer");
+        let row_len = 4 * width as usize;
+        for (y, row) in data.chunks_exact(row_len).take(height as usize).enumerate() {
+            unsafe {
+                let dst = map.cast::<u8>().add(y * stride as usize);
+                ptr::copy_nonoverlapping(row.as_ptr(), dst, row_len);
+            }
+        }
+        unsafe { (functions.bo_unmap)(self.bo, map_data) };
+        Ok(())
+    }
+
+    /// Copy the frame out of the buffer, without the padding of its rows
+    fn read(&self) -> Result<Vec<u8>> {
+        let (width, height, _) = self.frame;
+        let functions = &self.gbm.functions;
+        let mut stride = 0;
+        let mut map_data = ptr::null_mut();
+        let map = unsafe {
+            (functions.bo_map)(
+                self.bo,
+                0,
+                0,
+                width,
+                height,
+                GBM_BO_TRANSFER_READ,
+                &mut stride,
+                &mut map_data,
+            )
+        };
+        ensure!(!map.is_null(), "mapping a GPU buffer");
+        let row_len = 4 * width as usize;
+        let mut data = vec![0; row_len * height as usize];
+        for (y, row) in data.chunks_exact_mut(row_len).enumerate() {
+            unsafe {
+                let src = map.cast::<u8>().add(y * stride as usize);
+                ptr::copy_nonoverlapping(src, row.as_mut_ptr(), row_len);
+            }
+        }
+        unsafe { (functions.bo_unmap)(self.bo, map_data) };
+        Ok(data)
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.buffer.destroy();
+        unsafe { (self.gbm.functions.bo_destroy)(self.bo) };
+    }
+}
+
+/// The DRM code of format. Those of wl_shm are the same, except for the two formats every
+/// compositor supports.
+fn drm_format(format: wl_shm::Format) -> u32 {
+    let fourcc = |code: &[u8; 4]| u32::from_le_bytes(*code);
+    match format {
+        wl_shm::Format::Argb8888 => fourcc(b"AR24"),
+        wl_shm::Format::Xrgb8888 => fourcc(b"XR24"),
+        format => format.to_raw(),
+    }
+}
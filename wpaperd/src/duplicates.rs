@@ -0,0 +1,39 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use lazy_static::lazy_static;
+
+use crate::sync::lock;
+
+lazy_static! {
+    /// The image shown by each output, or the one it is about to show, by output name
+    static ref SHOWN: Mutex<HashMap<String, PathBuf>> = Mutex::new(HashMap::new());
+}
+
+/// Remember that output shows image
+pub fn show(output: &str, image: &Path) {
+    lock(&SHOWN).insert(output.to_string(), image.to_path_buf());
+}
+
+/// Forget the image of output, e.g. when it has been disconnected
+pub fn forget(output: &str) {
+    lock(&SHOWN).remove(output);
+}
+
+/// Call choose with the images shown by the outputs other than output, and remember the
+/// image it returns as the one of output. Nothing else is chosen meanwhile, so that two
+/// outputs loading their next image at the same time never pick the same one.
+pub fn claim(output: &str, choose: impl FnOnce(&[PathBuf]) -> Option<PathBuf>) -> Option<PathBuf> {
+    let mut shown = lock(&SHOWN);
+    let elsewhere: Vec<PathBuf> = shown
+        .iter()
+        .filter(|(name, _)| *name != output)
+        .map(|(_, image)| image.clone())
+        .collect();
+    let image = choose(&elsewhere)?;
+    shown.insert(output.to_string(), image.clone());
+    Some(image)
+}
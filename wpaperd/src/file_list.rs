@@ -0,0 +1,408 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use calloop::channel::Sender;
+use color_eyre::{
+    eyre::{ensure, WrapErr},
+    Result,
+};
+use hotwatch::{Event, Hotwatch};
+use lazy_static::lazy_static;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use wpaper_core::raw;
+use xdg::BaseDirectories;
+
+use crate::index::stable_hash;
+use crate::metrics;
+use crate::mounts;
+use crate::sync::lock;
+
+lazy_static! {
+    /// The images of every directory listed so far, shared by all the outputs
+    static ref FILE_LISTS: Mutex<HashMap<PathBuf, FileList>> = Mutex::new(HashMap::new());
+}
+
+/// Return all the images contained in the directory path, recursively. The directories are
+/// only read again when their modification time changes, so that large collections are not
+/// walked in full on every rotation. Watched directories are not even checked: only the
+/// directories the watcher reports a change in are read again. The list is kept in
+/// $XDG_CACHE_HOME/wpaper/file-list, so that after a restart only the directories modified
+/// meanwhile are read.
+pub fn list_images(path: &Path) -> Result<Vec<PathBuf>> {
+    ensure!(
+        mounts::is_available(path),
+        "{:?} is on a network mount that is not responding",
+        path
+    );
+    let mut file_lists = lock(&FILE_LISTS);
+    let file_list = file_lists
+        .entry(path.to_path_buf())
+        .or_insert_with(|| FileList::load(path));
+    let refresh = file_list.stale || !file_list.watched;
+    metrics::cache_lookup("file_list", !refresh && file_list.dirty.is_empty());
+    let changed = if refresh {
+        file_list.dirty.clear();
+        let changed = file_list
+            .refresh(path, path, false)
+            .with_context(|| format!("iterating files in directory {:?}", path))?;
+        file_list.stale = false;
+        changed
+    } else {
+        file_list
+            .refresh_dirty(path)
+            .with_context(|| format!("iterating files in directory {:?}", path))?
+    };
+    if changed {
+        file_list.images = None;
+        if let Err(err) = file_list.save(path) {
+            warn!("{:?}", err);
+        }
+    }
+    Ok(file_list.images().to_vec())
+}
+
+/// Read path again on the next call to list_images(), even if it is watched; used when
+/// the directory has just been written, before the watcher notices
+pub fn invalidate(path: &Path) {
+    lock(&FILE_LISTS)
+        .entry(path.to_path_buf())
+        .or_insert_with(|| FileList::load(path))
+        .stale = true;
+}
+
+/// Watches the directories of the configuration, so that images added or removed enter or
+/// leave the rotation right away
+pub struct DirWatcher {
+    hotwatch: Hotwatch,
+    dirs: HashSet<PathBuf>,
+}
+
+impl DirWatcher {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            hotwatch: Hotwatch::new().context("hotwatch failed to initialize")?,
+            dirs: HashSet::new(),
+        })
+    }
+
+    /// Watch exactly dirs, stopping to watch the directories that are not listed anymore
+    pub fn update(&mut self, dirs: HashSet<PathBuf>) {
+        for dir in self.dirs.difference(&dirs) {
+            if let Err(err) = self.hotwatch.unwatch(dir) {
+                warn!("stopping to watch the directory {:?}: {}", dir, err);
+            }
+            if let Some(file_list) = lock(&FILE_LISTS).get_mut(dir) {
+                file_list.watched = false;
+            }
+        }
+        for dir in dirs.difference(&self.dirs) {
+            let root = dir.clone();
+            let res = self.hotwatch.watch(dir, move |event: Event| {
+                let mut file_lists = lock(&FILE_LISTS);
+                let file_list = file_lists
+                    .entry(root.clone())
+                    .or_insert_with(|| FileList::load(&root));
+                match event {
+                    Event::Create(path) | Event::Remove(path) => file_list.changed(&root, &path),
+                    Event::Rename(from, to) => {
+                        file_list.changed(&root, &from);
+                        file_list.changed(&root, &to);
+                    }
+                    // The content of the files doesn't matter, and a removal is followed
+                    // by Remove
+                    Event::NoticeWrite(_)
+                    | Event::NoticeRemove(_)
+                    | Event::Write(_)
+                    | Event::Chmod(_) => {}
+                    // Events have been missed
+                    Event::Rescan | Event::Error(_, _) => file_list.stale = true,
+                }
+            });
+            match res {
+                Ok(()) => {
+                    let mut file_lists = lock(&FILE_LISTS);
+                    let file_list = file_lists
+                        .entry(dir.clone())
+                        .or_insert_with(|| FileList::load(dir));
+                    file_list.watched = true;
+                    // Changes that happened before watching would go unnoticed otherwise
+                    file_list.stale = true;
+                }
+                // The modification times are still checked on every rotation
+                Err(err) => warn!("watching the directory {:?}: {}", dir, err),
+            }
+        }
+        self.dirs = dirs;
+    }
+}
+
+/// Watches the images displayed, so that an image written again in place, e.g. by a script
+/// that regenerates it, is shown again
+pub struct ImageWatcher {
+    hotwatch: Hotwatch,
+    images: HashSet<PathBuf>,
+    /// The images that have changed, set by the watcher
+    changed: Arc<Mutex<HashSet<PathBuf>>>,
+    /// Wakes up the main loop
+    wake: Sender<()>,
+}
+
+impl ImageWatcher {
+    pub fn new(wake: Sender<()>) -> Result<Self> {
+        Ok(Self {
+            hotwatch: Hotwatch::new().context("hotwatch failed to initialize")?,
+            images: HashSet::new(),
+            changed: Arc::new(Mutex::new(HashSet::new())),
+            wake,
+        })
+    }
+
+    /// Watch exactly images, stopping to watch the images that are not displayed anymore
+    pub fn update(&mut self, images: HashSet<PathBuf>) {
+        for image in self.images.difference(&images) {
+            // The file might have been removed, which has already ended the watch
+            let _ = self.hotwatch.unwatch(image);
+        }
+        let added: Vec<PathBuf> = images.difference(&self.images).cloned().collect();
+        for image in &added {
+            self.watch(image);
+        }
+        self.images = images;
+    }
+
+    /// The images that have changed since the last call. They are watched again, as a file
+    /// replaced by another one, as editors and scripts often do, is not the one watched
+    /// anymore.
+    pub fn take_changed(&mut self) -> Vec<PathBuf> {
+        let changed: Vec<PathBuf> = lock(&self.changed).drain().collect();
+        for image in &changed {
+            if self.images.contains(image) {
+                let _ = self.hotwatch.unwatch(image);
+                self.watch(image);
+            }
+        }
+        changed
+    }
+
+    fn watch(&mut self, image: &Path) {
+        // Watching a file of a mount that is down would block
+        if !mounts::is_available(image) {
+            return;
+        }
+        let changed = self.changed.clone();
+        let wake = self.wake.clone();
+        let watched = image.to_path_buf();
+        let res = self.hotwatch.watch(image, move |event: Event| {
+            // The events are only sent once the file has stopped changing for a moment, so
+            // that a file being written is not read halfway
+            if matches!(
+                event,
+                Event::Write(_) | Event::Create(_) | Event::Remove(_) | Event::Rename(_, _)
+            ) {
+                lock(&changed).insert(watched.clone());
+                let _ = wake.send(());
+            }
+        });
+        if let Err(err) = res {
+            warn!("watching the image {:?}: {}", image, err);
+        }
+    }
+}
+
+/// What a directory contained the last time it was read
+#[derive(Deserialize, Serialize)]
+struct DirContent {
+    images: Vec<PathBuf>,
+    subdirs: Vec<PathBuf>,
+    // TOML writes the tables after the values
+    modified: SystemTime,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct FileList {
+    /// Every directory below the root, including the root itself
+    dirs: HashMap<PathBuf, DirContent>,
+    /// Set when the root is watched for changes
+    #[serde(skip)]
+    watched: bool,
+    /// Set when every directory has to be checked, e.g. when the watcher has missed events
+    #[serde(skip)]
+    stale: bool,
+    /// The directories in which the watcher has seen entries created, removed or renamed
+    #[serde(skip)]
+    dirty: HashSet<PathBuf>,
+    /// The images of every directory, sorted, until they change
+    #[serde(skip)]
+    images: Option<Vec<PathBuf>>,
+}
+
+impl FileList {
+    /// The list saved for root, or an empty one. Either way every directory is checked on
+    /// the first refresh, as anything could have changed while wpaperd was not running.
+    fn load(root: &Path) -> Self {
+        let res = cache_path(root).and_then(|path| match fs::read_to_string(&path) {
+            Ok(content) => {
+                toml::from_str(&content).with_context(|| format!("parsing file list {:?}", path))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err).with_context(|| format!("reading file list {:?}", path)),
+        });
+        let mut file_list = res.unwrap_or_else(|err| {
+            warn!("{:?}", err);
+            Self::default()
+        });
+        file_list.stale = true;
+        file_list
+    }
+
+    fn save(&self, root: &Path) -> Result<()> {
+        let path = cache_path(root)?;
+        // Other processes might read it meanwhile, replace it atomically
+        let tmp_path = path.with_extension(format!("toml.{}", std::process::id()));
+        fs::write(&tmp_path, toml::to_string(self)?)
+            .with_context(|| format!("writing file list {:?}", tmp_path))?;
+        fs::rename(&tmp_path, &path).with_context(|| format!("writing file list {:?}", path))
+    }
+
+    /// The watcher has seen path, inside root, created or removed: its directory is read
+    /// again on the next call to list_images()
+    fn changed(&mut self, root: &Path, path: &Path) {
+        match path.parent().filter(|dir| dir.starts_with(root)) {
+            Some(dir) => {
+                self.dirty.insert(dir.to_path_buf());
+            }
+            // The root itself has changed
+            None => self.stale = true,
+        }
+    }
+
+    /// Read again the directories reported by the watcher, the ones created in them and
+    /// the ones modified below them. Returns true if anything changed.
+    fn refresh_dirty(&mut self, root: &Path) -> io::Result<bool> {
+        let mut dirty: Vec<PathBuf> = self.dirty.drain().collect();
+        // The parents first, walking them covers their children
+        dirty.sort_unstable();
+        let mut changed = false;
+        let mut walked: Vec<PathBuf> = Vec::new();
+        for dir in dirty {
+            if walked.iter().any(|walked| dir.starts_with(walked)) {
+                continue;
+            }
+            // A directory created with its own subdirectories is walked from the first
+            // one already known
+            let start = dir
+                .ancestors()
+                .find(|dir| self.dirs.contains_key(*dir))
+                .unwrap_or(root)
+                .to_path_buf();
+            changed |= self.refresh(root, &start, true)?;
+            walked.push(start);
+        }
+        Ok(changed)
+    }
+
+    /// Read again the directories below start, itself included, that have been modified,
+    /// and the new ones; start is always read with force. Returns true if anything changed.
+    fn refresh(&mut self, root: &Path, start: &Path, force: bool) -> io::Result<bool> {
+        let mut changed = false;
+        // Symlinks are followed, the canonical paths make sure that loops are walked once
+        let mut visited = HashSet::new();
+        let mut seen = HashSet::new();
+        let mut stack = vec![start.to_path_buf()];
+        while let Some(dir) = stack.pop() {
+            let res = fs::canonicalize(&dir).and_then(|canonical| {
+                let modified = fs::metadata(&dir)?.modified()?;
+                Ok((canonical, modified))
+            });
+            let (canonical, modified) = match res {
+                Ok(res) => res,
+                Err(err) if dir == root => return Err(err),
+                Err(err) => {
+                    warn!("reading the directory {:?}: {}", dir, err);
+                    continue;
+                }
+            };
+            if !visited.insert(canonical) {
+                continue;
+            }
+            let up_to_date = !(force && dir == start)
+                && self
+                    .dirs
+                    .get(&dir)
+                    .is_some_and(|content| content.modified == modified);
+            if !up_to_date {
+                match read_dir(&dir, modified) {
+                    Ok(content) => {
+                        changed |= self.dirs.get(&dir).is_none_or(|old| {
+                            (&old.images, &old.subdirs) != (&content.images, &content.subdirs)
+                        });
+                        self.dirs.insert(dir.clone(), content);
+                    }
+                    Err(err) if dir == root => return Err(err),
+                    Err(err) => {
+                        warn!("reading the directory {:?}: {}", dir, err);
+                        continue;
+                    }
+                }
+            }
+            stack.extend(self.dirs[&dir].subdirs.iter().cloned());
+            seen.insert(dir);
+        }
+        // Forget the directories that have been removed
+        let len = self.dirs.len();
+        self.dirs
+            .retain(|dir, _| !dir.starts_with(start) || seen.contains(dir));
+        Ok(changed || self.dirs.len() != len)
+    }
+
+    fn images(&mut self) -> &[PathBuf] {
+        let dirs = &self.dirs;
+        self.images.get_or_insert_with(|| {
+            let mut images: Vec<PathBuf> = dirs
+                .values()
+                .flat_map(|content| content.images.iter().cloned())
+                .collect();
+            images.sort_unstable();
+            images
+        })
+    }
+}
+
+/// Where the file list of root is kept
+fn cache_path(root: &Path) -> Result<PathBuf> {
+    let hash = stable_hash(root.as_os_str().as_encoded_bytes());
+    BaseDirectories::with_prefix("wpaper")?
+        .place_cache_file(format!("file-list/{}.toml", hash))
+        .context("creating the cache directory")
+}
+
+fn read_dir(dir: &Path, modified: SystemTime) -> io::Result<DirContent> {
+    let mut content = DirContent {
+        modified,
+        images: Vec::new(),
+        subdirs: Vec::new(),
+    };
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        // fs::metadata() follows symlinks; broken ones are skipped
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => content.subdirs.push(path),
+            Ok(metadata) if metadata.is_file() && is_image(&path) => content.images.push(path),
+            _ => {}
+        }
+    }
+    Ok(content)
+}
+
+pub fn is_image(path: &Path) -> bool {
+    raw::is_raw(path)
+        || new_mime_guess::from_path(path)
+            .first()
+            .is_some_and(|guess| guess.type_() == "image")
+}
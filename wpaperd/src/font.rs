@@ -0,0 +1,839 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use color_eyre::{
+    eyre::{bail, ensure, eyre, WrapErr},
+    Result,
+};
+
+/// A TrueType font, enough of it to draw simple text: the glyph outlines, their advances and
+/// the mapping from characters to glyphs. Fonts with CFF outlines are not supported.
+pub struct Font {
+    data: Vec<u8>,
+    units_per_em: f32,
+    ascender: f32,
+    descender: f32,
+    line_gap: f32,
+    num_glyphs: u16,
+    long_loca: bool,
+    number_of_h_metrics: u16,
+    loca: usize,
+    glyf: usize,
+    hmtx: usize,
+    cmap: Cmap,
+}
+
+enum Cmap {
+    /// Segment mapping to delta values, only for the Basic Multilingual Plane
+    Format4(usize),
+    /// Segmented coverage of all the planes
+    Format12(usize),
+}
+
+/// Coverage of each pixel of a piece of text, from 0 to 255
+pub struct Mask {
+    pub width: u32,
+    pub coverage: Vec<u8>,
+    /// Position of the mask relative to the top left corner of the layout box of the text,
+    /// which the ink can exceed
+    pub offset: (i32, i32),
+    /// Size of the layout box: the advance of the longest line and the height of the lines
+    pub layout: (u32, u32),
+}
+
+#[derive(Clone, Copy)]
+struct Point {
+    x: f32,
+    y: f32,
+}
+
+/// Where to find a font: a path, or a name looked up with fc-match, e.g. "DejaVu Sans:bold"
+pub fn resolve(font: &str) -> Result<PathBuf> {
+    if font.contains('/') {
+        return Ok(PathBuf::from(font));
+    }
+    let output = Command::new("fc-match")
+        .args(["--format=%{file}", font])
+        .output()
+        .context("running fc-match to find the font")?;
+    let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    ensure!(
+        output.status.success() && !path.is_empty(),
+        "no font matches {:?}",
+        font
+    );
+    Ok(PathBuf::from(path))
+}
+
+impl Font {
+    pub fn open(path: &Path) -> Result<Self> {
+        let data = fs::read(path).with_context(|| format!("reading the font {:?}", path))?;
+        Self::parse(data).with_context(|| format!("parsing the font {:?}", path))
+    }
+
+    fn parse(data: Vec<u8>) -> Result<Self> {
+        let r = Reader(&data);
+        // Use the first font of a collection
+        let start = match &r.tag(0)? {
+            b"ttcf" => r.u32(12)? as usize,
+            _ => 0,
+        };
+        match &r.tag(start)? {
+            [0, 1, 0, 0] | b"true" => {}
+            b"OTTO" => bail!("fonts with CFF outlines are not supported"),
+            _ => bail!("not a TrueType font"),
+        }
+        let num_tables = r.u16(start + 4)? as usize;
+        let table = |tag: &[u8; 4]| -> Result<usize> {
+            (0..num_tables)
+                .map(|i| start + 12 + 16 * i)
+                .find(|&record| r.tag(record).is_ok_and(|t| t == *tag))
+                .map(|record| r.u32(record + 8).map(|offset| offset as usize))
+                .unwrap_or_else(|| Err(eyre!("the table {} is missing", tag.escape_ascii())))
+        };
+        let head = table(b"head")?;
+        let hhea = table(b"hhea")?;
+        let cmap = table(b"cmap")?;
+
+        // Prefer the tables of the full Unicode repertoire
+        let mut format4 = None;
+        let mut format12 = None;
+        for i in 0..r.u16(cmap + 2)? as usize {
+            let record = cmap + 4 + 8 * i;
+            let (platform, encoding) = (r.u16(record)?, r.u16(record + 2)?);
+            let subtable = cmap + r.u32(record + 4)? as usize;
+            let unicode = platform == 0 || (platform == 3 && matches!(encoding, 1 | 10));
+            match r.u16(subtable)? {
+                4 if unicode => format4 = Some(subtable),
+                12 if unicode => format12 = Some(subtable),
+                _ => {}
+            }
+        }
+        let cmap = match (format12, format4) {
+            (Some(subtable), _) => Cmap::Format12(subtable),
+            (None, Some(subtable)) => Cmap::Format4(subtable),
+            (None, None) => bail!("the font has no Unicode character map"),
+        };
+
+        Ok(Self {
+            units_per_em: f32::from(r.u16(head + 18)?),
+            long_loca: r.i16(head + 50)? == 1,
+            ascender: f32::from(r.i16(hhea + 4)?),
+            descender: f32::from(r.i16(hhea + 6)?),
+            line_gap: f32::from(r.i16(hhea + 8)?),
+            number_of_h_metrics: r.u16(hhea + 34)?,
+            num_glyphs: r.u16(table(b"maxp")? + 4)?,
+            loca: table(b"loca")?,
+            glyf: table(b"glyf")?,
+            hmtx: table(b"hmtx")?,
+            cmap,
+            data,
+        })
+    }
+
+    /// Draw text, whose lines are aligned according to align: 0 to the left, 0.5 centered
+    /// and 1 to the right. size is the height of the em square, in pixels.
+    pub fn rasterize(&self, text: &str, size: f32, align: f32) -> Mask {
+        let scale = size / self.units_per_em;
+        let ascender = self.ascender * scale;
+        let line_height = (self.ascender - self.descender + self.line_gap) * scale;
+
+        let lines: Vec<Vec<u16>> = text
+            .lines()
+            .map(|line| line.chars().map(|c| self.glyph_index(c)).collect())
+            .collect();
+        let widths: Vec<f32> = lines
+            .iter()
+            .map(|glyphs| {
+                glyphs
+                    .iter()
+                    .map(|&glyph| self.advance(glyph) * scale)
+                    .sum()
+            })
+            .collect();
+        let layout_width = widths.iter().copied().fold(0.0, f32::max);
+        let layout_height = line_height * lines.len() as f32;
+
+        // Every outline as line segments, in pixels relative to the layout box
+        let mut segments = Vec::new();
+        for (i, glyphs) in lines.iter().enumerate() {
+            let baseline = ascender + line_height * i as f32;
+            let mut x = (layout_width - widths[i]) * align;
+            for &glyph in glyphs {
+                for contour in self.outline(glyph, 0) {
+                    let to_pixels = |p: Point| Point {
+                        x: x + p.x * scale,
+                        y: baseline - p.y * scale,
+                    };
+                    flatten(&contour, to_pixels, &mut segments);
+                }
+                x += self.advance(glyph) * scale;
+            }
+        }
+
+        let layout = (
+            layout_width.ceil() as u32,
+            layout_height.ceil().max(0.0) as u32,
+        );
+        if segments.is_empty() {
+            return Mask {
+                width: 0,
+                coverage: Vec::new(),
+                offset: (0, 0),
+                layout,
+            };
+        }
+        let (mut min, mut max) = (segments[0].0, segments[0].0);
+        for &(a, b) in &segments {
+            for p in [a, b] {
+                min = Point {
+                    x: min.x.min(p.x),
+                    y: min.y.min(p.y),
+                };
+                max = Point {
+                    x: max.x.max(p.x),
+                    y: max.y.max(p.y),
+                };
+            }
+        }
+        // Leave a pixel on each side, the accumulation can spill over the next one
+        let offset = (min.x.floor() as i32 - 1, min.y.floor() as i32 - 1);
+        let width = (max.x.ceil() as i32 - offset.0 + 2) as usize;
+        let height = (max.y.ceil() as i32 - offset.1 + 2) as usize;
+        let mut accumulation = vec![0f32; width * height + 2];
+        for &(a, b) in &segments {
+            let shift = |p: Point| Point {
+                x: p.x - offset.0 as f32,
+                y: p.y - offset.1 as f32,
+            };
+            draw_line(&mut accumulation, width, height, shift(a), shift(b));
+        }
+        let mut sum = 0.0;
+        let coverage = accumulation[..width * height]
+            .iter()
+            .map(|value| {
+                sum += value;
+                (sum.abs().min(1.0) * 255.0).round() as u8
+            })
+            .collect();
+        Mask {
+            width: width as u32,
+            coverage,
+            offset,
+            layout,
+        }
+    }
+
+    fn glyph_index(&self, c: char) -> u16 {
+        let r = Reader(&self.data);
+        let code = c as u32;
+        let glyph = match self.cmap {
+            Cmap::Format4(subtable) => (|| -> Result<u16> {
+                if code > 0xffff {
+                    return Ok(0);
+                }
+                let code = code as u16;
+                let segments = r.u16(subtable + 6)? as usize / 2;
+                let end_codes = subtable + 14;
+                let start_codes = end_codes + 2 * segments + 2;
+                let deltas = start_codes + 2 * segments;
+                let range_offsets = deltas + 2 * segments;
+                for i in 0..segments {
+                    if r.u16(end_codes + 2 * i)? < code {
+                        continue;
+                    }
+                    let start_code = r.u16(start_codes + 2 * i)?;
+                    if start_code > code {
+                        return Ok(0);
+                    }
+                    let delta = r.u16(deltas + 2 * i)?;
+                    let range_offset = r.u16(range_offsets + 2 * i)? as usize;
+                    if range_offset == 0 {
+                        return Ok(code.wrapping_add(delta));
+                    }
+                    let address =
+                        range_offsets + 2 * i + range_offset + 2 * usize::from(code - start_code);
+                    return match r.u16(address)? {
+                        0 => Ok(0),
+                        glyph => Ok(glyph.wrapping_add(delta)),
+                    };
+                }
+                Ok(0)
+            })(),
+            Cmap::Format12(subtable) => (|| -> Result<u16> {
+                for i in 0..r.u32(subtable + 12)? as usize {
+                    let group = subtable + 16 + 12 * i;
+                    let (start, end) = (r.u32(group)?, r.u32(group + 4)?);
+                    if (start..=end).contains(&code) {
+                        // A broken group could map past the last glyph
+                        let glyph = r.u32(group + 8)?.checked_add(code - start);
+                        return Ok(glyph
+                            .and_then(|glyph| u16::try_from(glyph).ok())
+                            .unwrap_or(0));
+                    }
+                }
+                Ok(0)
+            })(),
+        };
+        // Glyph 0 is the one drawn for the missing characters
+        glyph.unwrap_or(0)
+    }
+
+    fn advance(&self, glyph: u16) -> f32 {
+        let r = Reader(&self.data);
+        // The last metric applies to all the following glyphs
+        let metric = glyph.min(self.number_of_h_metrics.saturating_sub(1)) as usize;
+        f32::from(r.u16(self.hmtx + 4 * metric).unwrap_or(0))
+    }
+
+    /// The contours of glyph, as quadratic outlines in font units: the points with their
+    /// on-curve flag
+    fn outline(&self, glyph: u16, depth: u32) -> Vec<Vec<(Point, bool)>> {
+        // Composite glyphs could reference each other, the ones deeper than this are broken
+        if glyph >= self.num_glyphs || depth > 8 {
+            return Vec::new();
+        }
+        self.parse_outline(glyph, depth).unwrap_or_default()
+    }
+
+    fn parse_outline(&self, glyph: u16, depth: u32) -> Result<Vec<Vec<(Point, bool)>>> {
+        let r = Reader(&self.data);
+        let glyph = glyph as usize;
+        let (start, end) = if self.long_loca {
+            (
+                r.u32(self.loca + 4 * glyph)? as usize,
+                r.u32(self.loca + 4 * glyph + 4)? as usize,
+            )
+        } else {
+            (
+                2 * r.u16(self.loca + 2 * glyph)? as usize,
+                2 * r.u16(self.loca + 2 * glyph + 2)? as usize,
+            )
+        };
+        if start >= end {
+            // e.g. the space
+            return Ok(Vec::new());
+        }
+        let offset = self.glyf + start;
+        let contours = r.i16(offset)?;
+        if contours < 0 {
+            return self.parse_composite(offset + 10, depth);
+        }
+
+        let contours = contours as usize;
+        let mut end_points = Vec::with_capacity(contours);
+        for i in 0..contours {
+            end_points.push(r.u16(offset + 10 + 2 * i)? as usize);
+        }
+        let points = end_points.last().map_or(0, |last| last + 1);
+        let instructions = offset + 10 + 2 * contours;
+        let mut pos = instructions + 2 + r.u16(instructions)? as usize;
+
+        let mut flags = Vec::with_capacity(points);
+        while flags.len() < points {
+            let flag = r.u8(pos)?;
+            pos += 1;
+            flags.push(flag);
+            // Repeat
+            if flag & 8 != 0 {
+                let count = r.u8(pos)?;
+                pos += 1;
+                for _ in 0..count {
+                    flags.push(flag);
+                }
+            }
+        }
+        flags.truncate(points);
+
+        // The coordinates are deltas, short ones are a byte with the sign in the flags
+        let mut read_coordinates = |short: u8, same_or_positive: u8| -> Result<Vec<f32>> {
+            let mut value = 0i32;
+            let mut values = Vec::with_capacity(points);
+            for &flag in &flags {
+                if flag & short != 0 {
+                    let delta = i32::from(r.u8(pos)?);
+                    pos += 1;
+                    value += if flag & same_or_positive != 0 {
+                        delta
+                    } else {
+                        -delta
+                    };
+                } else if flag & same_or_positive == 0 {
+                    value += i32::from(r.i16(pos)?);
+                    pos += 2;
+                }
+                values.push(value as f32);
+            }
+            Ok(values)
+        };
+        let xs = read_coordinates(2, 16)?;
+        let ys = read_coordinates(4, 32)?;
+
+        let mut outline = Vec::with_capacity(contours);
+        let mut first = 0;
+        for end in end_points {
+            if end < first || end >= points {
+                bail!("invalid contour");
+            }
+            outline.push(
+                (first..=end)
+                    .map(|i| (Point { x: xs[i], y: ys[i] }, flags[i] & 1 != 0))
+                    .collect(),
+            );
+            first = end + 1;
+        }
+        Ok(outline)
+    }
+
+    fn parse_composite(&self, mut pos: usize, depth: u32) -> Result<Vec<Vec<(Point, bool)>>> {
+        const ARGS_ARE_WORDS: u16 = 0x1;
+        const ARGS_ARE_XY_VALUES: u16 = 0x2;
+        const HAVE_SCALE: u16 = 0x8;
+        const MORE_COMPONENTS: u16 = 0x20;
+        const HAVE_XY_SCALE: u16 = 0x40;
+        const HAVE_TWO_BY_TWO: u16 = 0x80;
+
+        let r = Reader(&self.data);
+        let f2dot14 = |pos: usize| r.i16(pos).map(|value| f32::from(value) / 16384.0);
+        let mut outline = Vec::new();
+        loop {
+            let flags = r.u16(pos)?;
+            let glyph = r.u16(pos + 2)?;
+            pos += 4;
+            let (dx, dy) = if flags & ARGS_ARE_WORDS != 0 {
+                pos += 4;
+                (f32::from(r.i16(pos - 4)?), f32::from(r.i16(pos - 2)?))
+            } else {
+                pos += 2;
+                (
+                    f32::from(r.u8(pos - 2)? as i8),
+                    f32::from(r.u8(pos - 1)? as i8),
+                )
+            };
+            // Components positioned by matching points are rare, they are drawn in place
+            let (dx, dy) = if flags & ARGS_ARE_XY_VALUES != 0 {
+                (dx, dy)
+            } else {
+                (0.0, 0.0)
+            };
+            let [a, b, c, d] = if flags & HAVE_SCALE != 0 {
+                pos += 2;
+                let scale = f2dot14(pos - 2)?;
+                [scale, 0.0, 0.0, scale]
+            } else if flags & HAVE_XY_SCALE != 0 {
+                pos += 4;
+                [f2dot14(pos - 4)?, 0.0, 0.0, f2dot14(pos - 2)?]
+            } else if flags & HAVE_TWO_BY_TWO != 0 {
+                pos += 8;
+                [
+                    f2dot14(pos - 8)?,
+                    f2dot14(pos - 6)?,
+                    f2dot14(pos - 4)?,
+                    f2dot14(pos - 2)?,
+                ]
+            } else {
+                [1.0, 0.0, 0.0, 1.0]
+            };
+            for contour in self.outline(glyph, depth + 1) {
+                outline.push(
+                    contour
+                        .into_iter()
+                        .map(|(p, on_curve)| {
+                            let x = a * p.x + c * p.y + dx;
+                            let y = b * p.x + d * p.y + dy;
+                            (Point { x, y }, on_curve)
+                        })
+                        .collect(),
+                );
+            }
+            if flags & MORE_COMPONENTS == 0 {
+                return Ok(outline);
+            }
+        }
+    }
+}
+
+/// Turn a contour of quadratic curves into line segments. Between two off-curve points
+/// there is an implied on-curve point, halfway.
+fn flatten(
+    contour: &[(Point, bool)],
+    transform: impl Fn(Point) -> Point,
+    out: &mut Vec<(Point, Point)>,
+) {
+    let len = contour.len();
+    let Some(first_on) = contour.iter().position(|&(_, on_curve)| on_curve) else {
+        // Only off-curve points: start from the implied point between the first two
+        if len < 2 {
+            return;
+        }
+        let (a, b) = (contour[0].0, contour[1].0);
+        let start = Point {
+            x: (a.x + b.x) / 2.0,
+            y: (a.y + b.y) / 2.0,
+        };
+        let mut rotated = vec![(start, true)];
+        rotated.extend(contour[1..].iter().copied());
+        rotated.push(contour[0]);
+        return flatten(&rotated, transform, out);
+    };
+
+    let start = transform(contour[first_on].0);
+    let mut current = start;
+    let mut control: Option<Point> = None;
+    for i in 1..=len {
+        let (point, on_curve) = contour[(first_on + i) % len];
+        let point = transform(point);
+        match (control, on_curve) {
+            (None, true) => {
+                out.push((current, point));
+                current = point;
+            }
+            (None, false) => control = Some(point),
+            (Some(ctrl), true) => {
+                quadratic(current, ctrl, point, out);
+                current = point;
+                control = None;
+            }
+            (Some(ctrl), false) => {
+                let mid = Point {
+                    x: (ctrl.x + point.x) / 2.0,
+                    y: (ctrl.y + point.y) / 2.0,
+                };
+                quadratic(current, ctrl, mid, out);
+                current = mid;
+                control = Some(point);
+            }
+        }
+    }
+    if let Some(ctrl) = control {
+        quadratic(current, ctrl, start, out);
+    } else if current.x != start.x || current.y != start.y {
+        out.push((current, start));
+    }
+}
+
+fn quadratic(p0: Point, p1: Point, p2: Point, out: &mut Vec<(Point, Point)>) {
+    // Enough segments that each deviates from the curve by a fraction of a pixel
+    let deviation =
+        ((p0.x - 2.0 * p1.x + p2.x).powi(2) + (p0.y - 2.0 * p1.y + p2.y).powi(2)).sqrt();
+    let steps = ((deviation * 2.0).sqrt().ceil() as usize).clamp(1, 32);
+    let mut previous = p0;
+    for step in 1..=steps {
+        let t = step as f32 / steps as f32;
+        let mt = 1.0 - t;
+        let point = Point {
+            x: mt * mt * p0.x + 2.0 * mt * t * p1.x + t * t * p2.x,
+            y: mt * mt * p0.y + 2.0 * mt * t * p1.y + t * t * p2.y,
+        };
+        out.push((previous, point));
+        previous = point;
+    }
+}
+
+/// Add the signed area covered by the line from p0 to p1 to the accumulation buffer; the
+/// running sum of each row then gives the coverage of every pixel. Both points are
+/// inside the buffer, leaving a pixel free on the right.
+fn draw_line(accumulation: &mut [f32], width: usize, height: usize, p0: Point, p1: Point) {
+    if (p0.y - p1.y).abs() <= f32::EPSILON {
+        return;
+    }
+    let (direction, p0, p1) = if p0.y < p1.y {
+        (1.0, p0, p1)
+    } else {
+        (-1.0, p1, p0)
+    };
+    let dxdy = (p1.x - p0.x) / (p1.y - p0.y);
+    let mut x = p0.x;
+    for y in (p0.y as usize)..height.min(p1.y.ceil() as usize) {
+        let row = y * width;
+        let dy = ((y + 1) as f32).min(p1.y) - (y as f32).max(p0.y);
+        let x_next = x + dxdy * dy;
+        let d = dy * direction;
+        let (x0, x1) = if x < x_next { (x, x_next) } else { (x_next, x) };
+        let x0_floor = x0.floor();
+        let x0i = x0_floor as usize;
+        let x1_ceil = x1.ceil();
+        let x1i = x1_ceil as usize;
+        if x1i <= x0i + 1 {
+            // Within a single pixel
+            let middle = 0.5 * (x + x_next) - x0_floor;
+            accumulation[row + x0i] += d - d * middle;
+            accumulation[row + x0i + 1] += d * middle;
+        } else {
+            let s = (x1 - x0).recip();
+            let x0f = x0 - x0_floor;
+            let a0 = 0.5 * s * (1.0 - x0f) * (1.0 - x0f);
+            let x1f = x1 - x1_ceil + 1.0;
+            let am = 0.5 * s * x1f * x1f;
+            accumulation[row + x0i] += d * a0;
+            if x1i == x0i + 2 {
+                accumulation[row + x0i + 1] += d * (1.0 - a0 - am);
+            } else {
+                let a1 = s * (1.5 - x0f);
+                accumulation[row + x0i + 1] += d * (a1 - a0);
+                for xi in x0i + 2..x1i - 1 {
+                    accumulation[row + xi] += d * s;
+                }
+                let a2 = a1 + (x1i - x0i - 3) as f32 * s;
+                accumulation[row + x1i - 1] += d * (1.0 - a2 - am);
+            }
+            accumulation[row + x1i] += d * am;
+        }
+        x = x_next;
+    }
+}
+
+/// Big endian reads that fail instead of panicking on truncated fonts
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&self, pos: usize) -> Result<[u8; N]> {
+        self.0
+            .get(pos..pos + N)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| eyre!("the font is truncated"))
+    }
+
+    fn tag(&self, pos: usize) -> Result<[u8; 4]> {
+        self.bytes(pos)
+    }
+
+    fn u8(&self, pos: usize) -> Result<u8> {
+        Ok(self.bytes::<1>(pos)?[0])
+    }
+
+    fn u16(&self, pos: usize) -> Result<u16> {
+        self.bytes(pos).map(u16::from_be_bytes)
+    }
+
+    fn i16(&self, pos: usize) -> Result<i16> {
+        self.bytes(pos).map(i16::from_be_bytes)
+    }
+
+    fn u32(&self, pos: usize) -> Result<u32> {
+        self.bytes(pos).map(u32::from_be_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A triangle with its three points on the curve
+    fn simple_glyph() -> Vec<u8> {
+        let mut glyph = 1i16.to_be_bytes().to_vec();
+        glyph.extend([0; 8]);
+        // End point of the contour, then no instructions
+        glyph.extend(2u16.to_be_bytes());
+        glyph.extend(0u16.to_be_bytes());
+        glyph.extend([1, 1, 1]);
+        for delta in [0i16, 100, -100, 0, 0, 100] {
+            glyph.extend(delta.to_be_bytes());
+        }
+        glyph
+    }
+
+    /// A glyph made of others, each moved by its offset
+    fn composite_glyph(components: &[(u16, i16, i16)]) -> Vec<u8> {
+        let mut glyph = (-1i16).to_be_bytes().to_vec();
+        glyph.extend([0; 8]);
+        for (i, &(component, dx, dy)) in components.iter().enumerate() {
+            // Word arguments holding the offset, and more components but for the last
+            let more = if i + 1 < components.len() { 0x20 } else { 0 };
+            glyph.extend((0x3u16 | more).to_be_bytes());
+            glyph.extend(component.to_be_bytes());
+            glyph.extend(dx.to_be_bytes());
+            glyph.extend(dy.to_be_bytes());
+        }
+        glyph
+    }
+
+    fn words(values: impl IntoIterator<Item = u16>) -> Vec<u8> {
+        values.into_iter().flat_map(u16::to_be_bytes).collect()
+    }
+
+    /// Format 4 subtable for the segments (start, end, delta, glyphs), a segment with glyphs
+    /// mapping through them instead of with the delta alone
+    fn format4(segments: &[(u16, u16, u16, &[u16])]) -> Vec<u8> {
+        let count = segments.len() as u16;
+        // The offsets are from each one to its glyphs, which follow the last offset
+        let mut glyphs = 0;
+        let offsets: Vec<u16> = segments
+            .iter()
+            .enumerate()
+            .map(|(i, segment)| match segment.3 {
+                [] => 0,
+                ids => {
+                    let offset = 2 * (count - i as u16 + glyphs);
+                    glyphs += ids.len() as u16;
+                    offset
+                }
+            })
+            .collect();
+        [
+            words([4, 0, 0, 2 * count, 0, 0, 0]),
+            words(segments.iter().map(|segment| segment.1)),
+            words([0]),
+            words(segments.iter().map(|segment| segment.0)),
+            words(segments.iter().map(|segment| segment.2)),
+            words(offsets),
+            words(
+                segments
+                    .iter()
+                    .flat_map(|segment| segment.3.iter().copied()),
+            ),
+        ]
+        .concat()
+    }
+
+    /// Format 12 subtable for the groups (start, end, first glyph)
+    fn format12(groups: &[(u32, u32, u32)]) -> Vec<u8> {
+        let mut subtable = words([12, 0]);
+        subtable.extend([0; 8]);
+        subtable.extend((groups.len() as u32).to_be_bytes());
+        for &(start, end, glyph) in groups {
+            for value in [start, end, glyph] {
+                subtable.extend(value.to_be_bytes());
+            }
+        }
+        subtable
+    }
+
+    /// A font with the character map and glyphs, the tables in the order they are read
+    fn font(cmap_format: u16, cmap_subtable: Vec<u8>, glyphs: &[Vec<u8>]) -> Vec<u8> {
+        let num_glyphs = glyphs.len() as u16;
+        let mut head = vec![0; 54];
+        head[18..20].copy_from_slice(&1000u16.to_be_bytes());
+        // Long offsets in loca
+        head[50..52].copy_from_slice(&1i16.to_be_bytes());
+        let mut hhea = vec![0; 36];
+        hhea[4..6].copy_from_slice(&800i16.to_be_bytes());
+        hhea[6..8].copy_from_slice(&(-200i16).to_be_bytes());
+        hhea[34..36].copy_from_slice(&num_glyphs.to_be_bytes());
+        let mut maxp = vec![0; 6];
+        maxp[4..6].copy_from_slice(&num_glyphs.to_be_bytes());
+        let encoding: u16 = if cmap_format == 12 { 10 } else { 1 };
+        let mut cmap = words([0, 1, 3, encoding]);
+        cmap.extend(12u32.to_be_bytes());
+        cmap.extend(cmap_subtable);
+        let mut loca = 0u32.to_be_bytes().to_vec();
+        let mut glyf = Vec::new();
+        for glyph in glyphs {
+            glyf.extend(glyph);
+            loca.extend((glyf.len() as u32).to_be_bytes());
+        }
+        let hmtx = (0..num_glyphs)
+            .flat_map(|_| [600u16.to_be_bytes(), [0, 0]].concat())
+            .collect();
+
+        let tables: [(&[u8; 4], Vec<u8>); 7] = [
+            (b"head", head),
+            (b"hhea", hhea),
+            (b"maxp", maxp),
+            (b"cmap", cmap),
+            (b"loca", loca),
+            (b"glyf", glyf),
+            (b"hmtx", hmtx),
+        ];
+        let mut data = vec![0, 1, 0, 0];
+        data.extend((tables.len() as u16).to_be_bytes());
+        data.extend([0; 6]);
+        let mut offset = 12 + 16 * tables.len();
+        for (tag, table) in &tables {
+            data.extend(*tag);
+            data.extend([0; 4]);
+            data.extend((offset as u32).to_be_bytes());
+            data.extend((table.len() as u32).to_be_bytes());
+            offset += table.len();
+        }
+        for (_, table) in tables {
+            data.extend(table);
+        }
+        data
+    }
+
+    fn points(outline: &[Vec<(Point, bool)>]) -> Vec<Vec<(f32, f32)>> {
+        outline
+            .iter()
+            .map(|contour| contour.iter().map(|(p, _)| (p.x, p.y)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn format4_lookups() {
+        let cmap = format4(&[
+            // 'A' to 'C' are the glyphs 1 to 3, the delta wraps around
+            (0x41, 0x43, 1u16.wrapping_sub(0x41), &[]),
+            // 'a' to 'c' through the glyphs, 'b' is missing
+            (0x61, 0x63, 0, &[1, 0, 2]),
+            (0xffff, 0xffff, 1, &[]),
+        ]);
+        let glyphs = vec![simple_glyph(); 4];
+        let font = Font::parse(font(4, cmap, &glyphs)).unwrap();
+        let lookups = ['@', 'A', 'C', 'D', 'a', 'b', 'c', 'é', '😀'].map(|c| font.glyph_index(c));
+        assert_eq!(lookups, [0, 1, 3, 0, 1, 0, 2, 0, 0]);
+    }
+
+    #[test]
+    fn format12_lookups() {
+        let cmap = format12(&[
+            (0x41, 0x5a, 1),
+            (0x1f600, 0x1f600, 3),
+            // Past the last glyph that a u16 can index
+            (0x1f601, 0x1f601, 0x10000),
+            // Overflowing the glyph index
+            (0x1f602, 0x1f603, u32::MAX),
+        ]);
+        let glyphs = vec![simple_glyph(); 4];
+        let font = Font::parse(font(12, cmap, &glyphs)).unwrap();
+        let lookups = ['@', 'A', 'B', '😀', '😁', '😂', '😃'].map(|c| font.glyph_index(c));
+        assert_eq!(lookups, [0, 1, 2, 3, 0, 0, 0]);
+    }
+
+    #[test]
+    fn composite_glyphs() {
+        let glyphs = [
+            simple_glyph(),
+            simple_glyph(),
+            composite_glyph(&[(1, 10, 20), (1, -10, 0)]),
+            // Referencing itself
+            composite_glyph(&[(3, 0, 0), (1, 0, 0)]),
+        ];
+        let font = Font::parse(font(12, format12(&[]), &glyphs)).unwrap();
+        assert_eq!(
+            points(&font.outline(2, 0)),
+            [
+                [(10.0, 20.0), (110.0, 20.0), (10.0, 120.0)],
+                [(-10.0, 0.0), (90.0, 0.0), (-10.0, 100.0)],
+            ]
+        );
+        // The recursion stops instead of overflowing the stack, the glyphs deeper than the
+        // limit are left out
+        assert_eq!(font.outline(3, 0).len(), 8);
+    }
+
+    #[test]
+    fn truncated_fonts() {
+        let cmap = format12(&[(0x41, 0x42, 1)]);
+        let glyphs = [
+            simple_glyph(),
+            simple_glyph(),
+            composite_glyph(&[(1, 0, 0)]),
+        ];
+        let data = font(12, cmap, &glyphs);
+        let full = Font::parse(data.clone())
+            .unwrap()
+            .rasterize("AB", 20.0, 0.0);
+        assert!(full.coverage.iter().any(|&coverage| coverage > 0));
+        // Whatever is missing fails the parsing or leaves the glyphs out, without panicking
+        for len in 0..data.len() {
+            if let Ok(font) = Font::parse(data[..len].to_vec()) {
+                font.rasterize("AB", 20.0, 0.0);
+            }
+        }
+        assert!(Font::parse(data[..100].to_vec()).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use log::error;
+use smithay_client_toolkit::output::OutputInfo;
+use wpaper_core::exif;
+
+use crate::collection::Collection;
+use crate::index;
+use crate::output::{GroupMode, SortOrder};
+use crate::pipeline::Span;
+use crate::surface::{transformed_mode, Surface};
+
+/// Keeps the outputs of each group in lockstep: when the timer of an output expires, all the
+/// outputs of its group change image and restart their timers together, showing their new
+/// images on the same frame
+#[derive(Default)]
+pub struct Groups {
+    /// Position in the directory of the next image, for the sequential groups
+    positions: HashMap<String, usize>,
+}
+
+impl Groups {
+    pub fn synchronize(&mut self, surfaces: &mut [(u32, Surface)]) {
+        let mut expired_groups: Vec<String> = surfaces
+            .iter()
+            .filter(|(_, surface)| surface.timer.expired())
+            .filter_map(|(_, surface)| surface.output.group.clone())
+            .collect();
+        expired_groups.sort_unstable();
+        expired_groups.dedup();
+
+        for group in expired_groups {
+            let mut members: Vec<&mut Surface> = surfaces
+                .iter_mut()
+                .map(|(_, surface)| surface)
+                .filter(|surface| surface.output.group.as_ref() == Some(&group))
+                .collect();
+            members.sort_by(|a, b| a.info.name.cmp(&b.info.name));
+
+            let images = self.choose_images(&group, &mut members);
+            let spans = match (&images, members[0].output.group_mode) {
+                (Some(images), GroupMode::Span) => spans(&images[0], &members),
+                _ => None,
+            };
+            for (i, member) in members.into_iter().enumerate() {
+                member.expire(
+                    images.as_ref().map(|images| images[i].clone()),
+                    spans.as_ref().map(|spans| spans[i]),
+                );
+                member.synchronize();
+            }
+        }
+    }
+
+    /// The image of each member, None if they choose their own images
+    fn choose_images(&mut self, group: &str, members: &mut [&mut Surface]) -> Option<Vec<PathBuf>> {
+        let leader = &members[0].output;
+        if leader.group_mode == GroupMode::Independent {
+            return None;
+        }
+        let collection = if leader.rotates() {
+            match Collection::for_output(leader) {
+                Ok(mut collection) => {
+                    let (width, height) = members[0].dimensions();
+                    collection.prefer_aspect(leader.aspect(width, height));
+                    collection.prefer_unseen(leader.prefer_unseen);
+                    Some(collection)
+                }
+                Err(err) => {
+                    error!("{:?}", err);
+                    return None;
+                }
+            }
+        } else {
+            None
+        };
+        let mut files = match &collection {
+            Some(collection) => collection.images().to_vec(),
+            None => vec![leader.local_path()?],
+        };
+        if files.is_empty() {
+            return None;
+        }
+
+        Some(match leader.group_mode {
+            GroupMode::Same | GroupMode::Span => {
+                let recent: Vec<PathBuf> = members[0]
+                    .history()
+                    .take(leader.avoid_recent())
+                    .map(Path::to_path_buf)
+                    .collect();
+                // The queue of the first output is shared by the group
+                let image = match members[0].take_queued().or_else(|| {
+                    collection
+                        .as_ref()
+                        .and_then(|collection| collection.choose(&recent))
+                        .map(Path::to_path_buf)
+                }) {
+                    Some(image) => image,
+                    None => files.swap_remove(0),
+                };
+                vec![image; members.len()]
+            }
+            GroupMode::Sequential => {
+                match leader.sort {
+                    SortOrder::Name => files.sort(),
+                    SortOrder::CaptureTime => files.sort_by_cached_key(|file| {
+                        let capture_time = exif::capture_time(file);
+                        (capture_time.is_none(), capture_time, file.clone())
+                    }),
+                }
+                let position = self.positions.entry(group.to_string()).or_default();
+                let images = (0..members.len())
+                    .map(|i| files[(*position + i) % files.len()].clone())
+                    .collect();
+                *position = (*position + members.len()) % files.len();
+                images
+            }
+            GroupMode::Independent => return None,
+        })
+    }
+}
+
+/// The part of image shown by each member, when it is at least as wide or as tall as the
+/// layout of the members, within the aspect tolerance of the first one; None to show the
+/// whole image on each of them
+fn spans(image: &Path, members: &[&mut Surface]) -> Option<Vec<Span>> {
+    let rects = members
+        .iter()
+        .map(|member| logical_rect(&member.info))
+        .collect::<Option<Vec<_>>>()?;
+    let left = rects.iter().map(|rect| rect.0).fold(f32::MAX, f32::min);
+    let top = rects.iter().map(|rect| rect.1).fold(f32::MAX, f32::min);
+    let right = rects
+        .iter()
+        .map(|rect| rect.0 + rect.2)
+        .fold(f32::MIN, f32::max);
+    let bottom = rects
+        .iter()
+        .map(|rect| rect.1 + rect.3)
+        .fold(f32::MIN, f32::max);
+    let (width, height) = (right - left, bottom - top);
+
+    let (image_width, image_height) = index::read_dimensions(image)?;
+    let image_aspect = image_width as f32 / image_height as f32;
+    let layout_aspect = width / height;
+    let tolerance = members[0].output.aspect_tolerance();
+    // A panorama of a row of outputs, or a tall image for a column of them
+    let fits = if layout_aspect >= 1.0 {
+        image_aspect >= layout_aspect * (1.0 - tolerance)
+    } else {
+        image_aspect <= layout_aspect * (1.0 + tolerance)
+    };
+    // A single output, or outputs on top of each other, have nothing to split
+    let split = rects.iter().any(|rect| rect.2 < width || rect.3 < height);
+    (fits && split).then(|| {
+        rects
+            .iter()
+            .map(|&(x, y, rect_width, rect_height)| Span {
+                x: (x - left) / width,
+                y: (y - top) / height,
+                width: rect_width / width,
+                height: rect_height / height,
+            })
+            .collect()
+    })
+}
+
+/// Position and size of the output in the layout of the compositor, in logical pixels
+fn logical_rect(info: &OutputInfo) -> Option<(f32, f32, f32, f32)> {
+    let (width, height) = transformed_mode(info)?;
+    let scale = info.scale_factor.max(1) as f32;
+    (width > 0 && height > 0).then(|| {
+        (
+            info.location.0 as f32,
+            info.location.1 as f32,
+            width as f32 / scale,
+            height as f32 / scale,
+        )
+    })
+}
@@ -0,0 +1,27 @@
+use std::{path::Path, process::Command, thread};
+
+use log::{error, warn};
+
+/// Run command with sh, telling it which image is now displayed on output.
+/// The command runs in the background and is waited for in a separate thread.
+pub fn run_hook(command: &str, output: &str, image: &Path) {
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("WPAPERD_OUTPUT", output)
+        .env("WPAPERD_IMAGE", image)
+        .spawn();
+    match child {
+        Ok(mut child) => {
+            let command = command.to_string();
+            thread::spawn(move || match child.wait() {
+                Ok(status) if !status.success() => {
+                    warn!("command {:?} exited with {}", command, status)
+                }
+                Ok(_) => {}
+                Err(err) => error!("waiting for command {:?}: {:?}", command, err),
+            });
+        }
+        Err(err) => error!("running command {:?}: {:?}", command, err),
+    }
+}
@@ -0,0 +1,104 @@
+use std::{fs, path::Path};
+
+use smithay_client_toolkit::output::OutputInfo;
+
+use crate::seat;
+
+/// Tag of the display descriptor of the EDID holding the serial number as text
+const SERIAL_DESCRIPTOR: u8 = 0xff;
+
+/// How the configuration tells the displays apart, e.g. two monitors of the same model
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+    /// Name of the connector, e.g. DP-1
+    pub connector: String,
+    /// Make and model, e.g. "Dell Inc. DELL U2720Q"; empty when the compositor doesn't tell
+    pub model: String,
+    /// Serial number of the display, read from its EDID
+    pub serial: Option<String>,
+    /// Position among the connected displays of the same make and model, ordered by
+    /// connector, from 1
+    pub index: usize,
+}
+
+impl Identity {
+    /// The identity of the output described by info, among the connected outputs
+    pub fn new(info: &OutputInfo, connected: &[OutputInfo]) -> Self {
+        let model = model_of(info);
+        let mut same_model: Vec<&str> = connected
+            .iter()
+            .filter(|other| model_of(other) == model)
+            .map(|other| other.name.as_str())
+            .collect();
+        same_model.sort_unstable();
+        Self {
+            connector: info.name.clone(),
+            serial: read_serial(&info.name),
+            index: same_model
+                .iter()
+                .position(|connector| *connector == info.name)
+                .map_or(1, |i| i + 1),
+            model,
+        }
+    }
+
+    /// The names of the sections that configure the output, the most specific first: its
+    /// connector, its make, model and serial, its make and model followed by #index, and its
+    /// make and model
+    pub fn section_names(&self) -> Vec<String> {
+        let mut names = vec![self.connector.clone()];
+        if !self.model.is_empty() {
+            if let Some(serial) = &self.serial {
+                names.push(format!("{} {}", self.model, serial));
+            }
+            names.push(format!("{} #{}", self.model, self.index));
+            names.push(self.model.clone());
+        }
+        names
+    }
+}
+
+/// Whether the outputs described by a and b are displays of the same make and model
+pub fn same_model(a: &OutputInfo, b: &OutputInfo) -> bool {
+    a.id != b.id && !model_of(a).is_empty() && model_of(a) == model_of(b)
+}
+
+fn model_of(info: &OutputInfo) -> String {
+    format!("{} {}", info.make.trim(), info.model.trim())
+        .trim()
+        .to_string()
+}
+
+/// The serial number of the display plugged into connector, from the EDID the kernel exposes
+/// in sysfs. Displays of a nested compositor have none.
+fn read_serial(connector: &str) -> Option<String> {
+    let card = seat::card_of_output(connector)?;
+    let edid = fs::read(
+        Path::new(seat::DRM_DIR)
+            .join(format!("{}-{}", card, connector))
+            .join("edid"),
+    )
+    .ok()?;
+    edid_serial(&edid)
+}
+
+/// The serial number in an EDID: the text of its serial number descriptor, or else the
+/// number of its header, written like wlroots does
+fn edid_serial(edid: &[u8]) -> Option<String> {
+    if edid.len() < 128 {
+        return None;
+    }
+    // The four 18-byte descriptors of the base block; a display descriptor starts with
+    // three zeros and its tag
+    let text = edid[54..126]
+        .chunks_exact(18)
+        .find(|descriptor| descriptor[..3] == [0, 0, 0] && descriptor[3] == SERIAL_DESCRIPTOR)
+        .map(|descriptor| {
+            let text = &descriptor[5..];
+            let end = text.iter().position(|&c| c == b'\n').unwrap_or(text.len());
+            String::from_utf8_lossy(&text[..end]).trim().to_string()
+        })
+        .filter(|text| !text.is_empty());
+    let number = u32::from_le_bytes([edid[12], edid[13], edid[14], edid[15]]);
+    text.or_else(|| (number != 0).then(|| format!("0x{:08X}", number)))
+}
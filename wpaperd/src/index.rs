@@ -0,0 +1,331 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+use color_eyre::{eyre::WrapErr, Result};
+use lazy_static::lazy_static;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use wpaper_core::{exif, heic, svg};
+use xdg::BaseDirectories;
+
+use crate::file_list;
+use crate::sync::lock;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+lazy_static! {
+    /// The index of every directory opened so far, shared by all the outputs
+    static ref INDEXES: Mutex<HashMap<PathBuf, Index>> = Mutex::new(HashMap::new());
+}
+
+/// What is known about the images of a directory: a hash of their content, to recognize the
+/// duplicates, whether they could be decoded and their dimensions. It is kept in
+/// $XDG_CACHE_HOME/wpaper/index/<hash of the directory>.toml, so that it survives restarts.
+#[derive(Default, Deserialize, Serialize)]
+struct Index {
+    #[serde(flatten)]
+    files: HashMap<PathBuf, FileEntry>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct FileEntry {
+    size: u64,
+    /// Modification time, in nanoseconds since the epoch
+    modified: u64,
+    /// Hash of the content, only computed for the files that have the size of another one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    /// Set when the file could not be decoded
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    broken: bool,
+    /// Width and height once rotated according to the EXIF orientation, only read from the
+    /// header of the images when they are needed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dimensions: Option<(u32, u32)>,
+}
+
+/// Remove from images, which are all inside dir, the ones that could not be decoded and the
+/// duplicates of another one, keeping the first of each set of duplicates. Only the new
+/// images and the broken ones are checked, the others are trusted until the next rescan.
+pub fn filter(dir: &Path, images: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut indexes = lock(&INDEXES);
+    let index = indexes
+        .entry(dir.to_path_buf())
+        .or_insert_with(|| Index::load(dir));
+    if index.update(&images) {
+        if let Err(err) = index.save(dir) {
+            warn!("{:?}", err);
+        }
+    }
+    index.filter(images)
+}
+
+/// Skip image from now on, because it could not be decoded. It is tried again once it has
+/// been modified, or after a rescan.
+pub fn quarantine(image: &Path) {
+    let mut entry = match FileEntry::read(image) {
+        Ok(entry) => entry,
+        // It has been removed meanwhile, there is nothing to remember
+        Err(_) => return,
+    };
+    entry.broken = true;
+    let mut indexes = lock(&INDEXES);
+    // The deepest directory containing the image is the one it has been chosen from
+    let index = indexes
+        .iter_mut()
+        .filter(|(dir, _)| image.starts_with(dir))
+        .max_by_key(|(dir, _)| dir.components().count());
+    if let Some((dir, index)) = index {
+        warn!(
+            "{:?} is skipped until it is modified or wpaperctl rescan is run",
+            image
+        );
+        index.files.insert(image.to_path_buf(), entry);
+        if let Err(err) = index.save(dir) {
+            warn!("{:?}", err);
+        }
+    }
+}
+
+/// The dimensions of images, which are all inside dir and have been filtered already, None
+/// for those that cannot be read. They are read from the headers the first time.
+pub fn dimensions(dir: &Path, images: &[PathBuf]) -> Vec<Option<(u32, u32)>> {
+    let mut indexes = lock(&INDEXES);
+    let index = indexes
+        .entry(dir.to_path_buf())
+        .or_insert_with(|| Index::load(dir));
+    let mut changed = false;
+    let dimensions = images
+        .iter()
+        .map(|image| {
+            let entry = index.files.get_mut(image)?;
+            if entry.dimensions.is_none() {
+                entry.dimensions = read_dimensions(image);
+                changed |= entry.dimensions.is_some();
+            }
+            entry.dimensions
+        })
+        .collect();
+    if changed {
+        if let Err(err) = index.save(dir) {
+            warn!("{:?}", err);
+        }
+    }
+    dimensions
+}
+
+/// Forget everything known about the images of dir and index them again. Returns a summary
+/// of what has been found.
+pub fn rescan(dir: &Path) -> Result<String> {
+    file_list::invalidate(dir);
+    let images = file_list::list_images(dir)?;
+    let mut index = Index::default();
+    index.update(&images);
+    index.save(dir)?;
+    let unique = index.filter(images.clone()).len();
+    lock(&INDEXES).insert(dir.to_path_buf(), index);
+    Ok(format!(
+        "{}: {} images, {} duplicates",
+        dir.display(),
+        images.len(),
+        images.len() - unique
+    ))
+}
+
+fn index_path(dir: &Path) -> Result<PathBuf> {
+    let hash = stable_hash(dir.as_os_str().as_encoded_bytes());
+    BaseDirectories::with_prefix("wpaper")?
+        .place_cache_file(format!("index/{}.toml", hash))
+        .context("creating the cache directory")
+}
+
+impl Index {
+    /// Read the index of dir, starting from scratch if it cannot be read
+    fn load(dir: &Path) -> Self {
+        let res = index_path(dir).and_then(|path| match fs::read_to_string(&path) {
+            Ok(content) => {
+                toml::from_str(&content).with_context(|| format!("parsing index file {:?}", path))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err).with_context(|| format!("reading index file {:?}", path)),
+        });
+        res.unwrap_or_else(|err| {
+            warn!("{:?}", err);
+            Self::default()
+        })
+    }
+
+    fn save(&self, dir: &Path) -> Result<()> {
+        let path = index_path(dir)?;
+        // Other processes might read it meanwhile, replace it atomically
+        let tmp_path = path.with_extension(format!("toml.{}", std::process::id()));
+        fs::write(&tmp_path, toml::to_string(self)?)
+            .with_context(|| format!("writing index file {:?}", tmp_path))?;
+        fs::rename(&tmp_path, &path).with_context(|| format!("writing index file {:?}", path))
+    }
+
+    /// Add the new images and the broken ones that have been modified, forget the ones that
+    /// are gone and hash the images that have the same size. Returns true if anything
+    /// changed.
+    fn update(&mut self, images: &[PathBuf]) -> bool {
+        let listed: HashSet<&PathBuf> = images.iter().collect();
+        let len = self.files.len();
+        self.files.retain(|path, _| listed.contains(path));
+        let mut changed = self.files.len() != len;
+
+        for image in images {
+            if self.files.get(image).is_some_and(|entry| !entry.broken) {
+                continue;
+            }
+            let entry = match FileEntry::read(image) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    warn!("reading the metadata of {:?}: {}", image, err);
+                    continue;
+                }
+            };
+            let modified = self
+                .files
+                .get(image)
+                .is_none_or(|old| (old.size, old.modified) != (entry.size, entry.modified));
+            if modified {
+                self.files.insert(image.clone(), entry);
+                changed = true;
+            }
+        }
+
+        // Only files of the same size can be equal, the others are never read
+        let mut sizes: HashMap<u64, usize> = HashMap::new();
+        for entry in self.files.values() {
+            *sizes.entry(entry.size).or_default() += 1;
+        }
+        for (path, entry) in self.files.iter_mut() {
+            if entry.hash.is_none() && !entry.broken && sizes[&entry.size] > 1 {
+                match hash_file(path) {
+                    Ok(hash) => {
+                        entry.hash = Some(hash);
+                        changed = true;
+                    }
+                    Err(err) => warn!("reading {:?}: {}", path, err),
+                }
+            }
+        }
+        changed
+    }
+
+    fn filter(&self, images: Vec<PathBuf>) -> Vec<PathBuf> {
+        let mut seen = HashSet::new();
+        images
+            .into_iter()
+            .filter(|image| match self.files.get(image) {
+                Some(entry) if entry.broken => false,
+                Some(FileEntry {
+                    size,
+                    hash: Some(hash),
+                    ..
+                }) => seen.insert((*size, hash.clone())),
+                _ => true,
+            })
+            .collect()
+    }
+}
+
+impl FileEntry {
+    fn read(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |modified| modified.as_nanos() as u64);
+        Ok(Self {
+            size: metadata.len(),
+            modified,
+            hash: None,
+            broken: false,
+            dimensions: None,
+        })
+    }
+}
+
+/// The dimensions of the image, read from its header
+pub fn read_dimensions(path: &Path) -> Option<(u32, u32)> {
+    if svg::is_svg(path) {
+        return match svg::size(path) {
+            Ok((width, height)) => Some((width.round() as u32, height.round() as u32)),
+            Err(err) => {
+                warn!("{:?}", err);
+                None
+            }
+        };
+    }
+    if heic::is_heic(path) {
+        return match heic::size(path) {
+            Ok(size) => Some(size),
+            Err(err) => {
+                warn!("{:?}", err);
+                None
+            }
+        };
+    }
+    let (width, height) = match image::image_dimensions(path) {
+        Ok(dimensions) => dimensions,
+        Err(err) => {
+            warn!("reading the dimensions of {:?}: {}", path, err);
+            return None;
+        }
+    };
+    let orientation = exif::read(path).map_or(1, |exif| exif.orientation);
+    Some(if exif::swaps_dimensions(orientation) {
+        (height, width)
+    } else {
+        (width, height)
+    })
+}
+
+/// FNV-1a hash of data, as 16 hex digits. Unlike DefaultHasher it is stable across
+/// versions of Rust, for the hashes that are persisted, e.g. in the names of cache files.
+pub fn stable_hash(data: &[u8]) -> String {
+    format!("{:016x}", fnv1a(FNV_OFFSET_BASIS, data))
+}
+
+/// stable_hash() of the content of the file
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; 64 * 1024];
+    let mut hash = FNV_OFFSET_BASIS;
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hash = fnv1a(hash, &buf[..len]);
+    }
+    Ok(format!("{:016x}", hash))
+}
+
+/// Continue the FNV-1a hash with data
+fn fnv1a(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stable_hashes() {
+        // The names of the cache files depend on them, they must never change
+        assert_eq!(stable_hash(b""), "cbf29ce484222325");
+        assert_eq!(stable_hash(b"a"), "af63dc4c8601ec8c");
+        assert_eq!(stable_hash(b"foobar"), "85944171f73967e8");
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    env, fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use smithay_client_toolkit::{output::with_output_info, reexports::client::Display};
+use xdg::BaseDirectories;
+
+use crate::migration::CONFIG_VERSION;
+
+/// Ask a few questions and write a configuration file to config_file, optionally installing
+/// a systemd user unit that starts wpaperd
+pub fn run(config_file: &Path) -> Result<()> {
+    if config_file.exists()
+        && !ask_yes_no(
+            &format!("{} already exists, overwrite it?", config_file.display()),
+            false,
+        )?
+    {
+        return Ok(());
+    }
+
+    let outputs = detect_outputs().unwrap_or_else(|err| {
+        println!("Could not detect the outputs: {:#}", err);
+        Vec::new()
+    });
+    if !outputs.is_empty() {
+        println!("Detected outputs: {}", outputs.join(", "));
+    }
+
+    let default_path = env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join("Pictures/Wallpapers"))
+        .unwrap_or_default();
+    let path = loop {
+        let path = PathBuf::from(ask(
+            "Directory containing the wallpapers",
+            &default_path.to_string_lossy(),
+        )?);
+        if path.is_dir() {
+            break path;
+        }
+        println!("{} is not a directory", path.display());
+    };
+    let duration = loop {
+        let duration = ask("Change the wallpaper every (e.g. 30s, 5m, 1h30m)", "30m")?;
+        match humantime::parse_duration(&duration) {
+            Ok(duration) if !duration.is_zero() => break duration,
+            _ => println!("{} is not a valid duration", duration),
+        }
+    };
+    let per_output = outputs.len() > 1
+        && ask_yes_no(
+            "Write a section for each output, so that they can be configured separately?",
+            false,
+        )?;
+
+    let sections = if per_output { &outputs[..] } else { &[] };
+    // e.g. "1h 30m" is written as "1h30m"
+    let duration = humantime::format_duration(duration)
+        .to_string()
+        .replace(' ', "");
+    let config = config_content(&path, &duration, sections);
+    if let Some(parent) = config_file.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("creating the directory {:?}", parent))?;
+    }
+    fs::write(config_file, config)
+        .with_context(|| format!("writing configuration file {:?}", config_file))?;
+    println!("Configuration written to {}", config_file.display());
+
+    if ask_yes_no(
+        "Install a systemd user unit to start wpaperd on login?",
+        false,
+    )? {
+        let unit_file = install_systemd_unit()?;
+        println!(
+            "Unit written to {}, enable it with:\n\n    systemctl --user daemon-reload\n    \
+             systemctl --user enable --now wpaperd.service",
+            unit_file.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Names of the outputs currently connected
+fn detect_outputs() -> Result<Vec<String>> {
+    let display = Display::connect_to_env().context("connecting to the wayland display")?;
+    let mut queue = display.create_event_queue();
+    let env = crate::new_environment(&display, &mut queue)?;
+    let mut outputs: Vec<String> = env
+        .get_all_outputs()
+        .iter()
+        .filter_map(|output| with_output_info(output, |info| info.name.clone()))
+        .collect();
+    outputs.sort();
+    Ok(outputs)
+}
+
+fn config_content(path: &Path, duration: &str, outputs: &[String]) -> String {
+    let section = |name: &str| {
+        format!(
+            "[{}]\n\
+             # Image or directory of images to display\n\
+             path = {}\n\
+             # How long each image is displayed, only valid when path is a directory\n\
+             duration = \"{}\"\n",
+            name,
+            toml::Value::String(path.to_string_lossy().into_owned()),
+            duration
+        )
+    };
+    let mut content = String::from(
+        "# Configuration of wpaperd, see `wpaperd schema` for all the keys.\n\
+         # Each section configures an output; the section default is used for the outputs\n\
+         # that are not listed.\n\n",
+    );
+    content.push_str(&format!("version = {}\n\n", CONFIG_VERSION));
+    content.push_str(&section("default"));
+    for output in outputs {
+        content.push('\n');
+        content.push_str(&section(output));
+    }
+    content
+}
+
+fn install_systemd_unit() -> Result<PathBuf> {
+    let exe = env::current_exe().context("finding the wpaperd executable")?;
+    let unit_file = BaseDirectories::new()?
+        .place_config_file("systemd/user/wpaperd.service")
+        .context("creating the systemd user directory")?;
+    let unit = format!(
+        "[Unit]\n\
+         Description=Wallpaper daemon for Wayland\n\
+         PartOf=graphical-session.target\n\
+         After=graphical-session.target\n\n\
+         [Service]\n\
+         ExecStart={} --no-daemon\n\
+         Restart=on-failure\n\n\
+         [Install]\n\
+         WantedBy=graphical-session.target\n",
+        exe.display()
+    );
+    fs::write(&unit_file, unit).with_context(|| format!("writing {:?}", unit_file))?;
+    Ok(unit_file)
+}
+
+/// Print prompt and read the answer from the terminal
+fn read_answer(prompt: &str) -> Result<String> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer)? == 0 {
+        bail!("the input has been closed");
+    }
+    Ok(answer.trim().to_string())
+}
+
+/// Ask question, returning default when nothing is typed
+fn ask(question: &str, default: &str) -> Result<String> {
+    let answer = read_answer(&format!("{} [{}]: ", question, default))?;
+    Ok(if answer.is_empty() {
+        default.to_string()
+    } else {
+        answer
+    })
+}
+
+fn ask_yes_no(question: &str, default: bool) -> Result<bool> {
+    let choices = if default { "Y/n" } else { "y/N" };
+    loop {
+        let answer = read_answer(&format!("{} [{}]: ", question, choices))?;
+        match answer.to_lowercase().as_str() {
+            "" => return Ok(default),
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => println!("Please answer yes or no"),
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    os::unix::io::AsRawFd,
+    thread,
+    time::{Duration, Instant},
+};
+
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Result,
+};
+use log::info;
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+
+/// How long --replace waits for the running instance to exit; it fades its outputs out
+/// for up to 3 seconds first
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(10);
+const REPLACE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Returned when another wpaperd serves the display and seat, which is not a crash
+#[derive(Debug)]
+pub struct AlreadyRunning(Option<i32>);
+
+impl fmt::Display for AlreadyRunning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "wpaperd is already running on this display")?;
+        if let Some(pid) = self.0 {
+            write!(f, " (pid {})", pid)?;
+        }
+        write!(f, ", use --replace to take over")
+    }
+}
+
+impl std::error::Error for AlreadyRunning {}
+
+/// Makes sure that a single wpaperd serves a wayland display and seat: the lock file next
+/// to the socket is locked for as long as the instance runs, and holds its pid. The lock
+/// is released by the kernel when the process exits, even if it crashes.
+pub struct InstanceLock(File);
+
+impl InstanceLock {
+    /// Lock the instance. If another wpaperd holds it, fail, or with replace, ask it to exit
+    /// with SIGTERM and wait for it.
+    pub fn acquire(replace: bool) -> Result<Self> {
+        let path = wpaper_ipc::lock_path().context("getting the lock file path")?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("opening the lock file {:?}", path))?;
+        let mut lock = Self(file);
+        if lock.try_lock()? {
+            return Ok(lock);
+        }
+        let pid = lock.pid();
+        if !replace {
+            return Err(AlreadyRunning(pid).into());
+        }
+        let pid = pid.ok_or_else(|| eyre!("the running wpaperd has not written its pid yet"))?;
+        info!("asking the running wpaperd (pid {}) to exit", pid);
+        match kill(Pid::from_raw(pid), Signal::SIGTERM) {
+            // It has exited meanwhile
+            Ok(()) | Err(Errno::ESRCH) => {}
+            Err(err) => return Err(err).context("stopping the running wpaperd"),
+        }
+        let start = Instant::now();
+        while !lock.try_lock()? {
+            if start.elapsed() >= REPLACE_TIMEOUT {
+                bail!(
+                    "the running wpaperd (pid {}) has not exited after {}s",
+                    pid,
+                    REPLACE_TIMEOUT.as_secs()
+                );
+            }
+            thread::sleep(REPLACE_POLL_INTERVAL);
+        }
+        Ok(lock)
+    }
+
+    /// Record the pid of the process, to be called once detached
+    pub fn write_pid(&mut self) -> Result<()> {
+        self.0.set_len(0)?;
+        self.0.seek(SeekFrom::Start(0))?;
+        writeln!(self.0, "{}", std::process::id()).context("writing the pid to the lock file")
+    }
+
+    /// Returns false if another process holds the lock
+    fn try_lock(&self) -> Result<bool> {
+        match flock(self.0.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => Ok(true),
+            Err(Errno::EWOULDBLOCK) => Ok(false),
+            Err(err) => Err(err).context("locking the lock file"),
+        }
+    }
+
+    /// The pid written by the process holding the lock
+    fn pid(&mut self) -> Option<i32> {
+        let mut content = String::new();
+        self.0.seek(SeekFrom::Start(0)).ok()?;
+        self.0.read_to_string(&mut content).ok()?;
+        content.trim().parse().ok()
+    }
+}
@@ -0,0 +1,568 @@
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+use calloop::{
+    channel::{self, Channel},
+    generic::Generic,
+    Interest, LoopHandle, Mode, PostAction,
+};
+use color_eyre::{
+    eyre::{bail, ensure, eyre, WrapErr},
+    Result,
+};
+use log::error;
+use wpaper_ipc::{IpcEvent, IpcMessage, IpcResponse};
+
+use crate::config::Config;
+use crate::index;
+use crate::metrics;
+use crate::palette::to_hex;
+use crate::surface::Surface;
+use crate::thumbnails::{self, ThumbnailSize};
+use crate::votes::Votes;
+
+/// Longest request accepted, batches included
+const MAX_REQUEST_LEN: u64 = 1024 * 1024;
+/// How long a client can take to send each part of its request
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+/// Most images whose thumbnails can be asked for at once, besides the current one
+const MAX_THUMBNAILS: usize = 100;
+
+/// Bind the socket used by wpaperctl, replacing any stale socket left by a previous instance
+pub fn listen_on_ipc_socket(socket_path: &Path) -> Result<UnixListener> {
+    if socket_path.exists() {
+        fs::remove_file(socket_path)
+            .with_context(|| format!("removing the stale socket {:?}", socket_path))?;
+    }
+    let listener = UnixListener::bind(socket_path)
+        .with_context(|| format!("binding the socket {:?}", socket_path))?;
+    listener
+        .set_nonblocking(true)
+        .context("setting the socket as non blocking")?;
+    Ok(listener)
+}
+
+/// Accept the clients of listener in the event loop. Each request is read on a thread of its
+/// own, so that a client slow to send it doesn't hold up the outputs, and given to
+/// on_request once complete.
+pub fn accept_requests<D: 'static>(
+    handle: &LoopHandle<D>,
+    listener: UnixListener,
+    on_request: fn(&mut D, IpcMessage, UnixStream),
+) -> Result<()> {
+    let (tx, rx): (_, Channel<(IpcMessage, UnixStream)>) = channel::channel();
+    handle
+        .insert_source(rx, move |event, _, data| {
+            if let channel::Event::Msg((message, stream)) = event {
+                on_request(data, message, stream);
+            }
+        })
+        .map_err(io::Error::from)
+        .context("adding the requests to the event loop")?;
+    handle
+        .insert_source(
+            Generic::new(listener, Interest::READ, Mode::Level),
+            move |_, listener, _| {
+                loop {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            let tx = tx.clone();
+                            thread::spawn(move || {
+                                if let Some(message) = read_message(&stream) {
+                                    // The daemon is exiting if the loop is gone
+                                    let _ = tx.send((message, stream));
+                                }
+                            });
+                        }
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(err) => return Err(err),
+                    }
+                }
+                Ok(PostAction::Continue)
+            },
+        )
+        .map_err(io::Error::from)
+        .context("adding the socket to the event loop")?;
+    Ok(())
+}
+
+/// Read the request sent by a client, up to MAX_REQUEST_LEN bytes. Invalid requests are
+/// answered right away and None is returned.
+fn read_message(stream: &UnixStream) -> Option<IpcMessage> {
+    let mut line = String::new();
+    let res = stream.set_read_timeout(Some(READ_TIMEOUT)).and_then(|_| {
+        // A byte more than allowed tells that the request is too long
+        let mut reader = BufReader::new(stream.take(MAX_REQUEST_LEN + 1));
+        reader.read_line(&mut line)?;
+        // The requests of a batch follow, until the client closes its side
+        if line.trim_end() == "batch" {
+            line.truncate("batch".len());
+            line.push('\n');
+            reader.read_to_string(&mut line)?;
+        }
+        Ok(())
+    });
+    if let Err(err) = res {
+        error!("reading a request: {:?}", err);
+        return None;
+    }
+    if line.len() as u64 > MAX_REQUEST_LEN {
+        send_response(
+            stream,
+            IpcResponse::Error(format!(
+                "the request is longer than {} bytes",
+                MAX_REQUEST_LEN
+            )),
+        );
+        return None;
+    }
+
+    match line.parse() {
+        Ok(message) => Some(message),
+        Err(err) => {
+            send_response(stream, IpcResponse::Error(err.to_string()));
+            None
+        }
+    }
+}
+
+pub fn send_response(stream: &UnixStream, response: IpcResponse) {
+    if let Err(err) = response.write_to(stream) {
+        error!("{:?}", err);
+    }
+}
+
+/// Clients that subscribed to the events
+#[derive(Default)]
+pub struct Subscribers(Vec<UnixStream>);
+
+impl Subscribers {
+    pub fn add(&mut self, stream: UnixStream) {
+        send_response(&stream, IpcResponse::Ok(Vec::new()));
+        // A client that doesn't read its events must not block the daemon
+        if let Err(err) = stream.set_write_timeout(Some(Duration::from_millis(100))) {
+            error!("{:?}", err);
+            return;
+        }
+        self.0.push(stream);
+    }
+
+    /// Send event to every subscriber, forgetting the ones that have disconnected
+    pub fn notify(&mut self, event: IpcEvent) {
+        self.0
+            .retain(|mut stream| writeln!(stream, "{}", event).is_ok());
+    }
+}
+
+pub fn handle_message(
+    message: IpcMessage,
+    surfaces: &mut [(u32, Surface)],
+    votes: &mut Votes,
+    config: &Mutex<Config>,
+) -> IpcResponse {
+    match handle_message_impl(message, surfaces, votes, config) {
+        Ok(lines) => IpcResponse::Ok(lines),
+        Err(err) => IpcResponse::Error(format!("{:#}", err)),
+    }
+}
+
+fn handle_message_impl(
+    message: IpcMessage,
+    surfaces: &mut [(u32, Surface)],
+    votes: &mut Votes,
+    config: &Mutex<Config>,
+) -> Result<Vec<String>> {
+    Ok(match message {
+        IpcMessage::ContestStart { output, count } => {
+            let len = find_surface(surfaces, &output)?.start_contest(count)?;
+            vec![format!("{} images shortlisted on {}", len, output)]
+        }
+        IpcMessage::ContestStop { output } => {
+            let surface = find_surface(surfaces, &output)?;
+            ensure!(
+                surface.stop_contest(),
+                "no contest is running on {}",
+                output
+            );
+            Vec::new()
+        }
+        IpcMessage::Vote { output, accept } => {
+            let surface = find_surface(surfaces, &output)?;
+            ensure!(surface.in_contest(), "no contest is running on {}", output);
+            let image = surface
+                .current_image()
+                .ok_or_else(|| eyre!("no image is displayed on {}", output))?;
+            votes.reload()?;
+            votes.vote(image, accept);
+            votes.save()?;
+            if surface.next_contest_candidate() {
+                Vec::new()
+            } else {
+                vec![format!("the contest on {} is over", output)]
+            }
+        }
+        IpcMessage::ContestResults => {
+            votes.reload()?;
+            votes
+                .ranked()
+                .into_iter()
+                .map(|(image, image_votes)| {
+                    format!(
+                        "{:+} (+{} -{}) {}",
+                        image_votes.score(),
+                        image_votes.accepted,
+                        image_votes.rejected,
+                        image.display()
+                    )
+                })
+                .collect()
+        }
+        IpcMessage::RecordStart {
+            output,
+            interval,
+            framerate,
+            path,
+        } => {
+            find_surface(surfaces, &output)?.start_recording(&path, interval, framerate)?;
+            vec![format!("recording {} into {:?}", output, path)]
+        }
+        IpcMessage::RecordStop { output } => {
+            ensure!(
+                find_surface(surfaces, &output)?.stop_recording(),
+                "{} is not being recorded",
+                output
+            );
+            Vec::new()
+        }
+        IpcMessage::PreviousWallpaper { output } => {
+            for surface in unpinned_surfaces(surfaces, output.as_deref())? {
+                ensure!(
+                    surface.previous_wallpaper(),
+                    "there is no previous wallpaper for {}",
+                    surface.info.name
+                );
+                if output.is_none() {
+                    surface.synchronize();
+                }
+            }
+            Vec::new()
+        }
+        IpcMessage::NextWallpaper { output } => {
+            for surface in unpinned_surfaces(surfaces, output.as_deref())? {
+                surface.next_wallpaper();
+                // All the outputs change on the same frame
+                if output.is_none() {
+                    surface.synchronize();
+                }
+            }
+            Vec::new()
+        }
+        IpcMessage::History { output, count } => find_surface(surfaces, &output)?
+            .history()
+            .take(count)
+            .map(|image| image.display().to_string())
+            .collect(),
+        IpcMessage::Current { output, verbose } => {
+            let mut lines = Vec::new();
+            let all = output.is_none();
+            for surface in selected_surfaces(surfaces, output.as_deref())? {
+                let image = match surface.current_image() {
+                    Some(image) => image.display(),
+                    None if all => continue,
+                    None => bail!("no image is displayed on {}", surface.info.name),
+                };
+                lines.push(if all {
+                    format!("{} {}", surface.info.name, image)
+                } else {
+                    image.to_string()
+                });
+                if let Some(attribution) = surface.attribution().filter(|_| verbose) {
+                    lines.extend(
+                        attribution
+                            .describe()
+                            .into_iter()
+                            .map(|line| format!("  {}", line)),
+                    );
+                }
+            }
+            lines
+        }
+        IpcMessage::Palette { output } => find_surface(surfaces, &output)?
+            .palette()
+            .ok_or_else(|| eyre!("no image is displayed on {}", output))?
+            .iter()
+            .map(to_hex)
+            .collect(),
+        IpcMessage::Frame { output } => {
+            let path = find_surface(surfaces, &output)?
+                .export_frame()
+                .with_context(|| format!("exporting the frame of {}", output))?;
+            vec![path.display().to_string()]
+        }
+        IpcMessage::ToggleInversion { output } => {
+            for surface in selected_surfaces(surfaces, output.as_deref())? {
+                surface.toggle_inversion();
+            }
+            Vec::new()
+        }
+        IpcMessage::SetWallpaper { output, path } => {
+            for surface in unpinned_surfaces(surfaces, output.as_deref())? {
+                surface.set_wallpaper(&path)?;
+            }
+            Vec::new()
+        }
+        IpcMessage::Preview {
+            output,
+            path,
+            duration,
+        } => {
+            for surface in unpinned_surfaces(surfaces, output.as_deref())? {
+                surface.preview(&path, duration)?;
+            }
+            Vec::new()
+        }
+        IpcMessage::Pause { output } => {
+            for surface in selected_surfaces(surfaces, output.as_deref())? {
+                surface.pause();
+            }
+            Vec::new()
+        }
+        IpcMessage::Resume { output } => {
+            for surface in selected_surfaces(surfaces, output.as_deref())? {
+                surface.resume();
+            }
+            Vec::new()
+        }
+        IpcMessage::Pin { output, image } => {
+            find_surface(surfaces, &output)?.pin(image.as_deref())?;
+            Vec::new()
+        }
+        IpcMessage::Unpin { output } => {
+            ensure!(
+                find_surface(surfaces, &output)?.unpin(),
+                "{} is not pinned",
+                output
+            );
+            Vec::new()
+        }
+        IpcMessage::Outputs => surfaces
+            .iter()
+            .map(|(_, surface)| {
+                // The most specific name of a section for the display, after its connector
+                match surface.identity.section_names().get(1) {
+                    Some(name) => format!("{} id={:?}", surface.describe(), name),
+                    None => surface.describe(),
+                }
+            })
+            .collect(),
+        IpcMessage::GetSettings { output } => {
+            // The settings of an output that is not connected can be read as well
+            let settings = match find_surface(surfaces, &output) {
+                Ok(surface) => Config::lock(config)?.get_output(&surface.identity),
+                Err(_) => Config::lock(config)?.get_output_by_name(&output),
+            };
+            toml::to_string(&*settings)?
+                .lines()
+                .map(str::to_string)
+                .collect()
+        }
+        IpcMessage::SetSettings {
+            output,
+            settings,
+            runtime: true,
+        } => {
+            // The output is updated in the next iteration of the main loop
+            Config::lock(config)?.set_runtime_settings(&output, &settings)?;
+            Vec::new()
+        }
+        IpcMessage::SetSettings {
+            output, settings, ..
+        } => {
+            // The configuration is reloaded, and the output updated, once the file is written
+            Config::lock(config)?.set_output_settings(&output, &settings)?;
+            Vec::new()
+        }
+        IpcMessage::Queue { output, count } => find_surface(surfaces, &output)?
+            .queue(count)?
+            .into_iter()
+            .map(|(image, pinned)| {
+                format!("{} {}", if pinned { "*" } else { " " }, image.display())
+            })
+            .collect(),
+        IpcMessage::QueueSkip { output, image } => {
+            find_surface(surfaces, &output)?.queue_skip(image.as_deref())?;
+            Vec::new()
+        }
+        IpcMessage::QueuePin {
+            output,
+            pinned,
+            image,
+        } => {
+            find_surface(surfaces, &output)?.queue_pin(&image, pinned)?;
+            Vec::new()
+        }
+        IpcMessage::QueueFront { output, image } => {
+            find_surface(surfaces, &output)?.queue_front(&image)?;
+            Vec::new()
+        }
+        IpcMessage::Subscribe => bail!("subscriptions are handled by the main loop"),
+        IpcMessage::Thumbnails { .. } => bail!("thumbnails are handled by the main loop"),
+        IpcMessage::SetProfile { name } => {
+            // Every output is updated at once, in the next iteration of the main loop
+            Config::lock(config)?.set_profile(name.as_deref())?;
+            Vec::new()
+        }
+        IpcMessage::SetAnimations { enabled } => {
+            // Every output is updated at once, in the next iteration of the main loop
+            Config::lock(config)?.animations_override = Some(enabled);
+            Vec::new()
+        }
+        IpcMessage::Profiles => {
+            let config = Config::lock(config)?;
+            config
+                .profiles()
+                .into_iter()
+                .map(|profile| {
+                    let marker = if config.profile() == Some(profile) {
+                        "*"
+                    } else {
+                        " "
+                    };
+                    format!("{} {}", marker, profile)
+                })
+                .collect()
+        }
+        IpcMessage::Rescan => {
+            let mut dirs: Vec<PathBuf> = Config::lock(config)?.dirs().into_iter().collect();
+            dirs.sort_unstable();
+            dirs.iter()
+                .map(|dir| index::rescan(dir))
+                .collect::<Result<_>>()?
+        }
+        IpcMessage::Metrics => metrics::render(),
+        IpcMessage::Batch(messages) => {
+            // Check the outputs first, so that a typo doesn't leave the batch half applied.
+            // The settings can be changed for outputs that are not connected.
+            for message in &messages {
+                if let (Some(output), false) = (
+                    message.output(),
+                    matches!(
+                        message,
+                        IpcMessage::GetSettings { .. } | IpcMessage::SetSettings { .. }
+                    ),
+                ) {
+                    find_surface(surfaces, output)?;
+                }
+            }
+            let mut lines = Vec::new();
+            for (i, message) in messages.into_iter().enumerate() {
+                lines.extend(
+                    handle_message_impl(message, surfaces, votes, config)
+                        .with_context(|| format!("request {} of the batch", i + 1))?,
+                );
+            }
+            // The outputs getting a new image show it on the same frame
+            for (_, surface) in surfaces.iter_mut() {
+                surface.synchronize();
+            }
+            lines
+        }
+        IpcMessage::SetDuration { output, duration } => {
+            ensure!(!duration.is_zero(), "the duration must be greater than 0");
+            for surface in selected_surfaces(surfaces, output.as_deref())? {
+                surface.set_duration(duration)?;
+            }
+            Vec::new()
+        }
+    })
+}
+
+/// Answer a Thumbnails request. The images are picked right away, their thumbnails are then
+/// generated in the background and sent to the client once they are all ready.
+pub fn send_thumbnails(
+    surfaces: &mut [(u32, Surface)],
+    output: &str,
+    count: usize,
+    size: &str,
+    stream: UnixStream,
+) {
+    let res = (|| -> Result<_> {
+        ensure!(
+            count <= MAX_THUMBNAILS,
+            "at most {} thumbnails can be asked for",
+            MAX_THUMBNAILS
+        );
+        let size: ThumbnailSize = size.parse()?;
+        let surface = find_surface(surfaces, output)?;
+        let mut images: Vec<PathBuf> = surface
+            .current_image()
+            .map(Path::to_path_buf)
+            .into_iter()
+            .collect();
+        if surface.output.rotates() {
+            images.extend(surface.queue(count)?.into_iter().map(|(image, _)| image));
+        }
+        Ok((images, size))
+    })();
+    let (images, size) = match res {
+        Ok(res) => res,
+        Err(err) => return send_response(&stream, IpcResponse::Error(format!("{:#}", err))),
+    };
+    thumbnails::generate(images.clone(), size, move |thumbnails| {
+        let response = match thumbnails {
+            Ok(thumbnails) => IpcResponse::Ok(
+                images
+                    .iter()
+                    .zip(thumbnails)
+                    .map(|(image, thumbnail)| {
+                        format!("{}\t{}", image.display(), thumbnail.display())
+                    })
+                    .collect(),
+            ),
+            Err(err) => IpcResponse::Error(format!("{:#}", err)),
+        };
+        send_response(&stream, response);
+    });
+}
+
+/// The surface of output, or all of them when output is None
+fn selected_surfaces<'a>(
+    surfaces: &'a mut [(u32, Surface)],
+    output: Option<&str>,
+) -> Result<Vec<&'a mut Surface>> {
+    match output {
+        Some(output) => Ok(vec![find_surface(surfaces, output)?]),
+        None => Ok(surfaces.iter_mut().map(|(_, surface)| surface).collect()),
+    }
+}
+
+/// Like selected_surfaces, for the requests that change the image: the pinned outputs are
+/// left alone, and asking for one of them is an error
+fn unpinned_surfaces<'a>(
+    surfaces: &'a mut [(u32, Surface)],
+    output: Option<&str>,
+) -> Result<Vec<&'a mut Surface>> {
+    let surfaces = selected_surfaces(surfaces, output)?;
+    if let (Some(output), Some(pinned)) = (output, surfaces[0].pinned()) {
+        bail!("{} is pinned to {:?}, unpin it first", output, pinned);
+    }
+    Ok(surfaces
+        .into_iter()
+        .filter(|surface| surface.pinned().is_none())
+        .collect())
+}
+
+fn find_surface<'a>(surfaces: &'a mut [(u32, Surface)], name: &str) -> Result<&'a mut Surface> {
+    surfaces
+        .iter_mut()
+        .map(|(_, surface)| surface)
+        .find(|surface| surface.info.name == name)
+        .ok_or_else(|| eyre!("output {} not found", name))
+}
@@ -0,0 +1,146 @@
+use std::time::Instant;
+
+use color_eyre::{eyre::WrapErr, Result};
+use image::GenericImageView;
+use log::error;
+use smithay_client_toolkit::reexports::client::protocol::wl_shm;
+
+use crate::output::{Layer, ScalingFilter};
+use crate::pipeline::decode_image;
+
+/// The images stacked over the wallpaper of an output, in order, loaded at the size of the
+/// output. The drifting ones move slowly across it, wrapping around its edges, like the
+/// layers of a parallax.
+pub struct LayerStack {
+    settings: Vec<Layer>,
+    width: u32,
+    height: u32,
+    layers: Vec<LoadedLayer>,
+    start: Instant,
+}
+
+struct LoadedLayer {
+    /// Red, green, blue and alpha for each pixel, multiplied by the alpha and the opacity
+    data: Vec<u8>,
+    drift: [f32; 2],
+}
+
+impl LayerStack {
+    /// Decode and scale layers to cover width x height. The layers that cannot be read are
+    /// skipped, so that the others are still shown.
+    pub fn load(layers: &[Layer], width: u32, height: u32, filter: ScalingFilter) -> Self {
+        let loaded = layers
+            .iter()
+            .filter_map(|layer| {
+                load_layer(layer, width, height, filter)
+                    .map_err(|err| error!("{:?}", err))
+                    .ok()
+            })
+            .collect();
+        Self {
+            settings: layers.to_vec(),
+            width,
+            height,
+            layers: loaded,
+            start: Instant::now(),
+        }
+    }
+
+    /// Whether these are layers as configured, loaded for an output of width x height
+    pub fn matches(&self, layers: &[Layer], width: u32, height: u32) -> bool {
+        self.settings == layers && (self.width, self.height) == (width, height)
+    }
+
+    /// Whether any of the layers moves, which needs a new frame regularly
+    pub fn is_moving(&self) -> bool {
+        self.layers.iter().any(|layer| layer.drift != [0.0, 0.0])
+    }
+
+    /// Draw the layers over data, a frame in format, where they currently are. Without
+    /// animations, they stay where they start.
+    pub fn apply(&self, data: &mut [u8], format: wl_shm::Format, animations: bool) {
+        let elapsed = if animations {
+            self.start.elapsed().as_secs_f32()
+        } else {
+            0.0
+        };
+        let (width, height) = (self.width as usize, self.height as usize);
+        for layer in &self.layers {
+            let offset = |drift: f32, size: usize| {
+                ((drift * elapsed).round() as i64).rem_euclid(size as i64) as usize
+            };
+            let (offset_x, offset_y) = (
+                offset(layer.drift[0], width),
+                offset(layer.drift[1], height),
+            );
+            for (y, row) in data.chunks_exact_mut(width * 4).enumerate() {
+                let src_y = (y + height - offset_y) % height;
+                let src_row = &layer.data[src_y * width * 4..(src_y + 1) * width * 4];
+                for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                    let src_x = (x + width - offset_x) % width;
+                    let src = &src_row[src_x * 4..src_x * 4 + 4];
+                    if src[3] != 0 {
+                        blend(pixel, src, format);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn load_layer(
+    layer: &Layer,
+    width: u32,
+    height: u32,
+    filter: ScalingFilter,
+) -> Result<LoadedLayer> {
+    let image = decode_image(&layer.path, width, height)
+        .with_context(|| format!("loading the layer {:?}", layer.path))?;
+    let scale = f32::max(
+        width as f32 / image.width() as f32,
+        height as f32 / image.height() as f32,
+    );
+    let filter = filter.filter_type(scale, u64::from(width) * u64::from(height));
+    let image = if (image.width(), image.height()) == (width, height) {
+        image
+    } else {
+        image.resize_to_fill(width, height, filter)
+    };
+    let mut data = image.into_rgba8().into_raw();
+    let opacity = layer.opacity();
+    for pixel in data.chunks_exact_mut(4) {
+        let alpha = f32::from(pixel[3]) / 255.0 * opacity;
+        for component in &mut pixel[..3] {
+            *component = (f32::from(*component) * alpha).round() as u8;
+        }
+        pixel[3] = (alpha * 255.0).round() as u8;
+    }
+    Ok(LoadedLayer {
+        data,
+        drift: layer.drift.unwrap_or_default(),
+    })
+}
+
+/// Draw src, premultiplied, over pixel in format
+fn blend(pixel: &mut [u8], src: &[u8], format: wl_shm::Format) {
+    let transparency = u32::from(255 - src[3]);
+    match format {
+        wl_shm::Format::Xbgr2101010 => {
+            let value = u32::from_le_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]);
+            let blended = (0..3).fold(0, |acc, channel| {
+                let shift = channel * 10;
+                let component = (value >> shift) & 0x3ff;
+                let src = (u32::from(src[channel]) * 1023 + 127) / 255;
+                acc | ((src + (component * transparency + 127) / 255).min(0x3ff) << shift)
+            });
+            pixel.copy_from_slice(&blended.to_le_bytes());
+        }
+        // The pixels of the frame are premultiplied as well
+        _ => {
+            for (component, &src) in pixel.iter_mut().zip(src) {
+                *component = (u32::from(src) + (u32::from(*component) * transparency + 127) / 255)
+                    .min(255) as u8;
+            }
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use color_eyre::{eyre::WrapErr, Result};
+use image::{
+    codecs::png::{CompressionType, FilterType, PngEncoder},
+    ColorType,
+};
+use log::error;
+use smithay_client_toolkit::reexports::client::protocol::wl_shm;
+use xdg::BaseDirectories;
+
+/// Where the image displayed on output is exported for screen lockers,
+/// i.e. $XDG_RUNTIME_DIR/wpaper/lock/<output>.png
+fn lock_image_path(output: &str) -> Result<PathBuf> {
+    let xdg_dirs = BaseDirectories::with_prefix("wpaper")?;
+    xdg_dirs
+        .place_runtime_file(format!("lock/{}.png", output))
+        .context("creating the lock images directory")
+}
+
+/// Where the frame on screen of output is exported when asked for,
+/// i.e. $XDG_RUNTIME_DIR/wpaper/frame/<output>.png
+fn frame_path(output: &str) -> Result<PathBuf> {
+    let xdg_dirs = BaseDirectories::with_prefix("wpaper")?;
+    xdg_dirs
+        .place_runtime_file(format!("frame/{}.png", output))
+        .context("creating the frames directory")
+}
+
+/// Save the pixels of a buffer, in the given format, as the lock image of output.
+/// Encoding runs in a separate thread; the file is replaced atomically so that a locker
+/// starting meanwhile never reads a partial image.
+pub fn export_lock_image(
+    output: &str,
+    width: u32,
+    height: u32,
+    format: wl_shm::Format,
+    data: Vec<u8>,
+) {
+    let output = output.to_string();
+    thread::spawn(move || {
+        let res = lock_image_path(&output).and_then(|path| {
+            save_png(
+                &path,
+                width,
+                height,
+                format,
+                &data,
+                CompressionType::Default,
+            )
+        });
+        if let Err(err) = res {
+            error!("exporting the lock image of {}: {:?}", output, err);
+        }
+    });
+}
+
+/// Save the frame on screen of output, the pixels of its buffer in the given format, and
+/// return the path of the file. The client is waiting for it, so it is compressed quickly,
+/// at the expense of its size.
+pub fn export_frame(
+    output: &str,
+    width: u32,
+    height: u32,
+    format: wl_shm::Format,
+    data: &[u8],
+) -> Result<PathBuf> {
+    let path = frame_path(output)?;
+    save_png(&path, width, height, format, data, CompressionType::Fast)?;
+    Ok(path)
+}
+
+/// Write the pixels as a PNG to path, replacing it atomically
+fn save_png(
+    path: &Path,
+    width: u32,
+    height: u32,
+    format: wl_shm::Format,
+    data: &[u8],
+    compression: CompressionType,
+) -> Result<()> {
+    let tmp_path = path.with_extension("png.tmp");
+    let file = File::create(&tmp_path).with_context(|| format!("creating {:?}", tmp_path))?;
+    let encoder = PngEncoder::new_with_quality(BufWriter::new(file), compression, FilterType::Sub);
+    match format {
+        wl_shm::Format::Xbgr2101010 => {
+            // PNG stores 16-bit channels in big endian
+            let pixels: Vec<u8> = data
+                .chunks_exact(4)
+                .flat_map(|pixel| {
+                    let value = u32::from_le_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]);
+                    // Scale the 10-bit channels back to 16 bits
+                    (0..3).flat_map(move |channel| {
+                        let component = ((value >> (channel * 10)) & 0x3ff) as u16;
+                        ((component << 6) | (component >> 4)).to_be_bytes()
+                    })
+                })
+                .collect();
+            encoder.encode(&pixels, width, height, ColorType::Rgb16)
+        }
+        _ => encoder.encode(data, width, height, ColorType::Rgba8),
+    }
+    .with_context(|| format!("writing {:?}", tmp_path))?;
+    fs::rename(&tmp_path, path).with_context(|| format!("renaming {:?}", tmp_path))
+}
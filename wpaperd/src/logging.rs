@@ -0,0 +1,223 @@
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{eyre::WrapErr, Result};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use simplelog::{
+    ColorChoice, CombinedLogger, Config, SharedLogger, TermLogger, TerminalMode, WriteLogger,
+};
+
+/// When the log file grows larger than this, it is moved to <file>.1 and a new one is started
+const MAX_LOG_SIZE: u64 = 1024 * 1024;
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Where the logs are written and how detailed they are
+pub struct LogOptions<'a> {
+    /// Number of -v flags
+    pub verbosity: u64,
+    pub terminal: bool,
+    pub file: Option<&'a Path>,
+    pub journald: bool,
+}
+
+pub fn init(options: LogOptions) -> Result<()> {
+    let level = level(options.verbosity);
+    let mut loggers: Vec<Box<dyn SharedLogger>> = Vec::new();
+    if options.terminal {
+        loggers.push(TermLogger::new(
+            level,
+            Config::default(),
+            TerminalMode::Mixed,
+            ColorChoice::Auto,
+        ));
+    }
+    if let Some(path) = options.file {
+        loggers.push(WriteLogger::new(
+            level,
+            Config::default(),
+            RotatingFile::open(path)?,
+        ));
+    }
+    if options.journald {
+        loggers.push(Box::new(JournaldLogger::connect(level)?));
+    }
+    CombinedLogger::init(loggers).context("initializing the logger")
+}
+
+/// The log file of the process serving output, when the supervisor logs to path: the name of
+/// the output is appended to the name of the file, e.g. wpaperd-DP-1.log
+pub fn output_log_file(path: &Path, output: &str) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push("-");
+    name.push(output);
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+/// Each -v shows one more level of details. Without them, RUST_LOG can set the level by name.
+fn level(verbosity: u64) -> LevelFilter {
+    match verbosity {
+        0 => env::var("RUST_LOG")
+            .ok()
+            .and_then(|level| level.parse().ok())
+            .unwrap_or(LevelFilter::Warn),
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// A log file that keeps a single older copy, so that it never grows without limit
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("opening the log file {:?}", path))?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut old_path = self.path.clone().into_os_string();
+        old_path.push(".1");
+        fs::rename(&self.path, old_path)?;
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > MAX_LOG_SIZE {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Sends every record to the systemd journal with its priority and source location, so that
+/// they can be filtered with journalctl
+struct JournaldLogger {
+    level: LevelFilter,
+    socket: UnixDatagram,
+}
+
+impl JournaldLogger {
+    fn connect(level: LevelFilter) -> Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket
+            .connect(JOURNALD_SOCKET)
+            .context("connecting to the systemd journal")?;
+        Ok(Self { level, socket })
+    }
+}
+
+/// Append a field using the native journal protocol; values spanning multiple lines are
+/// prefixed by their length
+fn push_field(message: &mut Vec<u8>, name: &str, value: &str) {
+    message.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        message.push(b'\n');
+        message.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        message.push(b'=');
+    }
+    message.extend_from_slice(value.as_bytes());
+    message.push(b'\n');
+}
+
+impl Log for JournaldLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let priority = match record.level() {
+            Level::Error => "3",
+            Level::Warn => "4",
+            Level::Info => "6",
+            Level::Debug | Level::Trace => "7",
+        };
+        let mut message = Vec::new();
+        push_field(&mut message, "MESSAGE", &record.args().to_string());
+        push_field(&mut message, "PRIORITY", priority);
+        push_field(&mut message, "SYSLOG_IDENTIFIER", "wpaperd");
+        push_field(&mut message, "TARGET", record.target());
+        if let Some(file) = record.file() {
+            push_field(&mut message, "CODE_FILE", file);
+        }
+        if let Some(line) = record.line() {
+            push_field(&mut message, "CODE_LINE", &line.to_string());
+        }
+        // There is nowhere to report a failure to log
+        let _ = self.socket.send(&message);
+    }
+
+    fn flush(&self) {}
+}
+
+impl SharedLogger for JournaldLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_output_has_its_own_log_file() {
+        for (path, expected) in [
+            (
+                "/state/wpaper/wpaperd.log",
+                "/state/wpaper/wpaperd-DP-1.log",
+            ),
+            ("/tmp/wpaperd", "/tmp/wpaperd-DP-1"),
+            ("/tmp/wpaperd.debug.log", "/tmp/wpaperd.debug-DP-1.log"),
+        ] {
+            assert_eq!(
+                output_log_file(Path::new(path), "DP-1"),
+                PathBuf::from(expected)
+            );
+        }
+    }
+}
@@ -1,25 +1,79 @@
+mod attribution;
+mod bench;
+mod buffers;
+mod check;
+mod collection;
 mod config;
+mod contest;
+mod crash_report;
+mod day_night;
+mod dmabuf;
+mod duplicates;
+mod file_list;
+mod font;
+mod groups;
+mod hook;
+mod identity;
+mod index;
+mod init;
+mod instance;
+mod ipc_server;
+mod layers;
+mod lock_screen;
+mod logging;
+mod memory;
+mod metrics;
+mod migration;
+mod mounts;
+mod night;
 mod output;
 mod output_timer;
+mod overlay;
+mod palette;
+mod pipeline;
+mod pointer;
+mod power;
+mod queue;
+mod recorder;
+mod remote;
+mod schema;
+mod seat;
+mod state;
+mod supervisor;
 mod surface;
+mod sync;
+mod thermal;
+mod thumbnails;
+mod views;
+mod votes;
+mod workspaces;
 
 use std::{
     cell::RefCell,
     collections::HashMap,
-    path::PathBuf,
+    env,
+    ffi::OsString,
+    fs::{self, OpenOptions},
+    io,
+    os::unix::{io::AsRawFd, net::UnixStream},
+    path::{Path, PathBuf},
     process::exit,
     rc::Rc,
     sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
 };
 
-use calloop::channel::Sender;
+use calloop::signals::{Signal, Signals};
+use chrono::Local;
 use clap::Parser;
-use color_eyre::{eyre::WrapErr, Result};
+use color_eyre::{
+    eyre::{ensure, WrapErr},
+    Result,
+};
 use hotwatch::{Event, Hotwatch};
-use log::error;
-use nix::unistd::fork;
-use output_timer::OutputTimer;
-use simplelog::{ColorChoice, LevelFilter, TermLogger, TerminalMode};
+use log::{error, info, warn};
+use nix::unistd::{dup2, fork, setsid};
 use smithay_client_toolkit::{
     environment,
     environment::SimpleGlobal,
@@ -33,22 +87,48 @@ use smithay_client_toolkit::{
             protocol::{
                 wl_compositor::WlCompositor,
                 wl_output::{self, WlOutput},
-                wl_shm::WlShm,
+                wl_seat::WlSeat,
+                wl_shm::{self, WlShm},
             },
-            DispatchData, Display,
+            Attached, DispatchData, Display, EventQueue,
         },
         protocols::{
+            unstable::linux_dmabuf::v1::client::zwp_linux_dmabuf_v1,
             unstable::xdg_output::v1::client::zxdg_output_manager_v1,
+            viewporter::client::wp_viewporter,
             wlr::unstable::layer_shell::v1::client::zwlr_layer_shell_v1,
+            xdg_shell::client::xdg_wm_base,
         },
     },
-    shm::ShmHandler,
+    seat::{SeatData, SeatHandler, SeatHandling, SeatListener},
+    shell::{Shell, ShellHandler, ShellHandling},
+    shm::{ShmHandler, ShmHandling},
     WaylandSource,
 };
+use wpaper_core::heic;
+use wpaper_core::selection;
+use wpaper_ipc::{IpcEvent, IpcMessage};
 use xdg::BaseDirectories;
 
+use crate::buffers::Buffers;
 use crate::config::Config;
+use crate::dmabuf::{DmabufBuffers, DmabufHandler, Gbm};
+use crate::file_list::{DirWatcher, ImageWatcher};
+use crate::groups::Groups;
+use crate::identity::Identity;
+use crate::instance::{AlreadyRunning, InstanceLock};
+use crate::ipc_server::Subscribers;
+use crate::logging::LogOptions;
+use crate::memory::Memory;
+use crate::night::NightMode;
+use crate::output::Output;
+use crate::pointer::{PointerEvent, Pointers};
+use crate::power::Power;
+use crate::state::State;
 use crate::surface::Surface;
+use crate::thermal::Thermal;
+use crate::votes::Votes;
+use crate::workspaces::WorkspaceEvent;
 
 struct Env {
     compositor: SimpleGlobal<WlCompositor>,
@@ -56,6 +136,10 @@ struct Env {
     shm: ShmHandler,
     xdg_output: XdgOutputHandler,
     layer_shell: SimpleGlobal<zwlr_layer_shell_v1::ZwlrLayerShellV1>,
+    viewporter: SimpleGlobal<wp_viewporter::WpViewporter>,
+    linux_dmabuf: DmabufHandler,
+    shell: ShellHandler,
+    seats: SeatHandler,
 }
 
 environment!(Env,
@@ -63,10 +147,14 @@ environment!(Env,
         WlCompositor => compositor,
         WlShm => shm,
         zwlr_layer_shell_v1::ZwlrLayerShellV1 => layer_shell,
+        wp_viewporter::WpViewporter => viewporter,
+        zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1 => linux_dmabuf,
         zxdg_output_manager_v1::ZxdgOutputManagerV1 => xdg_output,
+        xdg_wm_base::XdgWmBase => shell,
     ],
     multis = [
         WlOutput => outputs,
+        WlSeat => seats,
     ]
 );
 
@@ -79,6 +167,27 @@ impl OutputHandling for Env {
     }
 }
 
+impl SeatHandling for Env {
+    fn listen<F>(&mut self, f: F) -> SeatListener
+    where
+        F: FnMut(Attached<WlSeat>, &SeatData, DispatchData) + 'static,
+    {
+        self.seats.listen(f)
+    }
+}
+
+impl ShellHandling for Env {
+    fn get_shell(&self) -> Option<Shell> {
+        self.shell.get_shell()
+    }
+}
+
+impl ShmHandling for Env {
+    fn shm_formats(&self) -> Vec<wl_shm::Format> {
+        self.shm.shm_formats()
+    }
+}
+
 #[derive(Parser)]
 #[clap(
     author = "Danilo Spinella <danilo.spinella@suse.com>",
@@ -94,45 +203,434 @@ struct Opts {
         help = "Stay in foreground, do not detach"
     )]
     no_daemon: bool,
+    #[clap(
+        long = "no-state",
+        help = "Do not save nor restore the displayed wallpapers across restarts"
+    )]
+    no_state: bool,
+    #[clap(
+        long = "per-output-process",
+        help = "Serve each output from a separate process, restarted when it crashes"
+    )]
+    per_output_process: bool,
+    #[clap(
+        short,
+        long,
+        parse(from_occurrences),
+        help = "Log more details, repeat it to log even more (-vv, -vvv)"
+    )]
+    verbose: u64,
+    #[clap(
+        long,
+        help = "Write the logs to this file, keeping a single older copy in <file>.1; when detached, defaults to the state directory"
+    )]
+    log_file: Option<PathBuf>,
+    #[clap(long, help = "Send the logs to the systemd journal")]
+    journald: bool,
+    #[clap(
+        long,
+        help = "Seed the random choices of images, so that they can be reproduced"
+    )]
+    seed: Option<u64>,
+    #[clap(
+        long,
+        help = "Start with this profile of the configuration file, see wpaperctl profile"
+    )]
+    profile: Option<String>,
+    #[clap(
+        long,
+        help = "Only serve the outputs of this seat, e.g. seat1, and name the socket after it so that an instance can run for each seat"
+    )]
+    seat: Option<String>,
+    #[clap(
+        long,
+        help = "Check the configuration file, including that every section has images to show, and exit"
+    )]
+    check_config: bool,
+    #[clap(
+        long,
+        help = "Check the configuration file, print the wallpaper each connected output would show and exit, without drawing anything"
+    )]
+    dry_run: bool,
+    #[clap(
+        long,
+        help = "Share the frames with the compositor through GPU buffers (linux-dmabuf and GBM) instead of shared memory, when both are available"
+    )]
+    dmabuf: bool,
+    #[clap(
+        long,
+        help = "Time the decoding, scaling, conversion, filters and buffer write of this image, with the settings of the default section, and exit"
+    )]
+    bench: Option<PathBuf>,
+    #[clap(
+        long = "bench-size",
+        multiple_occurrences = true,
+        help = "Size of the output for --bench, e.g. 2560x1440; repeat it to time several sizes [default: 1920x1080, 2560x1440 and 3840x2160]"
+    )]
+    bench_sizes: Vec<String>,
+    #[clap(
+        long,
+        default_value = "10",
+        help = "How many times --bench runs each stage for each size"
+    )]
+    bench_iterations: u32,
+    #[clap(
+        long,
+        help = "Ask the wpaperd running on the same display and seat to exit, and take over"
+    )]
+    replace: bool,
+    /// Used by --per-output-process to start the process of each output
+    #[clap(long, hide = true)]
+    output: Option<String>,
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-fn get_timer_closure(surface_timer: Arc<Mutex<OutputTimer>>, tx: Sender<()>) -> impl Fn() {
-    move || {
-        if surface_timer.lock().unwrap().check_timeout() {
-            tx.send(()).unwrap();
-        }
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Print the keys accepted in the configuration file
+    Schema {
+        #[clap(
+            long,
+            help = "Print a JSON Schema, for editors and configuration tools"
+        )]
+        json: bool,
+    },
+    /// Create the configuration file by answering a few questions
+    Init,
+}
+
+/// How often to check whether the compositor is back after the connection has been lost
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+/// After this, a compositor that has not come back is considered gone for good
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(120);
+/// Longest time spent exiting, fading the outputs out and finishing the recordings
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Data shared by the event sources and the main loop
+#[derive(Default)]
+struct LoopData {
+    /// Set when the daemon has been asked to terminate
+    should_exit: bool,
+    /// Requests received from wpaperctl that are waiting to be handled
+    ipc_requests: Vec<(IpcMessage, UnixStream)>,
+    /// Changes of the workspaces shown on the outputs
+    workspace_events: Vec<WorkspaceEvent>,
+    /// Moves of the pointers over the outputs
+    pointer_events: Vec<PointerEvent>,
+    /// SIGHUP, SIGUSR1 and SIGUSR2, received since the last iteration
+    signals: Vec<Signal>,
+}
+
+fn new_environment(
+    display: &Display,
+    queue: &mut EventQueue,
+) -> Result<environment::Environment<Env>> {
+    let (outputs, xdg_output) =
+        smithay_client_toolkit::output::XdgOutputHandler::new_output_handlers();
+    environment::Environment::new(
+        &display.attach(queue.token()),
+        queue,
+        Env {
+            compositor: SimpleGlobal::new(),
+            outputs,
+            shm: ShmHandler::new(),
+            xdg_output,
+            layer_shell: SimpleGlobal::new(),
+            viewporter: SimpleGlobal::new(),
+            linux_dmabuf: DmabufHandler::default(),
+            shell: ShellHandler::new(),
+            seats: SeatHandler::new(),
+        },
+    )
+    .context("initializing the wayland environment")
+}
+
+/// The names of the outputs of the compositor, only those of seat if given. Nothing is drawn
+/// on them.
+fn connected_outputs(seat: Option<&str>) -> Result<Vec<OutputInfo>> {
+    let display = Display::connect_to_env().context("connecting to the wayland display")?;
+    let mut queue = display.create_event_queue();
+    let env = new_environment(&display, &mut queue)?;
+    // The names of the outputs come with their xdg-output, in a later round
+    queue
+        .sync_roundtrip(&mut (), |_, _, _| {})
+        .context("reading the outputs")?;
+    let mut outputs: Vec<OutputInfo> = output_infos(&env, None)
+        .into_iter()
+        .filter(|info| seat.is_none_or(|seat| seat::is_on_seat(&info.name, seat)))
+        .collect();
+    outputs.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    Ok(outputs)
+}
+
+/// The outputs of the compositor, those served by other processes and seats included. The
+/// information of skip cannot be read while it is being updated.
+fn output_infos(env: &environment::Environment<Env>, skip: Option<&WlOutput>) -> Vec<OutputInfo> {
+    env.get_all_outputs()
+        .iter()
+        .filter(|output| skip != Some(*output))
+        .filter_map(|output| with_output_info(output, Clone::clone))
+        .filter(|info| !info.obsolete)
+        .collect()
+}
+
+/// Detach from the terminal: the parent exits right away, while the child starts a new
+/// session with the standard streams redirected to /dev/null
+fn daemonize() -> Result<()> {
+    match unsafe { fork()? } {
+        nix::unistd::ForkResult::Parent { child: _ } => exit(0),
+        nix::unistd::ForkResult::Child => {}
+    }
+    setsid().context("starting a new session")?;
+    let dev_null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .context("opening /dev/null")?;
+    for fd in 0..3 {
+        dup2(dev_null.as_raw_fd(), fd).context("redirecting the standard streams")?;
     }
+    Ok(())
 }
 
 fn main() -> Result<()> {
     color_eyre::install()?;
-    TermLogger::init(
-        LevelFilter::Warn,
-        simplelog::Config::default(),
-        TerminalMode::Mixed,
-        ColorChoice::Auto,
-    )?;
+    crash_report::install_panic_hook();
 
     let opts = Opts::parse();
+    // The problems found by a check are reported on the terminal, they are not crashes
+    let checking = opts.check_config || opts.dry_run || opts.bench.is_some();
+    let res = run(opts);
+    // Neither is another instance running
+    let crashed = res
+        .as_ref()
+        .is_err_and(|err| !checking && !err.is::<AlreadyRunning>());
+    if let (Err(err), true) = (&res, crashed) {
+        // When detached, the logs are the only place where the error can be seen
+        error!("{:#}", err);
+        crash_report::write(&format!("{:?}", err));
+    }
+    res
+}
+
+fn run(opts: Opts) -> Result<()> {
+    match opts.command {
+        Some(Command::Schema { json }) => {
+            let schema = if json {
+                schema::json_schema()?
+            } else {
+                schema::text_schema()?
+            };
+            print!("{}", schema);
+            return Ok(());
+        }
+        Some(Command::Init) => {
+            let config_file = match opts.config {
+                Some(config_file) => config_file,
+                None => BaseDirectories::with_prefix("wpaper")?.get_config_file("wpaperd.conf"),
+            };
+            return init::run(&config_file);
+        }
+        None => {}
+    }
+
+    if let Some(seat) = &opts.seat {
+        // Used for the names of the sockets, by this process and its children
+        env::set_var(wpaper_ipc::SEAT_VAR, seat);
+    }
+    // The files of the instances of different seats are kept apart
+    let instance_file = |name: &str, extension: &str| match &opts.seat {
+        Some(seat) => format!("{}-{}.{}", name, seat, extension),
+        None => format!("{}.{}", name, extension),
+    };
+
+    if let Some(image) = &opts.bench {
+        let sizes = if opts.bench_sizes.is_empty() {
+            bench::DEFAULT_SIZES.to_vec()
+        } else {
+            opts.bench_sizes
+                .iter()
+                .map(|size| bench::parse_size(size))
+                .collect::<Result<_>>()?
+        };
+        let config_file = match &opts.config {
+            Some(config_file) => Some(config_file.clone()),
+            None => BaseDirectories::with_prefix("wpaper")?.find_config_file("wpaperd.conf"),
+        };
+        // Without a configuration file, the defaults are timed
+        let output = match config_file {
+            Some(config_file) => {
+                let mut config = Config::new_from_path(&config_file)?;
+                config.set_profile(opts.profile.as_deref())?;
+                (*config.get_output_by_name("default")).clone()
+            }
+            None => Output::default(),
+        };
+        return bench::run(image, &output, &sizes, opts.bench_iterations);
+    }
 
+    if opts.check_config || opts.dry_run {
+        let xdg_dirs = BaseDirectories::with_prefix("wpaper")?;
+        let config_file = match &opts.config {
+            Some(config_file) => config_file.clone(),
+            None => xdg_dirs.get_config_file("wpaperd.conf"),
+        };
+        let mut config = Config::new_from_path(&config_file)?;
+        config.set_profile(opts.profile.as_deref())?;
+        check::check_config(&config)?;
+        if opts.dry_run {
+            // The state is only read, a missing one means that nothing is restored
+            let state_file = xdg_dirs
+                .find_state_file(instance_file("wpaperd", "state"))
+                .filter(|_| !opts.no_state);
+            let state = State::new_from_path(state_file.as_deref())?;
+            let outputs = connected_outputs(opts.seat.as_deref())?;
+            check::dry_run(&config, &state, &outputs);
+        }
+        return Ok(());
+    }
+
+    // Once detached nothing is printed anymore, so the logs go to a file unless they are
+    // sent to the journal
+    let log_file = match opts.log_file.clone() {
+        Some(log_file) => Some(log_file),
+        None if !opts.no_daemon && !opts.journald => Some(
+            BaseDirectories::with_prefix("wpaper")?
+                .place_state_file(instance_file("wpaperd", "log"))
+                .context("creating the state directory")?,
+        ),
+        None => None,
+    };
+    // Taken before detaching, so that the error is printed on the terminal. The processes
+    // of the outputs run under the lock of their supervisor.
+    let mut instance_lock = match opts.output {
+        Some(_) => None,
+        None => Some(InstanceLock::acquire(opts.replace)?),
+    };
     if !opts.no_daemon {
-        match unsafe { fork()? } {
-            nix::unistd::ForkResult::Parent { child: _ } => exit(0),
-            nix::unistd::ForkResult::Child => {}
+        daemonize()?;
+    }
+    logging::init(LogOptions {
+        verbosity: opts.verbose,
+        terminal: opts.no_daemon,
+        file: log_file.as_deref(),
+        journald: opts.journald,
+    })?;
+    if let Some(instance_lock) = &mut instance_lock {
+        instance_lock.write_pid()?;
+    }
+
+    if let Some(seed) = opts.seed {
+        selection::set_seed(seed);
+    }
+
+    if opts.per_output_process {
+        let mut child_args = vec![OsString::from("--no-daemon")];
+        if opts.verbose > 0 {
+            child_args.push(format!("-{}", "v".repeat(opts.verbose as usize)).into());
+        }
+        if opts.journald {
+            child_args.push("--journald".into());
+        }
+        if let Some(seed) = opts.seed {
+            child_args.push("--seed".into());
+            child_args.push(seed.to_string().into());
+        }
+        if let Some(profile) = &opts.profile {
+            child_args.push("--profile".into());
+            child_args.push(profile.into());
         }
+        if let Some(config_file) = &opts.config {
+            child_args.push("--config".into());
+            child_args.push(config_file.into());
+        }
+        if opts.no_state {
+            child_args.push("--no-state".into());
+        }
+        if let Some(seat) = &opts.seat {
+            child_args.push("--seat".into());
+            child_args.push(seat.into());
+        }
+        if opts.dmabuf {
+            child_args.push("--dmabuf".into());
+        }
+        return supervisor::run(child_args, log_file.as_deref(), opts.seat.as_deref());
     }
 
+    let xdg_dirs =
+        BaseDirectories::with_prefix("wpaper").context("finding the XDG base directories")?;
     let config_file = if let Some(config_file) = opts.config {
         config_file
     } else {
-        let xdg_dirs = BaseDirectories::with_prefix("wpaper").unwrap();
-        xdg_dirs.place_config_file("wpaperd.conf").unwrap()
+        xdg_dirs
+            .place_config_file("wpaperd.conf")
+            .context("creating the configuration directory")?
+    };
+    let mut config = Config::new_from_path(&config_file)?;
+    config.set_profile(opts.profile.as_deref())?;
+    config.reloaded = false;
+    for warning in &config.warnings {
+        warn!("{}", warning);
+    }
+    let config = Arc::new(Mutex::new(config));
+    let state_file = if opts.no_state {
+        None
+    } else {
+        Some(
+            xdg_dirs
+                .place_state_file(match &opts.output {
+                    // Each process has its own state, so that they don't overwrite each other
+                    Some(output) => instance_file(&format!("wpaperd-{}", output), "state"),
+                    None => instance_file("wpaperd", "state"),
+                })
+                .context("creating the state directory")?,
+        )
     };
-    let config = Arc::new(Mutex::new(Config::new_from_path(&config_file)?));
-    let display = Display::connect_to_env().unwrap();
+    let state = Arc::new(Mutex::new(State::new_from_path(state_file.as_deref())?));
+    let votes_file = xdg_dirs
+        .place_data_file("votes.toml")
+        .context("creating the data directory")?;
+    let mut votes = Votes::new_from_path(Some(&votes_file))?;
+    let mut subscribers = Subscribers::default();
+    loop {
+        let served = serve(
+            opts.output.as_deref(),
+            opts.seat.as_deref(),
+            opts.dmabuf,
+            &config,
+            &state,
+            &mut votes,
+            &mut subscribers,
+        )?;
+        match served {
+            Served::Exit => return Ok(()),
+            Served::Disconnected => wait_for_compositor()?,
+        }
+    }
+}
+
+enum Served {
+    /// wpaperd has been asked to exit
+    Exit,
+    /// The compositor went away, e.g. because it has been restarted
+    Disconnected,
+}
+
+/// Draw the wallpapers of the outputs of the compositor until wpaperd is asked to exit or
+/// the connection is lost. Everything tied to the connection is created here, so that it
+/// can be created again after reconnecting; the images shown are restored from the state.
+fn serve(
+    only_output: Option<&str>,
+    only_seat: Option<&str>,
+    dmabuf: bool,
+    config: &Arc<Mutex<Config>>,
+    state: &Arc<Mutex<State>>,
+    votes: &mut Votes,
+    subscribers: &mut Subscribers,
+) -> Result<Served> {
+    let display = Display::connect_to_env().context("connecting to the wayland display")?;
     let mut queue = display.create_event_queue();
-    let (outputs, xdg_output) =
-        smithay_client_toolkit::output::XdgOutputHandler::new_output_handlers();
 
     struct Status {
         env: environment::Environment<Env>,
@@ -140,54 +638,132 @@ fn main() -> Result<()> {
     }
 
     let status = Rc::new(Status {
-        env: smithay_client_toolkit::environment::Environment::new(
-            &display.attach(queue.token()),
-            &mut queue,
-            Env {
-                compositor: SimpleGlobal::new(),
-                outputs,
-                shm: ShmHandler::new(),
-                xdg_output,
-                layer_shell: SimpleGlobal::new(),
-            },
-        )
-        .unwrap(),
+        env: new_environment(&display, &mut queue)?,
         surfaces: RefCell::new(Vec::new()),
     });
 
     let env = &status.env;
 
-    let layer_shell = env.require_global::<zwlr_layer_shell_v1::ZwlrLayerShellV1>();
+    let layer_shell = env.get_global::<zwlr_layer_shell_v1::ZwlrLayerShellV1>();
+    let viewporter = env.get_global::<wp_viewporter::WpViewporter>();
+    if layer_shell.is_none() {
+        ensure!(
+            env.get_shell().is_some(),
+            "the compositor supports neither layer shell nor xdg shell"
+        );
+        warn!("the compositor does not support layer shell, using fullscreen windows instead");
+    }
+    let gbm = if dmabuf {
+        match (
+            Gbm::open(),
+            env.get_global::<zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1>(),
+        ) {
+            (Ok(gbm), Some(_)) => Some(Rc::new(gbm)),
+            (Err(err), _) => {
+                warn!("{:?}, using shared memory", err);
+                None
+            }
+            (_, None) => {
+                warn!("the compositor does not support linux-dmabuf, using shared memory");
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let (ev_tx, ev_rx) = calloop::channel::channel();
 
     let config_clone = config.clone();
+    let state_clone = state.clone();
+    let ev_tx_clone = ev_tx.clone();
     let status_rc = status.clone();
+    let only_output_clone = only_output.map(str::to_string);
+    let only_seat_clone = only_seat.map(str::to_string);
+    // Events for the subscribers that happen outside of the main loop
+    let pending_events = Rc::new(RefCell::new(Vec::new()));
+    let pending_events_clone = pending_events.clone();
     let output_handler = move |output: wl_output::WlOutput, info: &OutputInfo| {
         if info.obsolete {
             // an output has been removed, release it
-            status_rc
-                .surfaces
-                .borrow_mut()
-                .retain(|(i, _)| *i != info.id);
+            let mut surfaces = status_rc.surfaces.borrow_mut();
+            let len = surfaces.len();
+            surfaces.retain(|(i, _)| *i != info.id);
+            if surfaces.len() != len {
+                // The displays of the same model left are numbered again
+                if surfaces
+                    .iter()
+                    .any(|(_, surface)| identity::same_model(&surface.info, info))
+                {
+                    match Config::lock(&config_clone) {
+                        Ok(mut config) => config.reloaded = true,
+                        Err(err) => error!("{:?}", err),
+                    }
+                }
+                crash_report::output_removed(&info.name);
+                metrics::output_removed(&info.name);
+                pending_events_clone
+                    .borrow_mut()
+                    .push(IpcEvent::OutputRemoved {
+                        output: info.name.clone(),
+                    });
+            }
             output.release();
+        } else if matches!(&only_output_clone, Some(name) if *name != info.name) {
+            // Another process is serving this output
+        } else if matches!(&only_seat_clone, Some(seat) if !seat::is_on_seat(&info.name, seat)) {
+            // The instance of another seat is serving this output
         } else {
             // an output has been created, construct a surface for it
             let surface = status_rc.env.create_surface().detach();
-            let pool = status_rc
-                .env
-                .create_auto_pool()
-                .expect("failed to create a memory pool!");
-            let config = config_clone.lock().unwrap();
-            (*status_rc.surfaces.borrow_mut()).push((
-                info.id,
-                Surface::new(
-                    &output,
-                    surface,
-                    &layer_shell.clone(),
-                    info.clone(),
-                    pool,
-                    config.get_output_by_name(&info.name),
-                ),
-            ));
+            let dmabuf = gbm.clone().and_then(|gbm| {
+                let formats = status_rc.env.with_inner(|env| env.linux_dmabuf.formats());
+                DmabufBuffers::new(gbm, status_rc.env.require_global(), formats)
+                    .map_err(|err| warn!("{:?}, using shared memory", err))
+                    .ok()
+            });
+            let buffers = Buffers::new(status_rc.env.require_global(), surface.clone(), dmabuf);
+            let mut connected = output_infos(&status_rc.env, Some(&output));
+            connected.push(info.clone());
+            let identity = Identity::new(info, &connected);
+            let mut config = match Config::lock(&config_clone) {
+                Ok(config) => config,
+                Err(err) => {
+                    error!("adding {}: {:?}", info.name, err);
+                    return;
+                }
+            };
+            // The displays of the same model already there may be numbered differently now
+            if status_rc
+                .surfaces
+                .borrow()
+                .iter()
+                .any(|(_, surface)| identity::same_model(&surface.info, info))
+            {
+                config.reloaded = true;
+            }
+            let mut surface = Surface::new(
+                &output,
+                surface,
+                &status_rc.env,
+                layer_shell.as_ref(),
+                viewporter.as_ref(),
+                info.clone(),
+                identity.clone(),
+                buffers,
+                config.get_output(&identity),
+                ev_tx_clone.clone(),
+            );
+            if let Some(output_state) = state_clone.lock().unwrap().get_output_by_name(&info.name) {
+                surface.restore_state(output_state);
+            }
+            (*status_rc.surfaces.borrow_mut()).push((info.id, surface));
+            crash_report::output_added(info);
+            pending_events_clone
+                .borrow_mut()
+                .push(IpcEvent::OutputAdded {
+                    output: info.name.clone(),
+                });
         }
     };
 
@@ -203,68 +779,212 @@ fn main() -> Result<()> {
     let _listner_handle =
         env.listen_for_outputs(move |output, info, _| output_handler(output, info));
 
-    let mut event_loop = calloop::EventLoop::<()>::try_new()?;
+    // The outputs with a parallax follow the pointers
+    let _pointers = Pointers::watch(env, |event, mut ddata| {
+        if let Some(data) = ddata.get::<LoopData>() {
+            data.pointer_events.push(event);
+        }
+    });
+
+    let mut event_loop = calloop::EventLoop::<LoopData>::try_new()?;
 
     WaylandSource::new(queue)
         .quick_insert(event_loop.handle())
-        .unwrap();
+        .map_err(io::Error::from)
+        .context("adding the wayland connection to the event loop")?;
 
-    let (ev_tx, ev_rx) = calloop::channel::channel();
     event_loop
         .handle()
         .insert_source(ev_rx, |_, _, _| {})
-        .unwrap();
+        .map_err(io::Error::from)
+        .context("adding the timers to the event loop")?;
 
-    let ev_tx_clone = ev_tx.clone();
-    let config_clone = config.clone();
-    let mut hotwatch = Hotwatch::new().context("hotwatch failed to initialize")?;
-    hotwatch
-        .watch(&config_file, move |event: Event| {
-            if let Event::Write(_) = event {
-                let mut config = config_clone.lock().unwrap();
-                let new_config = Config::new_from_path(&config.path)
-                    .with_context(|| format!("reading configuration from file {:?}", config.path));
-                match new_config {
-                    Ok(new_config) => {
-                        *config = new_config;
-                        ev_tx_clone.send(()).unwrap();
-                    }
-                    Err(err) => {
-                        error!("{:?}", err);
-                    }
-                }
+    // The wallpapers can follow the workspaces, when the compositor tells which are shown
+    let (workspace_tx, workspace_rx) = calloop::channel::channel();
+    event_loop
+        .handle()
+        .insert_source(workspace_rx, |event, _, data| {
+            if let calloop::channel::Event::Msg(event) = event {
+                data.workspace_events.push(event);
             }
         })
-        .with_context(|| format!("watching file {:?}", &config_file))?;
+        .map_err(io::Error::from)
+        .context("adding the workspace events to the event loop")?;
+    workspaces::watch(workspace_tx);
+    // The workspace shown on each output, also for the outputs that appear later
+    let mut workspaces = HashMap::new();
 
-    let timer = timer::Timer::new();
+    // Scripts can also control the daemon without the socket: SIGHUP reloads the
+    // configuration, SIGUSR1 shows the next wallpapers and SIGUSR2 pauses or resumes
+    let signals = Signals::new(&[
+        Signal::SIGINT,
+        Signal::SIGTERM,
+        Signal::SIGHUP,
+        Signal::SIGUSR1,
+        Signal::SIGUSR2,
+    ])
+    .context("setting up the signal handlers")?;
+    event_loop
+        .handle()
+        .insert_source(signals, |event, _, data| match event.signal() {
+            Signal::SIGINT | Signal::SIGTERM => data.should_exit = true,
+            signal => data.signals.push(signal),
+        })
+        .map_err(io::Error::from)
+        .context("adding the signals to the event loop")?;
 
-    let mut timer_guards = HashMap::new();
+    let socket_path = match &only_output {
+        Some(output) => wpaper_ipc::output_socket_path(output),
+        None => wpaper_ipc::socket_path(),
+    }
+    .context("getting the socket path")?;
+    let listener = ipc_server::listen_on_ipc_socket(&socket_path)?;
+    // The requests are read on other threads, the stream is kept to answer them
+    ipc_server::accept_requests(&event_loop.handle(), listener, |data, message, stream| {
+        data.ipc_requests.push((message, stream))
+    })?;
+
+    let mut hotwatch = Hotwatch::new().context("hotwatch failed to initialize")?;
+    // Both the file of the system and the one of the user
+    let config_files = Config::lock(config)?.files();
+    for file in config_files {
+        let ev_tx_clone = ev_tx.clone();
+        let config_clone = config.clone();
+        hotwatch
+            .watch(&file, move |event: Event| {
+                if let Event::Write(_) = event {
+                    if reload_config(&config_clone) {
+                        let _ = ev_tx_clone.send(());
+                    }
+                }
+            })
+            .with_context(|| format!("watching file {:?}", file))?;
+    }
+    let mut dir_watcher = DirWatcher::new()?;
+    dir_watcher.update(Config::lock(config)?.dirs());
+    let mut image_watcher = ImageWatcher::new(ev_tx.clone())?;
+
+    let mut groups = Groups::default();
+    let mut thermal = Thermal::default();
+    let mut memory = Memory::default();
+    let mut power = Power::default();
+    // The timers are waited for by the event loop, the subscribers are told when they move
+    macro_rules! schedule_timer {
+        ($surface:ident) => {
+            if let Some(remaining) = $surface.timer.remaining() {
+                subscribers.notify(IpcEvent::Timer {
+                    output: $surface.info.name.clone(),
+                    remaining,
+                });
+            }
+        };
+    }
     macro_rules! add_timer_on_draw {
         ($surface:ident) => {
-            if $surface
-                .draw()
-                .with_context(|| format!("drawing surface for {}", $surface.info.name))?
-            {
-                if let Some(duration) = $surface.output.duration {
-                    timer_guards.insert(
-                        $surface.info.id,
-                        timer.schedule_with_delay(
-                            chrono::Duration::seconds(duration.as_secs().try_into().unwrap()),
-                            get_timer_closure($surface.timer.clone(), ev_tx.clone()),
-                        ),
-                    );
+            add_timer_on_draw!($surface, draw)
+        };
+        ($surface:ident, $draw:ident) => {
+            // Never let the daemon die because of a single output: log the error and make
+            // sure that the background is not left transparent
+            let drawn = match $surface.$draw() {
+                Ok(drawn) => drawn,
+                Err(err) => {
+                    error!("drawing surface for {}: {:?}", $surface.info.name, err);
+                    subscribers.notify(IpcEvent::Error {
+                        output: $surface.info.name.clone(),
+                        message: format!("{:#}", err),
+                    });
+                    if $surface.current_image().is_none() {
+                        $surface.show_fallback();
+                    }
+                    false
                 }
+            };
+            if drawn {
+                if let Some(image) = $surface.current_image() {
+                    subscribers.notify(IpcEvent::Wallpaper {
+                        output: $surface.info.name.clone(),
+                        image: image.to_path_buf(),
+                    });
+                }
+                if let Some(output_state) = $surface.get_state() {
+                    let mut state = state.lock().unwrap();
+                    state.update_output(&$surface.info.name, output_state);
+                    if let Err(err) = state.save() {
+                        error!("{:?}", err);
+                    }
+                }
+                schedule_timer!($surface);
             }
         };
     }
+    // When wpaperd exits at the latest, set once it has been asked to
+    let mut exiting: Option<Instant> = None;
     loop {
         let mut surfaces = status.surfaces.borrow_mut();
-        let reloaded = config.lock().unwrap().reloaded;
+        let throttled = thermal.throttled();
+        let memory_low = memory.low();
+        for (_, surface) in surfaces.iter_mut() {
+            surface.set_throttled(throttled);
+            surface.set_memory_low(memory_low);
+        }
+        let on_battery = Config::lock(config)?
+            .on_battery()
+            .filter(|_| power.on_battery())
+            .cloned();
+        for (_, surface) in surfaces.iter_mut() {
+            // The timers have been scheduled with the previous durations
+            if surface.set_on_battery(on_battery.clone()) {
+                schedule_timer!(surface);
+            }
+        }
+        let animations = Config::lock(config)?.animations();
+        for (_, surface) in surfaces.iter_mut() {
+            surface.set_animations(animations);
+        }
+        let night = {
+            let config = Config::lock(config)?;
+            // The dynamic wallpapers follow the sun where the night mode computes it
+            heic::set_location(config.night_mode().and_then(NightMode::location));
+            match config
+                .night_mode()
+                .map(|night_mode| night_mode.tint(Local::now()))
+            {
+                Some(Ok(tint)) => tint,
+                Some(Err(err)) => {
+                    error!("{:?}", err);
+                    None
+                }
+                None => None,
+            }
+        };
+        for (_, surface) in surfaces.iter_mut() {
+            surface.set_night(night);
+        }
+        for image in image_watcher.take_changed() {
+            for (_, surface) in surfaces.iter_mut() {
+                if surface.reload_image(&image) {
+                    info!("{:?} has been modified, showing it again", image);
+                }
+            }
+        }
+        groups.synchronize(&mut surfaces);
+        let reloaded = Config::lock(config)?.reloaded;
         if reloaded {
-            let config = config.lock().unwrap();
+            let mut config = Config::lock(config)?;
+            config.reloaded = false;
+            dir_watcher.update(config.dirs());
+            let connected = output_infos(&status.env, None);
             for (_, surface) in surfaces.iter_mut() {
-                surface.update_output(config.get_output_by_name(&surface.info.name));
+                surface.identity = Identity::new(&surface.info, &connected);
+                // Only the outputs whose settings have changed get a new image
+                let output = config.get_output(&surface.identity);
+                if output != *surface.config_output() {
+                    surface.update_output(output);
+                    subscribers.notify(IpcEvent::Settings {
+                        output: surface.info.name.clone(),
+                    });
+                }
                 add_timer_on_draw!(surface);
             }
         } else {
@@ -284,14 +1004,267 @@ fn main() -> Result<()> {
                 }
             }
             for i in removal {
-                timer_guards.remove(&surfaces.get(i).unwrap().1.info.id);
                 surfaces.remove(i);
             }
         }
+        // The outputs changing together show their new images in the same iteration, once
+        // they are all ready
+        let flip_timeouts: Vec<Duration> = surfaces
+            .iter()
+            .filter_map(|(_, surface)| surface.flip_timeout())
+            .collect();
+        if !flip_timeouts.is_empty()
+            && (flip_timeouts.contains(&Duration::ZERO)
+                || !surfaces
+                    .iter()
+                    .any(|(_, surface)| surface.is_synchronizing()))
+        {
+            for (_, surface) in surfaces.iter_mut() {
+                add_timer_on_draw!(surface, flip);
+            }
+        }
+
+        for event in pending_events.borrow_mut().drain(..) {
+            subscribers.notify(event);
+        }
+        image_watcher.update(
+            surfaces
+                .iter()
+                .filter_map(|(_, surface)| surface.current_image())
+                .map(Path::to_path_buf)
+                .collect(),
+        );
+
+        // Frames postponed to limit the frame rate and expiring timers must be handled even if
+        // nothing wakes us up
+        // The night mode also needs to look at the clock from time to time
+        let night_check = Config::lock(config)?
+            .night_mode()
+            .map(|_| night::CHECK_INTERVAL);
+        let timeout = surfaces
+            .iter()
+            .flat_map(|(_, surface)| {
+                [
+                    surface.frame_timeout(),
+                    surface.timer.timeout(),
+                    surface.flip_timeout(),
+                ]
+            })
+            .flatten()
+            .chain(night_check)
+            .chain(exiting.map(|deadline| deadline.saturating_duration_since(Instant::now())))
+            .min();
+        let mut data = LoopData::default();
+        let res = match display.flush() {
+            // The socket is full, the rest is sent on the next iteration
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            res => res.context("flushing the display"),
+        }
+        .and_then(|_| {
+            event_loop
+                .dispatch(timeout, &mut data)
+                .context("dispatching the event loop")
+        });
+        if let Err(err) = res {
+            // A protocol error is a bug that reconnecting would only repeat
+            if display.protocol_error().is_some() {
+                return Err(err);
+            }
+            warn!("{:?}", err);
+            warn!("the connection to the compositor has been lost, waiting for it to come back");
+            save_state(state, &surfaces)?;
+            for (_, surface) in surfaces.iter() {
+                crash_report::output_removed(&surface.info.name);
+                metrics::output_removed(&surface.info.name);
+                subscribers.notify(IpcEvent::OutputRemoved {
+                    output: surface.info.name.clone(),
+                });
+            }
+            return Ok(Served::Disconnected);
+        }
+
+        workspaces.extend(data.workspace_events);
+        for (_, surface) in surfaces.iter_mut() {
+            if let Some(workspace) = workspaces.get(&surface.info.name) {
+                surface.set_workspace(workspace);
+            }
+        }
+
+        for event in data.pointer_events {
+            if let Some((_, surface)) = surfaces
+                .iter_mut()
+                .find(|(_, surface)| surface.owns(&event.surface))
+            {
+                surface.set_pointer(event.position);
+            }
+        }
+
+        for signal in data.signals {
+            match signal {
+                Signal::SIGHUP => {
+                    // Handled at the start of the next iteration
+                    reload_config(config);
+                }
+                Signal::SIGUSR1 => {
+                    for (_, surface) in surfaces.iter_mut() {
+                        if surface.pinned().is_none() {
+                            surface.next_wallpaper();
+                        }
+                    }
+                }
+                Signal::SIGUSR2 => {
+                    // Resume only when every output is paused, so that they end up together
+                    let pause = surfaces.iter().any(|(_, surface)| !surface.is_paused());
+                    for (_, surface) in surfaces.iter_mut() {
+                        if pause {
+                            surface.pause();
+                        } else {
+                            surface.resume();
+                            schedule_timer!(surface);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for (message, stream) in data.ipc_requests {
+            if message == IpcMessage::Subscribe {
+                subscribers.add(stream);
+                continue;
+            }
+            // Decoding the images would hold up the outputs, the client is answered later
+            if let IpcMessage::Thumbnails {
+                output,
+                count,
+                size,
+            } = &message
+            {
+                ipc_server::send_thumbnails(&mut surfaces, output, *count, size, stream);
+                continue;
+            }
+            let moves_timers = |message: &IpcMessage| {
+                matches!(
+                    message,
+                    IpcMessage::SetDuration { .. }
+                        | IpcMessage::Resume { .. }
+                        | IpcMessage::Unpin { .. }
+                )
+            };
+            let duration_changed = match &message {
+                IpcMessage::Batch(messages) => messages.iter().any(moves_timers),
+                message => moves_timers(message),
+            };
+            let response = ipc_server::handle_message(message, &mut surfaces, votes, config);
+            ipc_server::send_response(&stream, response);
+            // The timers have been scheduled with the previous durations
+            if duration_changed {
+                for (_, surface) in surfaces.iter_mut() {
+                    schedule_timer!(surface);
+                }
+            }
+        }
 
-        display.flush().context("flushing the display")?;
-        event_loop
-            .dispatch(None, &mut ())
-            .context("dispatching the event loop")?;
+        if data.should_exit {
+            if exiting.is_none() {
+                // Saved before fading, so that a fade cut short loses nothing
+                save_state(state, &surfaces)?;
+                for (_, surface) in surfaces.iter_mut() {
+                    surface.fade_out();
+                }
+                exiting = Some(Instant::now() + SHUTDOWN_TIMEOUT);
+            } else {
+                // Asked again, the fade is not waited for
+                exiting = Some(Instant::now());
+            }
+        }
+        if let Some(deadline) = exiting {
+            let fading = surfaces.iter().any(|(_, surface)| surface.is_fading());
+            if !fading || Instant::now() >= deadline {
+                shut_down(&display, &mut surfaces, deadline, &socket_path)?;
+                return Ok(Served::Exit);
+            }
+        }
+    }
+}
+
+/// Destroy the surfaces before exiting: the recordings are finished until deadline, the
+/// roles are destroyed before their surfaces, and the compositor is told before the
+/// connection is closed
+fn shut_down(
+    display: &Display,
+    surfaces: &mut Vec<(u32, Surface)>,
+    deadline: Instant,
+    socket_path: &Path,
+) -> Result<()> {
+    for (_, surface) in surfaces.iter_mut() {
+        surface.finish_recording(deadline.saturating_duration_since(Instant::now()));
+    }
+    surfaces.clear();
+    if let Err(err) = display.flush() {
+        warn!("destroying the surfaces: {:?}", err);
+    }
+    fs::remove_file(socket_path).with_context(|| format!("removing the socket {:?}", socket_path))
+}
+
+/// Read the configuration files again, keeping the active profile and the settings changed at
+/// runtime. Returns false, after logging why, if the new configuration is not valid; the
+/// current one is then kept.
+fn reload_config(config: &Mutex<Config>) -> bool {
+    let mut config = match Config::lock(config) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{:?}", err);
+            return false;
+        }
+    };
+    let new_config = config
+        .reload()
+        .with_context(|| format!("reading configuration from file {:?}", config.path));
+    match new_config {
+        Ok(mut new_config) => {
+            for warning in &new_config.warnings {
+                warn!("{}", warning);
+            }
+            // Keep the active profile, unless it has been removed from the file
+            if let Err(err) = new_config.set_profile(config.profile()) {
+                error!("{:?}", err);
+            }
+            new_config.animations_override = config.animations_override;
+            *config = new_config;
+            config.reloaded = true;
+            true
+        }
+        Err(err) => {
+            error!("{:?}", err);
+            false
+        }
+    }
+}
+
+/// Save how much time was left for each output, so that it can be resumed
+fn save_state(state: &Mutex<State>, surfaces: &[(u32, Surface)]) -> Result<()> {
+    let mut state = state.lock().unwrap();
+    for (_, surface) in surfaces.iter() {
+        if let Some(output_state) = surface.get_state() {
+            state.update_output(&surface.info.name, output_state);
+        }
+    }
+    state.save()
+}
+
+/// Wait until the compositor accepts connections again, e.g. after it has been restarted
+fn wait_for_compositor() -> Result<()> {
+    let start = Instant::now();
+    loop {
+        thread::sleep(RECONNECT_INTERVAL);
+        if Display::connect_to_env().is_ok() {
+            return Ok(());
+        }
+        ensure!(
+            start.elapsed() < RECONNECT_TIMEOUT,
+            "the compositor did not come back within {}",
+            humantime::format_duration(RECONNECT_TIMEOUT)
+        );
     }
 }
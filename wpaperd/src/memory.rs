@@ -0,0 +1,127 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use log::info;
+
+const CGROUP_DIR: &str = "/sys/fs/cgroup";
+/// Memory can run out faster than the temperature rises
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Share of the memory that must stay available, in percent
+const LOW_AVAILABLE: u64 = 10;
+/// Share of the time the tasks were stalled waiting for memory over the last 10 seconds, in
+/// percent, above which the memory is considered short even if some is still available
+const HIGH_PRESSURE: f64 = 10.0;
+/// How much the memory must recover before restoring the full quality
+const HYSTERESIS_AVAILABLE: u64 = 5;
+const HYSTERESIS_PRESSURE: f64 = 5.0;
+
+/// Tells whether the system, or the cgroup wpaperd runs in, is short of memory: too little
+/// of it is available, or the kernel reports that tasks are stalled reclaiming it
+#[derive(Default)]
+pub struct Memory {
+    low: bool,
+    last_check: Option<Instant>,
+}
+
+impl Memory {
+    pub fn low(&mut self) -> bool {
+        if self
+            .last_check
+            .is_some_and(|last_check| last_check.elapsed() < CHECK_INTERVAL)
+        {
+            return self.low;
+        }
+        self.last_check = Some(Instant::now());
+        let cgroup = cgroup_dir();
+        let available = meminfo_available()
+            .into_iter()
+            .chain(cgroup.as_deref().and_then(cgroup_available))
+            .min();
+        let pressure = cgroup
+            .as_deref()
+            .and_then(|cgroup| pressure(&cgroup.join("memory.pressure")))
+            .or_else(|| pressure(Path::new("/proc/pressure/memory")));
+        let low = if self.low {
+            available.is_some_and(|available| available < LOW_AVAILABLE + HYSTERESIS_AVAILABLE)
+                || pressure.is_some_and(|pressure| pressure > HIGH_PRESSURE - HYSTERESIS_PRESSURE)
+        } else {
+            available.is_some_and(|available| available < LOW_AVAILABLE)
+                || pressure.is_some_and(|pressure| pressure >= HIGH_PRESSURE)
+        };
+        if low != self.low {
+            info!(
+                "the memory is {}, {} the drawing quality",
+                if low {
+                    "running low"
+                } else {
+                    "available again"
+                },
+                if low { "reducing" } else { "restoring" }
+            );
+            self.low = low;
+        }
+        low
+    }
+}
+
+/// The share of the memory of the system that is available, in percent
+fn meminfo_available() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name))?
+            .trim_start_matches(':')
+            .trim()
+            .trim_end_matches("kB")
+            .trim()
+            .parse::<u64>()
+            .ok()
+    };
+    percent(field("MemAvailable")?, field("MemTotal")?)
+}
+
+/// The share of the limit of the cgroup that is not used, in percent, the lowest of the cgroup
+/// and its parents, e.g. the slice of the user; None without a limit
+fn cgroup_available(cgroup: &Path) -> Option<u64> {
+    cgroup
+        .ancestors()
+        .take_while(|dir| dir.starts_with(CGROUP_DIR) && *dir != Path::new(CGROUP_DIR))
+        .filter_map(|dir| {
+            let read = |name: &str| -> Option<u64> {
+                fs::read_to_string(dir.join(name)).ok()?.trim().parse().ok()
+            };
+            // memory.max is "max" when there is no limit
+            let max = read("memory.max")?;
+            let current = read("memory.current")?;
+            percent(max.saturating_sub(current), max)
+        })
+        .min()
+}
+
+fn percent(part: u64, total: u64) -> Option<u64> {
+    (total > 0).then(|| part * 100 / total)
+}
+
+/// The directory of the cgroup v2 of wpaperd, None with cgroup v1
+fn cgroup_dir() -> Option<PathBuf> {
+    let cgroup = fs::read_to_string("/proc/self/cgroup").ok()?;
+    let path = cgroup.lines().find_map(|line| line.strip_prefix("0::"))?;
+    Some(Path::new(CGROUP_DIR).join(path.trim_start_matches('/')))
+}
+
+/// The avg10 of the "some" line of a pressure file: the share of the last 10 seconds during
+/// which at least one task was stalled on memory
+fn pressure(path: &Path) -> Option<f64> {
+    fs::read_to_string(path)
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("some "))?
+        .split(' ')
+        .find_map(|field| field.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
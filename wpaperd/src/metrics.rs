@@ -0,0 +1,336 @@
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+
+use crate::sync::lock;
+
+lazy_static! {
+    /// Updated by the main loop and by the threads loading the images
+    static ref METRICS: Mutex<Metrics> = Mutex::new(Metrics::default());
+}
+
+/// Upper bounds of the buckets of the decode time histogram, in seconds
+const DECODE_BUCKETS: &[f64] = &[0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+/// Upper bounds of the buckets of the histograms of the stages, in seconds; the stages run
+/// on every frame take a few milliseconds
+const STAGE_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// A stage of the drawing pipeline, timed for the metrics and by --bench
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    /// Reading the image file into pixels
+    Decode,
+    /// Scaling the image to the size of the output
+    Scale,
+    /// Converting the pixels to the buffer format, with the dithering and the color profile
+    Convert,
+    /// Applying the layers, the inversion, the night mode, the overlay and the fade to a
+    /// frame
+    Filters,
+    /// Copying a frame into the shared memory of the compositor
+    Write,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 5] = [
+        Stage::Decode,
+        Stage::Scale,
+        Stage::Convert,
+        Stage::Filters,
+        Stage::Write,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Decode => "decode",
+            Stage::Scale => "scale",
+            Stage::Convert => "convert",
+            Stage::Filters => "filters",
+            Stage::Write => "write",
+        }
+    }
+}
+
+/// Number of runs in each bucket of STAGE_BUCKETS, not cumulative, and their total time
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; 14],
+    seconds: f64,
+}
+
+/// Counters of what the daemon has been doing since it started, exported in the Prometheus
+/// text format by the metrics request
+#[derive(Default)]
+struct Metrics {
+    /// Frames committed, by output
+    draws: BTreeMap<String, u64>,
+    /// Images shown, by output
+    rotations: BTreeMap<String, u64>,
+    /// Size of the shared memory pools, by output
+    shm_bytes: BTreeMap<String, u64>,
+    /// Lookups by cache and by result, true for a hit
+    cache: BTreeMap<(&'static str, bool), u64>,
+    /// Number of decoded images in each bucket of DECODE_BUCKETS, not cumulative; the last
+    /// one counts the images slower than every bucket
+    decode_buckets: [u64; 11],
+    decode_seconds: f64,
+    /// Time spent in each stage
+    stages: BTreeMap<Stage, Histogram>,
+}
+
+/// A frame has been committed on output
+pub fn draw(output: &str) {
+    *lock(&METRICS).draws.entry(output.to_string()).or_default() += 1;
+}
+
+/// A new image is shown on output
+pub fn rotation(output: &str) {
+    *lock(&METRICS)
+        .rotations
+        .entry(output.to_string())
+        .or_default() += 1;
+}
+
+/// The memory pools of output now use bytes
+pub fn shm_pool_size(output: &str, bytes: usize) {
+    lock(&METRICS)
+        .shm_bytes
+        .insert(output.to_string(), bytes as u64);
+}
+
+/// The pools of output have been destroyed with it; its counters are kept, as counters
+/// never go back
+pub fn output_removed(output: &str) {
+    lock(&METRICS).shm_bytes.remove(output);
+}
+
+/// Something has been looked up in cache, hit tells whether it was found
+pub fn cache_lookup(cache: &'static str, hit: bool) {
+    *lock(&METRICS).cache.entry((cache, hit)).or_default() += 1;
+}
+
+/// An image has been decoded in duration
+pub fn decoded(duration: Duration) {
+    let seconds = duration.as_secs_f64();
+    let bucket = DECODE_BUCKETS
+        .iter()
+        .position(|&bound| seconds <= bound)
+        .unwrap_or(DECODE_BUCKETS.len());
+    {
+        let mut metrics = lock(&METRICS);
+        metrics.decode_buckets[bucket] += 1;
+        metrics.decode_seconds += seconds;
+    }
+    stage_done(Stage::Decode, duration);
+}
+
+/// Run f, stage of the pipeline, and count the time it took
+pub fn time<T>(stage: Stage, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let res = f();
+    stage_done(stage, start.elapsed());
+    res
+}
+
+/// stage of the pipeline has run in duration
+pub fn stage_done(stage: Stage, duration: Duration) {
+    let seconds = duration.as_secs_f64();
+    let bucket = STAGE_BUCKETS
+        .iter()
+        .position(|&bound| seconds <= bound)
+        .unwrap_or(STAGE_BUCKETS.len());
+    let mut metrics = lock(&METRICS);
+    let histogram = metrics.stages.entry(stage).or_default();
+    histogram.buckets[bucket] += 1;
+    histogram.seconds += seconds;
+}
+
+/// The metrics in the Prometheus text exposition format, a line each
+pub fn render() -> Vec<String> {
+    let metrics = lock(&METRICS);
+    let mut lines = Vec::new();
+    let header = |lines: &mut Vec<String>, name: &str, kind: &str, help: &str| {
+        lines.push(format!("# HELP {} {}", name, help));
+        lines.push(format!("# TYPE {} {}", name, kind));
+    };
+
+    header(
+        &mut lines,
+        "wpaperd_draws_total",
+        "counter",
+        "Frames committed to the compositor",
+    );
+    for (output, count) in &metrics.draws {
+        lines.push(format!(
+            "wpaperd_draws_total{{output=\"{}\"}} {}",
+            escape(output),
+            count
+        ));
+    }
+    header(
+        &mut lines,
+        "wpaperd_rotations_total",
+        "counter",
+        "Images shown, including the first one",
+    );
+    for (output, count) in &metrics.rotations {
+        lines.push(format!(
+            "wpaperd_rotations_total{{output=\"{}\"}} {}",
+            escape(output),
+            count
+        ));
+    }
+    header(
+        &mut lines,
+        "wpaperd_shm_pool_bytes",
+        "gauge",
+        "Size of the shared memory pools holding the frames",
+    );
+    for (output, bytes) in &metrics.shm_bytes {
+        lines.push(format!(
+            "wpaperd_shm_pool_bytes{{output=\"{}\"}} {}",
+            escape(output),
+            bytes
+        ));
+    }
+    header(
+        &mut lines,
+        "wpaperd_cache_requests_total",
+        "counter",
+        "Lookups in the caches of directory listings, thumbnails and downloaded images",
+    );
+    for ((cache, hit), count) in &metrics.cache {
+        lines.push(format!(
+            "wpaperd_cache_requests_total{{cache=\"{}\",result=\"{}\"}} {}",
+            cache,
+            if *hit { "hit" } else { "miss" },
+            count
+        ));
+    }
+    header(
+        &mut lines,
+        "wpaperd_decode_seconds",
+        "histogram",
+        "Time spent decoding an image",
+    );
+    let mut cumulative = 0;
+    for (i, count) in metrics.decode_buckets.iter().enumerate() {
+        cumulative += count;
+        let bound = DECODE_BUCKETS
+            .get(i)
+            .map_or("+Inf".to_string(), |bound| bound.to_string());
+        lines.push(format!(
+            "wpaperd_decode_seconds_bucket{{le=\"{}\"}} {}",
+            bound, cumulative
+        ));
+    }
+    lines.push(format!(
+        "wpaperd_decode_seconds_sum {}",
+        metrics.decode_seconds
+    ));
+    lines.push(format!("wpaperd_decode_seconds_count {}", cumulative));
+    header(
+        &mut lines,
+        "wpaperd_stage_seconds",
+        "histogram",
+        "Time spent in each stage of the drawing pipeline",
+    );
+    for (stage, histogram) in &metrics.stages {
+        let mut cumulative = 0;
+        for (i, count) in histogram.buckets.iter().enumerate() {
+            cumulative += count;
+            let bound = STAGE_BUCKETS
+                .get(i)
+                .map_or("+Inf".to_string(), |bound| bound.to_string());
+            lines.push(format!(
+                "wpaperd_stage_seconds_bucket{{stage=\"{}\",le=\"{}\"}} {}",
+                stage.name(),
+                bound,
+                cumulative
+            ));
+        }
+        lines.push(format!(
+            "wpaperd_stage_seconds_sum{{stage=\"{}\"}} {}",
+            stage.name(),
+            histogram.seconds
+        ));
+        lines.push(format!(
+            "wpaperd_stage_seconds_count{{stage=\"{}\"}} {}",
+            stage.name(),
+            cumulative
+        ));
+    }
+    lines
+}
+
+/// The header lines of a family of metrics, and its series in order of appearance with
+/// their values
+type Family = (Vec<String>, Vec<(String, f64)>);
+
+/// Join the metrics rendered by several processes, summing the samples of the same series.
+/// The series only present in some of them, e.g. those of their own outputs, are kept as is.
+pub fn merge(responses: Vec<Vec<String>>) -> Vec<String> {
+    let mut families: Vec<Family> = Vec::new();
+    for response in responses {
+        let mut family = None;
+        for line in response {
+            if line.starts_with("# HELP ") {
+                family = Some(
+                    match families.iter().position(|(header, _)| header[0] == line) {
+                        Some(i) => i,
+                        None => {
+                            families.push((vec![line], Vec::new()));
+                            families.len() - 1
+                        }
+                    },
+                );
+                continue;
+            }
+            let (header, samples) = match family {
+                Some(i) => &mut families[i],
+                None => continue,
+            };
+            if line.starts_with('#') {
+                if !header.contains(&line) {
+                    header.push(line);
+                }
+            } else if let Some((series, value)) = line.rsplit_once(' ') {
+                let value: f64 = value.parse().unwrap_or(0.0);
+                match samples.iter_mut().find(|(other, _)| other == series) {
+                    Some((_, total)) => *total += value,
+                    None => samples.push((series.to_string(), value)),
+                }
+            }
+        }
+    }
+    families
+        .into_iter()
+        .flat_map(|(header, samples)| {
+            header.into_iter().chain(
+                samples
+                    .into_iter()
+                    .map(|(series, value)| format!("{} {}", series, value)),
+            )
+        })
+        .collect()
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
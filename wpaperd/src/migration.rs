@@ -0,0 +1,102 @@
+use std::path::Path;
+
+use color_eyre::{
+    eyre::{ensure, eyre},
+    Result,
+};
+use toml::{value::Table, Value};
+
+use crate::output::OUTPUT_KEYS;
+
+/// Version of the format of the configuration files, written in their version key. The files
+/// without one predate it and are version 0.
+pub const CONFIG_VERSION: i64 = 1;
+
+/// The top-level tables that are not output sections
+const OTHER_SECTIONS: &[&str] = &["profiles", "on-battery", "night-mode", "collection"];
+
+/// Each migration brings a configuration from the version at its index to the next one,
+/// describing what it changed
+const MIGRATIONS: &[fn(&mut Table, &mut Vec<String>)] = &[migrate_mode_to_fit];
+
+/// Bring document, the content of the configuration file at path, to CONFIG_VERSION, in
+/// place. The version key is removed. Returns a warning for each change and for each key of
+/// the output sections that wpaperd doesn't know, which would be ignored otherwise.
+pub fn migrate(document: &mut Table, path: &Path) -> Result<Vec<String>> {
+    let version = match document.remove("version") {
+        None => 0,
+        Some(Value::Integer(version)) if version >= 0 => version,
+        Some(version) => return Err(eyre!("invalid version {} in {:?}", version, path)),
+    };
+    ensure!(
+        version <= CONFIG_VERSION,
+        "{:?} is written for a newer wpaperd, with version {} of the configuration; this one reads up to version {}",
+        path,
+        version,
+        CONFIG_VERSION
+    );
+    let mut warnings = Vec::new();
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(document, &mut warnings);
+    }
+    if version < CONFIG_VERSION {
+        warnings.push(format!(
+            "{:?} has been read as version {} of the configuration, set version = {} once it is updated",
+            path, version, CONFIG_VERSION
+        ));
+    }
+    for_each_output_section(document, |name, section| {
+        for key in section.keys() {
+            if !OUTPUT_KEYS.iter().any(|(known, _, _)| known == key) {
+                warnings.push(format!(
+                    "unknown key {} in section {} of {:?}, it is ignored",
+                    key, name, path
+                ));
+            }
+        }
+    });
+    Ok(warnings)
+}
+
+/// Call f with the name and the content of every output section, those of the profiles
+/// included
+fn for_each_output_section(document: &mut Table, mut f: impl FnMut(&str, &mut Table)) {
+    for (name, section) in document.iter_mut() {
+        match section {
+            Value::Table(profiles) if name == "profiles" => {
+                for (profile, sections) in profiles.iter_mut() {
+                    if let Value::Table(sections) = sections {
+                        for (name, section) in sections.iter_mut() {
+                            if let Value::Table(section) = section {
+                                f(&format!("{} of profile {}", name, profile), section);
+                            }
+                        }
+                    }
+                }
+            }
+            Value::Table(section) if !OTHER_SECTIONS.contains(&name.as_str()) => f(name, section),
+            _ => {}
+        }
+    }
+}
+
+/// Version 0 to 1: the first releases had a mode key that did nothing, the way the images
+/// are scaled is now set by fit
+fn migrate_mode_to_fit(document: &mut Table, warnings: &mut Vec<String>) {
+    for_each_output_section(document, |name, section| {
+        let mode = match section.remove("mode") {
+            Some(mode) => mode,
+            None => return,
+        };
+        match mode.as_str() {
+            Some(fit @ ("fill" | "contain" | "tile")) if !section.contains_key("fit") => {
+                section.insert("fit".to_string(), Value::String(fit.to_string()));
+                warnings.push(format!("mode of section {} is now called fit", name));
+            }
+            _ => warnings.push(format!(
+                "mode of section {} is no longer used, see fit instead",
+                name
+            )),
+        }
+    });
+}
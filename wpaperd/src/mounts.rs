@@ -0,0 +1,217 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{mpsc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use log::{info, warn};
+
+use crate::file_list;
+use crate::sync::lock;
+
+/// Types of the filesystems whose calls can hang for a long time when their server is
+/// unreachable
+const NETWORK_FILESYSTEMS: &[&str] = &[
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smb3",
+    "smbfs",
+    "9p",
+    "ceph",
+    "glusterfs",
+    "afs",
+    "davfs",
+    "fuse.sshfs",
+    "fuse.rclone",
+];
+/// How long a mount has to answer before it is considered down
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often the mounts that answered are checked again, and the others retried
+const RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+lazy_static! {
+    static ref MOUNTS: Mutex<Mounts> = Mutex::new(Mounts::default());
+}
+
+#[derive(Default)]
+struct Mounts {
+    /// Mount points of the network filesystems, read from /proc/mounts
+    network: Vec<PathBuf>,
+    read_at: Option<Instant>,
+    health: HashMap<PathBuf, Health>,
+}
+
+struct Health {
+    available: bool,
+    checked: Instant,
+    /// Set while a probe is running; a probe of a mount that is down can take minutes
+    probing: bool,
+}
+
+/// Whether path can be read without blocking: it is on a local filesystem, or on a network
+/// mount that answered its last probe. A mount is probed again every RECHECK_INTERVAL; when
+/// it was up, the call waits for the answer at most PROBE_TIMEOUT, when it is down the probe
+/// runs in the background and the call returns at once.
+pub fn is_available(path: &Path) -> bool {
+    let mut mounts = lock(&MOUNTS);
+    let mount = match mounts.network_mount(path) {
+        Some(mount) => mount,
+        None => return true,
+    };
+    let (was_available, due) = match mounts.health.get(&mount) {
+        Some(health) if health.probing => return health.available,
+        Some(health) => (
+            health.available,
+            health.checked.elapsed() >= RECHECK_INTERVAL,
+        ),
+        None => (true, true),
+    };
+    if !due {
+        return was_available;
+    }
+    mounts.health.insert(
+        mount.clone(),
+        Health {
+            available: was_available,
+            checked: Instant::now(),
+            probing: true,
+        },
+    );
+    drop(mounts);
+
+    let (tx, rx) = mpsc::channel();
+    let probed = mount.clone();
+    thread::spawn(move || {
+        // Reading the entries, not only the attributes that the client caches, needs the
+        // server to answer
+        let res = fs::read_dir(&probed).and_then(|mut entries| entries.next().transpose());
+        let available = res.is_ok();
+        let mut mounts = lock(&MOUNTS);
+        if let Some(health) = mounts.health.get_mut(&probed) {
+            match res {
+                Ok(_) if !health.available => {
+                    info!("{:?} is back, its images are shown again", probed)
+                }
+                Err(err) if health.available => warn!(
+                    "reading {:?}: {}, its images are skipped until it is back",
+                    probed, err
+                ),
+                _ => {}
+            }
+            *health = Health {
+                available,
+                checked: Instant::now(),
+                probing: false,
+            };
+        }
+        let _ = tx.send(available);
+    });
+    if !was_available {
+        return false;
+    }
+    match rx.recv_timeout(PROBE_TIMEOUT) {
+        Ok(available) => available,
+        Err(_) => {
+            warn!(
+                "{:?} is not responding, its images are skipped until it is back",
+                mount
+            );
+            // Unless the probe has answered in the meantime
+            if let Some(health) = lock(&MOUNTS)
+                .health
+                .get_mut(&mount)
+                .filter(|health| health.probing)
+            {
+                health.available = false;
+            }
+            false
+        }
+    }
+}
+
+/// Whether path is a directory. When it is on a mount that is down, a path without the
+/// extension of an image is taken for one, so that the output rotates again once the mount
+/// is back.
+pub fn is_dir(path: &Path) -> bool {
+    if is_available(path) {
+        path.is_dir()
+    } else {
+        !file_list::is_image(path)
+    }
+}
+
+/// Whether path exists, assuming it does when it is on a mount that is down
+pub fn exists(path: &Path) -> bool {
+    !is_available(path) || path.exists()
+}
+
+impl Mounts {
+    /// The mount point of the network filesystem containing path, if any. The path is not
+    /// resolved, which would itself block.
+    fn network_mount(&mut self, path: &Path) -> Option<PathBuf> {
+        if self
+            .read_at
+            .is_none_or(|read_at| read_at.elapsed() >= RECHECK_INTERVAL)
+        {
+            self.network = read_network_mounts();
+            self.read_at = Some(Instant::now());
+        }
+        self.network
+            .iter()
+            .filter(|mount| path.starts_with(mount))
+            .max_by_key(|mount| mount.as_os_str().len())
+            .cloned()
+    }
+}
+
+/// The mount points of /proc/mounts whose filesystem is in NETWORK_FILESYSTEMS
+fn read_network_mounts() -> Vec<PathBuf> {
+    let mounts = match fs::read_to_string("/proc/mounts") {
+        Ok(mounts) => mounts,
+        Err(err) => {
+            warn!("reading /proc/mounts: {}", err);
+            return Vec::new();
+        }
+    };
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let mount_point = fields.nth(1)?;
+            let fs_type = fields.next()?;
+            NETWORK_FILESYSTEMS
+                .contains(&fs_type)
+                .then(|| PathBuf::from(unescape(mount_point)))
+        })
+        .collect()
+}
+
+/// /proc/mounts writes the spaces, tabs, newlines and backslashes of the paths as octal
+/// escapes, e.g. \040 for a space
+fn unescape(field: &str) -> String {
+    let mut unescaped = String::new();
+    let mut rest = field;
+    while let Some(start) = rest.find('\\') {
+        unescaped.push_str(&rest[..start]);
+        let code = rest
+            .get(start + 1..start + 4)
+            .and_then(|code| u8::from_str_radix(code, 8).ok());
+        match code {
+            Some(code) => {
+                unescaped.push(char::from(code));
+                rest = &rest[start + 4..];
+            }
+            None => {
+                unescaped.push('\\');
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
@@ -0,0 +1,221 @@
+use std::{f64::consts::PI, time::Duration};
+
+use chrono::{DateTime, Local, NaiveDate, NaiveTime, Utc};
+use color_eyre::{
+    eyre::{ensure, eyre},
+    Result,
+};
+use serde::Deserialize;
+use smithay_client_toolkit::reexports::client::protocol::wl_shm;
+use wpaper_core::sun;
+
+/// Night starts and ends at most this late, the clock is checked this often
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_TEMPERATURE: u32 = 4000;
+const DEFAULT_BRIGHTNESS: f32 = 0.8;
+/// Color temperature of the images as they are, which is left unchanged
+const NEUTRAL_TEMPERATURE: u32 = 6500;
+/// Altitude of the center of the sun at sunrise and sunset, below the horizon because of the
+/// refraction of the atmosphere and of the radius of the sun
+const SUNRISE_ALTITUDE: f64 = -0.833;
+
+/// The night-mode section: between sunset and sunrise, the wallpapers are tinted warmer and
+/// dimmed. Night is either computed from the location or given as fixed times.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NightMode {
+    /// In degrees, north is positive
+    pub latitude: Option<f64>,
+    /// In degrees, east is positive
+    pub longitude: Option<f64>,
+    /// When the night starts, e.g. "20:30", local time
+    pub start: Option<String>,
+    /// When the night ends, e.g. "07:00", local time
+    pub end: Option<String>,
+    /// In kelvin, lower is warmer
+    pub temperature: Option<u32>,
+    /// From 0 (black) to 1 (unchanged)
+    pub brightness: Option<f32>,
+}
+
+/// How the pixels are changed at night: a factor for each of red, green and blue
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NightTint([f32; 3]);
+
+impl NightMode {
+    pub fn validate(&self) -> Result<()> {
+        match (self.latitude, self.longitude, &self.start, &self.end) {
+            (Some(latitude), Some(longitude), None, None) => {
+                ensure!(
+                    (-90.0..=90.0).contains(&latitude),
+                    "latitude must be between -90 and 90, for section night-mode"
+                );
+                ensure!(
+                    (-180.0..=180.0).contains(&longitude),
+                    "longitude must be between -180 and 180, for section night-mode"
+                );
+            }
+            (None, None, Some(start), Some(end)) => {
+                parse_time(start)?;
+                parse_time(end)?;
+            }
+            _ => {
+                return Err(eyre!(
+                    "either latitude and longitude or start and end must be set, for section night-mode"
+                ))
+            }
+        }
+        ensure!(
+            (1000..=NEUTRAL_TEMPERATURE).contains(&self.temperature()),
+            "temperature must be between 1000 and {}, for section night-mode",
+            NEUTRAL_TEMPERATURE
+        );
+        ensure!(
+            (0.0..=1.0).contains(&self.brightness()),
+            "brightness must be between 0 and 1, for section night-mode"
+        );
+        Ok(())
+    }
+
+    /// Latitude and longitude, when the night is computed from them
+    pub fn location(&self) -> Option<(f64, f64)> {
+        Some((self.latitude?, self.longitude?))
+    }
+
+    fn temperature(&self) -> u32 {
+        self.temperature.unwrap_or(DEFAULT_TEMPERATURE)
+    }
+
+    fn brightness(&self) -> f32 {
+        self.brightness.unwrap_or(DEFAULT_BRIGHTNESS)
+    }
+
+    /// The tint to apply at now, None during the day
+    pub fn tint(&self, now: DateTime<Local>) -> Result<Option<NightTint>> {
+        Ok(self.is_night(now)?.then(|| {
+            let brightness = self.brightness();
+            NightTint(white_point(self.temperature()).map(|channel| channel * brightness))
+        }))
+    }
+
+    fn is_night(&self, now: DateTime<Local>) -> Result<bool> {
+        if let (Some(latitude), Some(longitude)) = (self.latitude, self.longitude) {
+            let now = now.with_timezone(&Utc);
+            let date = now.naive_utc().date();
+            return Ok(match sun_times(date, latitude, longitude) {
+                Some((sunrise, sunset)) => now < sunrise || now >= sunset,
+                // The sun doesn't rise during the polar night
+                None => polar_night(date, latitude),
+            });
+        }
+        let (start, end) = match (&self.start, &self.end) {
+            (Some(start), Some(end)) => (parse_time(start)?, parse_time(end)?),
+            _ => return Ok(false),
+        };
+        let time = now.time();
+        Ok(if start <= end {
+            start <= time && time < end
+        } else {
+            // The night goes past midnight
+            time >= start || time < end
+        })
+    }
+}
+
+impl Default for NightTint {
+    /// The tint at night with the default temperature and brightness
+    fn default() -> Self {
+        NightTint(white_point(DEFAULT_TEMPERATURE).map(|channel| channel * DEFAULT_BRIGHTNESS))
+    }
+}
+
+impl NightTint {
+    /// Tint the pixels in data, which are in the buffer format
+    pub fn apply(&self, data: &mut [u8], format: wl_shm::Format) {
+        for pixel in data.chunks_exact_mut(4) {
+            match format {
+                wl_shm::Format::Xbgr2101010 => {
+                    let value = u32::from_le_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]);
+                    let tinted = (0..3).fold(0, |acc, channel| {
+                        let shift = channel * 10;
+                        let component = ((value >> shift) & 0x3ff) as f32;
+                        acc | (((component * self.0[channel]).round() as u32) << shift)
+                    });
+                    pixel.copy_from_slice(&tinted.to_le_bytes());
+                }
+                // Scaling the colors keeps them premultiplied by the untouched alpha
+                _ => {
+                    for (component, factor) in pixel[..3].iter_mut().zip(self.0) {
+                        *component = (f32::from(*component) * factor).round() as u8;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn parse_time(time: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| {
+        eyre!(
+            "invalid time {:?}, expected HH:MM, for section night-mode",
+            time
+        )
+    })
+}
+
+/// The color of a black body at temperature, relative to white, which is at
+/// NEUTRAL_TEMPERATURE; the fit of the blackbody curve by Tanner Helland
+fn white_point(temperature: u32) -> [f32; 3] {
+    let color = |temperature: u32| {
+        let t = temperature as f64 / 100.0;
+        let red = if t <= 66.0 {
+            255.0
+        } else {
+            329.698_727_446 * (t - 60.0).powf(-0.133_204_759_2)
+        };
+        let green = if t <= 66.0 {
+            99.470_802_586_1 * t.ln() - 161.119_568_166_1
+        } else {
+            288.122_169_528_3 * (t - 60.0).powf(-0.075_514_849_2)
+        };
+        let blue = if t >= 66.0 {
+            255.0
+        } else if t <= 19.0 {
+            0.0
+        } else {
+            138.517_731_223_1 * (t - 10.0).ln() - 305.044_792_730_7
+        };
+        [red, green, blue].map(|channel| channel.clamp(0.0, 255.0))
+    };
+    let (tinted, neutral) = (color(temperature), color(NEUTRAL_TEMPERATURE));
+    [0, 1, 2].map(|i| (tinted[i] / neutral[i]).min(1.0) as f32)
+}
+
+/// Sunrise and sunset on date at the location, from the sunrise equation; None when the sun
+/// doesn't rise or doesn't set on that day
+fn sun_times(
+    date: NaiveDate,
+    latitude: f64,
+    longitude: f64,
+) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let (declination, transit) = sun::position(date, longitude);
+    let radians = |degrees: f64| degrees * PI / 180.0;
+    let cos_hour_angle = (radians(SUNRISE_ALTITUDE).sin()
+        - radians(latitude).sin() * declination.sin())
+        / (radians(latitude).cos() * declination.cos());
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let half_day = cos_hour_angle.acos() / (2.0 * PI);
+    Some((
+        sun::julian_to_utc(transit - half_day),
+        sun::julian_to_utc(transit + half_day),
+    ))
+}
+
+/// Whether the sun stays below the horizon all day
+fn polar_night(date: NaiveDate, latitude: f64) -> bool {
+    let (declination, _) = sun::position(date, 0.0);
+    // The sun is in the other hemisphere
+    latitude * declination < 0.0
+}
@@ -1,11 +1,516 @@
-use std::{path::PathBuf, time::Duration};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+pub use wpaper_core::settings::{Color, Crop, Dither, ScalingFilter};
 
-#[derive(Default, Deserialize)]
+use crate::collection::{Fit, Source};
+use crate::day_night::DayNight;
+use crate::mounts;
+use crate::remote;
+
+#[derive(Clone, Default, Deserialize, PartialEq, Serialize)]
 pub struct Output {
     pub path: Option<PathBuf>,
     pub mode: Option<()>,
+    /// Named collections of images shown instead of path
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub collections: Vec<String>,
+    /// Only the images with one of these tags are shown, those of their collection or those
+    /// of the sidecar files
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// The paths of the collections, set when the configuration is read
+    #[serde(skip)]
+    pub collection_sources: Vec<Source>,
     #[serde(default, with = "humantime_serde")]
     pub duration: Option<Duration>,
+    #[serde(default)]
+    pub force_8bit: bool,
+    pub icc_profile: Option<PathBuf>,
+    pub exec: Option<String>,
+    #[serde(default)]
+    pub lock_screen: bool,
+    pub palette_template: Option<PathBuf>,
+    pub palette_file: Option<PathBuf>,
+    /// Outputs in the same group change their images together
+    pub group: Option<String>,
+    #[serde(default)]
+    pub group_mode: GroupMode,
+    /// Order of the images shown by sequential groups
+    #[serde(default)]
+    pub sort: SortOrder,
+    /// Slowly pan and zoom across the image
+    #[serde(default)]
+    pub ken_burns: bool,
+    pub ken_burns_fps: Option<u32>,
+    /// Shift the image toward the pointer by up to this share of the size of the output
+    pub parallax: Option<f32>,
+    /// Number of recently shown images that are not chosen again
+    pub avoid_recent: Option<usize>,
+    /// Let the random choice pick an image shown by another output
+    #[serde(default)]
+    pub allow_duplicates: bool,
+    /// Prefer the images that have not been shown for this long, by any output
+    #[serde(default, with = "humantime_serde")]
+    pub prefer_unseen: Option<Duration>,
+    /// How long the images downloaded from a URL path are used before downloading it again
+    #[serde(default, with = "humantime_serde")]
+    pub ttl: Option<Duration>,
+    /// How long the output takes to fade to black when wpaperd exits
+    #[serde(default, with = "humantime_serde")]
+    pub fade_out: Option<Duration>,
+    /// Color around the images that don't cover the output and behind transparent images
+    pub background: Option<Color>,
+    /// Filter used to scale the images to the size of the output
+    #[serde(default)]
+    pub scaling_filter: ScalingFilter,
+    /// Dithering of the images written to buffers with 8 bits per channel
+    #[serde(default)]
+    pub dither: Dither,
+    /// How the images are scaled to the size of the output, unless their sidecar file says
+    /// otherwise
+    #[serde(default)]
+    pub fit: Fit,
+    /// Which part of the images is kept when fill crops them
+    #[serde(default)]
+    pub crop: Crop,
+    /// Fill the bars around the images that contain leaves with a blurred copy of the image
+    #[serde(default)]
+    pub blur_background: bool,
+    /// Scale the images in linear light instead of sRGB, which costs more time
+    #[serde(default)]
+    pub linear_scaling: bool,
+    /// Scale of the tiles, relative to the size of the image
+    pub tile_scale: Option<f32>,
+    /// Mirror every other tile, so that patterns which are not seamless look seamless
+    #[serde(default)]
+    pub tile_mirror: bool,
+    /// Prefer the images with about the same aspect ratio as the output
+    #[serde(default)]
+    pub match_aspect: bool,
+    /// How far the aspect ratio of an image can be from the one of the output, e.g. 0.1 for
+    /// 10%, with match_aspect; also used by the span groups
+    pub aspect_tolerance: Option<f32>,
+    /// Image or directory shown instead of path while a workspace is shown on the output,
+    /// by workspace name, when running under Sway or Hyprland
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub workspaces: BTreeMap<String, PathBuf>,
+    /// Image or directory shown while the images of the output are on a network mount that
+    /// is not responding, or while its directories have no images
+    pub fallback_path: Option<PathBuf>,
+    /// Text drawn over the wallpaper. It is a table, which TOML needs after the other keys.
+    pub overlay: Option<Overlay>,
+    /// Image replacing path at night, blended in and out gradually. It is a table too.
+    pub day_night: Option<DayNight>,
+    /// Images stacked over the wallpaper, from the lowest
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub layers: Vec<Layer>,
+}
+
+impl Output {
+    pub fn ken_burns_fps(&self) -> u32 {
+        self.ken_burns_fps.unwrap_or(DEFAULT_KEN_BURNS_FPS)
+    }
+
+    pub fn avoid_recent(&self) -> usize {
+        self.avoid_recent.unwrap_or(DEFAULT_AVOID_RECENT)
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl.unwrap_or(DEFAULT_TTL)
+    }
+
+    pub fn background(&self) -> Color {
+        self.background.unwrap_or(DEFAULT_BACKGROUND)
+    }
+
+    /// The aspect ratio the images are chosen for on an output of width x height, with the
+    /// tolerance, when match_aspect is set
+    pub fn aspect(&self, width: u32, height: u32) -> Option<(f32, f32)> {
+        (self.match_aspect && height > 0)
+            .then(|| (width as f32 / height as f32, self.aspect_tolerance()))
+    }
+
+    pub fn aspect_tolerance(&self) -> f32 {
+        self.aspect_tolerance.unwrap_or(0.15)
+    }
+
+    pub fn tile_scale(&self) -> f32 {
+        self.tile_scale.unwrap_or(1.0)
+    }
+
+    /// The path, when it is a URL
+    pub fn url(&self) -> Option<&str> {
+        self.path
+            .as_deref()
+            .filter(|path| remote::is_url(path))
+            .and_then(Path::to_str)
+    }
+
+    /// Where the images are read from: the path itself or, for a URL, the directory where
+    /// its images are downloaded. None for a URL without a cache directory, which
+    /// remote::update() reports.
+    pub fn local_path(&self) -> Option<PathBuf> {
+        match self.url() {
+            Some(url) => remote::cache_dir(url).ok(),
+            None => self.path.clone(),
+        }
+    }
+
+    /// The directories and images shown: those of the collections, or the local path
+    pub fn sources(&self) -> Vec<Source> {
+        if !self.collection_sources.is_empty() {
+            return self.collection_sources.clone();
+        }
+        self.local_path()
+            .map(|path| Source {
+                path,
+                tags: Vec::new(),
+            })
+            .into_iter()
+            .collect()
+    }
+
+    /// Whether the output changes its image over time, choosing it from a directory or from
+    /// collections
+    pub fn rotates(&self) -> bool {
+        !self.collection_sources.is_empty()
+            || self.local_path().is_some_and(|path| mounts::is_dir(&path))
+    }
+
+    /// Whether image is one of the images shown
+    pub fn contains(&self, image: &Path) -> bool {
+        self.sources().iter().any(|source| {
+            if mounts::is_dir(&source.path) {
+                image.starts_with(&source.path)
+            } else {
+                image == source.path
+            }
+        })
+    }
+}
+
+const DEFAULT_KEN_BURNS_FPS: u32 = 15;
+/// By default the same image is never shown twice in a row
+const DEFAULT_AVOID_RECENT: usize = 1;
+const DEFAULT_TTL: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_BACKGROUND: Color = Color([0, 0, 0, 0xff]);
+
+/// Text drawn over the wallpaper, e.g. the name of the image or a clock
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Overlay {
+    /// The text, where {name}, {file}, {dir}, {source}, {author}, {license}, {page} and
+    /// {time} are replaced
+    pub text: String,
+    /// Path of a TrueType font, or a name looked up with fc-match
+    pub font: Option<String>,
+    /// Height of the font, in pixels of the output
+    pub size: Option<f32>,
+    #[serde(default)]
+    pub position: Position,
+    pub color: Option<Color>,
+    pub opacity: Option<f32>,
+    /// strftime format of {time}
+    pub time_format: Option<String>,
 }
+
+impl Overlay {
+    pub fn font(&self) -> &str {
+        self.font.as_deref().unwrap_or(DEFAULT_OVERLAY_FONT)
+    }
+
+    pub fn size(&self) -> f32 {
+        self.size.unwrap_or(DEFAULT_OVERLAY_SIZE)
+    }
+
+    pub fn color(&self) -> Color {
+        self.color.unwrap_or(DEFAULT_OVERLAY_COLOR)
+    }
+
+    pub fn opacity(&self) -> f32 {
+        self.opacity.unwrap_or(1.0)
+    }
+
+    pub fn time_format(&self) -> &str {
+        self.time_format.as_deref().unwrap_or(DEFAULT_TIME_FORMAT)
+    }
+}
+
+/// An image drawn over the wallpaper, e.g. a semi-transparent foreground
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layer {
+    pub path: PathBuf,
+    pub opacity: Option<f32>,
+    /// How fast the layer moves, horizontally and vertically, in pixels of the output per
+    /// second; it wraps around the edges
+    pub drift: Option<[f32; 2]>,
+}
+
+impl Layer {
+    pub fn opacity(&self) -> f32 {
+        self.opacity.unwrap_or(1.0)
+    }
+}
+
+const DEFAULT_OVERLAY_FONT: &str = "sans-serif";
+const DEFAULT_OVERLAY_SIZE: f32 = 24.0;
+const DEFAULT_OVERLAY_COLOR: Color = Color([0xff; 4]);
+const DEFAULT_TIME_FORMAT: &str = "%H:%M";
+
+/// Where the overlay is placed on the output
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Position {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    #[default]
+    BottomRight,
+}
+
+impl Position {
+    /// Horizontal and vertical alignment, from 0 (left, top) to 1 (right, bottom)
+    pub fn alignment(self) -> (f32, f32) {
+        match self {
+            Position::TopLeft => (0.0, 0.0),
+            Position::Top => (0.5, 0.0),
+            Position::TopRight => (1.0, 0.0),
+            Position::Left => (0.0, 0.5),
+            Position::Center => (0.5, 0.5),
+            Position::Right => (1.0, 0.5),
+            Position::BottomLeft => (0.0, 1.0),
+            Position::Bottom => (0.5, 1.0),
+            Position::BottomRight => (1.0, 1.0),
+        }
+    }
+}
+
+/// Which images are shown by the outputs of a group
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupMode {
+    /// Each output picks its own random image
+    #[default]
+    Independent,
+    /// All the outputs show the same image
+    Same,
+    /// The outputs, sorted by name, show consecutive images of the directory
+    Sequential,
+    /// All the outputs show the same image; when it is wide or tall enough, it is split
+    /// across them following their layout
+    Span,
+}
+
+/// How the images of a directory are ordered
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SortOrder {
+    /// By path, in alphabetical order
+    #[default]
+    Name,
+    /// By the date the photos were taken, read from their EXIF metadata; images without it
+    /// come last, sorted by path
+    CaptureTime,
+}
+
+/// Type and description of each key of Output, used to generate the schema of the config.
+/// A key missing here makes `wpaperd schema` fail.
+pub const OUTPUT_KEYS: &[(&str, &str, &str)] = &[
+    (
+        "path",
+        "string",
+        "Path to the image or to the directory of images; an http(s) URL of an image or of a feed of images is downloaded with curl. Required unless collections or tags are set",
+    ),
+    (
+        "mode",
+        "null",
+        "Reserved for a future option, no value is accepted yet",
+    ),
+    (
+        "collections",
+        "array",
+        "Names of collections, defined in the collection table, whose images are shown instead of the ones of path",
+    ),
+    (
+        "tags",
+        "array",
+        "Only show the images with one of these tags, given by their collection or by the sidecar file of their directory; without path nor collections, the images of every collection are considered",
+    ),
+    (
+        "duration",
+        "string",
+        "How much time an image is displayed before changing it, e.g. \"500ms\", \"30s\" or \"1h30m\"; only valid when path is a directory or with collections, defaults to the duration of the default section",
+    ),
+    (
+        "force_8bit",
+        "boolean",
+        "Always use 8 bits per channel, even when the compositor supports 10-bit buffers",
+    ),
+    ("icc_profile", "string", "Path to the ICC profile of the display"),
+    (
+        "exec",
+        "string",
+        "Command run with sh -c every time the wallpaper changes",
+    ),
+    (
+        "lock_screen",
+        "boolean",
+        "Export the wallpaper to $XDG_RUNTIME_DIR/wpaper/lock/<output>.png for screen lockers",
+    ),
+    (
+        "palette_template",
+        "string",
+        "Template filled with the colors of the wallpaper, requires palette_file",
+    ),
+    (
+        "palette_file",
+        "string",
+        "Where the filled palette template is written, requires palette_template",
+    ),
+    (
+        "group",
+        "string",
+        "Name of a group of outputs whose images change together",
+    ),
+    (
+        "group_mode",
+        "string",
+        "Images shown by a group: \"independent\" (default), \"same\", \"span\" or \"sequential\"; read from the first output of the group",
+    ),
+    (
+        "sort",
+        "string",
+        "Order of the images shown by a sequential group: \"name\" (default) or \"capture-time\"",
+    ),
+    (
+        "ken_burns",
+        "boolean",
+        "Slowly pan and zoom across the image during the rotation interval",
+    ),
+    (
+        "ken_burns_fps",
+        "integer",
+        "Frames per second of the Ken Burns effect, defaults to 15",
+    ),
+    (
+        "parallax",
+        "number",
+        "Shift the image toward the pointer by up to this share of the size of the output, e.g. 0.02 for 2%, at most 0.1; cannot be set with ken_burns nor day_night",
+    ),
+    (
+        "background",
+        "string",
+        "Color around the images that don't cover the output and behind transparent images: #rrggbb, #rrggbbaa or transparent; defaults to black",
+    ),
+    (
+        "scaling_filter",
+        "string",
+        "Filter used to scale the images: \"nearest\", \"triangle\", \"catmull-rom\", \"lanczos3\" (default) or \"auto\", which chooses one for each image depending on how much it is scaled and on the size of the output",
+    ),
+    (
+        "dither",
+        "string",
+        "Dithering of the images on outputs with 8 bits per channel, against banding in gradients: \"none\" (default), \"ordered\" or \"blue-noise\"",
+    ),
+    (
+        "fit",
+        "string",
+        "How the images are scaled to the output: \"fill\" (default), \"contain\" or \"tile\", which repeats them; the sidecar file of a directory can set it for each image",
+    ),
+    (
+        "crop",
+        "string",
+        "Which part of the images fill keeps: \"center\" (default), \"entropy\", the one with the most detail, or \"attention\", the one with the most edges, saturated colors and skin tones",
+    ),
+    (
+        "blur_background",
+        "boolean",
+        "Fill the bars around the images that contain leaves with a blurred, enlarged copy of the image instead of the background",
+    ),
+    (
+        "linear_scaling",
+        "boolean",
+        "Scale the images in linear light, which keeps fine textures from darkening and shimmering when they are reduced, at the cost of more time",
+    ),
+    (
+        "tile_scale",
+        "number",
+        "Scale of the tiles when fit is \"tile\", relative to the size of the image; defaults to 1",
+    ),
+    (
+        "tile_mirror",
+        "boolean",
+        "Mirror every other tile when fit is \"tile\", so that patterns which are not seamless look seamless",
+    ),
+    (
+        "match_aspect",
+        "boolean",
+        "Prefer the images of the directory with about the same aspect ratio as the output",
+    ),
+    (
+        "aspect_tolerance",
+        "number",
+        "How far the aspect ratio of an image can be from the output's with match_aspect, or from the layout of a span group, e.g. 0.1 for 10%; defaults to 0.15",
+    ),
+    (
+        "avoid_recent",
+        "integer",
+        "Number of recently shown images that are not chosen again, up to 50; defaults to 1",
+    ),
+    (
+        "allow_duplicates",
+        "boolean",
+        "Let the random choice pick an image that another output is showing; by default it is avoided while there are others",
+    ),
+    (
+        "prefer_unseen",
+        "string",
+        "Only choose the images that no output has shown for this long, e.g. \"30d\", while there are some; otherwise the ones shown the longest time ago",
+    ),
+    (
+        "fade_out",
+        "string",
+        "How long the output takes to fade to black when wpaperd exits, e.g. \"300ms\"; by default the wallpaper disappears at once",
+    ),
+    (
+        "ttl",
+        "string",
+        "How long the images downloaded from a URL are used before downloading it again, e.g. \"1d\"; only valid when path is a URL, defaults to 1h",
+    ),
+    (
+        "fallback_path",
+        "string",
+        "Image or directory shown while the images of the output are on a network mount that is not responding, or while its directories have no images; by default the image already shown stays, or the background fills the output",
+    ),
+    (
+        "workspaces",
+        "object",
+        "Image or directory shown instead of path while a workspace is shown on the output, by workspace name, e.g. { 1 = \"/home/user/code.png\" }; only used under Sway and Hyprland",
+    ),
+    (
+        "overlay",
+        "object",
+        "Text drawn over the wallpaper: text (where {name}, {file}, {dir}, {source}, {author}, {license}, {page} and {time} are replaced), font (a path or a fontconfig name, defaults to sans-serif), size (in pixels, defaults to 24), position (e.g. \"top-left\", \"center\", defaults to \"bottom-right\"), color, opacity (0 to 1) and time_format (strftime, defaults to %H:%M)",
+    ),
+    (
+        "day_night",
+        "object",
+        "Image shown at night instead of path, which must be an image: night_path, night_start and day_start (local times, default to \"19:00\" and \"07:00\") and blend (how long one image takes to replace the other, e.g. \"30m\", defaults to 1h); cannot be set with ken_burns",
+    ),
+    (
+        "layers",
+        "array",
+        "Images stacked over the wallpaper, from the lowest, each scaled to fill the output: path, opacity (0 to 1, defaults to 1) and drift (how fast it moves, e.g. [20, 0] for 20 pixels per second to the right, wrapping around)",
+    ),
+];
@@ -1,37 +1,151 @@
-use std::{sync::Arc, time::Instant};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::output::Output;
 
+/// Counts how long the current image has been displayed. The image is changed once the
+/// deadline, i.e. the start plus the duration, has passed; changing the duration moves the
+/// deadline, the time already elapsed counting towards the new duration.
 pub struct OutputTimer {
     output: Arc<Output>,
-    time_changed: Instant,
-    pub expired: bool,
+    /// Duration set at runtime, it replaces the one in the configuration until it is reloaded
+    duration_override: Option<Duration>,
+    /// Duration of the on-battery section, while the system runs on battery
+    battery_duration: Option<Duration>,
+    /// Duration set for the current image in the sidecar file of its directory
+    image_duration: Option<Duration>,
+    started: Instant,
+    /// When the timer has been paused; the time stands still until it is resumed
+    paused_at: Option<Instant>,
 }
 
 impl OutputTimer {
     pub fn update_output(&mut self, output: Arc<Output>) {
         self.output = output;
+        self.duration_override = None;
     }
 
-    pub fn check_timeout(&mut self) -> bool {
-        // Config might have changed
-        if let Some(duration) = self.output.duration {
-            let now = Instant::now();
-            if now.checked_duration_since(self.time_changed).unwrap() > duration {
-                self.expired = true;
-                self.time_changed = now;
-                return true;
-            }
+    pub fn duration(&self) -> Option<Duration> {
+        // The outputs that don't rotate their images keep not rotating them, whatever the
+        // image or the power supply
+        self.output.duration?;
+        self.image_duration
+            .or(self.duration_override)
+            .or(self.battery_duration)
+            .or(self.output.duration)
+    }
+
+    /// Change the duration without touching the configuration. The time already elapsed
+    /// counts towards the new duration.
+    pub fn set_duration(&mut self, duration: Duration) {
+        self.duration_override = Some(duration);
+    }
+
+    /// Use duration instead of the configured one, or stop using it with None. The time
+    /// already elapsed counts towards the new duration.
+    pub fn set_battery_duration(&mut self, duration: Option<Duration>) {
+        self.battery_duration = duration;
+    }
+
+    /// Display the current image for duration, or for the usual duration with None. The
+    /// time already elapsed counts towards it.
+    pub fn set_image_duration(&mut self, duration: Option<Duration>) {
+        self.image_duration = duration;
+    }
+
+    /// When the next image is due, None if the output has no duration set
+    pub fn deadline(&self) -> Option<Instant> {
+        self.duration().map(|duration| self.started + duration)
+    }
+
+    /// The time of the timer, which stops while it is paused
+    fn now(&self) -> Instant {
+        self.paused_at.unwrap_or_else(Instant::now)
+    }
+
+    pub fn expired(&self) -> bool {
+        self.deadline()
+            .is_some_and(|deadline| deadline <= self.now())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
+    /// Stop counting, the remaining time is kept until the timer is resumed
+    pub fn pause(&mut self) {
+        if self.paused_at.is_none() {
+            self.paused_at = Some(Instant::now());
         }
+    }
 
-        false
+    /// Count again from where the timer was paused
+    pub fn resume(&mut self) {
+        if let Some(paused_at) = self.paused_at.take() {
+            self.started += paused_at.elapsed();
+        }
+    }
+
+    /// Make the timer expire now
+    pub fn expire(&mut self) {
+        if let Some(started) = self
+            .duration()
+            .and_then(|duration| Instant::now().checked_sub(duration))
+        {
+            self.started = started;
+        }
+    }
+
+    /// Restart counting from now, for a new image
+    pub fn reset(&mut self) {
+        self.started = Instant::now();
+        if self.paused_at.is_some() {
+            self.paused_at = Some(self.started);
+        }
+        self.image_duration = None;
+    }
+
+    /// Time left until the timer expires, None if the output has no duration set
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline()
+            .map(|deadline| deadline.saturating_duration_since(self.now()))
+    }
+
+    /// How long to wait for the timer, rounded up to the next millisecond so that it has
+    /// expired once the wait is over. None if it has already expired, is paused or has no
+    /// duration.
+    pub fn timeout(&self) -> Option<Duration> {
+        if self.is_paused() {
+            return None;
+        }
+        let remaining = self
+            .deadline()?
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())?;
+        let millis = remaining.as_nanos().div_ceil(1_000_000);
+        Some(Duration::from_millis(millis.min(u64::MAX as u128) as u64))
+    }
+
+    /// Move the start of the timer back so that it expires after remaining
+    pub fn set_remaining(&mut self, remaining: Duration) {
+        if let Some(duration) = self.duration() {
+            let elapsed = duration.saturating_sub(remaining);
+            if let Some(started) = self.now().checked_sub(elapsed) {
+                self.started = started;
+            }
+        }
     }
 
     pub fn new(output: Arc<Output>) -> Self {
         Self {
             output,
-            time_changed: Instant::now(),
-            expired: false,
+            duration_override: None,
+            battery_duration: None,
+            image_duration: None,
+            started: Instant::now(),
+            paused_at: None,
         }
     }
 }
@@ -0,0 +1,165 @@
+use std::{fmt::Write, path::Path, time::Duration};
+
+use chrono::Local;
+use color_eyre::Result;
+use smithay_client_toolkit::reexports::client::protocol::wl_shm;
+
+use crate::attribution::Attribution;
+use crate::font::{self, Font, Mask};
+use crate::output::Overlay;
+
+/// strftime specifiers that change every second; without them the clock changes every minute
+const SECONDS_SPECIFIERS: &[&str] = &["%S", "%T", "%X", "%r", "%s", "%+", "%f", "%.f"];
+/// The clock is redrawn a little after it changes, so that the new text is never missed
+const CLOCK_SLACK: Duration = Duration::from_millis(5);
+
+/// Draws the text of an overlay over the frames of a surface. The text is only rasterized
+/// again when it changes, e.g. when a new image is shown or the clock ticks.
+pub struct TextLayer {
+    settings: Overlay,
+    font: Font,
+    /// The text drawn last and its mask
+    rendered: Option<(String, Mask)>,
+}
+
+impl TextLayer {
+    pub fn new(settings: &Overlay) -> Result<Self> {
+        Ok(Self {
+            settings: settings.clone(),
+            font: Font::open(&font::resolve(settings.font())?)?,
+            rendered: None,
+        })
+    }
+
+    /// The text shown over image, with its placeholders replaced. attribution tells where
+    /// the image has been downloaded from and who made it.
+    fn text(&self, image: &Path, attribution: Option<&Attribution>) -> String {
+        let lossy = |name: Option<&std::ffi::OsStr>| {
+            name.map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        };
+        let detail = |field: fn(&Attribution) -> &Option<String>| {
+            attribution
+                .and_then(|attribution| field(attribution).as_deref())
+                .unwrap_or("")
+        };
+        let mut time = String::new();
+        if self.settings.text.contains("{time}") {
+            // The format has been validated with the configuration, this never fails
+            let _ = write!(time, "{}", Local::now().format(self.settings.time_format()));
+        }
+        self.settings
+            .text
+            .replace("{name}", &lossy(image.file_stem()))
+            .replace("{file}", &lossy(image.file_name()))
+            .replace(
+                "{dir}",
+                &lossy(image.parent().and_then(|parent| parent.file_name())),
+            )
+            .replace(
+                "{source}",
+                attribution.map_or("", |attribution| &attribution.source),
+            )
+            .replace("{author}", detail(|attribution| &attribution.author))
+            .replace("{license}", detail(|attribution| &attribution.license))
+            .replace("{page}", detail(|attribution| &attribution.page))
+            .replace("{time}", &time)
+    }
+
+    /// Whether the text has changed since it was last drawn over image
+    pub fn outdated(&self, image: &Path, attribution: Option<&Attribution>) -> bool {
+        self.rendered
+            .as_ref()
+            .is_some_and(|(text, _)| *text != self.text(image, attribution))
+    }
+
+    /// How long until the clock changes, None if the text has no clock
+    pub fn next_change(&self) -> Option<Duration> {
+        if !self.settings.text.contains("{time}") {
+            return None;
+        }
+        let format = self.settings.time_format();
+        let period = if SECONDS_SPECIFIERS
+            .iter()
+            .any(|specifier| format.contains(specifier))
+        {
+            1000
+        } else {
+            60_000
+        };
+        let now = Local::now().timestamp_millis();
+        Some(Duration::from_millis((period - now.rem_euclid(period)) as u64) + CLOCK_SLACK)
+    }
+
+    /// Draw the text over data, a frame of width x height pixels in format showing image
+    pub fn apply(
+        &mut self,
+        data: &mut [u8],
+        (width, height): (u32, u32),
+        format: wl_shm::Format,
+        image: &Path,
+        attribution: Option<&Attribution>,
+    ) {
+        let text = self.text(image, attribution);
+        let (align_x, align_y) = self.settings.position.alignment();
+        let size = self.settings.size();
+        if self.rendered.as_ref().is_none_or(|(old, _)| *old != text) {
+            let mask = self.font.rasterize(&text, size, align_x);
+            self.rendered = Some((text, mask));
+        }
+        let mask = match &self.rendered {
+            // Nothing to draw, e.g. the text is only whitespace
+            Some((_, mask)) if mask.width > 0 => mask,
+            _ => return,
+        };
+
+        // Keep the text away from the edges by half its size
+        let margin = size / 2.0;
+        let place = |output: u32, layout: u32, align: f32| {
+            (margin + (output as f32 - 2.0 * margin - layout as f32) * align).round() as i64
+        };
+        let left = place(width, mask.layout.0, align_x) + i64::from(mask.offset.0);
+        let top = place(height, mask.layout.1, align_y) + i64::from(mask.offset.1);
+
+        let color = self.settings.color();
+        let opacity = self.settings.opacity() * f32::from(color.0[3]) / 255.0;
+        for (mask_y, row) in mask.coverage.chunks(mask.width as usize).enumerate() {
+            let y = top + mask_y as i64;
+            if y < 0 || y >= i64::from(height) {
+                continue;
+            }
+            for (mask_x, &coverage) in row.iter().enumerate() {
+                let x = left + mask_x as i64;
+                if coverage == 0 || x < 0 || x >= i64::from(width) {
+                    continue;
+                }
+                let alpha = f32::from(coverage) / 255.0 * opacity;
+                let offset = 4 * (y as usize * width as usize + x as usize);
+                blend(&mut data[offset..offset + 4], format, color.0, alpha);
+            }
+        }
+    }
+}
+
+/// Mix color into a pixel. The pixels with alpha are premultiplied, or opaque, so the same
+/// operation applies to every channel.
+fn blend(pixel: &mut [u8], format: wl_shm::Format, [r, g, b, _]: [u8; 4], alpha: f32) {
+    let mix = |dst: f32, src: f32| src * alpha + dst * (1.0 - alpha);
+    match format {
+        wl_shm::Format::Xbgr2101010 => {
+            let value = u32::from_le_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]);
+            let mut mixed = 0;
+            for (i, component) in [r, g, b].into_iter().enumerate() {
+                let dst = ((value >> (10 * i)) & 0x3ff) as f32;
+                let src = f32::from(component) * 1023.0 / 255.0;
+                mixed |= (mix(dst, src).round() as u32).min(0x3ff) << (10 * i);
+            }
+            pixel.copy_from_slice(&mixed.to_le_bytes());
+        }
+        _ => {
+            for (dst, src) in pixel.iter_mut().zip([r, g, b, 0xff]) {
+                *dst = mix(f32::from(*dst), f32::from(src)).round() as u8;
+            }
+        }
+    }
+}
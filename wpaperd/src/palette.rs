@@ -0,0 +1,94 @@
+use std::{fs, path::Path};
+
+use color_eyre::{eyre::WrapErr, Result};
+use image::DynamicImage;
+
+/// Number of colors extracted from each wallpaper
+pub const PALETTE_SIZE: usize = 8;
+
+/// Find the dominant colors of image using the median cut algorithm.
+/// The colors are sorted from the most to the least common.
+pub fn extract_palette(image: &DynamicImage, size: usize) -> Vec<[u8; 3]> {
+    // A small version of the image is more than enough to find its dominant colors
+    let pixels: Vec<[u8; 3]> = image
+        .thumbnail(128, 128)
+        .into_rgb8()
+        .pixels()
+        .map(|pixel| pixel.0)
+        .collect();
+    if pixels.is_empty() {
+        return Vec::new();
+    }
+
+    let mut buckets = vec![pixels];
+    while buckets.len() < size {
+        // Split the bucket with the widest range in any of its channels
+        let (index, channel, range) = buckets
+            .iter()
+            .enumerate()
+            .map(|(index, bucket)| {
+                let (channel, range) = widest_channel(bucket);
+                (index, channel, range)
+            })
+            .max_by_key(|(_, _, range)| *range)
+            .unwrap();
+        if range == 0 {
+            break;
+        }
+        let mut bucket = buckets.swap_remove(index);
+        bucket.sort_unstable_by_key(|pixel| pixel[channel]);
+        let upper = bucket.split_off(bucket.len() / 2);
+        buckets.push(bucket);
+        buckets.push(upper);
+    }
+
+    buckets.sort_by_key(|bucket| std::cmp::Reverse(bucket.len()));
+    buckets
+        .iter()
+        .map(|bucket| {
+            let mut sum = [0_u64; 3];
+            for pixel in bucket {
+                for (channel, value) in pixel.iter().enumerate() {
+                    sum[channel] += u64::from(*value);
+                }
+            }
+            sum.map(|value| (value / bucket.len() as u64) as u8)
+        })
+        .collect()
+}
+
+fn widest_channel(bucket: &[[u8; 3]]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let min = bucket.iter().map(|pixel| pixel[channel]).min().unwrap_or(0);
+            let max = bucket.iter().map(|pixel| pixel[channel]).max().unwrap_or(0);
+            (channel, max - min)
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap()
+}
+
+pub fn to_hex(color: &[u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+/// Fill the template with the palette and write it to destination.
+/// The template can contain {color0}, {color1}, ... {image} and {output}.
+pub fn write_palette(
+    template: &Path,
+    destination: &Path,
+    palette: &[[u8; 3]],
+    output: &str,
+    image: &Path,
+) -> Result<()> {
+    let mut content = fs::read_to_string(template)
+        .with_context(|| format!("reading palette template {:?}", template))?;
+    for (i, color) in palette.iter().enumerate() {
+        content = content.replace(&format!("{{color{}}}", i), &to_hex(color));
+    }
+    content = content
+        .replace("{image}", &image.to_string_lossy())
+        .replace("{output}", output);
+    fs::write(destination, content)
+        .with_context(|| format!("writing the palette to {:?}", destination))
+}
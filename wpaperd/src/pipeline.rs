@@ -0,0 +1,55 @@
+use std::path::Path;
+use std::time::Instant;
+
+use color_eyre::Result;
+use image::DynamicImage;
+pub use wpaper_core::pipeline::*;
+
+use crate::collection::Fit;
+use crate::metrics;
+use crate::output::{Output, ScalingFilter};
+
+/// Decodes the image files like wpaper_core::pipeline::FileDecoder, timing them for the
+/// metrics
+pub struct FileDecoder;
+
+impl Decoder for FileDecoder {
+    fn decode(&self, path: &Path, width: u32, height: u32) -> Result<DynamicImage> {
+        decode_image(path, width, height)
+    }
+
+    fn decodes_reduced_fast(&self, path: &Path) -> bool {
+        wpaper_core::pipeline::FileDecoder.decodes_reduced_fast(path)
+    }
+}
+
+/// Decode the image at path with wpaper_core::pipeline::decode_image, timing it for the
+/// metrics
+pub fn decode_image(path: &Path, width: u32, height: u32) -> Result<DynamicImage> {
+    let start = Instant::now();
+    let image = wpaper_core::pipeline::decode_image(path, width, height)?;
+    metrics::decoded(start.elapsed());
+    Ok(image)
+}
+
+/// Scales the images according to fit and to the settings of output, over its background
+pub fn fit_scaler(output: &Output, fit: Fit, filter: ScalingFilter) -> FitScaler {
+    FitScaler::new(
+        fit,
+        filter,
+        output.crop,
+        output.background(),
+        output.tile_scale(),
+        output.tile_mirror,
+        output.blur_background,
+        output.linear_scaling,
+    )
+}
+
+/// Scales the images to cover the layout spanned by output, over its background
+pub fn span_scaler(output: &Output, span: Span, filter: ScalingFilter) -> SpanScaler {
+    SpanScaler::new(span, filter, output.background(), output.linear_scaling)
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,413 @@
+//! The drawing pipeline run against a headless stand-in for the compositor. The frames are
+//! compared with the golden images in tests/golden; run the tests with UPDATE_GOLDEN=1 to
+//! write them again after an intended change.
+
+use std::{env, path::Path, sync::Mutex};
+
+use image::{DynamicImage, GenericImageView, ImageBuffer, Rgb, Rgba, RgbaImage};
+use smithay_client_toolkit::reexports::client::protocol::wl_shm;
+use wpaper_core::inversion::invert;
+
+use super::*;
+use crate::night::NightMode;
+use crate::output::{Color, Crop, Dither};
+
+/// Returns the same image whatever the path, and remembers the sizes it was asked for
+struct MockDecoder {
+    image: DynamicImage,
+    requested: Mutex<Vec<(u32, u32)>>,
+}
+
+impl MockDecoder {
+    fn new(image: DynamicImage) -> Self {
+        Self {
+            image,
+            requested: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl Decoder for MockDecoder {
+    fn decode(&self, _path: &Path, width: u32, height: u32) -> Result<DynamicImage> {
+        self.requested.lock().unwrap().push((width, height));
+        Ok(self.image.clone())
+    }
+}
+
+/// Takes the place of a wayland surface, keeping every frame it is given
+#[derive(Default)]
+struct MockSink {
+    frames: Vec<(Vec<u8>, (u32, u32), wl_shm::Format)>,
+}
+
+impl FrameSink for MockSink {
+    fn present(&mut self, data: &[u8], size: (u32, u32), format: wl_shm::Format) -> Result<()> {
+        assert_eq!(data.len(), 4 * size.0 as usize * size.1 as usize);
+        self.frames.push((data.to_vec(), size, format));
+        Ok(())
+    }
+}
+
+/// Red grows to the right, green to the bottom
+fn gradient(width: u32, height: u32) -> DynamicImage {
+    DynamicImage::ImageRgba8(ImageBuffer::from_fn(width, height, |x, y| {
+        let ramp = |value: u32, size: u32| (value * 255 / (size - 1).max(1)) as u8;
+        Rgba([ramp(x, width), ramp(y, height), 0x80, 0xff])
+    }))
+}
+
+fn checkerboard(size: u32, square: u32) -> DynamicImage {
+    DynamicImage::ImageRgba8(ImageBuffer::from_fn(size, size, |x, y| {
+        if (x / square + y / square).is_multiple_of(2) {
+            Rgba([0xff, 0xff, 0xff, 0xff])
+        } else {
+            Rgba([0, 0, 0, 0xff])
+        }
+    }))
+}
+
+/// Decode, scale and convert an image like a surface does, then present it to sink
+fn render(
+    decoder: &MockDecoder,
+    output: &Output,
+    fit: Fit,
+    filter: ScalingFilter,
+    (width, height): (u32, u32),
+    format: wl_shm::Format,
+    sink: &mut MockSink,
+) {
+    let (decode_width, decode_height) = decode_size(fit, width, height);
+    let image = decoder
+        .decode(Path::new("image.png"), decode_width, decode_height)
+        .unwrap();
+    let image = fit_scaler(output, fit, filter).scale(image, width, height);
+    assert_eq!((image.width(), image.height()), (width, height));
+    let data = to_buffer_data(image, format, None, output.background(), output.dither);
+    sink.present(&data, (width, height), format).unwrap();
+}
+
+/// Compare a frame in the 8-bit format with tests/golden/<name>.png
+fn assert_golden(
+    name: &str,
+    (data, (width, height), format): &(Vec<u8>, (u32, u32), wl_shm::Format),
+) {
+    assert_eq!(*format, wl_shm::Format::Abgr8888);
+    // The bytes of Abgr8888 in little endian are R, G, B and A
+    let frame = RgbaImage::from_raw(*width, *height, data.clone()).unwrap();
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{}.png", name));
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        frame.save(&path).unwrap();
+        return;
+    }
+    let golden = image::open(&path)
+        .unwrap_or_else(|err| panic!("opening {:?}: {}; run with UPDATE_GOLDEN=1", path, err))
+        .into_rgba8();
+    assert!(
+        golden == frame,
+        "the frame differs from {:?}; run with UPDATE_GOLDEN=1 if the change is intended",
+        path
+    );
+}
+
+#[test]
+fn fill_crops_the_sides() {
+    let decoder = MockDecoder::new(gradient(8, 4));
+    let mut sink = MockSink::default();
+    render(
+        &decoder,
+        &Output::default(),
+        Fit::Fill,
+        ScalingFilter::Nearest,
+        (4, 4),
+        wl_shm::Format::Abgr8888,
+        &mut sink,
+    );
+    assert_eq!(*decoder.requested.lock().unwrap(), [(4, 4)]);
+    assert_golden("fill", &sink.frames[0]);
+}
+
+#[test]
+fn smart_crops_keep_the_detailed_part() {
+    // A flat gray image with a checkerboard on its right third
+    let image = DynamicImage::ImageRgba8(ImageBuffer::from_fn(90, 30, |x, y| {
+        if x >= 60 && (x / 3 + y / 3) % 2 == 0 {
+            Rgba([255, 255, 255, 255])
+        } else {
+            Rgba([100, 100, 100, 255])
+        }
+    }));
+    for crop in [Crop::Entropy, Crop::Attention] {
+        let output = Output {
+            crop,
+            ..Output::default()
+        };
+        let scaled =
+            fit_scaler(&output, Fit::Fill, ScalingFilter::Nearest).scale(image.clone(), 30, 30);
+        assert_eq!(scaled.dimensions(), (30, 30));
+        assert_eq!(scaled.to_rgba8(), image.crop_imm(60, 0, 30, 30).to_rgba8());
+    }
+    let output = Output::default();
+    let scaled =
+        fit_scaler(&output, Fit::Fill, ScalingFilter::Nearest).scale(image.clone(), 30, 30);
+    assert_eq!(scaled.to_rgba8(), image.crop_imm(30, 0, 30, 30).to_rgba8());
+}
+
+#[test]
+fn contain_shows_the_background_around_the_image() {
+    let decoder = MockDecoder::new(gradient(8, 4));
+    let output = Output {
+        background: Some(Color([0xff, 0, 0, 0xff])),
+        ..Output::default()
+    };
+    let mut sink = MockSink::default();
+    render(
+        &decoder,
+        &output,
+        Fit::Contain,
+        ScalingFilter::Triangle,
+        (6, 6),
+        wl_shm::Format::Abgr8888,
+        &mut sink,
+    );
+    assert_golden("contain", &sink.frames[0]);
+}
+
+#[test]
+fn blurred_background_fills_the_bars_with_the_image() {
+    let decoder = MockDecoder::new(gradient(32, 16));
+    let output = Output {
+        background: Some(Color([0xff, 0, 0, 0xff])),
+        blur_background: true,
+        ..Output::default()
+    };
+    let mut sink = MockSink::default();
+    render(
+        &decoder,
+        &output,
+        Fit::Contain,
+        ScalingFilter::Triangle,
+        (32, 32),
+        wl_shm::Format::Abgr8888,
+        &mut sink,
+    );
+    let (data, _, _) = &sink.frames[0];
+    let frame = RgbaImage::from_raw(32, 32, data.clone()).unwrap();
+    // The bars take the colors of the image, whose blue is 0x80, not the red background
+    for (x, y) in [(0, 0), (31, 0), (16, 2), (16, 29), (31, 31)] {
+        let Rgba([_, _, b, a]) = *frame.get_pixel(x, y);
+        assert!((0x70..=0x90).contains(&b) && a == 0xff, "{:?}", (x, y, b));
+    }
+    // The image itself is untouched in the middle
+    assert_eq!(frame.get_pixel(0, 16)[2], 0x80);
+    assert_eq!(frame.get_pixel(0, 16)[0], 0);
+}
+
+#[test]
+fn linear_scaling_keeps_the_brightness_of_fine_details() {
+    let decoder = MockDecoder::new(checkerboard(64, 1));
+    let gray = |linear_scaling| {
+        let output = Output {
+            linear_scaling,
+            ..Output::default()
+        };
+        let mut sink = MockSink::default();
+        render(
+            &decoder,
+            &output,
+            Fit::Fill,
+            ScalingFilter::Triangle,
+            (8, 8),
+            wl_shm::Format::Abgr8888,
+            &mut sink,
+        );
+        sink.frames[0].0[4 * (4 * 8 + 4)]
+    };
+    // Half the light of white is 0xbc in sRGB, averaging the levels gives 0x80
+    assert!((0x7c..=0x84).contains(&gray(false)), "{:#x}", gray(false));
+    assert!((0xb8..=0xc0).contains(&gray(true)), "{:#x}", gray(true));
+}
+
+#[test]
+fn tile_repeats_the_image_at_its_size() {
+    let decoder = MockDecoder::new(gradient(3, 2));
+    let output = Output {
+        tile_mirror: true,
+        ..Output::default()
+    };
+    let mut sink = MockSink::default();
+    render(
+        &decoder,
+        &output,
+        Fit::Tile,
+        ScalingFilter::Lanczos3,
+        (8, 5),
+        wl_shm::Format::Abgr8888,
+        &mut sink,
+    );
+    // The image must not be reduced while decoding
+    assert_eq!(*decoder.requested.lock().unwrap(), [(u32::MAX, u32::MAX)]);
+    assert_golden("tile-mirror", &sink.frames[0]);
+}
+
+#[test]
+fn filters() {
+    let decoder = MockDecoder::new(checkerboard(16, 2));
+    for (name, filter) in [
+        ("nearest", ScalingFilter::Nearest),
+        ("triangle", ScalingFilter::Triangle),
+        ("catmull-rom", ScalingFilter::CatmullRom),
+        ("lanczos3", ScalingFilter::Lanczos3),
+    ] {
+        let mut sink = MockSink::default();
+        render(
+            &decoder,
+            &Output::default(),
+            Fit::Fill,
+            filter,
+            (6, 6),
+            wl_shm::Format::Abgr8888,
+            &mut sink,
+        );
+        assert_golden(&format!("filter-{}", name), &sink.frames[0]);
+    }
+}
+
+#[test]
+fn inversion_transition() {
+    let decoder = MockDecoder::new(gradient(4, 4));
+    let mut sink = MockSink::default();
+    render(
+        &decoder,
+        &Output::default(),
+        Fit::Fill,
+        ScalingFilter::Nearest,
+        (4, 4),
+        wl_shm::Format::Abgr8888,
+        &mut sink,
+    );
+    let (original, size, format) = sink.frames.pop().unwrap();
+
+    let mut frame = original.clone();
+    invert(&mut frame, format, 0.5);
+    sink.present(&frame, size, format).unwrap();
+    assert_golden("inversion-half", &sink.frames[0]);
+
+    // Inverting twice gives the original colors back
+    let mut frame = original.clone();
+    invert(&mut frame, format, 1.0);
+    invert(&mut frame, format, 1.0);
+    assert_eq!(frame, original);
+}
+
+#[test]
+fn ten_bit_frames_match_the_eight_bit_ones() {
+    let decoder = MockDecoder::new(gradient(8, 8));
+    let mut sink = MockSink::default();
+    for format in [wl_shm::Format::Abgr8888, wl_shm::Format::Xbgr2101010] {
+        render(
+            &decoder,
+            &Output::default(),
+            Fit::Fill,
+            ScalingFilter::Nearest,
+            (8, 8),
+            format,
+            &mut sink,
+        );
+    }
+    let (eight_bit, ten_bit) = (&sink.frames[0].0, &sink.frames[1].0);
+    for (rgba, packed) in eight_bit.chunks_exact(4).zip(ten_bit.chunks_exact(4)) {
+        let packed = u32::from_le_bytes([packed[0], packed[1], packed[2], packed[3]]);
+        for (channel, &component) in rgba[..3].iter().enumerate() {
+            // Both are rounded to their nearest level
+            let ten_bit = ((packed >> (10 * channel)) & 0x3ff) as f32 / 1023.0;
+            let eight_bit = f32::from(component) / 255.0;
+            assert!((ten_bit - eight_bit).abs() <= 0.5 / 1023.0 + 0.5 / 255.0);
+        }
+    }
+}
+
+#[test]
+fn spans_join_into_the_whole_layout() {
+    let output = Output::default();
+    let whole =
+        fit_scaler(&output, Fit::Fill, ScalingFilter::Triangle).scale(gradient(20, 6), 8, 3);
+    for (x, offset) in [(0.0, 0), (0.5, 4)] {
+        let span = Span {
+            x,
+            y: 0.0,
+            width: 0.5,
+            height: 1.0,
+        };
+        let part = span_scaler(&output, span, ScalingFilter::Triangle).scale(gradient(20, 6), 4, 3);
+        assert_eq!(part.to_rgba8(), whole.crop_imm(offset, 0, 4, 3).to_rgba8());
+    }
+}
+
+#[test]
+fn dithering_keeps_the_levels_between_those_of_8_bits() {
+    // About a quarter of the way from 100 to 101
+    let level = 100 * 257 + 64;
+    let image = DynamicImage::ImageRgba16(ImageBuffer::from_pixel(
+        64,
+        64,
+        Rgba([level, level, level, u16::MAX]),
+    ));
+    for method in [Dither::Ordered, Dither::BlueNoise] {
+        let data = to_buffer_data(
+            image.clone(),
+            wl_shm::Format::Abgr8888,
+            None,
+            Color([0, 0, 0, 0xff]),
+            method,
+        );
+        let upper = data.chunks_exact(4).filter(|pixel| pixel[0] == 101).count();
+        assert!(data
+            .chunks_exact(4)
+            .all(|pixel| pixel[0] == 100 || pixel[0] == 101));
+        // Each threshold of the map is used once
+        assert_eq!(upper, (64.0 * 64.0 * 64.0 / 257.0_f32).round() as usize);
+    }
+}
+
+#[test]
+fn night_mode_tints_warmer_and_dims_past_midnight() {
+    let night_mode = NightMode {
+        latitude: None,
+        longitude: None,
+        start: Some("22:00".to_string()),
+        end: Some("06:30".to_string()),
+        temperature: None,
+        brightness: None,
+    };
+    night_mode.validate().unwrap();
+    let at = |time: &str| {
+        let time = chrono::NaiveTime::parse_from_str(time, "%H:%M").unwrap();
+        chrono::Local::today().and_time(time).unwrap()
+    };
+    assert!(night_mode.tint(at("12:00")).unwrap().is_none());
+    assert!(night_mode.tint(at("06:30")).unwrap().is_none());
+    let tint = night_mode.tint(at("02:00")).unwrap().unwrap();
+
+    let mut data = vec![200; 4];
+    tint.apply(&mut data, wl_shm::Format::Abgr8888);
+    let [r, g, b, a] = [data[0], data[1], data[2], data[3]];
+    assert!(r < 200 && g < r && b < g);
+    assert_eq!(a, 200);
+}
+
+#[test]
+fn sixteen_bit_images_round_to_the_nearest_level() {
+    // Closer to 101 than to 100, dropping the low byte would give 100
+    let level = 100 * 257 + 200;
+    let image = DynamicImage::ImageRgb16(ImageBuffer::from_pixel(2, 2, Rgb([level, 0, u16::MAX])));
+    let data = to_buffer_data(
+        image,
+        wl_shm::Format::Abgr8888,
+        None,
+        Color([0, 0, 0, 0xff]),
+        Dither::None,
+    );
+    assert_eq!(&data[..4], &[101, 0, 255, 255]);
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use smithay_client_toolkit::{
+    environment::{Environment, GlobalHandler, MultiGlobalHandler},
+    reexports::client::{
+        protocol::{
+            wl_compositor::WlCompositor,
+            wl_pointer::{self, WlPointer},
+            wl_seat::WlSeat,
+            wl_shm::WlShm,
+            wl_surface::WlSurface,
+        },
+        Attached, DispatchData,
+    },
+    seat::{
+        pointer::{ThemeManager, ThemeSpec, ThemedPointer},
+        with_seat_data, SeatData, SeatHandling, SeatListener,
+    },
+};
+
+/// The pointer has moved over the surface of an output, or has left it
+pub struct PointerEvent {
+    pub surface: WlSurface,
+    /// Position in the surface, None when the pointer has left it
+    pub position: Option<(f64, f64)>,
+}
+
+/// Keeps the pointers of the seats, so that the outputs with a parallax can follow them
+pub struct Pointers {
+    _listener: SeatListener,
+}
+
+impl Pointers {
+    /// Bind the pointer of every seat that has one, now and when the seats change. Their
+    /// events over the outputs are given to on_event.
+    pub fn watch<E>(env: &Environment<E>, on_event: fn(PointerEvent, DispatchData)) -> Self
+    where
+        E: SeatHandling
+            + MultiGlobalHandler<WlSeat>
+            + GlobalHandler<WlCompositor>
+            + GlobalHandler<WlShm>,
+    {
+        let theme = ThemeManager::init(
+            ThemeSpec::System,
+            env.require_global(),
+            env.require_global(),
+        );
+        // By the id of their seat
+        let mut pointers: HashMap<u32, ThemedPointer> = HashMap::new();
+        let mut seat_changed = move |seat: Attached<WlSeat>, data: &SeatData| {
+            let id = seat.as_ref().id();
+            if data.has_pointer && !data.defunct {
+                pointers.entry(id).or_insert_with(|| {
+                    let mut focus = None;
+                    theme.theme_pointer_with_impl(&seat, move |event, pointer, ddata| {
+                        handle_event(event, &pointer, &mut focus, on_event, ddata)
+                    })
+                });
+            } else if let Some(pointer) = pointers.remove(&id) {
+                release(&pointer);
+            }
+        };
+        for seat in env.get_all_seats() {
+            with_seat_data(&seat, |data| seat_changed(seat.clone(), data));
+        }
+        Self {
+            _listener: env.listen_for_seats(move |seat, data, _| seat_changed(seat, data)),
+        }
+    }
+}
+
+/// Follow the pointer between the surfaces, focus being the one it is over
+fn handle_event(
+    event: wl_pointer::Event,
+    pointer: &ThemedPointer,
+    focus: &mut Option<WlSurface>,
+    on_event: fn(PointerEvent, DispatchData),
+    ddata: DispatchData,
+) {
+    let (surface, position) = match event {
+        wl_pointer::Event::Enter {
+            serial,
+            surface,
+            surface_x,
+            surface_y,
+        } => {
+            // Once bound, the cursor over the outputs is wpaperd's to set, it would be
+            // hidden otherwise
+            let _ = pointer.set_cursor("left_ptr", Some(serial));
+            *focus = Some(surface.clone());
+            (surface, Some((surface_x, surface_y)))
+        }
+        wl_pointer::Event::Motion {
+            surface_x,
+            surface_y,
+            ..
+        } => match focus {
+            Some(surface) => (surface.clone(), Some((surface_x, surface_y))),
+            None => return,
+        },
+        wl_pointer::Event::Leave { surface, .. } => {
+            *focus = None;
+            (surface, None)
+        }
+        _ => return,
+    };
+    on_event(PointerEvent { surface, position }, ddata);
+}
+
+fn release(pointer: &WlPointer) {
+    // Before version 3, the pointer cannot be released and stays until the seat goes away
+    if pointer.as_ref().version() >= 3 {
+        pointer.release();
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use log::info;
+use serde::Deserialize;
+
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+/// Reading sysfs is cheap, but there is no need to do it on every frame
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The on-battery section of the configuration: how the outputs save power while the
+/// system runs on battery
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OnBattery {
+    /// Replaces the duration of the outputs that rotate their images
+    #[serde(default, with = "humantime_serde")]
+    pub duration: Option<Duration>,
+    /// Keep the Ken Burns effect and the transitions
+    #[serde(default)]
+    pub animations: bool,
+    /// Scale the images with a cheaper filter
+    #[serde(default = "default_fast_scaling")]
+    pub fast_scaling: bool,
+}
+
+fn default_fast_scaling() -> bool {
+    true
+}
+
+/// Tells whether the system runs on battery: it has a battery and none of its external power
+/// supplies, e.g. the AC adapter or USB-C, is online
+#[derive(Default)]
+pub struct Power {
+    on_battery: bool,
+    last_check: Option<Instant>,
+}
+
+impl Power {
+    pub fn on_battery(&mut self) -> bool {
+        if self
+            .last_check
+            .is_some_and(|last_check| last_check.elapsed() < CHECK_INTERVAL)
+        {
+            return self.on_battery;
+        }
+        self.last_check = Some(Instant::now());
+        let on_battery = read_on_battery();
+        if on_battery != self.on_battery {
+            info!(
+                "the system is running on {}",
+                if on_battery {
+                    "battery"
+                } else {
+                    "external power"
+                }
+            );
+            self.on_battery = on_battery;
+        }
+        on_battery
+    }
+}
+
+fn read_on_battery() -> bool {
+    let entries = match fs::read_dir(POWER_SUPPLY_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    let mut has_battery = false;
+    for entry in entries.filter_map(Result::ok) {
+        let supply = entry.path();
+        match read(&supply.join("type")).as_deref() {
+            // The batteries of wireless mice and the like have the device scope
+            Some("Battery") => {
+                has_battery |= read(&supply.join("scope")).as_deref() != Some("Device")
+            }
+            Some(_) if read(&supply.join("online")).as_deref() == Some("1") => return false,
+            _ => {}
+        }
+    }
+    has_battery
+}
+
+fn read(path: &Path) -> Option<String> {
+    Some(fs::read_to_string(path).ok()?.trim().to_string())
+}
@@ -0,0 +1,102 @@
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+};
+
+use crate::collection::Collection;
+
+/// The images an output shows next, in order. It is filled with random choices of the
+/// collection when it is inspected; once empty, the next images are chosen when they are
+/// needed.
+#[derive(Default)]
+pub struct Queue {
+    entries: VecDeque<Entry>,
+}
+
+struct Entry {
+    image: PathBuf,
+    /// Pinned entries stay in the queue when the settings of the output change
+    pinned: bool,
+}
+
+impl Queue {
+    /// The first count images of the queue, choosing the missing ones from collection.
+    /// recent are the images displayed last, from the most recent, which are avoided.
+    pub fn upcoming(
+        &mut self,
+        count: usize,
+        collection: &Collection,
+        recent: &[PathBuf],
+    ) -> Vec<(&Path, bool)> {
+        while self.entries.len() < count {
+            // The images already queued are shown before the new one, avoid them first
+            let avoided: Vec<PathBuf> = self
+                .entries
+                .iter()
+                .rev()
+                .map(|entry| entry.image.clone())
+                .chain(recent.iter().cloned())
+                .collect();
+            match collection.choose(&avoided) {
+                Some(image) => self.entries.push_back(Entry {
+                    image: image.to_path_buf(),
+                    pinned: false,
+                }),
+                None => break,
+            }
+        }
+        self.entries
+            .iter()
+            .take(count)
+            .map(|entry| (entry.image.as_path(), entry.pinned))
+            .collect()
+    }
+
+    /// Take the image to show next
+    pub fn pop(&mut self) -> Option<PathBuf> {
+        self.entries.pop_front().map(|entry| entry.image)
+    }
+
+    /// Remove image from the queue, or the first image if None. Returns false if there was
+    /// no such image.
+    pub fn skip(&mut self, image: Option<&Path>) -> bool {
+        let index = match image {
+            Some(image) => self.position(image),
+            None => (!self.entries.is_empty()).then_some(0),
+        };
+        index.and_then(|index| self.entries.remove(index)).is_some()
+    }
+
+    /// Keep image in the queue when the settings change, or stop keeping it. Returns false
+    /// if image is not queued.
+    pub fn set_pinned(&mut self, image: &Path, pinned: bool) -> bool {
+        match self.position(image) {
+            Some(index) => {
+                self.entries[index].pinned = pinned;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Show image next, moving it if it is already queued. It is pinned, as it has been
+    /// chosen explicitly.
+    pub fn move_to_front(&mut self, image: &Path) {
+        if let Some(index) = self.position(image) {
+            self.entries.remove(index);
+        }
+        self.entries.push_front(Entry {
+            image: image.to_path_buf(),
+            pinned: true,
+        });
+    }
+
+    /// Forget the images that were chosen randomly, e.g. because the path changed
+    pub fn retain_pinned(&mut self) {
+        self.entries.retain(|entry| entry.pinned);
+    }
+
+    fn position(&self, image: &Path) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.image == image)
+    }
+}
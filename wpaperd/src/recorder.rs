@@ -0,0 +1,122 @@
+use std::{
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::Duration,
+};
+
+use color_eyre::{
+    eyre::{ensure, WrapErr},
+    Result,
+};
+use log::{error, warn};
+use smithay_client_toolkit::reexports::client::protocol::wl_shm;
+
+/// Records the frames drawn on a surface into a video, using ffmpeg as encoder.
+/// The video ends when the recorder is dropped.
+pub struct Recorder {
+    frames_tx: Sender<Vec<u8>>,
+    dimensions: (u32, u32),
+    format: wl_shm::Format,
+    /// Record a frame every interval rotations
+    interval: u32,
+    rotations: u32,
+    /// Receives once ffmpeg has exited
+    done_rx: Receiver<()>,
+}
+
+impl Recorder {
+    pub fn start(
+        path: &Path,
+        dimensions: (u32, u32),
+        format: wl_shm::Format,
+        interval: u32,
+        framerate: u32,
+    ) -> Result<Self> {
+        ensure!(interval > 0, "the interval must be greater than 0");
+        ensure!(framerate > 0, "the framerate must be greater than 0");
+        let pixel_format = match format {
+            wl_shm::Format::Xbgr2101010 => "x2bgr10le",
+            _ => "rgba",
+        };
+        let mut child = Command::new("ffmpeg")
+            .args([
+                "-loglevel",
+                "error",
+                "-y",
+                "-f",
+                "rawvideo",
+                "-pixel_format",
+            ])
+            .arg(pixel_format)
+            .arg("-video_size")
+            .arg(format!("{}x{}", dimensions.0, dimensions.1))
+            .arg("-framerate")
+            .arg(framerate.to_string())
+            .args(["-i", "-", "-pix_fmt", "yuv420p"])
+            .arg(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .context("running ffmpeg")?;
+        let mut stdin = child.stdin.take().unwrap();
+
+        // Encoding can be slow, feed ffmpeg from another thread so that drawing is not blocked
+        let (frames_tx, frames_rx) = mpsc::channel::<Vec<u8>>();
+        let (done_tx, done_rx) = mpsc::channel();
+        let path = path.to_path_buf();
+        thread::spawn(move || {
+            for frame in frames_rx {
+                if let Err(err) = stdin.write_all(&frame) {
+                    error!("writing a frame to ffmpeg for {:?}: {:?}", path, err);
+                    break;
+                }
+            }
+            // Closing stdin tells ffmpeg that the video is over
+            drop(stdin);
+            match child.wait() {
+                Ok(status) if !status.success() => {
+                    error!("ffmpeg exited with {} while recording {:?}", status, path)
+                }
+                Ok(_) => {}
+                Err(err) => error!("waiting for ffmpeg: {:?}", err),
+            }
+            let _ = done_tx.send(());
+        });
+
+        Ok(Self {
+            frames_tx,
+            dimensions,
+            format,
+            interval,
+            rotations: 0,
+            done_rx,
+        })
+    }
+
+    /// Called for every frame drawn. Returns false if the recording cannot continue.
+    pub fn add_frame(
+        &mut self,
+        data: &[u8],
+        dimensions: (u32, u32),
+        format: wl_shm::Format,
+    ) -> bool {
+        if dimensions != self.dimensions || format != self.format {
+            warn!("the output has changed, the recording has been stopped");
+            return false;
+        }
+
+        let record = self.rotations.is_multiple_of(self.interval);
+        self.rotations += 1;
+        !record || self.frames_tx.send(data.to_vec()).is_ok()
+    }
+
+    /// End the video and wait up to timeout for ffmpeg to write it, e.g. before exiting.
+    /// Returns false if it is still writing.
+    pub fn finish(self, timeout: Duration) -> bool {
+        drop(self.frames_tx);
+        self.done_rx.recv_timeout(timeout).is_ok()
+    }
+}
@@ -0,0 +1,375 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use color_eyre::{
+    eyre::{ensure, WrapErr},
+    Result,
+};
+use image::ImageFormat;
+use lazy_static::lazy_static;
+use log::warn;
+use xdg::BaseDirectories;
+
+use crate::attribution::{self, Attribution};
+use crate::file_list;
+use crate::index::stable_hash;
+use crate::metrics;
+use crate::sync::lock;
+
+lazy_static! {
+    /// When each source can be downloaded again, shared by all the outputs showing it
+    static ref FETCHES: Mutex<HashMap<String, Fetch>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Default)]
+struct Fetch {
+    next: Option<Instant>,
+    running: bool,
+}
+
+/// Images of a feed downloaded on each refresh, the first ones listed
+const MAX_FEED_IMAGES: usize = 30;
+/// A failed download is tried again after this, or after the ttl if it is shorter
+const RETRY_AFTER: Duration = Duration::from_secs(5 * 60);
+/// Seconds after which curl gives up on a download
+const DOWNLOAD_TIMEOUT: &str = "120";
+/// Keys of JSON objects and names of XML elements whose URLs are images even without
+/// an image extension, e.g. in the answers of the Unsplash API
+const IMAGE_KEYS: &[&str] = &["image", "img", "photo", "picture", "raw", "full", "hdurl"];
+
+pub fn is_url(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|path| path.starts_with("http://") || path.starts_with("https://"))
+}
+
+/// Where the images downloaded from url are kept, i.e.
+/// $XDG_CACHE_HOME/wpaper/remote/<hash of url>
+pub fn cache_dir(url: &str) -> Result<PathBuf> {
+    Ok(BaseDirectories::with_prefix("wpaper")
+        .context("finding the XDG base directories")?
+        .get_cache_home()
+        .join("remote")
+        .join(stable_hash(url.as_bytes())))
+}
+
+/// Make sure that the images of url are in its cache directory. The first time the source
+/// is downloaded right away; then it is downloaded again in the background once ttl has
+/// elapsed, while the images downloaded before keep being shown, also when offline.
+pub fn update(url: &str, ttl: Duration) -> Result<()> {
+    let dir = cache_dir(url)?;
+    if cached_images(&dir).is_empty() {
+        // Nothing to show yet, wait for the download
+        let res = fetch(url, &dir);
+        finish(url, res.is_ok(), ttl);
+        return res.with_context(|| format!("no image has been downloaded yet from {}", url));
+    }
+    if start(url) {
+        let url = url.to_string();
+        thread::spawn(move || {
+            let res = fetch(&url, &dir);
+            if let Err(err) = &res {
+                warn!("{:?}; the images downloaded before are used meanwhile", err);
+            }
+            finish(&url, res.is_ok(), ttl);
+        });
+    }
+    Ok(())
+}
+
+/// Returns true and marks url as being downloaded if it is due for a new download
+fn start(url: &str) -> bool {
+    let mut fetches = lock(&FETCHES);
+    let fetch = fetches.entry(url.to_string()).or_default();
+    if fetch.running || fetch.next.is_some_and(|next| next > Instant::now()) {
+        return false;
+    }
+    fetch.running = true;
+    true
+}
+
+fn finish(url: &str, success: bool, ttl: Duration) {
+    let mut fetches = lock(&FETCHES);
+    let fetch = fetches.entry(url.to_string()).or_default();
+    fetch.running = false;
+    fetch.next = Some(Instant::now() + if success { ttl } else { ttl.min(RETRY_AFTER) });
+}
+
+/// The attribution of image when it has been downloaded from url: the one found in the
+/// feed, or url itself
+pub fn attribution(url: &str, image: &Path) -> Attribution {
+    Attribution::read(image).unwrap_or_else(|| Attribution::new(url))
+}
+
+/// Download url into dir. It is either an image, replacing the one downloaded before, or a
+/// feed (RSS, Atom, JSON, HTML...) whose images replace the ones of the previous feed. The
+/// author, license and page of each image found in the feed are saved next to it.
+fn fetch(url: &str, dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("creating the directory {:?}", dir))?;
+    let data = download(url)?;
+    let kept = if let Ok(format) = image::guess_format(&data) {
+        let path = save(dir, "image", format, &data)?;
+        Attribution::new(url).save(&path)?;
+        vec![path]
+    } else {
+        let text = feed_text(&data);
+        let mut urls = image_urls(&text);
+        ensure!(!urls.is_empty(), "no images found in {}", url);
+        urls.truncate(MAX_FEED_IMAGES);
+        let attributions = attribution::from_feed(&text, &urls);
+        let cached = cached_images(dir);
+        let mut kept = Vec::new();
+        for ((_, image_url), attribution) in urls.into_iter().zip(attributions) {
+            let name = stable_hash(image_url.as_bytes());
+            // The images of a feed never change, only the new ones are downloaded
+            let cached = cached
+                .iter()
+                .find(|path| path.file_stem().is_some_and(|stem| stem == name.as_str()));
+            metrics::cache_lookup("remote", cached.is_some());
+            let res = match cached {
+                Some(path) => Ok(path.clone()),
+                None => download(&image_url).and_then(|data| {
+                    let format = image::guess_format(&data)
+                        .with_context(|| format!("{} is not an image", image_url))?;
+                    save(dir, &name, format, &data)
+                }),
+            };
+            // The details of the feed may have changed, they are saved again
+            match res.and_then(|path| attribution.save(&path).map(|_| path)) {
+                Ok(path) => kept.push(path),
+                Err(err) => warn!("{:?}", err),
+            }
+        }
+        ensure!(
+            !kept.is_empty(),
+            "none of the images of {} could be downloaded",
+            url
+        );
+        kept
+    };
+
+    for path in cached_images(dir) {
+        if !kept.contains(&path) {
+            if let Err(err) = fs::remove_file(&path) {
+                warn!("removing the old image {:?}: {}", path, err);
+            }
+            Attribution::remove(&path);
+        }
+    }
+    file_list::invalidate(dir);
+    Ok(())
+}
+
+fn download(url: &str) -> Result<Vec<u8>> {
+    let output = Command::new("curl")
+        .args([
+            "--fail",
+            "--silent",
+            "--show-error",
+            "--location",
+            "--max-time",
+            DOWNLOAD_TIMEOUT,
+        ])
+        .arg(url)
+        .output()
+        .context("running curl")?;
+    ensure!(
+        output.status.success(),
+        "downloading {}: {}",
+        url,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(output.stdout)
+}
+
+/// Write data to dir as name, with the extension of format. The file is replaced atomically
+/// so that an image is never read while it is being written.
+fn save(dir: &Path, name: &str, format: ImageFormat, data: &[u8]) -> Result<PathBuf> {
+    let extension = format.extensions_str().first().copied().unwrap_or("img");
+    let path = dir.join(format!("{}.{}", name, extension));
+    let tmp_path = dir.join(format!(".{}.part", name));
+    fs::write(&tmp_path, data).with_context(|| format!("writing {:?}", tmp_path))?;
+    fs::rename(&tmp_path, &path).with_context(|| format!("writing {:?}", path))?;
+    Ok(path)
+}
+
+fn cached_images(dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| ImageFormat::from_path(path).is_ok())
+        .collect()
+}
+
+fn feed_text(data: &[u8]) -> String {
+    // URLs in JSON can have their slashes escaped, and the ones in XML their ampersands
+    String::from_utf8_lossy(data)
+        .replace("\\/", "/")
+        .replace("&amp;", "&")
+}
+
+/// The URLs of the images linked by a feed, in order, with their offset in text: the ones
+/// with an image extension and the ones in an element or key named like an image
+fn image_urls(text: &str) -> Vec<(usize, String)> {
+    let mut urls = Vec::new();
+    let mut seen = HashSet::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("http") {
+        let (before, from) = rest.split_at(start);
+        let len = from
+            .find(|c: char| c.is_whitespace() || "\"'<>\\".contains(c))
+            .unwrap_or(from.len());
+        let url = &from[..len];
+        rest = &from[len..];
+        if !(url.starts_with("http://") || url.starts_with("https://")) {
+            continue;
+        }
+        let url_path = url.split(['?', '#']).next().unwrap_or(url);
+        if (ImageFormat::from_path(url_path).is_ok() || is_image_key(before))
+            && seen.insert(url.to_string())
+        {
+            urls.push((text.len() - from.len(), url.to_string()));
+        }
+    }
+    urls
+}
+
+/// Whether the JSON key or XML element preceding a URL, at the end of before, names an image
+fn is_image_key(before: &str) -> bool {
+    let name: Vec<char> = before
+        .trim_end_matches(|c: char| c.is_whitespace() || "\"':=>".contains(c))
+        .chars()
+        .rev()
+        .take_while(|c| c.is_alphanumeric() || *c == '_' || *c == '-')
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .collect();
+    // The key is one of the words of the name, e.g. image_url or largeImage, but not a part of
+    // one, as in photographer
+    let mut words = vec![String::new()];
+    for (i, c) in name.iter().enumerate() {
+        if *c == '_' || *c == '-' {
+            words.push(String::new());
+            continue;
+        }
+        if c.is_uppercase() && i > 0 && name[i - 1].is_lowercase() {
+            words.push(String::new());
+        }
+        words.last_mut().unwrap().extend(c.to_lowercase());
+    }
+    words.iter().any(|word| IMAGE_KEYS.contains(&word.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn downloaded(feed: &str) -> Vec<String> {
+        image_urls(&feed_text(feed.as_bytes()))
+            .into_iter()
+            .map(|(_, url)| url)
+            .collect()
+    }
+
+    #[test]
+    fn images_of_a_json_feed() {
+        // The shape of the answers of the Unsplash API, with its escaped slashes
+        let feed = r#"[{
+            "id": "Dwu85P9SOIk",
+            "description": "A man drinking coffee",
+            "urls": {
+                "raw": "https:\/\/images.unsplash.com\/photo-1417325384643?ixid=MnwxfDB8MXxyYW5k",
+                "full": "https:\/\/images.unsplash.com\/photo-1417325384643?q=85&fm=jpg",
+                "regular": "https:\/\/images.unsplash.com\/photo-1417325384643?w=1080"
+            },
+            "links": {
+                "html": "https:\/\/unsplash.com\/photos\/Dwu85P9SOIk",
+                "download": "https:\/\/unsplash.com\/photos\/Dwu85P9SOIk\/download"
+            },
+            "user": {
+                "name": "Dany",
+                "portfolio_url": "https:\/\/example.com\/portfolio",
+                "photographer_url": "https:\/\/example.com\/dany",
+                "profile_image": {"small": "https:\/\/images.unsplash.com\/profile-1?w=32"}
+            }
+        }, {
+            "largeImageURL": "https:\/\/cdn.example.com\/get\/42",
+            "thumbnail": "https:\/\/cdn.example.com\/thumbs\/42.webp",
+            "hdurl": "https:\/\/apod.nasa.gov\/apod\/image\/2301\/Comet",
+            "url": "https:\/\/apod.nasa.gov\/apod\/ap230101.html",
+            "again": "https:\/\/cdn.example.com\/thumbs\/42.webp"
+        }]"#;
+        assert_eq!(
+            downloaded(feed),
+            [
+                "https://images.unsplash.com/photo-1417325384643?ixid=MnwxfDB8MXxyYW5k",
+                "https://images.unsplash.com/photo-1417325384643?q=85&fm=jpg",
+                "https://cdn.example.com/get/42",
+                "https://cdn.example.com/thumbs/42.webp",
+                "https://apod.nasa.gov/apod/image/2301/Comet",
+            ]
+        );
+    }
+
+    #[test]
+    fn images_of_an_rss_feed() {
+        let feed = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:media="http://search.yahoo.com/mrss/">
+  <channel>
+    <title>Wallpapers</title>
+    <link>https://example.com/</link>
+    <item>
+      <title>Forest</title>
+      <link>https://example.com/posts/forest?ref=rss&amp;page=2</link>
+      <description>&lt;img src="https://example.com/img/forest.JPG?w=800&amp;h=600"&gt;</description>
+      <enclosure url="https://example.com/img/forest-full.png" length="1234" type="image/png"/>
+    </item>
+    <item>
+      <title>Sea</title>
+      <link>https://example.com/posts/sea</link>
+      <image>https://example.com/render?id=sea</image>
+      <comments>https://example.com/posts/sea#comments</comments>
+      <enclosure url="https://example.com/podcast/sea.mp3" type="audio/mpeg"/>
+    </item>
+  </channel>
+</rss>"#;
+        assert_eq!(
+            downloaded(feed),
+            [
+                "https://example.com/img/forest.JPG?w=800&h=600",
+                "https://example.com/img/forest-full.png",
+                "https://example.com/render?id=sea",
+            ]
+        );
+    }
+
+    #[test]
+    fn image_keys() {
+        for key in [
+            "\"image\": \"",
+            "<img>",
+            "image_url=",
+            "\"largeImageURL\":",
+            "\"hd-photo\": '",
+        ] {
+            assert!(is_image_key(key), "{}", key);
+        }
+        for key in [
+            "\"photographer\": \"",
+            "<link>",
+            "\"imagery\":",
+            "\"url\": \"",
+            "",
+        ] {
+            assert!(!is_image_key(key), "{}", key);
+        }
+    }
+}
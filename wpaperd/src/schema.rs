@@ -0,0 +1,236 @@
+use std::fmt::Write;
+
+use color_eyre::{eyre::eyre, Result};
+use serde::{
+    de::{self, value, Visitor},
+    forward_to_deserialize_any, Deserialize, Deserializer,
+};
+
+use crate::migration::CONFIG_VERSION;
+use crate::output::{Output, OUTPUT_KEYS};
+
+/// Deserializer that fails right away, only recording the fields of the struct that serde
+/// asked for; this way the schema always follows the serde types
+#[derive(Default)]
+struct FieldNames(&'static [&'static str]);
+
+impl<'de> Deserializer<'de> for &mut FieldNames {
+    type Error = value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("only structs are supported"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0 = fields;
+        Err(de::Error::custom("fields recorded"))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+        ignored_any
+    }
+}
+
+/// Every key of an output section, with its JSON type and description
+fn output_keys() -> Result<Vec<(&'static str, &'static str, &'static str)>> {
+    let mut field_names = FieldNames::default();
+    let _ = Output::deserialize(&mut field_names);
+    field_names
+        .0
+        .iter()
+        .map(|name| {
+            OUTPUT_KEYS
+                .iter()
+                .find(|(key, _, _)| key == name)
+                .copied()
+                .ok_or_else(|| eyre!("the key {} is not documented", name))
+        })
+        .collect()
+}
+
+/// JSON Schema of the configuration file: a table of output sections, "default" included
+pub fn json_schema() -> Result<String> {
+    let mut properties = String::new();
+    for (i, (key, json_type, description)) in output_keys()?.into_iter().enumerate() {
+        if i != 0 {
+            properties.push_str(",\n");
+        }
+        write!(
+            properties,
+            "        {}: {{ \"type\": {}, \"description\": {} }}",
+            json_string(key),
+            json_string(json_type),
+            json_string(description)
+        )?;
+    }
+    Ok(format!(
+        r##"{{
+  "$schema": "http://json-schema.org/draft-07/schema#",
+  "title": "wpaperd configuration",
+  "description": "Each section configures an output; the section default is used for the outputs that are not listed",
+  "type": "object",
+  "properties": {{
+    "version": {{
+      "type": "integer",
+      "minimum": 0,
+      "maximum": {version},
+      "description": "Version of the format of the file, older ones are migrated when read"
+    }},
+    "animations": {{
+      "type": "boolean",
+      "description": "Set to false to disable the transitions, the Ken Burns effect and the moving layers of every output, defaults to true"
+    }},
+    "profiles": {{
+      "type": "object",
+      "description": "Named profiles, each one a table of output sections selectable with wpaperctl profile",
+      "additionalProperties": {{
+        "type": "object",
+        "additionalProperties": {{ "$ref": "#/definitions/output" }}
+      }}
+    }},
+    "on-battery": {{
+      "type": "object",
+      "description": "Applied to every output while the system runs on battery",
+      "properties": {{
+        "duration": {{ "type": "string", "description": "Replaces the duration of the outputs that rotate their images" }},
+        "animations": {{ "type": "boolean", "description": "Keep the Ken Burns effect and the transitions, defaults to false" }},
+        "fast_scaling": {{ "type": "boolean", "description": "Scale the images with a cheaper filter, defaults to true" }}
+      }},
+      "additionalProperties": false
+    }},
+    "night-mode": {{
+      "type": "object",
+      "description": "Tints every output warmer and dims it between sunset and sunrise, computed from latitude and longitude or given by start and end",
+      "properties": {{
+        "latitude": {{ "type": "number", "minimum": -90, "maximum": 90 }},
+        "longitude": {{ "type": "number", "minimum": -180, "maximum": 180 }},
+        "start": {{ "type": "string", "pattern": "^[0-9]{{1,2}}:[0-9]{{2}}$", "description": "When the night starts, local time" }},
+        "end": {{ "type": "string", "pattern": "^[0-9]{{1,2}}:[0-9]{{2}}$", "description": "When the night ends, local time" }},
+        "temperature": {{ "type": "integer", "minimum": 1000, "maximum": 6500, "description": "Color temperature in kelvin, defaults to 4000" }},
+        "brightness": {{ "type": "number", "minimum": 0, "maximum": 1, "description": "Defaults to 0.8" }}
+      }},
+      "additionalProperties": false
+    }},
+    "collection": {{
+      "type": "object",
+      "description": "Named collections of images, shown by the outputs that list them in collections",
+      "additionalProperties": {{
+        "type": "object",
+        "properties": {{
+          "paths": {{ "type": "array", "items": {{ "type": "string" }}, "description": "Directories and images of the collection" }},
+          "tags": {{ "type": "array", "items": {{ "type": "string" }}, "description": "Tags of every image of the collection" }}
+        }},
+        "required": ["paths"],
+        "additionalProperties": false
+      }}
+    }}
+  }},
+  "additionalProperties": {{ "$ref": "#/definitions/output" }},
+  "definitions": {{
+    "output": {{
+      "type": "object",
+      "properties": {{
+{}
+      }}
+    }}
+  }}
+}}"##,
+        properties,
+        version = CONFIG_VERSION
+    ))
+}
+
+/// The keys of an output section, in a human readable form
+pub fn text_schema() -> Result<String> {
+    let mut text = String::new();
+    for (key, json_type, description) in output_keys()? {
+        writeln!(text, "{} ({})\n    {}", key, json_type, description)?;
+    }
+    Ok(text)
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A TOML value of the JSON type, None for null which TOML cannot express
+    fn sample(json_type: &str) -> Option<&'static str> {
+        match json_type {
+            "string" => Some("\"a\""),
+            "boolean" => Some("true"),
+            "integer" => Some("1"),
+            "number" => Some("0.05"),
+            "array" => Some("[]"),
+            "object" => Some("{}"),
+            "null" => None,
+            _ => panic!("{} is not a JSON Schema type", json_type),
+        }
+    }
+
+    #[test]
+    fn every_key_of_output_is_documented() {
+        let keys = output_keys().unwrap();
+        for (key, _, _) in OUTPUT_KEYS {
+            assert!(
+                keys.iter().any(|(name, _, _)| name == key),
+                "{} is not a key of Output",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn the_documented_types_are_the_ones_of_output() {
+        for (key, json_type, _) in OUTPUT_KEYS {
+            // The value can still be invalid, e.g. an unknown fit, but not of the wrong type
+            let parsed = sample(json_type)
+                .map(|value| toml::from_str::<Output>(&format!("{} = {}", key, value)));
+            if let Some(Err(err)) = parsed {
+                assert!(
+                    !err.to_string().contains("invalid type"),
+                    "{} is not a {}: {}",
+                    key,
+                    json_type,
+                    err
+                );
+            }
+            let wrong_type = if *json_type == "boolean" {
+                "string"
+            } else {
+                "boolean"
+            };
+            assert!(
+                toml::from_str::<Output>(&format!("{} = {}", key, sample(wrong_type).unwrap()))
+                    .is_err(),
+                "{} accepts a {}",
+                key,
+                wrong_type
+            );
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::{fs, path::Path};
+
+use log::debug;
+
+/// Where the kernel lists the DRM cards and their connectors
+pub const DRM_DIR: &str = "/sys/class/drm";
+
+/// Seat of the devices that udev has not assigned to any other seat
+const DEFAULT_SEAT: &str = "seat0";
+
+/// The seat the output is attached to. The output name given by the compositor is the name
+/// of the DRM connector, e.g. DP-1, which is found in sysfs as card<N>-DP-1; the seat of
+/// the card is the ID_SEAT property in the udev database. Outputs that are not found there,
+/// e.g. the ones of a nested compositor, belong to the default seat.
+pub fn output_seat(output: &str) -> String {
+    card_of_output(output)
+        .and_then(|card| card_seat(&card))
+        .unwrap_or_else(|| DEFAULT_SEAT.to_string())
+}
+
+/// Whether the output belongs to seat
+pub fn is_on_seat(output: &str, seat: &str) -> bool {
+    let output_seat = output_seat(output);
+    if output_seat != seat {
+        debug!("{} is on {}, skipping it", output, output_seat);
+    }
+    output_seat == seat
+}
+
+/// The card the output connector belongs to, e.g. card1
+pub fn card_of_output(output: &str) -> Option<String> {
+    fs::read_dir(DRM_DIR).ok()?.flatten().find_map(|entry| {
+        let name = entry.file_name().into_string().ok()?;
+        let (card, connector) = name.split_once('-')?;
+        (connector == output && card.starts_with("card")).then(|| card.to_string())
+    })
+}
+
+/// The ID_SEAT property of the card, read from the udev database entry named after its
+/// device number
+fn card_seat(card: &str) -> Option<String> {
+    let dev = fs::read_to_string(Path::new(DRM_DIR).join(card).join("dev")).ok()?;
+    let entry = fs::read_to_string(format!("/run/udev/data/c{}", dev.trim())).ok()?;
+    Some(
+        entry
+            .lines()
+            .find_map(|line| line.strip_prefix("E:ID_SEAT="))
+            .unwrap_or(DEFAULT_SEAT)
+            .to_string(),
+    )
+}
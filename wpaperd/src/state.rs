@@ -0,0 +1,105 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use color_eyre::{eyre::WrapErr, Result};
+use serde::{Deserialize, Serialize};
+
+/// What was being displayed on an output when the state was last saved
+#[derive(Clone, Deserialize, Serialize)]
+pub struct OutputState {
+    pub image: PathBuf,
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub remaining: Option<Duration>,
+    /// The rotation has been paused with wpaperctl pause
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub paused: bool,
+    /// The image has been pinned with wpaperctl pin
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+pub struct State {
+    #[serde(flatten)]
+    data: HashMap<String, OutputState>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl State {
+    /// Read the state from path, starting from an empty state if the file does not exist yet.
+    /// When path is None, the state is kept in memory only and never saved.
+    pub fn new_from_path(path: Option<&Path>) -> Result<Self> {
+        let mut state = match path {
+            Some(path) if path.exists() => toml::from_str(
+                &fs::read_to_string(path)
+                    .with_context(|| format!("reading state file {:?}", path))?,
+            )
+            .with_context(|| format!("parsing state file {:?}", path))?,
+            _ => Self::default(),
+        };
+        state.path = path.map(Path::to_path_buf);
+        Ok(state)
+    }
+
+    pub fn get_output_by_name(&self, name: &str) -> Option<&OutputState> {
+        self.data.get(name)
+    }
+
+    pub fn update_output(&mut self, name: &str, output_state: OutputState) {
+        self.data.insert(name.to_string(), output_state);
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(path) = &self.path {
+            // Replaced atomically, so that a crash while writing does not lose the state
+            let tmp_path = path.with_extension(format!("state.{}", std::process::id()));
+            fs::write(&tmp_path, toml::to_string(self)?)
+                .with_context(|| format!("writing state file {:?}", tmp_path))?;
+            fs::rename(&tmp_path, path)
+                .with_context(|| format!("writing state file {:?}", path))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_state_is_replaced_without_leftovers() {
+        let dir = std::env::temp_dir().join(format!("wpaper-state-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wpaperd.state");
+        let mut state = State::new_from_path(Some(&path)).unwrap();
+        for image in ["/a.jpg", "/b.jpg"] {
+            state.update_output(
+                "DP-1",
+                OutputState {
+                    image: image.into(),
+                    remaining: Some(Duration::from_secs(60)),
+                    paused: false,
+                    pinned: true,
+                },
+            );
+            state.save().unwrap();
+        }
+        let saved = State::new_from_path(Some(&path)).unwrap();
+        let output = saved.get_output_by_name("DP-1").unwrap();
+        assert_eq!(output.image, Path::new("/b.jpg"));
+        assert!(output.pinned);
+        let files: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,344 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    env,
+    ffi::OsString,
+    fs, io,
+    os::unix::net::UnixStream,
+    path::Path,
+    process::{Child, Command},
+    rc::Rc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use calloop::signals::{Signal, Signals};
+use color_eyre::{eyre::WrapErr, Result};
+use log::{error, warn};
+use nix::{
+    sys::signal::{kill, Signal as NixSignal},
+    unistd::Pid,
+};
+use smithay_client_toolkit::{
+    output::with_output_info,
+    reexports::{calloop, client::Display},
+    WaylandSource,
+};
+use wpaper_ipc::{IpcMessage, IpcResponse};
+
+use crate::ipc_server;
+use crate::logging;
+use crate::metrics;
+use crate::seat;
+
+/// How long to wait before starting again the process of an output that has exited
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+/// How long the processes are given to exit, fading their outputs out, before being killed
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the processes being stopped are checked
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// How long a process is given to answer a forwarded request, so that a process that hangs
+/// does not keep the others' responses from being sent
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The process serving an output
+struct OutputProcess {
+    child: Option<Child>,
+    /// When the process should be started again after exiting
+    restart_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct SupervisorData {
+    should_exit: bool,
+    ipc_requests: Vec<(IpcMessage, UnixStream)>,
+}
+
+/// Start a wpaperd process for each output, with child_args and --output, and start them
+/// again when they exit. This way a crash caused by an image only affects its own output.
+/// Each process logs to its own file next to log_file, so that they never rotate it under
+/// each other. Requests from wpaperctl are forwarded to the processes of the outputs they
+/// target.
+pub fn run(
+    child_args: Vec<OsString>,
+    log_file: Option<&Path>,
+    only_seat: Option<&str>,
+) -> Result<()> {
+    let exe = env::current_exe().context("finding the wpaperd executable")?;
+    let display = Display::connect_to_env().context("connecting to the wayland display")?;
+    let mut queue = display.create_event_queue();
+    let env = crate::new_environment(&display, &mut queue)?;
+
+    // The output listener only records the changes, processes are handled in the main loop
+    // The outputs of the other seats are left to their own instances
+    let on_seat = {
+        let only_seat = only_seat.map(str::to_string);
+        move |name: &str| {
+            only_seat
+                .as_deref()
+                .is_none_or(|seat| seat::is_on_seat(name, seat))
+        }
+    };
+    let output_changes = Rc::new(RefCell::new(Vec::<(String, bool)>::new()));
+    for output in env.get_all_outputs() {
+        if let Some(name) = with_output_info(&output, |info| info.name.clone()) {
+            if on_seat(&name) {
+                output_changes.borrow_mut().push((name, true));
+            }
+        }
+    }
+    let output_changes_clone = output_changes.clone();
+    let _listener_handle = env.listen_for_outputs(move |_, info, _| {
+        if on_seat(&info.name) {
+            output_changes_clone
+                .borrow_mut()
+                .push((info.name.clone(), !info.obsolete))
+        }
+    });
+
+    let mut event_loop = calloop::EventLoop::<SupervisorData>::try_new()?;
+    WaylandSource::new(queue)
+        .quick_insert(event_loop.handle())
+        .map_err(io::Error::from)
+        .context("adding the wayland connection to the event loop")?;
+    let signals = Signals::new(&[Signal::SIGINT, Signal::SIGTERM])
+        .context("setting up the signal handlers")?;
+    event_loop
+        .handle()
+        .insert_source(signals, |_, _, data| data.should_exit = true)
+        .map_err(io::Error::from)
+        .context("adding the signals to the event loop")?;
+    let socket_path = wpaper_ipc::socket_path().context("getting the socket path")?;
+    let listener = ipc_server::listen_on_ipc_socket(&socket_path)?;
+    // The requests are read on other threads, the stream is kept to answer them
+    ipc_server::accept_requests(&event_loop.handle(), listener, |data, message, stream| {
+        data.ipc_requests.push((message, stream))
+    })?;
+
+    let mut processes = HashMap::<String, OutputProcess>::new();
+    loop {
+        for (name, present) in output_changes.borrow_mut().drain(..) {
+            if present {
+                processes.entry(name).or_insert(OutputProcess {
+                    child: None,
+                    restart_at: Some(Instant::now()),
+                });
+            } else if let Some(mut process) = processes.remove(&name) {
+                stop_children(process.child.iter_mut().collect());
+            }
+        }
+
+        let now = Instant::now();
+        for (name, process) in processes.iter_mut() {
+            if let Some(child) = &mut process.child {
+                match child.try_wait() {
+                    Ok(Some(status)) => {
+                        warn!(
+                            "the process for {} exited with {}, restarting it",
+                            name, status
+                        );
+                        process.child = None;
+                        process.restart_at = Some(now + RESTART_DELAY);
+                    }
+                    Ok(None) => {}
+                    Err(err) => error!("waiting for the process of {}: {:?}", name, err),
+                }
+            }
+            if matches!(process.restart_at, Some(restart_at) if restart_at <= now) {
+                process.restart_at = None;
+                let mut command = Command::new(&exe);
+                command.args(&child_args).arg("--output").arg(name);
+                if let Some(log_file) = log_file {
+                    command
+                        .arg("--log-file")
+                        .arg(logging::output_log_file(log_file, name));
+                }
+                match command.spawn() {
+                    Ok(child) => process.child = Some(child),
+                    Err(err) => {
+                        error!("starting the process for {}: {:?}", name, err);
+                        process.restart_at = Some(now + RESTART_DELAY);
+                    }
+                }
+            }
+        }
+
+        display.flush().context("flushing the display")?;
+        let mut data = SupervisorData::default();
+        // Wake up regularly to notice the processes that have exited
+        event_loop
+            .dispatch(Some(RESTART_DELAY), &mut data)
+            .context("dispatching the event loop")?;
+
+        for (message, stream) in data.ipc_requests {
+            // The processes are waited for on another thread, so that they can be restarted
+            // meanwhile
+            let outputs: Vec<String> = processes.keys().cloned().collect();
+            thread::spawn(move || {
+                let response = forward_message(&message, outputs.iter());
+                ipc_server::send_response(&stream, response);
+            });
+        }
+
+        if data.should_exit {
+            // All at once, so that the outputs fade out together
+            stop_children(
+                processes
+                    .values_mut()
+                    .filter_map(|process| process.child.as_mut())
+                    .collect(),
+            );
+            fs::remove_file(&socket_path)
+                .with_context(|| format!("removing the socket {:?}", socket_path))?;
+            return Ok(());
+        }
+    }
+}
+
+/// Ask the processes to terminate, so that they save their state, and wait for them. The
+/// ones still running after STOP_TIMEOUT are killed.
+fn stop_children(mut children: Vec<&mut Child>) {
+    for child in &children {
+        if let Err(err) = kill(Pid::from_raw(child.id() as i32), NixSignal::SIGTERM) {
+            error!("terminating process {}: {:?}", child.id(), err);
+        }
+    }
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    while !children.is_empty() {
+        children.retain_mut(|child| match child.try_wait() {
+            Ok(Some(_)) => false,
+            Ok(None) => true,
+            Err(err) => {
+                error!("waiting for process {}: {:?}", child.id(), err);
+                false
+            }
+        });
+        if Instant::now() >= deadline {
+            for child in &mut children {
+                warn!("process {} did not exit in time, killing it", child.id());
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+            return;
+        }
+        thread::sleep(STOP_POLL_INTERVAL);
+    }
+}
+
+/// Send the message to the process of the output it targets. Messages for all the outputs
+/// are sent to every process at once and their responses are joined.
+fn forward_message<'a>(
+    message: &IpcMessage,
+    outputs: impl Iterator<Item = &'a String>,
+) -> IpcResponse {
+    if *message == IpcMessage::Subscribe {
+        return IpcResponse::Error(
+            "subscribing is not supported with --per-output-process".to_string(),
+        );
+    }
+    if let IpcMessage::Batch(messages) = message {
+        return forward_batch(messages, outputs);
+    }
+    let mut outputs: Vec<&str> = match message.output() {
+        Some(output) => vec![output],
+        None => outputs.map(String::as_str).collect(),
+    };
+    outputs.sort_unstable();
+    // The votes and the configuration file are shared, any process can answer
+    if matches!(message, IpcMessage::ContestResults | IpcMessage::Profiles) {
+        outputs.truncate(1);
+    }
+
+    let requests = outputs
+        .into_iter()
+        .map(|output| (output, message.clone()))
+        .collect();
+    let mut responses = Vec::new();
+    for response in send_to_outputs(requests) {
+        match response {
+            IpcResponse::Ok(output_lines) => responses.push(output_lines),
+            IpcResponse::Error(err) => return IpcResponse::Error(err),
+        }
+    }
+    // Each process counts what it does, the totals are what the user asked for
+    if *message == IpcMessage::Metrics {
+        return IpcResponse::Ok(metrics::merge(responses));
+    }
+    let mut lines: Vec<String> = responses.into_iter().flatten().collect();
+    // Every process rescans the same directories, each is reported once
+    if *message == IpcMessage::Rescan {
+        lines.sort_unstable();
+        lines.dedup();
+    }
+    IpcResponse::Ok(lines)
+}
+
+/// Send each message to the process of its output, all at once, and return their responses
+/// in the same order
+fn send_to_outputs(requests: Vec<(&str, IpcMessage)>) -> Vec<IpcResponse> {
+    thread::scope(|scope| {
+        let handles: Vec<_> = requests
+            .iter()
+            .map(|(output, message)| scope.spawn(move || send_to_output(output, message)))
+            .collect();
+        handles
+            .into_iter()
+            .zip(&requests)
+            .map(|(handle, (output, _))| {
+                handle.join().unwrap_or_else(|_| {
+                    IpcResponse::Error(format!("forwarding the request to {} failed", output))
+                })
+            })
+            .collect()
+    })
+}
+
+fn send_to_output(output: &str, message: &IpcMessage) -> IpcResponse {
+    let response = wpaper_ipc::output_socket_path(output)
+        .and_then(|socket_path| wpaper_ipc::send_to(&socket_path, message, Some(FORWARD_TIMEOUT)));
+    response.unwrap_or_else(|err| match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => IpcResponse::Error(format!(
+            "the process serving {} did not answer in {}s",
+            output,
+            FORWARD_TIMEOUT.as_secs()
+        )),
+        _ => IpcResponse::Error(format!("no process is serving {}: {}", output, err)),
+    })
+}
+
+/// Split the batch between the processes, each receiving the requests for its output and the
+/// ones for all the outputs. Each process applies its part at once, but the processes are
+/// not synchronized with each other.
+fn forward_batch<'a>(
+    messages: &[IpcMessage],
+    outputs: impl Iterator<Item = &'a String>,
+) -> IpcResponse {
+    let mut outputs: Vec<&String> = outputs.collect();
+    outputs.sort_unstable();
+    if let Some(output) = messages
+        .iter()
+        .filter_map(IpcMessage::output)
+        .find(|target| !outputs.iter().any(|output| output == target))
+    {
+        return IpcResponse::Error(format!("no process is serving {}", output));
+    }
+    let requests = outputs
+        .into_iter()
+        .filter_map(|output| {
+            let batch: Vec<IpcMessage> = messages
+                .iter()
+                .filter(|message| message.output().is_none_or(|target| target == output))
+                .cloned()
+                .collect();
+            (!batch.is_empty()).then_some((output.as_str(), IpcMessage::Batch(batch)))
+        })
+        .collect();
+    let mut lines = Vec::new();
+    for response in send_to_outputs(requests) {
+        match response {
+            IpcResponse::Ok(output_lines) => lines.extend(output_lines),
+            IpcResponse::Error(err) => return IpcResponse::Error(err),
+        }
+    }
+    IpcResponse::Ok(lines)
+}
@@ -1,29 +1,93 @@
+use std::borrow::Cow;
 use std::cell::Cell;
-use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use color_eyre::eyre::{ensure, Context};
+use calloop::channel::Sender;
+use chrono::Local;
+
+use color_eyre::eyre::{ensure, eyre};
 use color_eyre::Result;
-use dowser::Dowser;
-use image::imageops::FilterType;
-use image::open;
-use log::warn;
+use image::DynamicImage;
+use log::{error, warn};
 use smithay_client_toolkit::{
-    output::OutputInfo,
+    environment::Environment,
+    output::{add_output_listener, OutputInfo, OutputListener},
     reexports::{
         client::protocol::{wl_output, wl_shm, wl_surface},
         client::{Attached, Main},
+        protocols::viewporter::client::{wp_viewport, wp_viewporter},
         protocols::wlr::unstable::layer_shell::v1::client::{
             zwlr_layer_shell_v1, zwlr_layer_surface_v1,
         },
     },
-    shm::AutoMemPool,
+    shell::{self, ShellHandling, ShellSurface},
+    shm::ShmHandling,
 };
+use wpaper_core::fade::FadeOut;
+use wpaper_core::heic::{self, Schedule};
+use wpaper_core::icc::ColorTransform;
+use wpaper_core::image_picker::ImagePicker;
+use wpaper_core::inversion::Inversion;
+use wpaper_core::ken_burns::{KenBurns, ZOOM as KEN_BURNS_ZOOM};
+use wpaper_core::parallax::Parallax;
 
-use crate::output::Output;
+use crate::attribution::Attribution;
+use crate::buffers::Buffers;
+use crate::collection::{read_image_settings, Collection, Fit, NoImages};
+use crate::contest::Contest;
+use crate::day_night::{self, BLEND_STEPS};
+use crate::duplicates;
+use crate::hook;
+use crate::identity::Identity;
+use crate::index;
+use crate::layers::LayerStack;
+use crate::lock_screen::{self, export_lock_image};
+use crate::metrics::{self, Stage};
+use crate::mounts;
+use crate::night::NightTint;
+use crate::output::{Dither, Output, ScalingFilter};
 use crate::output_timer::OutputTimer;
+use crate::overlay::TextLayer;
+use crate::palette::{extract_palette, write_palette, PALETTE_SIZE};
+use crate::pipeline::{
+    decode_size, fit_scaler, span_scaler, to_buffer_data, widen, Decoder, FileDecoder, FrameSink,
+    Scaler, Span,
+};
+use crate::power::OnBattery;
+use crate::queue::Queue;
+use crate::recorder::Recorder;
+use crate::remote;
+use crate::state::OutputState;
+use crate::views;
+
+/// Number of images remembered for each output
+const HISTORY_SIZE: usize = 50;
+/// Neutral color shown when something went wrong, so that the background is never left
+/// transparent or filled with garbage
+const FALLBACK_COLOR: [u8; 3] = [0x30, 0x30, 0x30];
+/// How much smaller than the output the images are decoded while the memory is low
+const LOW_MEMORY_REDUCTION: u32 = 2;
+/// Minimum time between two frames of an animation while the system is thermally throttled
+const THROTTLED_FRAME_INTERVAL: Duration = Duration::from_millis(100);
+/// How much smaller than the output the preview shown at startup is decoded
+const PREVIEW_REDUCTION: u32 = 8;
+/// Number of images kept ready to be shown again when switching workspaces
+const WORKSPACE_CACHE_SIZE: usize = 4;
+/// How long an image ready to be shown waits for the other outputs changing with it, e.g.
+/// for one whose image is on a slow network mount
+const FLIP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How the surface is displayed on its output
+enum Role {
+    Layer(Main<zwlr_layer_surface_v1::ZwlrLayerSurfaceV1>),
+    /// Fallback used when the compositor does not support layer shell
+    Window(Box<dyn ShellSurface>),
+}
 
 #[derive(PartialEq, Copy, Clone)]
 enum RenderEvent {
@@ -33,190 +97,1834 @@ enum RenderEvent {
 
 pub struct Surface {
     surface: wl_surface::WlSurface,
-    layer_surface: Main<zwlr_layer_surface_v1::ZwlrLayerSurfaceV1>,
+    /// Always set, it is only taken when the surface is dropped
+    role: Option<Role>,
+    /// Lets the buffer have the physical size of the output, which is larger than the
+    /// surface when the output is scaled
+    viewport: Option<Main<wp_viewport::WpViewport>>,
     next_render_event: Rc<Cell<Option<RenderEvent>>>,
     pub info: OutputInfo,
-    pool: AutoMemPool,
+    /// How the configuration tells the display apart, among every connected one
+    pub identity: Identity,
+    /// The new info of the output, e.g. after its mode changed, until it is applied
+    info_update: Arc<Mutex<Option<OutputInfo>>>,
+    _info_listener: OutputListener,
+    /// Size of the surface chosen by the compositor
+    configured: (u32, u32),
+    buffers: Buffers,
     dimensions: (u32, u32),
+    /// The settings of the output, with the path of the workspace shown when it has one
     pub output: Arc<Output>,
+    /// The settings of the output as configured
+    config_output: Arc<Output>,
+    /// The workspace shown on the output, under Sway and Hyprland
+    workspace: Option<String>,
+    /// The image last displayed for the paths of other workspaces, None being the
+    /// configured path, from the oldest
+    workspace_cache: Vec<(Option<PathBuf>, LoadedImage)>,
     need_redraw: bool,
-    pub timer: Arc<Mutex<OutputTimer>>,
+    pub timer: OutputTimer,
+    /// The image currently displayed, before applying the filters
+    displayed: Option<LoadedImage>,
+    /// Image to display on the next draw instead of a random one
+    next_image: Option<PathBuf>,
+    /// Image requested explicitly with set_wallpaper(), it can be outside the configured path
+    requested_image: Option<PathBuf>,
+    /// The part of the requested image shown by this output, when it spans its group
+    requested_span: Option<Span>,
+    /// The rotation has been paused with wpaperctl pause
+    paused: bool,
+    /// Image shown until wpaperctl unpin, whatever happens
+    pinned: Option<PathBuf>,
+    contest: Option<Contest>,
+    image_picker: ImagePicker,
+    queue: Queue,
+    pending_image: Option<Receiver<Result<LoadedImage>>>,
+    /// Set while loading an image that has to be shown together with the other outputs
+    /// changing at the same time
+    synchronized: bool,
+    /// The image loaded while synchronized, waiting for flip(), and since when
+    held: Option<(LoadedImage, Instant)>,
+    event_tx: Sender<()>,
+    /// True if the compositor accepts buffers with 10 bits per channel
+    supports_10bit: bool,
+    recorder: Option<Recorder>,
+    inversion: Inversion,
+    overlay: Option<TextLayer>,
+    /// The step of the blend of the day and night images drawn last, see day_night
+    night_step: Option<u32>,
+    /// The images stacked over the wallpaper
+    layers: Option<LayerStack>,
+    /// Set when the compositor is ready for the next frame of an animation
+    next_frame: Rc<Cell<bool>>,
+    last_frame: Option<Instant>,
+    /// When the system is too hot, animations run at a lower frame rate and images are
+    /// scaled with a cheaper filter
+    throttled: bool,
+    /// When the memory is low, the images are decoded at a lower resolution and scaled with
+    /// a cheaper filter, without the Ken Burns effect nor the parallax, and the workspaces
+    /// don't keep theirs
+    memory_low: bool,
+    /// The on-battery settings, while the system runs on battery
+    on_battery: Option<OnBattery>,
+    /// Cleared by the global animations setting, which disables them everywhere
+    animations_enabled: bool,
+    /// The tint of the night mode, at night
+    night: Option<NightTint>,
+    /// An image shown for a while with wpaperctl preview
+    previewed: Option<Previewed>,
+    /// Set once wpaperd is exiting, when the output fades out
+    fade_out: Option<FadeOut>,
+    /// Where the pointer is over the output, from -1 to 1 on each axis, for the parallax
+    pointer: Option<(f32, f32)>,
+}
+
+/// An image shown for a while, outside of the rotation: it is neither part of the history
+/// nor saved in the state, and the timer stands still meanwhile
+struct Previewed {
+    image: PathBuf,
+    until: Instant,
+    /// The image to show again afterwards, set once the previewed image is displayed
+    previous: Option<LoadedImage>,
+}
+
+/// An image scaled and converted to the pixel format of the wayland buffer
+struct LoadedImage {
+    path: PathBuf,
+    width: u32,
+    height: u32,
+    format: wl_shm::Format,
+    data: Vec<u8>,
+    /// The night image of day_night, blended with data depending on the time
+    night: Option<Vec<u8>>,
+    /// The schedule of a dynamic wallpaper and the index of its image in data
+    schedule: Option<(Schedule, usize)>,
+    /// Dominant colors of the image
+    palette: Vec<[u8; 3]>,
+    /// Set when the image is animated with the Ken Burns effect, data is then its first frame
+    ken_burns: Option<KenBurns>,
+    /// Set when the image follows the pointer, data is then its centered part
+    parallax: Option<Parallax>,
+    /// How long the image is displayed, if its sidecar file says so
+    duration: Option<Duration>,
+    /// A quick low resolution version, shown until the image itself is ready
+    preview: bool,
+    /// The layers of the output, loaded along with the image when those of the surface
+    /// are outdated
+    layers: Option<LayerStack>,
+    /// Where the image has been downloaded from, when it is remote
+    attribution: Option<Attribution>,
 }
 
 impl Surface {
-    pub fn new(
+    /// The surface is placed in the background using layer shell. If the compositor does not
+    /// support it, a fullscreen window is used instead.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<E: ShellHandling + ShmHandling>(
         wl_output: &wl_output::WlOutput,
         surface: wl_surface::WlSurface,
-        layer_shell: &Attached<zwlr_layer_shell_v1::ZwlrLayerShellV1>,
+        env: &Environment<E>,
+        layer_shell: Option<&Attached<zwlr_layer_shell_v1::ZwlrLayerShellV1>>,
+        viewporter: Option<&Attached<wp_viewporter::WpViewporter>>,
         info: OutputInfo,
-        pool: AutoMemPool,
+        identity: Identity,
+        buffers: Buffers,
         output: Arc<Output>,
+        event_tx: Sender<()>,
     ) -> Self {
-        let layer_surface = layer_shell.get_layer_surface(
-            &surface,
-            Some(wl_output),
-            zwlr_layer_shell_v1::Layer::Background,
-            "example".to_owned(),
-        );
-
-        layer_surface.set_size(0, 0);
-        layer_surface.set_anchor(
-            zwlr_layer_surface_v1::Anchor::Top
-                | zwlr_layer_surface_v1::Anchor::Left
-                | zwlr_layer_surface_v1::Anchor::Right
-                | zwlr_layer_surface_v1::Anchor::Bottom,
-        );
-        layer_surface.set_exclusive_zone(-1);
-
         let next_render_event = Rc::new(Cell::new(None::<RenderEvent>));
         let next_render_event_handle = Rc::clone(&next_render_event);
-        layer_surface.quick_assign(move |layer_surface, event, _| {
-            match (event, next_render_event_handle.get()) {
-                (zwlr_layer_surface_v1::Event::Closed, _) => {
-                    next_render_event_handle.set(Some(RenderEvent::Closed));
+        let role = if let Some(layer_shell) = layer_shell {
+            let layer_surface = layer_shell.get_layer_surface(
+                &surface,
+                Some(wl_output),
+                zwlr_layer_shell_v1::Layer::Background,
+                "example".to_owned(),
+            );
+
+            layer_surface.set_size(0, 0);
+            layer_surface.set_anchor(
+                zwlr_layer_surface_v1::Anchor::Top
+                    | zwlr_layer_surface_v1::Anchor::Left
+                    | zwlr_layer_surface_v1::Anchor::Right
+                    | zwlr_layer_surface_v1::Anchor::Bottom,
+            );
+            layer_surface.set_exclusive_zone(-1);
+
+            layer_surface.quick_assign(move |layer_surface, event, _| {
+                match (event, next_render_event_handle.get()) {
+                    (zwlr_layer_surface_v1::Event::Closed, _) => {
+                        next_render_event_handle.set(Some(RenderEvent::Closed));
+                    }
+                    (
+                        zwlr_layer_surface_v1::Event::Configure {
+                            serial,
+                            width,
+                            height,
+                        },
+                        next,
+                    ) if next != Some(RenderEvent::Closed) => {
+                        layer_surface.ack_configure(serial);
+                        next_render_event_handle
+                            .set(Some(RenderEvent::Configure { width, height }));
+                    }
+                    (_, _) => {}
                 }
-                (
-                    zwlr_layer_surface_v1::Event::Configure {
-                        serial,
-                        width,
-                        height,
-                    },
-                    next,
-                ) if next != Some(RenderEvent::Closed) => {
-                    layer_surface.ack_configure(serial);
-                    next_render_event_handle.set(Some(RenderEvent::Configure { width, height }));
+            });
+            Role::Layer(layer_surface)
+        } else {
+            // When the compositor chooses no size, cover the whole output
+            let scale = info.scale_factor.max(1) as u32;
+            let output_size = transformed_mode(&info)
+                .map(|(width, height)| (width / scale, height / scale))
+                .unwrap_or((0, 0));
+            let window = env.create_shell_surface(&surface, move |event, _| {
+                match (event, next_render_event_handle.get()) {
+                    (shell::Event::Close, _) => {
+                        next_render_event_handle.set(Some(RenderEvent::Closed));
+                    }
+                    (shell::Event::Configure { new_size, .. }, next)
+                        if next != Some(RenderEvent::Closed) =>
+                    {
+                        let (width, height) = new_size.unwrap_or(output_size);
+                        next_render_event_handle
+                            .set(Some(RenderEvent::Configure { width, height }));
+                    }
+                    (_, _) => {}
                 }
-                (_, _) => {}
-            }
-        });
+            });
+            window.set_app_id("wpaperd".to_owned());
+            window.set_title("wpaperd".to_owned());
+            window.set_fullscreen(Some(wl_output));
+            Role::Window(window)
+        };
+
+        let viewport = viewporter.map(|viewporter| viewporter.get_viewport(&surface));
 
         // Commit so that the server will send a configure event
         surface.commit();
 
+        let info_update = Arc::new(Mutex::new(None));
+        let info_update_handle = info_update.clone();
+        let info_listener = add_output_listener(wl_output, move |_, info, _| {
+            // A removed output is handled by the main loop
+            if !info.obsolete {
+                *info_update_handle.lock().unwrap() = Some(info.clone());
+            }
+        });
+
+        let overlay = text_layer(&output, &info.name);
         Self {
             surface,
-            layer_surface,
+            role: Some(role),
+            viewport,
             next_render_event,
             info,
-            pool,
+            identity,
+            info_update,
+            _info_listener: info_listener,
+            configured: (0, 0),
+            buffers,
             dimensions: (0, 0),
             need_redraw: false,
             output: output.clone(),
-            timer: Arc::new(Mutex::new(OutputTimer::new(output))),
+            config_output: output.clone(),
+            workspace: None,
+            workspace_cache: Vec::new(),
+            timer: OutputTimer::new(output),
+            displayed: None,
+            next_image: None,
+            requested_image: None,
+            requested_span: None,
+            paused: false,
+            pinned: None,
+            contest: None,
+            image_picker: ImagePicker::new(HISTORY_SIZE),
+            queue: Queue::default(),
+            pending_image: None,
+            synchronized: false,
+            held: None,
+            event_tx,
+            supports_10bit: env.shm_formats().contains(&wl_shm::Format::Xbgr2101010),
+            recorder: None,
+            inversion: Inversion::default(),
+            overlay,
+            night_step: None,
+            layers: None,
+            next_frame: Rc::new(Cell::new(false)),
+            last_frame: None,
+            throttled: false,
+            memory_low: false,
+            on_battery: None,
+            animations_enabled: true,
+            night: None,
+            previewed: None,
+            fade_out: None,
+            pointer: None,
         }
     }
 
     /// Handles any events that have occurred since the last call, redrawing if needed.
     /// Returns true if the surface should be dropped.
     pub fn handle_events(&mut self) -> bool {
+        let info_update = self.info_update.lock().unwrap().take();
+        if let Some(info) = info_update {
+            // The buffers follow the resolution and the transform of the output
+            self.info = info;
+            self.apply_size();
+        }
         match self.next_render_event.take() {
             Some(RenderEvent::Closed) => true,
             Some(RenderEvent::Configure { width, height }) => {
-                self.dimensions = (width, height);
-                self.need_redraw = true;
+                self.configured = (width, height);
+                let dimensions = self.dimensions;
+                self.apply_size();
+                // A configure that changes nothing, e.g. after the compositor reloaded its
+                // settings, gets the frame on screen again instead of a new decode
+                if self.dimensions != dimensions || !self.reattach() {
+                    self.need_redraw = true;
+                }
                 false
             }
             None => false,
         }
     }
 
-    /// Returns true if something has been drawn to the surface
-    pub fn draw(&mut self) -> Result<bool> {
-        {
-            let mut output_timer = self.timer.lock().unwrap();
-            if !(self.need_redraw || output_timer.expired) || self.dimensions.0 == 0 {
-                return Ok(false);
+    /// Commit the frame on screen again, if it is the complete image that is displayed at
+    /// the current dimensions and nothing else is being loaded
+    fn reattach(&mut self) -> bool {
+        let up_to_date =
+            self.displayed.as_ref().is_some_and(|image| {
+                !image.preview && (image.width, image.height) == self.dimensions
+            }) && self.pending_image.is_none()
+                && !self.need_redraw;
+        if !up_to_date || self.buffers.reattach() != Some(self.dimensions) {
+            return false;
+        }
+        self.surface.commit();
+        true
+    }
+
+    /// Follow the size of the surface and the mode of the output
+    fn apply_size(&mut self) {
+        let (width, height) = self.surface_size();
+        if let Some(viewport) = &self.viewport {
+            // The buffer is shown at the size chosen by the compositor
+            viewport.set_destination(width as i32, height as i32);
+        }
+        self.resize(self.buffer_size(width, height));
+    }
+
+    /// Size of the surface chosen by the compositor, in the orientation of the output: some
+    /// compositors configure the surface of a rotated output with its size before the
+    /// rotation, the wallpaper would then be stretched
+    fn surface_size(&self) -> (u32, u32) {
+        let (width, height) = self.configured;
+        match transformed_mode(&self.info) {
+            Some((mode_width, mode_height))
+                if width != height
+                    && mode_width != mode_height
+                    && (width > height) != (mode_width > mode_height) =>
+            {
+                (height, width)
             }
-            output_timer.expired = false;
-            self.need_redraw = false;
+            _ => (width, height),
         }
+    }
 
-        let path = self.output.path.as_ref().unwrap();
+    /// Use buffers of dimensions from now on. The image displayed is scaled again from the
+    /// original, or rasterized again for SVG images, so that it stays sharp.
+    fn resize(&mut self, dimensions: (u32, u32)) {
+        if dimensions == self.dimensions {
+            return;
+        }
+        self.dimensions = dimensions;
+        self.need_redraw = true;
+        self.workspace_cache.clear();
+        if let Some(image) = &self.displayed {
+            if self.next_image.is_none() {
+                self.requested_image.get_or_insert(image.path.clone());
+            }
+        }
+    }
 
-        let stride = 4 * self.dimensions.0 as i32;
-        let width = self.dimensions.0 as i32;
-        let height = self.dimensions.1 as i32;
+    /// Size of the buffer for a surface of width x height, in pixels. When possible this is
+    /// the resolution of the output, so that the compositor doesn't rescale the wallpaper
+    /// on scaled outputs, fractional scales included.
+    fn buffer_size(&self, width: u32, height: u32) -> (u32, u32) {
+        match (&self.viewport, transformed_mode(&self.info)) {
+            (Some(_), Some(mode)) if width != 0 && height != 0 => mode,
+            _ => (width, height),
+        }
+    }
 
-        self.pool
-            .resize((stride * height) as usize)
-            .context("resizing the wayland pool")?;
+    /// Use 10 bits per channel when possible, so that 16-bit images are not truncated.
+    /// Buffers with 10 bits per channel have no alpha, a background that is not opaque
+    /// needs 8 bits.
+    fn buffer_format(&self) -> wl_shm::Format {
+        if self.supports_10bit && !self.output.force_8bit && self.output.background().is_opaque() {
+            wl_shm::Format::Xbgr2101010
+        } else {
+            wl_shm::Format::Abgr8888
+        }
+    }
 
-        let (canvas, buffer) = self
-            .pool
-            .buffer(width, height, stride, wl_shm::Format::Abgr8888)
-            .context("creating the wayland buffer from the pool")?;
+    /// Start loading a new image that fits the current dimensions of the surface
+    fn load_image_in_background(&mut self) {
+        // While the images are on a network mount that is down, the fallback path is shown
+        // instead, or the image already displayed stays until the next rotation
+        let sources = self.output.sources();
+        let unavailable = !sources.is_empty()
+            && sources
+                .iter()
+                .all(|source| !mounts::is_available(&source.path));
+        let output = match &self.output.fallback_path {
+            Some(fallback_path) if unavailable => {
+                workspace_output(&self.output, Some(fallback_path.clone()))
+            }
+            None if unavailable && self.displayed.is_some() => return,
+            _ => self.output.clone(),
+        };
+        let span = self.requested_span.take();
+        let next_image = self.take_next_image();
+        let (width, height) = self.dimensions;
+        let format = self.buffer_format();
+        let fast_scaling = self
+            .on_battery
+            .as_ref()
+            .is_some_and(|on_battery| on_battery.fast_scaling);
+        let filter = if self.throttled || self.memory_low || fast_scaling {
+            self.output.scaling_filter.cheaper()
+        } else {
+            self.output.scaling_filter
+        };
+        // A part of a panorama cannot move on its own, and the movements need a larger image
+        let movable = self.animations() && span.is_none() && !self.memory_low;
+        let ken_burns = self.output.ken_burns && movable;
+        let parallax = self.output.parallax.filter(|_| movable);
+        let reduction = if self.memory_low {
+            LOW_MEMORY_REDUCTION
+        } else {
+            1
+        };
+        let recent: Vec<PathBuf> = self
+            .image_picker
+            .history()
+            .take(self.output.avoid_recent())
+            .map(Path::to_path_buf)
+            .collect();
+        // Until the first image is ready, the output would only show the bare background
+        let with_preview = self.displayed.is_none();
+        let load_layers = !self.output.layers.is_empty()
+            && !self
+                .layers
+                .as_ref()
+                .is_some_and(|layers| layers.matches(&self.output.layers, width, height));
+        let name = self.info.name.clone();
+        let (tx, rx) = mpsc::channel();
+        let event_tx = self.event_tx.clone();
+        // Decoding and scaling are the slow part, do them in a separate thread so that
+        // every output is drawn concurrently; the result is then committed by draw()
+        thread::spawn(move || {
+            // The receiver might have been dropped if a new image has been requested meanwhile
+            let send = |image| {
+                let _ = tx.send(image);
+                let _ = event_tx.send(());
+            };
+            let preview = |image| send(Ok(image));
+            let image = load_image(
+                &FileDecoder,
+                &name,
+                &output,
+                next_image.map(|image| (image, span)),
+                width,
+                height,
+                format,
+                filter,
+                ken_burns,
+                parallax,
+                reduction,
+                &recent,
+                with_preview.then_some(&preview as &dyn Fn(LoadedImage)),
+            );
+            send(image.map(|mut image| {
+                if load_layers {
+                    image.layers = Some(LayerStack::load(&output.layers, width, height, filter));
+                }
+                image
+            }));
+        });
+        // Replacing the receiver discards any image that is still being loaded
+        self.pending_image = Some(rx);
+        self.held = None;
+    }
+
+    /// Returns true if a new image has been displayed
+    pub fn draw(&mut self) -> Result<bool> {
+        if self
+            .previewed
+            .as_ref()
+            .is_some_and(|previewed| Instant::now() >= previewed.until)
+        {
+            self.end_preview()?;
+        }
+        // The schedule of the dynamic wallpaper shown has moved on to another of its images
+        if let Some(path) = self
+            .displayed
+            .as_ref()
+            .filter(|image| {
+                self.pending_image.is_none()
+                    && image
+                        .schedule
+                        .as_ref()
+                        .is_some_and(|(schedule, frame)| schedule.frame(Local::now()) != *frame)
+            })
+            .map(|image| image.path.clone())
+        {
+            self.reload_image(&path);
+        }
+        let expired = self.timer.expired();
+        if (self.need_redraw || expired) && self.dimensions.0 != 0 {
+            if expired {
+                self.timer.reset();
+                // When the timer expires during a contest, show the next candidate
+                if self.contest.is_some() {
+                    self.next_contest_candidate();
+                } else {
+                    self.next_image = self.image_picker.next().or_else(|| self.queue.pop());
+                }
+            }
+            self.need_redraw = false;
+            self.load_image_in_background();
+            // Nothing to wait for, e.g. when the images are on a mount that is down
+            if self.pending_image.is_none() {
+                self.synchronized = false;
+            }
+        }
 
-        let mut tries = 0;
-        let image = if path.is_dir() {
-            loop {
-                let files = Vec::<PathBuf>::try_from(
-                    Dowser::filtered(|p: &Path| {
-                        if let Some(guess) = new_mime_guess::from_path(&p).first() {
-                            guess.type_() == "image"
-                        } else {
-                            false
+        let image = match self.pending_image.as_ref().map(Receiver::try_recv) {
+            Some(Ok(res)) => {
+                // The image itself follows its preview
+                if !res.as_ref().is_ok_and(|image| image.preview) {
+                    self.pending_image = None;
+                }
+                if res.is_err() {
+                    self.synchronized = false;
+                }
+                match res {
+                    // Not an error: the directories are checked again when the timer expires
+                    Err(err) if err.is::<NoImages>() => {
+                        warn!("{}, for {}", err, self.info.name);
+                        if self.displayed.is_none() {
+                            self.show_background();
                         }
-                    })
-                    .with_path(path),
-                )
-                .with_context(|| format!("iterating files in directory {:?}", path))?;
-                let img_path = files[rand::random::<usize>() % files.len()].clone();
-                match open(&img_path).with_context(|| format!("opening the image {:?}", img_path)) {
-                    Ok(image) => {
-                        break image;
+                        return Ok(false);
                     }
-                    Err(err) => {
-                        warn!("{:?}", err);
-                        tries += 1;
+                    res => Some(res?),
+                }
+            }
+            Some(Err(TryRecvError::Empty)) | None => None,
+            Some(Err(TryRecvError::Disconnected)) => {
+                self.pending_image = None;
+                self.synchronized = false;
+                return Err(eyre!("the thread loading the image has panicked"));
+            }
+        };
+        let mut image = image;
+        if let Some(layers) = image.as_mut().and_then(|image| image.layers.take()) {
+            self.layers = Some(layers);
+        }
+
+        match image {
+            Some(image) if image.preview && (image.width, image.height) == self.dimensions => {
+                self.displayed = Some(image);
+                self.render(false)?;
+                // The image is announced once it is complete
+                Ok(false)
+            }
+            Some(image)
+                if (image.width, image.height) == self.dimensions
+                    && self
+                        .previewed
+                        .as_ref()
+                        .is_some_and(|previewed| previewed.image == image.path) =>
+            {
+                let previous = self.displayed.replace(image);
+                if let Some(previewed) = &mut self.previewed {
+                    // When previewing another image, go back to the one before the first
+                    if previewed.previous.is_none() {
+                        previewed.previous = previous;
                     }
                 }
+                self.render(false)?;
+                Ok(false)
+            }
+            Some(image) if (image.width, image.height) == self.dimensions && self.synchronized => {
+                self.synchronized = false;
+                self.held = Some((image, Instant::now()));
+                Ok(false)
+            }
+            // The output might have been resized while the image was loading
+            Some(image) if (image.width, image.height) == self.dimensions => {
+                self.show(image)?;
+                Ok(true)
+            }
+            _ => {
+                // Draw the next frame of the inversion transition
+                if self.next_frame.get() && self.frame_due() {
+                    self.next_frame.set(false);
+                    self.last_frame = Some(Instant::now());
+                    self.render(false)?;
+                } else if self.overlay_outdated() || self.current_night_step()? != self.night_step {
+                    self.render(false)?;
+                }
+                Ok(false)
+            }
+        }
+    }
 
-                ensure!(
-                    tries < 5,
-                    "tried reading an image from the directory {:?} without success",
-                    &path
-                );
+    /// Wait for the other outputs before showing the image about to be loaded, if any, until
+    /// flip() is called. Used when several outputs change at the same time, so that they all
+    /// show their new image on the same frame.
+    pub fn synchronize(&mut self) {
+        // An output not configured yet loads nothing
+        if (self.need_redraw || self.timer.expired()) && self.dimensions.0 != 0 {
+            self.synchronized = true;
+        }
+    }
+
+    /// Whether the image of a synchronized change is still being loaded
+    pub fn is_synchronizing(&self) -> bool {
+        self.synchronized
+    }
+
+    /// How long the image of a synchronized change can still wait for the other outputs,
+    /// None if there is none ready
+    pub fn flip_timeout(&self) -> Option<Duration> {
+        self.held
+            .as_ref()
+            .map(|(_, since)| FLIP_TIMEOUT.saturating_sub(since.elapsed()))
+    }
+
+    /// Show the image of a synchronized change. Returns true if a new image has been
+    /// displayed.
+    pub fn flip(&mut self) -> Result<bool> {
+        match self.held.take() {
+            // The output might have been resized meanwhile
+            Some((image, _)) if (image.width, image.height) == self.dimensions => {
+                self.show(image)?;
+                Ok(true)
             }
-        } else {
-            let img_path = path.to_path_buf();
-            open(&img_path).with_context(|| format!("opening the image {:?}", img_path))?
+            Some(_) => {
+                self.need_redraw = true;
+                Ok(false)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Display image, which has just been loaded, as the new image of the rotation
+    fn show(&mut self, mut image: LoadedImage) -> Result<()> {
+        // Another image has been asked for during the preview, which is over
+        if self.previewed.take().is_some() {
+            self.update_pause();
+        }
+        self.image_picker.displayed(&image.path);
+        views::shown(&image.path);
+        duplicates::show(&self.info.name, &image.path);
+        metrics::rotation(&self.info.name);
+        self.timer.set_image_duration(image.duration);
+        if let Some(ken_burns) = &mut image.ken_burns {
+            // The movement lasts until the next image is shown
+            ken_burns.restart(self.timer.remaining());
+        }
+        if let Some(parallax) = &mut image.parallax {
+            parallax.point_at(self.pointer);
+        }
+        if let (Some(template), Some(destination)) =
+            (&self.output.palette_template, &self.output.palette_file)
+        {
+            if let Err(err) = write_palette(
+                template,
+                destination,
+                &image.palette,
+                &self.info.name,
+                &image.path,
+            ) {
+                error!("{:?}", err);
+            }
+        }
+        if self.output.lock_screen {
+            export_lock_image(
+                &self.info.name,
+                image.width,
+                image.height,
+                image.format,
+                image.data.clone(),
+            );
+        }
+        if let Some(command) = &self.output.exec {
+            hook::run_hook(command, &self.info.name, &image.path);
+        }
+        self.displayed = Some(image);
+        self.render(true)
+    }
+
+    /// Write the displayed image with its filters applied to a free buffer and commit it.
+    /// When rotated is true, a new image is being displayed.
+    fn render(&mut self, rotated: bool) -> Result<()> {
+        let animations = self.animations();
+        let moving_layers = self.layers_moving();
+        self.night_step = self.current_night_step()?;
+        let image = match &mut self.displayed {
+            Some(image) => image,
+            None => return Ok(()),
         };
 
-        let image = image
-            .resize_to_fill(width.try_into()?, height.try_into()?, FilterType::Lanczos3)
-            .into_rgba8();
+        let mut data = match (
+            &mut image.ken_burns,
+            &mut image.parallax,
+            &image.night,
+            self.night_step,
+        ) {
+            (Some(ken_burns), ..) => Cow::Owned(ken_burns.frame(image.width, image.height)),
+            (_, Some(parallax), ..) => Cow::Owned(parallax.frame(image.width, image.height)),
+            (_, _, Some(night), Some(BLEND_STEPS)) => Cow::Borrowed(night),
+            (_, _, Some(night), Some(step)) if step > 0 => {
+                Cow::Owned(day_night::blend(&image.data, night, step, image.format))
+            }
+            _ => Cow::Borrowed(&image.data),
+        };
+        let filters_start = Instant::now();
+        if let Some(layers) = self
+            .layers
+            .as_ref()
+            .filter(|layers| layers.matches(&self.output.layers, image.width, image.height))
+        {
+            layers.apply(data.to_mut(), image.format, animations);
+        }
+        if self.inversion.level() > 0.0 {
+            self.inversion.apply(data.to_mut(), image.format);
+        }
+        if let Some(night) = &self.night {
+            night.apply(data.to_mut(), image.format);
+        }
+        if let Some(overlay) = &mut self.overlay {
+            overlay.apply(
+                data.to_mut(),
+                (image.width, image.height),
+                image.format,
+                &image.path,
+                image.attribution.as_ref(),
+            );
+        }
+        if let Some(fade_out) = &mut self.fade_out {
+            fade_out.apply(data.to_mut(), image.format);
+        }
+        metrics::stage_done(Stage::Filters, filters_start.elapsed());
 
-        let mut writer = BufWriter::new(canvas);
-        writer
-            .write_all(image.as_raw())
-            .context("writing the image to the surface")?;
-        writer.flush().context("flushing the surface writer")?;
+        let buffers = &mut self.buffers;
+        metrics::time(Stage::Write, || {
+            buffers.present(&data, (image.width, image.height), image.format)
+        })?;
 
-        // Attach the buffer to the surface and mark the entire surface as damaged
-        self.surface.attach(Some(&buffer), 0, 0);
-        self.surface
-            .damage_buffer(0, 0, width as i32, height as i32);
+        // Ask the compositor when to draw the next frame of the animations
+        let fading = self
+            .fade_out
+            .as_ref()
+            .is_some_and(|fade_out| !fade_out.is_over());
+        let following_pointer = image
+            .parallax
+            .as_ref()
+            .is_some_and(|parallax| parallax.is_moving(image.width, image.height));
+        if self.inversion.is_animating()
+            || image.ken_burns.is_some()
+            || following_pointer
+            || moving_layers
+            || fading
+        {
+            let next_frame = self.next_frame.clone();
+            self.surface
+                .frame()
+                .quick_assign(move |_, _, _| next_frame.set(true));
+        }
 
         // Finally, commit the surface
         self.surface.commit();
+        metrics::draw(&self.info.name);
+        metrics::shm_pool_size(&self.info.name, self.buffers.allocated());
+
+        if rotated {
+            if let Some(recorder) = &mut self.recorder {
+                if !recorder.add_frame(&data, self.dimensions, image.format) {
+                    self.recorder = None;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Cover the output with a neutral color. Used after an error when no image is
+    /// displayed.
+    pub fn show_fallback(&mut self) {
+        self.fill(FALLBACK_COLOR);
+    }
+
+    /// Cover the output with its background color, when there is no image to show
+    fn show_background(&mut self) {
+        let [r, g, b, _] = self.output.background().0;
+        self.fill([r, g, b]);
+    }
+
+    fn fill(&mut self, [r, g, b]: [u8; 3]) {
+        let (width, height) = self.dimensions;
+        if width == 0 {
+            return;
+        }
+        let format = self.buffer_format();
+        let pixel = match format {
+            wl_shm::Format::Xbgr2101010 => {
+                let scale = |component: u8| u32::from(component) * 1023 / 255;
+                (scale(r) | (scale(g) << 10) | (scale(b) << 20)).to_le_bytes()
+            }
+            _ => [r, g, b, 0xff],
+        };
+        let data = pixel.repeat((width * height) as usize);
+        match self.buffers.present(&data, self.dimensions, format) {
+            Ok(()) => {
+                self.surface.commit();
+                metrics::draw(&self.info.name);
+                metrics::shm_pool_size(&self.info.name, self.buffers.allocated());
+            }
+            Err(err) => error!("filling {} with a color: {:?}", self.info.name, err),
+        }
+    }
+
+    /// Whether layers drift across the displayed image
+    fn layers_moving(&self) -> bool {
+        self.animations()
+            && match (&self.layers, &self.displayed) {
+                (Some(layers), Some(image)) => {
+                    layers.is_moving()
+                        && layers.matches(&self.output.layers, image.width, image.height)
+                }
+                _ => false,
+            }
+    }
+
+    /// Minimum time between two frames of the animations
+    fn frame_interval(&self) -> Duration {
+        let mut interval = Duration::ZERO;
+        if self.layers_moving()
+            || self
+                .displayed
+                .as_ref()
+                .is_some_and(|image| image.ken_burns.is_some())
+        {
+            interval = Duration::from_secs(1) / self.output.ken_burns_fps();
+        }
+        if self.throttled {
+            interval = interval.max(THROTTLED_FRAME_INTERVAL);
+        }
+        interval
+    }
+
+    /// Frames closer than frame_interval() are postponed
+    fn frame_due(&self) -> bool {
+        self.last_frame
+            .is_none_or(|last_frame| last_frame.elapsed() >= self.frame_interval())
+    }
+
+    /// How long until the pending frame of an animation, the next text of the overlay, the
+    /// next step between the day and night images, or the next image of a dynamic wallpaper,
+    /// can be drawn; None if there is none
+    pub fn frame_timeout(&self) -> Option<Duration> {
+        let animation = self.next_frame.get().then(|| {
+            self.last_frame.map_or(Duration::ZERO, |last_frame| {
+                self.frame_interval().saturating_sub(last_frame.elapsed())
+            })
+        });
+        let overlay = self
+            .overlay
+            .as_ref()
+            .filter(|_| self.displayed.is_some())
+            .and_then(TextLayer::next_change);
+        let night = self
+            .output
+            .day_night
+            .as_ref()
+            .filter(|_| matches!(self.current_night_step(), Ok(Some(_))))
+            .and_then(|day_night| day_night.next_change(Local::now().time()).ok());
+        let schedule = self
+            .displayed
+            .as_ref()
+            .filter(|image| image.schedule.is_some())
+            .map(|_| heic::CHECK_INTERVAL);
+        let preview_end = self
+            .previewed
+            .as_ref()
+            .map(|previewed| previewed.until.saturating_duration_since(Instant::now()));
+        animation
+            .into_iter()
+            .chain(overlay)
+            .chain(night)
+            .chain(schedule)
+            .chain(preview_end)
+            .min()
+    }
+
+    /// Whether the text of the overlay has changed since the last frame, e.g. the clock
+    fn overlay_outdated(&self) -> bool {
+        match (&self.overlay, &self.displayed) {
+            (Some(overlay), Some(image)) => {
+                overlay.outdated(&image.path, image.attribution.as_ref())
+            }
+            _ => false,
+        }
+    }
+
+    /// The step of the blend of the day and night images due now, None unless the displayed
+    /// image has a night one
+    fn current_night_step(&self) -> Result<Option<u32>> {
+        let day_night = match (&self.output.day_night, &self.displayed) {
+            (Some(day_night), Some(image)) if image.night.is_some() => day_night,
+            _ => return Ok(None),
+        };
+        day_night.night_step(Local::now().time()).map(Some)
+    }
+
+    pub fn set_throttled(&mut self, throttled: bool) {
+        self.throttled = throttled;
+    }
+
+    /// Reduce the quality while the memory is low. The images already loaded are kept, the
+    /// next ones are loaded with the new quality.
+    pub fn set_memory_low(&mut self, memory_low: bool) {
+        if memory_low {
+            self.workspace_cache.clear();
+        }
+        self.memory_low = memory_low;
+    }
+
+    /// Whether surface is the wayland surface of the output
+    pub fn owns(&self, surface: &wl_surface::WlSurface) -> bool {
+        self.surface == *surface
+    }
+
+    /// Follow the pointer, at position in the surface, with the parallax of the output; None
+    /// when it has left the output
+    pub fn set_pointer(&mut self, position: Option<(f64, f64)>) {
+        let (width, height) = self.surface_size();
+        self.pointer = position.filter(|_| width > 0 && height > 0).map(|(x, y)| {
+            let shift = |position: f64, size: u32| (position / f64::from(size) * 2.0 - 1.0) as f32;
+            (shift(x, width), shift(y, height))
+        });
+        if let Some(parallax) = self
+            .displayed
+            .as_mut()
+            .and_then(|image| image.parallax.as_mut())
+        {
+            parallax.point_at(self.pointer);
+            self.next_frame.set(true);
+        }
+    }
+
+    /// Switch between the normal colors and the negative ones
+    pub fn toggle_inversion(&mut self) {
+        self.inversion.toggle(self.animations());
+        self.next_frame.set(true);
+    }
+
+    /// Start fading the output to black, if it has a fade_out and the animations are
+    /// enabled. Returns false when there is nothing to fade and the output can go away at
+    /// once.
+    pub fn fade_out(&mut self) -> bool {
+        let duration = match self.output.fade_out {
+            Some(duration) if self.animations() && self.displayed.is_some() => duration,
+            _ => return false,
+        };
+        self.fade_out = Some(FadeOut::new(duration));
+        self.next_frame.set(true);
+        true
+    }
+
+    /// Whether the output is fading out and has not been drawn black yet
+    pub fn is_fading(&self) -> bool {
+        self.fade_out
+            .as_ref()
+            .is_some_and(|fade_out| !fade_out.is_over())
+    }
+
+    /// Tint the current image for the night, or show it as it is with None
+    pub fn set_night(&mut self, night: Option<NightTint>) {
+        if night != self.night {
+            self.night = night;
+            self.next_frame.set(true);
+        }
+    }
+
+    /// Apply the on-battery settings, or stop applying them with None. Returns true if the
+    /// duration might have changed, so that the timer needs to be scheduled again.
+    pub fn set_on_battery(&mut self, on_battery: Option<OnBattery>) -> bool {
+        if on_battery == self.on_battery {
+            return false;
+        }
+        let animations = self.animations();
+        self.on_battery = on_battery;
+        self.timer.set_battery_duration(
+            self.on_battery
+                .as_ref()
+                .and_then(|on_battery| on_battery.duration),
+        );
+        self.animations_changed(animations);
+        true
+    }
+
+    /// Enable or disable every animation of the output, following the global animations
+    /// setting
+    pub fn set_animations(&mut self, enabled: bool) {
+        if enabled != self.animations_enabled {
+            let animations = self.animations();
+            self.animations_enabled = enabled;
+            self.animations_changed(animations);
+        }
+    }
+
+    /// Catch up with the animations being enabled or disabled, they were before when
+    /// animations is true
+    fn animations_changed(&mut self, animations: bool) {
+        // The layers drift again
+        if self.layers_moving() {
+            self.next_frame.set(true);
+        }
+        if animations == self.animations() {
+            return;
+        }
+        // The running transitions jump to their end
+        if !self.animations() {
+            self.fade_out = self.fade_out.take().filter(|fade_out| fade_out.is_over());
+            self.inversion.finish();
+            self.next_frame.set(true);
+        }
+        // Start or stop the Ken Burns effect or the parallax on the current image
+        if self.output.ken_burns || self.output.parallax.is_some() {
+            if let Some(image) = self.current_image() {
+                self.requested_image = Some(image.to_path_buf());
+                self.need_redraw = true;
+            }
+        }
+    }
+
+    /// Whether the animations are enabled; they can be disabled globally and while on
+    /// battery
+    fn animations(&self) -> bool {
+        self.animations_enabled
+            && self
+                .on_battery
+                .as_ref()
+                .is_none_or(|on_battery| on_battery.animations)
+    }
+
+    /// Change how long each image is displayed, until the configuration is reloaded
+    pub fn set_duration(&mut self, duration: Duration) -> Result<()> {
+        ensure!(
+            self.output.rotates(),
+            "a duration can only be set when {} shows a directory or collections",
+            self.info.name
+        );
+        self.timer.set_duration(duration);
+        Ok(())
+    }
+
+    /// Resume from a previously saved state: the saved image is shown on the next draw and
+    /// the timer continues from where it was left
+    pub fn restore_state(&mut self, output_state: &OutputState) {
+        self.next_image = Some(output_state.image.clone());
+        if let Some(remaining) = output_state.remaining {
+            self.timer.set_remaining(remaining);
+        }
+        self.paused = output_state.paused;
+        if output_state.pinned {
+            self.pinned = Some(output_state.image.clone());
+        }
+        self.update_pause();
+    }
+
+    pub fn get_state(&self) -> Option<OutputState> {
+        // A previewed image is not part of the rotation
+        let previous = self
+            .previewed
+            .as_ref()
+            .and_then(|previewed| previewed.previous.as_ref());
+        previous
+            .or(self.displayed.as_ref())
+            .map(|image| OutputState {
+                image: image.path.clone(),
+                remaining: self.timer.remaining(),
+                paused: self.paused,
+                pinned: self.pinned.is_some(),
+            })
+    }
 
-        Ok(true)
+    /// Keep the current image until resumed; the timer stops where it is
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.update_pause();
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.update_pause();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Show image, or keep the current one with None, until unpinned
+    pub fn pin(&mut self, image: Option<&Path>) -> Result<()> {
+        let image = match image {
+            Some(image) => {
+                ensure!(image.is_file(), "{:?} is not a file", image);
+                self.need_redraw = true;
+                image.to_path_buf()
+            }
+            None => self
+                .current_image()
+                .ok_or_else(|| eyre!("no image is displayed on {}", self.info.name))?
+                .to_path_buf(),
+        };
+        self.pinned = Some(image);
+        self.update_pause();
+        Ok(())
+    }
+
+    /// Let the image change again, the timer starts from the beginning. Returns false if
+    /// no image was pinned.
+    pub fn unpin(&mut self) -> bool {
+        if self.pinned.take().is_none() {
+            return false;
+        }
+        self.timer.reset();
+        self.update_pause();
+        true
+    }
+
+    /// The image the output is pinned to
+    pub fn pinned(&self) -> Option<&Path> {
+        self.pinned.as_deref()
+    }
+
+    /// The timer only runs when the output is neither paused nor pinned
+    fn update_pause(&mut self) {
+        if self.paused || self.pinned.is_some() || self.previewed.is_some() {
+            self.timer.pause();
+        } else {
+            self.timer.resume();
+        }
+    }
+
+    /// The compositor now shows workspace on the output. When the workspace has its own path,
+    /// its images are shown instead of the ones of the configured path. The image displayed
+    /// for each path is kept, so that switching back to a workspace is immediate.
+    pub fn set_workspace(&mut self, workspace: &str) {
+        if self.workspace.as_deref() == Some(workspace) {
+            return;
+        }
+        let previous_path = self.workspace_path();
+        self.workspace = Some(workspace.to_string());
+        let path = self.workspace_path();
+        if path == previous_path {
+            return;
+        }
+        self.output = workspace_output(&self.config_output, path.clone());
+        if self.pinned.is_some() || self.contest.is_some() {
+            return;
+        }
+
+        if let Some(image) = self
+            .displayed
+            .take()
+            .filter(|image| !image.preview && !self.memory_low)
+        {
+            self.workspace_cache.push((previous_path, image));
+            if self.workspace_cache.len() > WORKSPACE_CACHE_SIZE {
+                self.workspace_cache.remove(0);
+            }
+        }
+        self.next_image = None;
+        self.requested_image = None;
+        self.requested_span = None;
+        let cached = self
+            .workspace_cache
+            .iter()
+            .position(|(cached_path, _)| *cached_path == path)
+            .map(|i| self.workspace_cache.remove(i).1)
+            .filter(|image| {
+                (image.width, image.height) == self.dimensions
+                    && image.format == self.buffer_format()
+            });
+        metrics::cache_lookup("workspaces", cached.is_some());
+        match cached {
+            // draw() shows it like any image that has just been loaded
+            Some(image) => {
+                let (tx, rx) = mpsc::channel();
+                let _ = tx.send(Ok(image));
+                self.pending_image = Some(rx);
+            }
+            None => self.need_redraw = true,
+        }
+    }
+
+    /// The path of the workspace shown, if it has one
+    fn workspace_path(&self) -> Option<PathBuf> {
+        let workspace = self.workspace.as_ref()?;
+        self.config_output.workspaces.get(workspace).cloned()
+    }
+
+    /// Return the image queued for the next draw, but only if it is still part of the
+    /// configured path
+    fn take_next_image(&mut self) -> Option<PathBuf> {
+        if let Some(pinned) = &self.pinned {
+            self.requested_image = None;
+            self.next_image = None;
+            return Some(pinned.clone());
+        }
+        if let Some(img_path) = self.requested_image.take() {
+            return Some(img_path);
+        }
+        let img_path = self.next_image.take()?;
+        if self.output.contains(&img_path) && mounts::is_available(&img_path) && img_path.is_file()
+        {
+            Some(img_path)
+        } else {
+            None
+        }
+    }
+
+    /// Cycle a shortlist of count random images taken from the output directory.
+    /// Returns the number of images in the shortlist.
+    pub fn start_contest(&mut self, count: usize) -> Result<usize> {
+        self.ensure_rotates()?;
+        let contest = Contest::new(Collection::for_output(&self.output)?.images(), count);
+        let len = contest.len();
+        self.contest = Some(contest);
+        self.next_contest_candidate();
+        Ok(len)
+    }
+
+    pub fn stop_contest(&mut self) -> bool {
+        self.contest.take().is_some()
+    }
+
+    pub fn in_contest(&self) -> bool {
+        self.contest.is_some()
+    }
+
+    /// Show the next image of the contest. Returns false and ends the contest when all the
+    /// candidates have already been displayed.
+    pub fn next_contest_candidate(&mut self) -> bool {
+        match self.contest.as_mut().and_then(Contest::next_candidate) {
+            Some(candidate) => {
+                self.next_image = Some(candidate);
+                self.need_redraw = true;
+                true
+            }
+            None => {
+                self.contest = None;
+                false
+            }
+        }
+    }
+
+    /// Record a frame every interval rotations into the video at path
+    pub fn start_recording(&mut self, path: &Path, interval: u32, framerate: u32) -> Result<()> {
+        ensure!(
+            self.dimensions.0 != 0,
+            "output {} has not been configured yet",
+            self.info.name
+        );
+        self.recorder = Some(Recorder::start(
+            path,
+            self.dimensions,
+            self.buffer_format(),
+            interval,
+            framerate,
+        )?);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> bool {
+        self.recorder.take().is_some()
+    }
+
+    /// Stop the recording, if any, and wait up to timeout for its video to be written
+    pub fn finish_recording(&mut self, timeout: Duration) {
+        if let Some(recorder) = self.recorder.take() {
+            if !recorder.finish(timeout) {
+                warn!(
+                    "the recording of {} did not finish in time, it may be incomplete",
+                    self.info.name
+                );
+            }
+        }
+    }
+
+    /// Go back to the image displayed before the current one.
+    /// Returns false if there is no such image in the history.
+    pub fn previous_wallpaper(&mut self) -> bool {
+        match self.image_picker.previous() {
+            Some(image) => {
+                self.next_image = Some(image);
+                self.need_redraw = true;
+                self.timer.reset();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Change image on the next draw and restart the timer, as if it had just expired.
+    /// Used to keep the outputs of a group in lockstep.
+    pub fn expire(&mut self, image: Option<PathBuf>, span: Option<Span>) {
+        // The outputs that don't rotate stay out of the group until they do
+        if self.timer.is_paused() {
+            return;
+        }
+        self.timer.expire();
+        if image.is_some() {
+            self.requested_image = image;
+            self.requested_span = span;
+        }
+    }
+
+    /// Load image again if it is the one displayed, after it has been modified; the timer is
+    /// left alone. Returns true if the image is reloaded.
+    pub fn reload_image(&mut self, image: &Path) -> bool {
+        // The copies kept for the workspaces show the old content
+        self.workspace_cache
+            .retain(|(_, cached)| cached.path.as_path() != image);
+        if self.current_image() != Some(image) || !image.is_file() {
+            return false;
+        }
+        self.requested_image = Some(image.to_path_buf());
+        self.need_redraw = true;
+        true
+    }
+
+    /// Show image right away, wherever it is. The rotation then continues as usual.
+    pub fn set_wallpaper(&mut self, image: &Path) -> Result<()> {
+        ensure!(image.is_file(), "{:?} is not a file", image);
+        self.requested_image = Some(image.to_path_buf());
+        self.requested_span = None;
+        self.need_redraw = true;
+        self.timer.reset();
+        Ok(())
+    }
+
+    /// Show image for duration, then go back to the current one. The rotation doesn't move
+    /// meanwhile and the image is not remembered as shown.
+    pub fn preview(&mut self, image: &Path, duration: Duration) -> Result<()> {
+        ensure!(image.is_file(), "{:?} is not a file", image);
+        // Previewing one image after another goes back to the image before the first one
+        let previous = self
+            .previewed
+            .take()
+            .and_then(|previewed| previewed.previous);
+        self.previewed = Some(Previewed {
+            image: image.to_path_buf(),
+            until: Instant::now() + duration,
+            previous,
+        });
+        self.requested_image = Some(image.to_path_buf());
+        self.requested_span = None;
+        self.need_redraw = true;
+        self.update_pause();
+        Ok(())
+    }
+
+    fn end_preview(&mut self) -> Result<()> {
+        let previewed = match self.previewed.take() {
+            Some(previewed) => previewed,
+            None => return Ok(()),
+        };
+        self.update_pause();
+        match previewed.previous {
+            Some(previous) if (previous.width, previous.height) == self.dimensions => {
+                self.displayed = Some(previous);
+                self.render(false)?;
+            }
+            // The output has been resized meanwhile, or nothing was displayed before
+            previous => {
+                self.requested_image = previous.map(|previous| previous.path);
+                self.pending_image = None;
+                self.need_redraw = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Show the next image, as if the timer expired
+    pub fn next_wallpaper(&mut self) {
+        if self.contest.is_some() {
+            self.next_contest_candidate();
+        } else {
+            self.next_image = self.image_picker.next().or_else(|| self.queue.pop());
+        }
+        self.need_redraw = true;
+        self.timer.reset();
+    }
+
+    /// The next count images of the rotation, with whether they are pinned
+    pub fn queue(&mut self, count: usize) -> Result<Vec<(PathBuf, bool)>> {
+        self.ensure_rotates()?;
+        let mut collection = Collection::for_output(&self.output)?;
+        let (width, height) = self.dimensions;
+        collection.prefer_aspect(self.output.aspect(width, height));
+        collection.prefer_unseen(self.output.prefer_unseen);
+        let recent: Vec<PathBuf> = self
+            .image_picker
+            .history()
+            .take(self.output.avoid_recent())
+            .map(Path::to_path_buf)
+            .collect();
+        Ok(self
+            .queue
+            .upcoming(count, &collection, &recent)
+            .into_iter()
+            .map(|(image, pinned)| (image.to_path_buf(), pinned))
+            .collect())
+    }
+
+    /// Remove image from the queue, or the next image if None
+    pub fn queue_skip(&mut self, image: Option<&Path>) -> Result<()> {
+        ensure!(
+            self.queue.skip(image),
+            "{} is not in the queue of {}",
+            image.map_or("no image".into(), |image| format!("{:?}", image)),
+            self.info.name
+        );
+        Ok(())
+    }
+
+    /// Keep image in the queue when the settings of the output change, or stop keeping it
+    pub fn queue_pin(&mut self, image: &Path, pinned: bool) -> Result<()> {
+        ensure!(
+            self.queue.set_pinned(image, pinned),
+            "{:?} is not in the queue of {}",
+            image,
+            self.info.name
+        );
+        Ok(())
+    }
+
+    /// Show image after the current one
+    pub fn queue_front(&mut self, image: &Path) -> Result<()> {
+        self.ensure_rotates()?;
+        ensure!(
+            self.output.contains(image) && mounts::is_available(image) && image.is_file(),
+            "{:?} is not an image of {}",
+            image,
+            self.info.name
+        );
+        self.queue.move_to_front(image);
+        Ok(())
+    }
+
+    /// Take the next image of the queue, if any
+    pub fn take_queued(&mut self) -> Option<PathBuf> {
+        self.queue.pop()
+    }
+
+    /// Only the outputs choosing their images from a directory or from collections have a
+    /// queue and contests
+    fn ensure_rotates(&self) -> Result<()> {
+        ensure!(
+            self.output.rotates(),
+            "{} shows neither a directory nor collections",
+            self.info.name
+        );
+        Ok(())
+    }
+
+    /// Size of the buffers, in pixels
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.dimensions
+    }
+
+    /// The images displayed on this output, from the most recent
+    pub fn history(&self) -> impl Iterator<Item = &Path> {
+        self.image_picker.history()
+    }
+
+    /// Dominant colors of the image currently displayed
+    pub fn palette(&self) -> Option<&[[u8; 3]]> {
+        self.displayed
+            .as_ref()
+            .map(|image| image.palette.as_slice())
+    }
+
+    /// Save the frame on screen, with the filters and the overlay, as a PNG and return its
+    /// path, so that other clients can use it without decoding the image again
+    pub fn export_frame(&mut self) -> Result<PathBuf> {
+        let (data, (width, height), format) = self.buffers.on_screen()?;
+        lock_screen::export_frame(&self.info.name, width, height, format, &data)
+    }
+
+    /// The capabilities of the output, as shown to wpaperctl
+    pub fn describe(&self) -> String {
+        format!(
+            "{} {}x{} scale={} 10bit={}",
+            self.info.name,
+            self.dimensions.0,
+            self.dimensions.1,
+            self.info.scale_factor,
+            self.supports_10bit
+        )
+    }
+
+    pub fn current_image(&self) -> Option<&Path> {
+        self.displayed.as_ref().map(|image| image.path.as_path())
+    }
+
+    /// Where the image currently displayed has been downloaded from, if it is remote
+    pub fn attribution(&self) -> Option<&Attribution> {
+        self.displayed
+            .as_ref()
+            .and_then(|image| image.attribution.as_ref())
+    }
+
+    /// The settings of the output as configured, without the path of the workspace
+    pub fn config_output(&self) -> &Arc<Output> {
+        &self.config_output
     }
 
     pub fn update_output(&mut self, output: Arc<Output>) {
-        self.output = output;
-        self.timer
-            .lock()
-            .unwrap()
-            .update_output(self.output.clone());
+        if output.overlay != self.output.overlay {
+            self.overlay = text_layer(&output, &self.info.name);
+        }
+        self.config_output = output;
+        self.output = workspace_output(&self.config_output, self.workspace_path());
+        self.workspace_cache.clear();
+        self.next_image = None;
+        self.contest = None;
+        self.queue.retain_pinned();
+        // The new image gets the whole duration
+        self.timer.update_output(self.output.clone());
+        self.timer.reset();
 
         self.need_redraw = true;
     }
 }
 
+/// Resolution of the current mode of the output once its transform is applied, e.g. taller
+/// than wide for a monitor rotated to portrait; the mode itself is never rotated
+pub fn transformed_mode(info: &OutputInfo) -> Option<(u32, u32)> {
+    let mode = info.modes.iter().find(|mode| mode.is_current)?;
+    let (width, height) = (mode.dimensions.0 as u32, mode.dimensions.1 as u32);
+    match info.transform {
+        wl_output::Transform::_90
+        | wl_output::Transform::_270
+        | wl_output::Transform::Flipped90
+        | wl_output::Transform::Flipped270 => Some((height, width)),
+        _ => Some((width, height)),
+    }
+}
+
+/// The settings of output showing the images of path instead of its own, if set
+fn workspace_output(output: &Arc<Output>, path: Option<PathBuf>) -> Arc<Output> {
+    match path {
+        Some(path) => Arc::new(Output {
+            path: Some(path),
+            collections: Vec::new(),
+            tags: Vec::new(),
+            collection_sources: Vec::new(),
+            day_night: None,
+            ..Output::clone(output)
+        }),
+        None => output.clone(),
+    }
+}
+
+/// The overlay of output, None if it has none or if its font cannot be loaded
+fn text_layer(output: &Output, name: &str) -> Option<TextLayer> {
+    let settings = output.overlay.as_ref()?;
+    TextLayer::new(settings)
+        .map_err(|err| error!("loading the overlay of {}: {:?}", name, err))
+        .ok()
+}
+
+/// Choose, decode and scale the image to display on the output called name; this runs
+/// outside of the wayland thread. next_image comes with the part of it to show, when it
+/// spans a group. ken_burns tells whether the Ken Burns effect of output is enabled,
+/// parallax how far the image follows the pointer when it does. preview is given a low
+/// resolution version of the image, when it can be made much faster than the image itself.
+#[allow(clippy::too_many_arguments)]
+fn load_image(
+    decoder: &dyn Decoder,
+    name: &str,
+    output: &Output,
+    next_image: Option<(PathBuf, Option<Span>)>,
+    width: u32,
+    height: u32,
+    format: wl_shm::Format,
+    filter: ScalingFilter,
+    ken_burns: bool,
+    parallax: Option<f32>,
+    reduction: u32,
+    recent: &[PathBuf],
+    preview: Option<&dyn Fn(LoadedImage)>,
+) -> Result<LoadedImage> {
+    if let Some(url) = output.url() {
+        remote::update(url, output.ttl())?;
+    }
+    // The Ken Burns effect and the parallax need a larger image to move across, the parallax
+    // a margin on each side
+    let zoom = match parallax {
+        _ if ken_burns => KEN_BURNS_ZOOM,
+        Some(parallax) => 1.0 + 2.0 * parallax,
+        None => 1.0,
+    };
+    let scale = |size: u32| (size as f32 * zoom).round() as u32;
+    let (width, height, output_size) = (scale(width), scale(height), (width, height));
+    let mut tries = 0;
+    let decode = |img_path: &Path, fit: Fit| {
+        if let Some(preview) = preview.filter(|_| fit != Fit::Tile) {
+            if decoder.decodes_reduced_fast(img_path) {
+                let reduce = |size: u32| (size / PREVIEW_REDUCTION).max(1);
+                // An error is reported by the decoding of the image itself
+                if let Ok(image) = decoder.decode(img_path, reduce(width), reduce(height)) {
+                    let (width, height) = output_size;
+                    let image =
+                        fit_scaler(output, fit, ScalingFilter::Nearest).scale(image, width, height);
+                    preview(LoadedImage {
+                        path: img_path.to_path_buf(),
+                        width,
+                        height,
+                        format,
+                        data: to_buffer_data(
+                            image,
+                            format,
+                            None,
+                            output.background(),
+                            Dither::None,
+                        ),
+                        night: None,
+                        schedule: None,
+                        palette: Vec::new(),
+                        ken_burns: None,
+                        parallax: None,
+                        duration: None,
+                        preview: true,
+                        layers: None,
+                        attribution: None,
+                    });
+                }
+            }
+        }
+        let (width, height) = decode_size(fit, width, height);
+        // Scaled up to the size of the output afterwards
+        let reduce = |size: u32| (size / reduction).max(1);
+        decoder.decode(img_path, reduce(width), reduce(height))
+    };
+    let mut span = None;
+    let (img_path, image, fit) = if let Some((img_path, image_span)) = next_image {
+        let settings = read_image_settings(output, &img_path)?;
+        let fit = settings.fit.unwrap_or(output.fit);
+        let image = match image_span {
+            Some(image_span) => {
+                span = Some(image_span);
+                let (width, height) = image_span.layout_size(width, height);
+                decoder.decode(&img_path, width, height)?
+            }
+            None => decode(&img_path, fit)?,
+        };
+        (img_path.clone(), image, (fit, settings))
+    } else if output.rotates() {
+        let mut collection = Collection::for_output(output)?;
+        // The size of the output, not the larger one of the Ken Burns effect
+        collection.prefer_aspect(output.aspect(output_size.0, output_size.1));
+        collection.prefer_unseen(output.prefer_unseen);
+        let sources = || {
+            let paths: Vec<PathBuf> = output.sources().into_iter().map(|s| s.path).collect();
+            format!("{:?}", paths)
+        };
+        if collection.is_empty() {
+            let no_images = NoImages {
+                sources: sources(),
+                tags: output.tags.clone(),
+            };
+            // Once, the fallback path being empty as well
+            if let Some(fallback_path) = &output.fallback_path {
+                warn!("{}, showing {:?} on {}", no_images, fallback_path, name);
+                let fallback = Output {
+                    path: Some(fallback_path.clone()),
+                    collections: Vec::new(),
+                    tags: Vec::new(),
+                    collection_sources: Vec::new(),
+                    fallback_path: None,
+                    day_night: None,
+                    ..output.clone()
+                };
+                return load_image(
+                    decoder,
+                    name,
+                    &fallback,
+                    None,
+                    output_size.0,
+                    output_size.1,
+                    format,
+                    filter,
+                    ken_burns,
+                    parallax,
+                    reduction,
+                    recent,
+                    preview,
+                );
+            }
+            return Err(no_images.into());
+        }
+        loop {
+            // The images shown by the other outputs are avoided first, then the recent ones
+            let img_path = duplicates::claim(name, |elsewhere| {
+                let mut avoided = Vec::new();
+                if !output.allow_duplicates {
+                    avoided.extend_from_slice(elsewhere);
+                }
+                avoided.extend_from_slice(recent);
+                collection.choose(&avoided).map(Path::to_path_buf)
+            })
+            .ok_or_else(|| eyre!("no image could be chosen from {}", sources()))?;
+            let settings = collection.image_settings(&img_path);
+            let fit = settings.fit.unwrap_or(output.fit);
+            match decode(&img_path, fit) {
+                Ok(image) => break (img_path, image, (fit, settings)),
+                Err(err) => {
+                    warn!("{:?}", err);
+                    index::quarantine(&img_path);
+                    tries += 1;
+                }
+            }
+
+            ensure!(
+                tries < 5,
+                "tried reading an image from {} without success",
+                sources()
+            );
+        }
+    } else {
+        let img_path = output
+            .local_path()
+            .ok_or_else(|| eyre!("no path is set for the output"))?;
+        let settings = read_image_settings(output, &img_path)?;
+        let fit = settings.fit.unwrap_or(output.fit);
+        (img_path.clone(), decode(&img_path, fit)?, (fit, settings))
+    };
+    let (fit, settings) = fit;
+    // Decoded with the image of the schedule shown now
+    let schedule = if heic::is_heic(&img_path) {
+        heic::read_schedule(&img_path)?.map(|schedule| {
+            let frame = schedule.frame(Local::now());
+            (schedule, frame)
+        })
+    } else {
+        None
+    };
+
+    // Resizing keeps the original color depth. The decoded image is consumed here so that
+    // it is freed before the pixels are converted. Dithering needs the levels between those
+    // of 8 bits that scaling creates, which it only keeps in 16 bits.
+    let scale = |image: DynamicImage| {
+        let image = if output.dither != Dither::None && format == wl_shm::Format::Abgr8888 {
+            DynamicImage::ImageRgba16(widen(image))
+        } else {
+            image
+        };
+        metrics::time(Stage::Scale, || match span {
+            Some(span) => span_scaler(output, span, filter).scale(image, width, height),
+            None => fit_scaler(output, fit, filter).scale(image, width, height),
+        })
+    };
+    let image = scale(image);
+    let palette = extract_palette(&image, PALETTE_SIZE);
+    let color_transform = output
+        .icc_profile
+        .as_deref()
+        .map(ColorTransform::from_icc_path)
+        .transpose()?;
+    let convert = |image: DynamicImage| {
+        metrics::time(Stage::Convert, || {
+            to_buffer_data(
+                image,
+                format,
+                color_transform.as_ref(),
+                output.background(),
+                output.dither,
+            )
+        })
+    };
+    let data = convert(image);
+    // Placed like the day image, the path, to be blended with it
+    let night = output
+        .day_night
+        .as_ref()
+        .map(|day_night| -> Result<Vec<u8>> {
+            let (width, height) = decode_size(fit, width, height);
+            let reduce = |size: u32| (size / reduction).max(1);
+            let image = decoder.decode(&day_night.night_path, reduce(width), reduce(height))?;
+            Ok(convert(scale(image)))
+        })
+        .transpose()?;
+
+    let (data, ken_burns, parallax) = if ken_burns {
+        let mut ken_burns = KenBurns::new(data, width, height, format);
+        (
+            ken_burns.frame(output_size.0, output_size.1),
+            Some(ken_burns),
+            None,
+        )
+    } else if parallax.is_some() {
+        let mut parallax = Parallax::new(data, width, height);
+        (
+            parallax.frame(output_size.0, output_size.1),
+            None,
+            Some(parallax),
+        )
+    } else {
+        (data, None, None)
+    };
+
+    Ok(LoadedImage {
+        width: output_size.0,
+        height: output_size.1,
+        format,
+        data,
+        night,
+        schedule,
+        palette,
+        ken_burns,
+        parallax,
+        duration: settings.duration,
+        preview: false,
+        layers: None,
+        attribution: output.url().map(|url| remote::attribution(url, &img_path)),
+        path: img_path,
+    })
+}
+
 impl Drop for Surface {
     fn drop(&mut self) {
-        self.layer_surface.destroy();
+        duplicates::forget(&self.info.name);
+        // The role must be destroyed before the surface
+        match self.role.take() {
+            Some(Role::Layer(layer_surface)) => layer_surface.destroy(),
+            Some(Role::Window(window)) => drop(window),
+            None => {}
+        }
+        if let Some(viewport) = self.viewport.take() {
+            viewport.destroy();
+        }
         self.surface.destroy();
     }
 }
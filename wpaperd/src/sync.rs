@@ -0,0 +1,8 @@
+use std::sync::{Mutex, MutexGuard};
+
+/// Lock mutex, even if a thread panicked while holding it. Only for the data that is always
+/// left consistent, like the caches and counters shared by the outputs, for which that
+/// panic doesn't matter.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
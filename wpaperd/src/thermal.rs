@@ -0,0 +1,90 @@
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use log::info;
+
+const THERMAL_ZONES_DIR: &str = "/sys/class/thermal";
+/// Reading sysfs is cheap, but there is no need to do it on every frame
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Used for the zones that have no passive trip point, in millidegrees Celsius
+const DEFAULT_TRIP_TEMP: i64 = 90_000;
+/// How much a zone must cool below its trip point before restoring the full quality
+const HYSTERESIS: i64 = 5_000;
+
+/// Tells whether the system is thermally throttled, by comparing the temperature of every
+/// thermal zone with the point where the kernel starts throttling it
+#[derive(Default)]
+pub struct Thermal {
+    throttled: bool,
+    last_check: Option<Instant>,
+}
+
+impl Thermal {
+    pub fn throttled(&mut self) -> bool {
+        if self
+            .last_check
+            .is_some_and(|last_check| last_check.elapsed() < CHECK_INTERVAL)
+        {
+            return self.throttled;
+        }
+        self.last_check = Some(Instant::now());
+        let throttled = match headroom() {
+            Some(headroom) if headroom <= 0 => true,
+            Some(headroom) if headroom < HYSTERESIS => self.throttled,
+            _ => false,
+        };
+        if throttled != self.throttled {
+            info!(
+                "the system is {}, {} the drawing quality",
+                if throttled { "too hot" } else { "cool again" },
+                if throttled { "reducing" } else { "restoring" }
+            );
+            self.throttled = throttled;
+        }
+        throttled
+    }
+}
+
+/// The smallest distance of a thermal zone from its trip point, None if there are no zones
+fn headroom() -> Option<i64> {
+    fs::read_dir(THERMAL_ZONES_DIR)
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with("thermal_zone")
+        })
+        .filter_map(|entry| {
+            let zone = entry.path();
+            let temp = read_value(&zone.join("temp"))?;
+            Some(trip_temp(&zone) - temp)
+        })
+        .min()
+}
+
+/// The lowest temperature at which the kernel starts cooling the zone passively
+fn trip_temp(zone: &Path) -> i64 {
+    (0..)
+        .map_while(|i| {
+            let kind = fs::read_to_string(zone.join(format!("trip_point_{}_type", i))).ok()?;
+            Some((
+                kind,
+                read_value(&zone.join(format!("trip_point_{}_temp", i))),
+            ))
+        })
+        .filter(|(kind, _)| matches!(kind.trim(), "passive" | "hot"))
+        .filter_map(|(_, temp)| temp)
+        // Some drivers report trip points that are not set as 0
+        .filter(|&temp| temp > 0)
+        .min()
+        .unwrap_or(DEFAULT_TRIP_TEMP)
+}
+
+fn read_value(path: &Path) -> Option<i64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
@@ -0,0 +1,375 @@
+use std::{
+    fs,
+    io::Write,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    panic,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::UNIX_EPOCH,
+};
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use image::{GenericImageView, ImageOutputFormat};
+use lazy_static::lazy_static;
+use xdg::BaseDirectories;
+
+use crate::metrics;
+use crate::pipeline::decode_image;
+use crate::sync::lock;
+
+/// Thumbnails generated at the same time. Each takes a core and the memory of a full image
+/// while it is decoded, a large request must not take them all from the outputs.
+const WORKERS: usize = 4;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+lazy_static! {
+    /// The jobs of the workers, which are started with the first one
+    static ref JOBS: Mutex<Sender<Job>> = Mutex::new(start_workers());
+}
+
+/// The sizes of the freedesktop.org thumbnail specification, each with its own directory
+#[derive(Clone, Copy)]
+pub enum ThumbnailSize {
+    Normal,
+    Large,
+    XLarge,
+    XxLarge,
+}
+
+impl ThumbnailSize {
+    fn dir_name(self) -> &'static str {
+        match self {
+            ThumbnailSize::Normal => "normal",
+            ThumbnailSize::Large => "large",
+            ThumbnailSize::XLarge => "x-large",
+            ThumbnailSize::XxLarge => "xx-large",
+        }
+    }
+
+    fn pixels(self) -> u32 {
+        match self {
+            ThumbnailSize::Normal => 128,
+            ThumbnailSize::Large => 256,
+            ThumbnailSize::XLarge => 512,
+            ThumbnailSize::XxLarge => 1024,
+        }
+    }
+}
+
+impl FromStr for ThumbnailSize {
+    type Err = color_eyre::Report;
+
+    fn from_str(size: &str) -> Result<Self> {
+        Ok(match size {
+            "normal" => ThumbnailSize::Normal,
+            "large" => ThumbnailSize::Large,
+            "x-large" => ThumbnailSize::XLarge,
+            "xx-large" => ThumbnailSize::XxLarge,
+            _ => return Err(eyre!("invalid thumbnail size {}", size)),
+        })
+    }
+}
+
+fn start_workers() -> Sender<Job> {
+    let (tx, rx) = mpsc::channel::<Job>();
+    let rx = Arc::new(Mutex::new(rx));
+    for _ in 0..WORKERS {
+        let rx = rx.clone();
+        thread::spawn(move || run_jobs(&rx));
+    }
+    tx
+}
+
+fn run_jobs(rx: &Mutex<Receiver<Job>>) {
+    loop {
+        // The lock is only held while waiting for the next job
+        let job = match lock(rx).recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        job();
+    }
+}
+
+/// Generate the thumbnails of images on the workers, in the background, and give them to
+/// done in the same order once they are all ready
+pub fn generate(
+    images: Vec<PathBuf>,
+    size: ThumbnailSize,
+    done: impl FnOnce(Result<Vec<PathBuf>>) + Send + 'static,
+) {
+    let count = images.len();
+    let (tx, rx) = mpsc::channel();
+    {
+        let jobs = lock(&JOBS);
+        for (i, image) in images.into_iter().enumerate() {
+            let tx = tx.clone();
+            let job = move || {
+                let thumbnail = panic::catch_unwind(|| thumbnail(&image, size))
+                    .unwrap_or_else(|_| Err(eyre!("generating a thumbnail panicked")));
+                let _ = tx.send((i, thumbnail));
+            };
+            // The workers never exit, the channel cannot be closed
+            let _ = jobs.send(Box::new(job));
+        }
+    }
+    drop(tx);
+    // Waiting for the thumbnails doesn't take a worker
+    thread::spawn(move || {
+        let mut thumbnails: Vec<(usize, Result<PathBuf>)> = rx.iter().collect();
+        if thumbnails.len() < count {
+            return done(Err(eyre!("a thumbnail could not be generated")));
+        }
+        thumbnails.sort_unstable_by_key(|(i, _)| *i);
+        done(
+            thumbnails
+                .into_iter()
+                .map(|(_, thumbnail)| thumbnail)
+                .collect(),
+        );
+    });
+}
+
+/// The thumbnail of image, as described by the freedesktop.org thumbnail specification:
+/// $XDG_CACHE_HOME/thumbnails/<size>/<md5 of the URI>.png. It is only generated when it is
+/// missing or older than the image, so the thumbnails made by file managers are reused.
+pub fn thumbnail(image: &Path, size: ThumbnailSize) -> Result<PathBuf> {
+    let uri = file_uri(image)?;
+    let dir = BaseDirectories::new()?
+        .get_cache_home()
+        .join("thumbnails")
+        .join(size.dir_name());
+    let hash = to_hex(&md5(uri.as_bytes()));
+    let path = dir.join(format!("{}.png", hash));
+
+    let mtime = fs::metadata(image)
+        .and_then(|metadata| metadata.modified())
+        .with_context(|| format!("reading the modification time of {:?}", image))?;
+    let up_to_date = fs::metadata(&path)
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|thumbnail_mtime| thumbnail_mtime >= mtime);
+    metrics::cache_lookup("thumbnail", up_to_date);
+    if up_to_date {
+        return Ok(path);
+    }
+
+    let pixels = size.pixels();
+    let decoded = decode_image(image, pixels, pixels)?;
+    // Images smaller than the thumbnail are never scaled up
+    let decoded = if decoded.width() > pixels || decoded.height() > pixels {
+        decoded.thumbnail(pixels, pixels)
+    } else {
+        decoded
+    };
+    let mut png = Vec::new();
+    decoded
+        .write_to(&mut png, ImageOutputFormat::Png)
+        .with_context(|| format!("encoding the thumbnail of {:?}", image))?;
+    let mtime = mtime
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |mtime| mtime.as_secs());
+    let png = with_text_chunks(
+        &png,
+        &[("Thumb::URI", &uri), ("Thumb::MTime", &mtime.to_string())],
+    );
+
+    // The directories and the thumbnails are private, and written atomically
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("creating the directory {:?}", dir))?;
+    let tmp_path = dir.join(format!(".{}.wpaperd-{}.png", hash, std::process::id()));
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .and_then(|mut file| file.write_all(&png))
+        .with_context(|| format!("writing {:?}", tmp_path))?;
+    fs::rename(&tmp_path, &path).with_context(|| format!("writing {:?}", path))?;
+    Ok(path)
+}
+
+/// The file:// URI of path, escaped like GLib does, which the hash of the thumbnail is
+/// computed from
+fn file_uri(path: &Path) -> Result<String> {
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("resolving the path {:?}", path))?;
+    let mut uri = String::from("file://");
+    for &byte in path.as_os_str().as_encoded_bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@/".contains(&byte) {
+            uri.push(byte as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    Ok(uri)
+}
+
+/// Insert tEXt chunks, with the given keywords and values, after the header of a PNG file
+fn with_text_chunks(png: &[u8], texts: &[(&str, &str)]) -> Vec<u8> {
+    // The signature (8 bytes), then the IHDR chunk: length, type, 13 bytes of data and CRC
+    let header_end = 8 + 4 + 4 + 13 + 4;
+    let mut out = png[..header_end].to_vec();
+    for (keyword, text) in texts {
+        let mut chunk = b"tEXt".to_vec();
+        chunk.extend_from_slice(keyword.as_bytes());
+        chunk.push(0);
+        chunk.extend_from_slice(text.as_bytes());
+        out.extend_from_slice(&(chunk.len() as u32 - 4).to_be_bytes());
+        out.extend_from_slice(&chunk);
+        out.extend_from_slice(&crc32(&chunk).to_be_bytes());
+    }
+    out.extend_from_slice(&png[header_end..]);
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (!(crc & 1)).wrapping_add(1))
+        })
+    })
+}
+
+/// MD5 digest of data, as required by the thumbnail specification
+fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 64] = [
+        7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5,
+        9, 14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10,
+        15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+    ];
+    let constants: Vec<u32> = (0..64)
+        .map(|i| ((i as f64 + 1.0).sin().abs() * 4_294_967_296.0) as u32)
+        .collect();
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+    for block in message.chunks_exact(64) {
+        let words: Vec<u32> = block
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f
+                .wrapping_add(a)
+                .wrapping_add(constants[i])
+                .wrapping_add(words[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(SHIFTS[i]));
+        }
+        for (value, add) in state.iter_mut().zip([a, b, c, d]) {
+            *value = value.wrapping_add(add);
+        }
+    }
+
+    let mut digest = [0; 16];
+    for (bytes, value) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_le_bytes());
+    }
+    digest
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use image::{ImageBuffer, Rgba};
+
+    use super::*;
+
+    #[test]
+    fn md5_digests() {
+        assert_eq!(to_hex(&md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(to_hex(&md5(b"abc")), "900150983cd24fb0d6963f7d28e17f72");
+        // Longer than a block, the length is in the second one
+        assert_eq!(
+            to_hex(&md5(&[b'a'; 100])),
+            "36a92cc94a9e0fa21f625f8bfb007adf"
+        );
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn file_uris_are_escaped() {
+        let dir = env::temp_dir().join(format!("wpaper-thumbnails-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sunset 50%#été.jpg");
+        fs::write(&path, b"").unwrap();
+        let uri = file_uri(&dir.join(".").join("sunset 50%#été.jpg"));
+        let dir = dir.canonicalize().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        // The path is resolved first, and the bytes of é are escaped one by one
+        assert_eq!(
+            uri.unwrap(),
+            format!(
+                "file://{}/sunset%2050%25%23%C3%A9t%C3%A9.jpg",
+                dir.display()
+            )
+        );
+    }
+
+    #[test]
+    fn text_chunks_follow_the_header() {
+        let image = ImageBuffer::from_pixel(2, 2, Rgba([255u8, 0, 0, 255]));
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgba8(image)
+            .write_to(&mut png, ImageOutputFormat::Png)
+            .unwrap();
+        let out = with_text_chunks(&png, &[("Thumb::MTime", "42"), ("A", "")]);
+
+        let header_end = 33;
+        assert_eq!(out[..header_end], png[..header_end]);
+        let mut pos = header_end;
+        for (keyword, text) in [("Thumb::MTime", "42"), ("A", "")] {
+            let data = [keyword.as_bytes(), b"\0", text.as_bytes()].concat();
+            assert_eq!(out[pos..pos + 4], (data.len() as u32).to_be_bytes());
+            assert_eq!(&out[pos + 4..pos + 8], b"tEXt");
+            assert_eq!(out[pos + 8..pos + 8 + data.len()], data);
+            pos += 8 + data.len();
+            let crc = crc32(&out[pos - data.len() - 4..pos]);
+            assert_eq!(out[pos..pos + 4], crc.to_be_bytes());
+            pos += 4;
+        }
+        assert_eq!(out[pos..], png[header_end..]);
+        // Still a valid PNG file
+        let decoded = image::load_from_memory(&out).unwrap();
+        assert_eq!(decoded.dimensions(), (2, 2));
+    }
+}
@@ -0,0 +1,69 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::{eyre::WrapErr, Result};
+use log::warn;
+use xdg::BaseDirectories;
+
+/// When each image was last shown, in seconds since the epoch, by any output. It is kept in
+/// $XDG_DATA_HOME/wpaper/views.toml, so that the images not shown for a long time can be
+/// preferred across restarts.
+type Views = HashMap<PathBuf, u64>;
+
+/// Remember that image is shown now. The file is read again first, as other wpaperd
+/// processes might have written it meanwhile.
+pub fn shown(image: &Path) {
+    let res = views_path().and_then(|path| {
+        let mut views = load(&path)?;
+        views.insert(image.to_path_buf(), now());
+        // Other processes might read it meanwhile, replace it atomically
+        let tmp_path = path.with_extension(format!("toml.{}", std::process::id()));
+        fs::write(&tmp_path, toml::to_string(&views)?)
+            .with_context(|| format!("writing views file {:?}", tmp_path))?;
+        fs::rename(&tmp_path, &path).with_context(|| format!("writing views file {:?}", path))
+    });
+    if let Err(err) = res {
+        warn!("{:?}", err);
+    }
+}
+
+/// When each of images was last shown, None for those never shown
+pub fn last_shown(images: &[PathBuf]) -> Vec<Option<u64>> {
+    let views = views_path()
+        .and_then(|path| load(&path))
+        .unwrap_or_else(|err| {
+            warn!("{:?}", err);
+            Views::new()
+        });
+    images
+        .iter()
+        .map(|image| views.get(image).copied())
+        .collect()
+}
+
+fn load(path: &Path) -> Result<Views> {
+    match fs::read_to_string(path) {
+        Ok(content) => {
+            toml::from_str(&content).with_context(|| format!("parsing views file {:?}", path))
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Views::new()),
+        Err(err) => Err(err).with_context(|| format!("reading views file {:?}", path)),
+    }
+}
+
+fn views_path() -> Result<PathBuf> {
+    BaseDirectories::with_prefix("wpaper")?
+        .place_data_file("views.toml")
+        .context("creating the data directory")
+}
+
+/// In seconds since the epoch, like the times of the views
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
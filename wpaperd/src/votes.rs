@@ -0,0 +1,85 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{eyre::WrapErr, Result};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+pub struct ImageVotes {
+    pub accepted: u32,
+    pub rejected: u32,
+}
+
+impl ImageVotes {
+    pub fn score(&self) -> i64 {
+        i64::from(self.accepted) - i64::from(self.rejected)
+    }
+}
+
+/// Votes received by each image during the contests, persisted across restarts
+#[derive(Default, Deserialize, Serialize)]
+pub struct Votes {
+    #[serde(flatten)]
+    data: HashMap<PathBuf, ImageVotes>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl Votes {
+    /// Read the votes from path, starting with no votes if the file does not exist yet
+    pub fn new_from_path(path: Option<&Path>) -> Result<Self> {
+        let mut votes = match path {
+            Some(path) if path.exists() => toml::from_str(
+                &fs::read_to_string(path)
+                    .with_context(|| format!("reading votes file {:?}", path))?,
+            )
+            .with_context(|| format!("parsing votes file {:?}", path))?,
+            _ => Self::default(),
+        };
+        votes.path = path.map(Path::to_path_buf);
+        Ok(votes)
+    }
+
+    /// Read the votes again, as they might have been changed by another wpaperd process
+    pub fn reload(&mut self) -> Result<()> {
+        *self = Self::new_from_path(self.path.as_deref())?;
+        Ok(())
+    }
+
+    pub fn vote(&mut self, image: &Path, accept: bool) {
+        let votes = self.data.entry(image.to_path_buf()).or_default();
+        if accept {
+            votes.accepted += 1;
+        } else {
+            votes.rejected += 1;
+        }
+    }
+
+    /// All the voted images, from the most to the least liked
+    pub fn ranked(&self) -> Vec<(&Path, ImageVotes)> {
+        let mut ranked: Vec<_> = self
+            .data
+            .iter()
+            .map(|(image, votes)| (image.as_path(), *votes))
+            .collect();
+        ranked.sort_by(|(a_image, a), (b_image, b)| {
+            b.score()
+                .cmp(&a.score())
+                .then(b.accepted.cmp(&a.accepted))
+                .then(a_image.cmp(b_image))
+        });
+        ranked
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(path) = &self.path {
+            fs::write(path, toml::to_string(self)?)
+                .with_context(|| format!("writing votes file {:?}", path))?;
+        }
+
+        Ok(())
+    }
+}
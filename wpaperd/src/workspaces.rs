@@ -0,0 +1,321 @@
+use std::{
+    env,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    thread,
+};
+
+use calloop::channel::Sender;
+use color_eyre::{
+    eyre::{bail, ensure, eyre, WrapErr},
+    Result,
+};
+use log::{info, warn};
+
+/// The workspace shown on an output has changed: the name of the output and the one of the
+/// workspace
+pub type WorkspaceEvent = (String, String);
+
+/// Sway IPC message types
+const SWAY_GET_WORKSPACES: u32 = 1;
+const SWAY_SUBSCRIBE: u32 = 2;
+const SWAY_MAGIC: &[u8] = b"i3-ipc";
+
+/// Follow the workspaces shown on each output, when running under Sway or Hyprland. The
+/// workspaces already shown are sent first, then every change, from a separate thread
+/// which ends when the compositor goes away or nobody listens anymore.
+pub fn watch(tx: Sender<WorkspaceEvent>) {
+    let res = if let Some(socket) = env::var_os("SWAYSOCK") {
+        spawn("sway", move || watch_sway(PathBuf::from(socket), &tx))
+    } else if let Ok(signature) = env::var("HYPRLAND_INSTANCE_SIGNATURE") {
+        spawn("hyprland", move || watch_hyprland(&signature, &tx))
+    } else {
+        return;
+    };
+    if let Err(err) = res {
+        warn!("{:?}", err);
+    }
+}
+
+fn spawn(compositor: &'static str, f: impl FnOnce() -> Result<()> + Send + 'static) -> Result<()> {
+    info!("following the workspaces of {}", compositor);
+    thread::Builder::new()
+        .name(format!("{}-workspaces", compositor))
+        .spawn(move || {
+            if let Err(err) = f() {
+                warn!("following the workspaces of {}: {:?}", compositor, err);
+            }
+        })
+        .context("starting the workspace thread")?;
+    Ok(())
+}
+
+fn watch_sway(socket: PathBuf, tx: &Sender<WorkspaceEvent>) -> Result<()> {
+    let connect = || {
+        UnixStream::connect(&socket).with_context(|| format!("connecting to sway at {:?}", socket))
+    };
+    let mut stream = connect()?;
+    sway_send(&mut stream, SWAY_GET_WORKSPACES, "")?;
+    let (_, workspaces) = sway_read(&mut stream)?;
+    for workspace in json::array_items(&workspaces) {
+        if json::literal(workspace, &["visible"]) == Some("true") {
+            if let (Some(output), Some(name)) = (
+                json::string(workspace, &["output"]),
+                json::string(workspace, &["name"]),
+            ) {
+                send(tx, output, name)?;
+            }
+        }
+    }
+
+    // Subscribing turns the connection into a stream of events
+    let mut stream = connect()?;
+    sway_send(&mut stream, SWAY_SUBSCRIBE, r#"["workspace"]"#)?;
+    let (_, reply) = sway_read(&mut stream)?;
+    ensure!(
+        json::literal(&reply, &["success"]) == Some("true"),
+        "subscribing to the workspace events of sway: {}",
+        reply
+    );
+    loop {
+        let (_, event) = sway_read(&mut stream)?;
+        if json::string(&event, &["change"]).as_deref() == Some("focus") {
+            if let (Some(output), Some(name)) = (
+                json::string(&event, &["current", "output"]),
+                json::string(&event, &["current", "name"]),
+            ) {
+                send(tx, output, name)?;
+            }
+        }
+    }
+}
+
+fn sway_send(stream: &mut UnixStream, message_type: u32, payload: &str) -> io::Result<()> {
+    let mut message = SWAY_MAGIC.to_vec();
+    message.extend((payload.len() as u32).to_ne_bytes());
+    message.extend(message_type.to_ne_bytes());
+    message.extend(payload.as_bytes());
+    stream.write_all(&message)
+}
+
+/// The type and the payload of the next message from sway
+fn sway_read(stream: &mut UnixStream) -> Result<(u32, String)> {
+    let mut header = [0; 14];
+    stream
+        .read_exact(&mut header)
+        .context("reading from sway")?;
+    ensure!(header.starts_with(SWAY_MAGIC), "invalid message from sway");
+    let word = |at: usize| u32::from_ne_bytes(header[at..at + 4].try_into().unwrap());
+    let mut payload = vec![0; word(6) as usize];
+    stream
+        .read_exact(&mut payload)
+        .context("reading from sway")?;
+    Ok((word(10), String::from_utf8_lossy(&payload).into_owned()))
+}
+
+fn watch_hyprland(signature: &str, tx: &Sender<WorkspaceEvent>) -> Result<()> {
+    // Hyprland moved its sockets from /tmp to the runtime directory
+    let dirs = [
+        env::var_os("XDG_RUNTIME_DIR").map(|dir| PathBuf::from(dir).join("hypr")),
+        Some(PathBuf::from("/tmp/hypr")),
+    ];
+    let dir = dirs
+        .into_iter()
+        .flatten()
+        .map(|dir| dir.join(signature))
+        .find(|dir| dir.join(".socket2.sock").exists())
+        .ok_or_else(|| eyre!("the sockets of hyprland {} cannot be found", signature))?;
+
+    // Plain text replies, e.g. "Monitor DP-1 (ID 0):", then indented details including
+    // "active workspace: 3 (3)" and "focused: yes"
+    let mut stream =
+        UnixStream::connect(dir.join(".socket.sock")).context("connecting to hyprland")?;
+    stream.write_all(b"monitors")?;
+    let mut monitors = String::new();
+    stream.read_to_string(&mut monitors)?;
+    let mut output = None;
+    let mut focused = None;
+    for line in monitors.lines() {
+        let line = line.trim();
+        if let Some(rest) = line.strip_prefix("Monitor ") {
+            output = rest.split_whitespace().next().map(str::to_string);
+        } else if let (Some(workspace), Some(output)) =
+            (line.strip_prefix("active workspace: "), &output)
+        {
+            send(tx, output.clone(), hyprland_workspace_name(workspace))?;
+        } else if line == "focused: yes" {
+            focused = output.clone();
+        }
+    }
+
+    let stream =
+        UnixStream::connect(dir.join(".socket2.sock")).context("connecting to hyprland")?;
+    for line in BufReader::new(stream).lines() {
+        let line = line.context("reading from hyprland")?;
+        let (event, data) = match line.split_once(">>") {
+            Some(event) => event,
+            None => continue,
+        };
+        match event {
+            // The focused output shows another workspace
+            "workspace" => {
+                if let Some(output) = &focused {
+                    send(tx, output.clone(), data.to_string())?;
+                }
+            }
+            "focusedmon" => {
+                if let Some((output, workspace)) = data.split_once(',') {
+                    focused = Some(output.to_string());
+                    send(tx, output.to_string(), workspace.to_string())?;
+                }
+            }
+            "moveworkspace" => {
+                if let Some((workspace, output)) = data.split_once(',') {
+                    send(tx, output.to_string(), workspace.to_string())?;
+                }
+            }
+            _ => {}
+        }
+    }
+    bail!("hyprland closed the connection")
+}
+
+/// The name in "<id> (<name>)"
+fn hyprland_workspace_name(workspace: &str) -> String {
+    workspace
+        .split_once(" (")
+        .and_then(|(_, name)| name.strip_suffix(')'))
+        .unwrap_or(workspace)
+        .to_string()
+}
+
+fn send(tx: &Sender<WorkspaceEvent>, output: String, workspace: String) -> Result<()> {
+    // The main loop is gone, e.g. because the connection to the compositor has been lost
+    tx.send((output, workspace))
+        .map_err(|_| eyre!("the main loop has stopped"))
+}
+
+/// Just enough JSON to read the messages of sway: values are found by their path of keys
+/// and returned as they are written, without being decoded, except for strings
+mod json {
+    /// The string value at path, decoded
+    pub fn string(json: &str, path: &[&str]) -> Option<String> {
+        let value = value(json, path)?;
+        let (string, _) = parse_string(value)?;
+        Some(string)
+    }
+
+    /// The value at path, e.g. true or 42, as written
+    pub fn literal<'a>(json: &'a str, path: &[&str]) -> Option<&'a str> {
+        let value = value(json, path)?;
+        Some(&value[..skip_value(value)?])
+    }
+
+    /// The items of a top-level array, as written
+    pub fn array_items(json: &str) -> Vec<&str> {
+        let mut items = Vec::new();
+        let mut rest = match json.trim_start().strip_prefix('[') {
+            Some(rest) => rest,
+            None => return items,
+        };
+        loop {
+            rest = rest.trim_start();
+            if rest.starts_with(']') {
+                return items;
+            }
+            let len = match skip_value(rest) {
+                Some(len) => len,
+                None => return items,
+            };
+            items.push(&rest[..len]);
+            rest = rest[len..].trim_start();
+            rest = match rest.strip_prefix(',') {
+                Some(rest) => rest,
+                None => return items,
+            };
+        }
+    }
+
+    /// The JSON starting with the value at path
+    fn value<'a>(json: &'a str, path: &[&str]) -> Option<&'a str> {
+        let (key, path) = match path.split_first() {
+            Some(first) => first,
+            None => return Some(json.trim_start()),
+        };
+        let mut rest = json.trim_start().strip_prefix('{')?;
+        loop {
+            rest = rest.trim_start();
+            let (name, len) = parse_string(rest)?;
+            rest = rest[len..].trim_start().strip_prefix(':')?.trim_start();
+            if name == *key {
+                return value(rest, path);
+            }
+            rest = rest[skip_value(rest)?..].trim_start().strip_prefix(',')?;
+        }
+    }
+
+    /// The length of the value at the start of json
+    fn skip_value(json: &str) -> Option<usize> {
+        match json.chars().next()? {
+            '"' => parse_string(json).map(|(_, len)| len),
+            '{' | '[' => {
+                let mut depth = 0;
+                let mut chars = json.char_indices();
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '{' | '[' => depth += 1,
+                        '}' | ']' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                return Some(i + 1);
+                            }
+                        }
+                        '"' => {
+                            let (_, len) = parse_string(&json[i..])?;
+                            // Skip the rest of the string, its first quote is already read
+                            for _ in 1..json[i..i + len].chars().count() {
+                                chars.next();
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                None
+            }
+            _ => Some(
+                json.find(|c: char| c == ',' || c == '}' || c == ']' || c.is_whitespace())
+                    .unwrap_or(json.len()),
+            ),
+        }
+    }
+
+    /// The string at the start of json, decoded, and its length as written
+    fn parse_string(json: &str) -> Option<(String, usize)> {
+        let mut string = String::new();
+        let mut chars = json.strip_prefix('"')?.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Some((string, i + 2)),
+                '\\' => match chars.next()?.1 {
+                    'n' => string.push('\n'),
+                    't' => string.push('\t'),
+                    'r' => string.push('\r'),
+                    'b' => string.push('\u{8}'),
+                    'f' => string.push('\u{c}'),
+                    'u' => {
+                        let hex: String = (0..4)
+                            .filter_map(|_| chars.next())
+                            .map(|(_, c)| c)
+                            .collect();
+                        let code = u32::from_str_radix(&hex, 16).ok()?;
+                        string.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                    }
+                    c => string.push(c),
+                },
+                c => string.push(c),
+            }
+        }
+        None
+    }
+}